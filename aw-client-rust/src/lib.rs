@@ -8,10 +8,32 @@ use std::collections::HashMap;
 use std::vec::Vec;
 
 use chrono::{DateTime, Utc};
+use serde::Deserialize;
 use serde_json::Map;
 
 pub use aw_models::{Bucket, BucketMetadata, Event};
 
+/// A bucket as returned by aw-server
+///
+/// The metadata of [`Bucket`] isn't deserialized, so that clients can't set it when creating
+/// buckets, but the metadata computed by the server is needed by clients.
+#[derive(Deserialize)]
+struct ServerBucket {
+    #[serde(flatten)]
+    bucket: Bucket,
+    #[serde(default)]
+    metadata: BucketMetadata,
+}
+
+impl From<ServerBucket> for Bucket {
+    fn from(server_bucket: ServerBucket) -> Bucket {
+        Bucket {
+            metadata: server_bucket.metadata,
+            ..server_bucket.bucket
+        }
+    }
+}
+
 pub struct AwClient {
     client: reqwest::blocking::Client,
     pub baseurl: String,
@@ -43,18 +65,27 @@ impl AwClient {
 
     pub fn get_bucket(&self, bucketname: &str) -> Result<Bucket, reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}", self.baseurl, bucketname);
-        let bucket = self.client.get(url).send()?.error_for_status()?.json()?;
-        Ok(bucket)
+        let bucket: ServerBucket = self.client.get(url).send()?.error_for_status()?.json()?;
+        Ok(bucket.into())
     }
 
     pub fn get_buckets(&self) -> Result<HashMap<String, Bucket>, reqwest::Error> {
         let url = format!("{}/api/0/buckets/", self.baseurl);
-        self.client.get(url).send()?.json()
+        let buckets: HashMap<String, ServerBucket> =
+            self.client.get(url).send()?.error_for_status()?.json()?;
+        Ok(buckets
+            .into_iter()
+            .map(|(id, bucket)| (id, bucket.into()))
+            .collect())
     }
 
     pub fn create_bucket(&self, bucket: &Bucket) -> Result<(), reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}", self.baseurl, bucket.id);
-        self.client.post(url).json(bucket).send()?;
+        self.client
+            .post(url)
+            .json(bucket)
+            .send()?
+            .error_for_status()?;
         Ok(())
     }
 
@@ -78,6 +109,17 @@ impl AwClient {
         self.create_bucket(&bucket)
     }
 
+    /// Updates the data attributes of a bucket, other fields can't be changed
+    pub fn update_bucket(&self, bucket: &Bucket) -> Result<(), reqwest::Error> {
+        let url = format!("{}/api/0/buckets/{}", self.baseurl, bucket.id);
+        self.client
+            .put(url)
+            .json(bucket)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
     pub fn delete_bucket(&self, bucketname: &str) -> Result<(), reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}", self.baseurl, bucketname);
         self.client.delete(url).send()?;
@@ -109,7 +151,42 @@ impl AwClient {
             url.query_pairs_mut()
                 .append_pair("limit", s.to_string().as_str());
         };
-        self.client.get(url).send()?.json()
+        self.client.get(url).send()?.error_for_status()?.json()
+    }
+
+    /// Returns up to `limit` events between `start` and `stop`, oldest first, after the event
+    /// `after`
+    ///
+    /// For paging through large buckets: pass the timestamp and ID of the last event of a page
+    /// to get the next one.
+    pub fn get_events_page(
+        &self,
+        bucketname: &str,
+        start: Option<DateTime<Utc>>,
+        stop: Option<DateTime<Utc>>,
+        after: Option<(DateTime<Utc>, i64)>,
+        limit: u64,
+    ) -> Result<Vec<Event>, reqwest::Error> {
+        let mut url = reqwest::Url::parse(
+            format!("{}/api/0/buckets/{}/events/page", self.baseurl, bucketname).as_str(),
+        )
+        .unwrap();
+        if let Some(s) = start {
+            url.query_pairs_mut()
+                .append_pair("start", s.to_rfc3339().as_str());
+        };
+        if let Some(s) = stop {
+            url.query_pairs_mut()
+                .append_pair("end", s.to_rfc3339().as_str());
+        };
+        if let Some((timestamp, id)) = after {
+            url.query_pairs_mut()
+                .append_pair("after", timestamp.to_rfc3339().as_str())
+                .append_pair("after_id", id.to_string().as_str());
+        };
+        url.query_pairs_mut()
+            .append_pair("limit", limit.to_string().as_str());
+        self.client.get(url).send()?.error_for_status()?.json()
     }
 
     pub fn insert_event(&self, bucketname: &str, event: &Event) -> Result<(), reqwest::Error> {
@@ -125,7 +202,11 @@ impl AwClient {
         events: Vec<Event>,
     ) -> Result<(), reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}/events", self.baseurl, bucketname);
-        self.client.post(url).json(&events).send()?;
+        self.client
+            .post(url)
+            .json(&events)
+            .send()?
+            .error_for_status()?;
         Ok(())
     }
 
@@ -139,7 +220,11 @@ impl AwClient {
             "{}/api/0/buckets/{}/heartbeat?pulsetime={}",
             self.baseurl, bucketname, pulsetime
         );
-        self.client.post(url).json(&event).send()?;
+        self.client
+            .post(url)
+            .json(&event)
+            .send()?
+            .error_for_status()?;
         Ok(())
     }
 
@@ -153,7 +238,28 @@ impl AwClient {
     }
 
     pub fn get_event_count(&self, bucketname: &str) -> Result<i64, reqwest::Error> {
-        let url = format!("{}/api/0/buckets/{}/events/count", self.baseurl, bucketname);
+        self.get_event_count_range(bucketname, None, None)
+    }
+
+    /// Counts the events between `start` and `stop`, like [`AwClient::get_events`] returns them
+    pub fn get_event_count_range(
+        &self,
+        bucketname: &str,
+        start: Option<DateTime<Utc>>,
+        stop: Option<DateTime<Utc>>,
+    ) -> Result<i64, reqwest::Error> {
+        let mut url = reqwest::Url::parse(
+            format!("{}/api/0/buckets/{}/events/count", self.baseurl, bucketname).as_str(),
+        )
+        .unwrap();
+        if let Some(s) = start {
+            url.query_pairs_mut()
+                .append_pair("start", s.to_rfc3339().as_str());
+        };
+        if let Some(s) = stop {
+            url.query_pairs_mut()
+                .append_pair("end", s.to_rfc3339().as_str());
+        };
         let res = self.client.get(url).send()?.error_for_status()?.text()?;
         let count: i64 = match res.trim().parse() {
             Ok(count) => count,
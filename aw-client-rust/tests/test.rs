@@ -109,6 +109,34 @@ mod test {
         println!("Events: {events:?}");
         assert!(events[0].duration == Duration::seconds(1));
 
+        // The metadata computed by the server is returned, though clients can't set it
+        let bucket = client.get_bucket(&bucketname).unwrap();
+        assert_eq!(bucket.metadata.end, Some(event.timestamp));
+        let buckets = client.get_buckets().unwrap();
+        assert_eq!(buckets[&bucketname].metadata.end, Some(event.timestamp));
+
+        // Only the events within the time range are counted
+        let count_after = |start| client.get_event_count_range(&bucketname, Some(start), None);
+        assert_eq!(
+            count_after(event.timestamp - Duration::hours(1)).unwrap(),
+            1
+        );
+        assert_eq!(
+            count_after(event.timestamp + Duration::hours(1)).unwrap(),
+            0
+        );
+
+        // Events are paged through oldest first
+        let page = client
+            .get_events_page(&bucketname, None, None, None, 10)
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        let after = Some((page[0].timestamp, page[0].id.unwrap()));
+        let page = client
+            .get_events_page(&bucketname, None, None, after, 10)
+            .unwrap();
+        assert!(page.is_empty());
+
         client
             .delete_event(&bucketname, events[0].id.unwrap())
             .unwrap();
@@ -8,6 +8,7 @@ use chrono::Utc;
 use rusqlite::Connection;
 
 use serde_json::value::Value;
+use serde_json::Map;
 
 use aw_models::Bucket;
 use aw_models::BucketMetadata;
@@ -19,9 +20,9 @@ use rusqlite::types::ToSql;
 
 use super::DatastoreError;
 
-fn _get_db_version(conn: &Connection) -> i32 {
+fn _get_db_version(conn: &Connection) -> Result<i32, DatastoreError> {
     conn.pragma_query_value(None, "user_version", |row| row.get(0))
-        .unwrap()
+        .map_err(|err| DatastoreError::InternalError(format!("Failed to get db version: {err}")))
 }
 
 /*
@@ -183,7 +184,7 @@ impl DatastoreInstance {
         migrate_enabled: bool,
     ) -> Result<DatastoreInstance, DatastoreError> {
         let mut first_init = false;
-        let db_version = _get_db_version(conn);
+        let db_version = _get_db_version(conn)?;
 
         if migrate_enabled {
             first_init = _create_tables(conn, db_version);
@@ -208,6 +209,12 @@ impl DatastoreInstance {
         Ok(ds)
     }
 
+    /// Reloads the cached buckets, such as after a transaction was rolled back
+    pub fn reload_buckets(&mut self, conn: &Connection) -> Result<(), DatastoreError> {
+        self.buckets_cache.clear();
+        self.get_stored_buckets(conn)
+    }
+
     fn get_stored_buckets(&mut self, conn: &Connection) -> Result<(), DatastoreError> {
         let mut stmt = match conn.prepare(
             "
@@ -363,6 +370,8 @@ impl DatastoreInstance {
                 // Take out events from struct before caching
                 let events = bucket.events;
                 bucket.events = None;
+                // The bucket is empty until events are inserted, no matter the metadata given
+                bucket.metadata = BucketMetadata::default();
                 // Cache bucket
                 self.buckets_cache.insert(bucket.id.clone(), bucket.clone());
                 // Insert events
@@ -418,6 +427,30 @@ impl DatastoreInstance {
         }
     }
 
+    pub fn update_bucket_data(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        data: Map<String, Value>,
+    ) -> Result<(), DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+        let data_str = serde_json::to_string(&data).unwrap();
+        match conn.execute(
+            "UPDATE buckets SET data = ?1 WHERE id = ?2",
+            params![data_str, bucket.bid],
+        ) {
+            Ok(_) => {
+                if let Some(cached) = self.buckets_cache.get_mut(bucket_id) {
+                    cached.data = data;
+                }
+                Ok(())
+            }
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to update data of bucket {bucket_id}: {err}"
+            ))),
+        }
+    }
+
     pub fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
         let cached_bucket = self.buckets_cache.get(bucket_id);
         match cached_bucket {
@@ -430,6 +463,19 @@ impl DatastoreInstance {
         self.buckets_cache.clone()
     }
 
+    pub fn get_buckets_page(&self, after: Option<&str>, limit: usize) -> Vec<Bucket> {
+        let mut ids: Vec<&String> = self
+            .buckets_cache
+            .keys()
+            .filter(|id| after.map_or(true, |after| id.as_str() > after))
+            .collect();
+        ids.sort();
+        ids.into_iter()
+            .take(limit)
+            .map(|id| self.buckets_cache[id].clone())
+            .collect()
+    }
+
     pub fn insert_events(
         &mut self,
         conn: &Connection,
@@ -763,40 +809,97 @@ impl DatastoreInstance {
                 &endtime_filter_ns,
                 &limit,
             ],
-            |row| {
-                let id = row.get(0)?;
-                let mut starttime_ns: i64 = row.get(1)?;
-                let mut endtime_ns: i64 = row.get(2)?;
-                let data_str: String = row.get(3)?;
-
-                if starttime_ns < starttime_filter_ns {
-                    starttime_ns = starttime_filter_ns
-                }
-                if endtime_ns > endtime_filter_ns {
-                    endtime_ns = endtime_filter_ns
-                }
-                let duration_ns = endtime_ns - starttime_ns;
+            |row| event_from_row(row, starttime_filter_ns, endtime_filter_ns),
+        ) {
+            Ok(rows) => rows,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to map get_events SQL statement: {err}"
+                )))
+            }
+        };
+        for row in rows {
+            match row {
+                Ok(event) => list.push(event),
+                Err(err) => warn!("Corrupt event in bucket {}: {}", bucket_id, err),
+            };
+        }
 
-                let time_seconds: i64 = starttime_ns / 1_000_000_000;
-                let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
-                let data: serde_json::map::Map<String, Value> =
-                    serde_json::from_str(&data_str).unwrap();
+        Ok(list)
+    }
 
-                Ok(Event {
-                    id: Some(id),
-                    timestamp: DateTime::<Utc>::from_utc(
-                        NaiveDateTime::from_timestamp_opt(time_seconds, time_subnanos).unwrap(),
-                        Utc,
-                    ),
-                    duration: Duration::nanoseconds(duration_ns),
-                    data,
-                })
-            },
+    /// Returns a page of the events in a time range, oldest first
+    ///
+    /// Events are filtered and clamped to the time range like by `get_events`. Pages continue
+    /// after the timestamp and ID of the last event of the previous page (`after`), so events
+    /// with the same timestamp are neither skipped nor repeated between pages.
+    pub fn get_events_page(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        after: Option<(DateTime<Utc>, i64)>,
+        limit: u64,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+
+        let mut list = Vec::new();
+
+        let starttime_filter_ns: i64 = match starttime_opt {
+            Some(dt) => dt.timestamp_nanos(),
+            None => 0,
+        };
+        let endtime_filter_ns: i64 = match endtime_opt {
+            Some(dt) => dt.timestamp_nanos(),
+            None => std::i64::MAX,
+        };
+        if starttime_filter_ns > endtime_filter_ns {
+            warn!("Starttime in event query was lower than endtime!");
+            return Ok(list);
+        }
+        // Starts before all events, as IDs are positive
+        let (after_ns, after_id) = match after {
+            Some((timestamp, id)) => (timestamp.timestamp_nanos(), id),
+            None => (std::i64::MIN, 0),
+        };
+
+        // Paged by the clamped starttime, which is the timestamp of the returned events
+        let mut stmt = match conn.prepare(
+            "
+                SELECT id, starttime, endtime, data
+                FROM events
+                WHERE bucketrow = ?1
+                    AND endtime >= ?2
+                    AND starttime <= ?3
+                    AND (MAX(starttime, ?2) > ?4 OR (MAX(starttime, ?2) = ?4 AND id > ?5))
+                ORDER BY MAX(starttime, ?2) ASC, id ASC
+                LIMIT ?6
+            ;",
+        ) {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to prepare get_events_page SQL statement: {err}"
+                )))
+            }
+        };
+
+        let rows = match stmt.query_map(
+            [
+                &bucket.bid.unwrap(),
+                &starttime_filter_ns,
+                &endtime_filter_ns,
+                &after_ns,
+                &after_id,
+                &(limit as i64),
+            ],
+            |row| event_from_row(row, starttime_filter_ns, endtime_filter_ns),
         ) {
             Ok(rows) => rows,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
-                    "Failed to map get_events SQL statement: {err}"
+                    "Failed to map get_events_page SQL statement: {err}"
                 )))
             }
         };
@@ -968,4 +1071,64 @@ impl DatastoreInstance {
             },
         }
     }
+
+    pub fn integrity_check(&self, conn: &Connection) -> Result<Vec<String>, DatastoreError> {
+        let mut stmt = match conn.prepare("PRAGMA integrity_check") {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to prepare integrity_check SQL statement: {err}"
+                )))
+            }
+        };
+
+        let result = stmt.query_map([], |row| row.get::<usize, String>(0));
+        match result {
+            Ok(rows) => rows.collect::<Result<Vec<String>, _>>().map_err(|err| {
+                DatastoreError::InternalError(format!("Failed to read integrity_check row: {err}"))
+            }),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to run integrity_check: {err}"
+            ))),
+        }
+    }
+
+    pub fn vacuum(&self, conn: &Connection) -> Result<(), DatastoreError> {
+        conn.execute_batch("VACUUM")
+            .map_err(|err| DatastoreError::InternalError(format!("Failed to vacuum: {err}")))
+    }
+}
+
+/// Reads an event from a row of id, starttime, endtime and data, clamped to the filter range
+fn event_from_row(
+    row: &rusqlite::Row,
+    starttime_filter_ns: i64,
+    endtime_filter_ns: i64,
+) -> rusqlite::Result<Event> {
+    let id = row.get(0)?;
+    let mut starttime_ns: i64 = row.get(1)?;
+    let mut endtime_ns: i64 = row.get(2)?;
+    let data_str: String = row.get(3)?;
+
+    if starttime_ns < starttime_filter_ns {
+        starttime_ns = starttime_filter_ns
+    }
+    if endtime_ns > endtime_filter_ns {
+        endtime_ns = endtime_filter_ns
+    }
+    let duration_ns = endtime_ns - starttime_ns;
+
+    let time_seconds: i64 = starttime_ns / 1_000_000_000;
+    let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
+    let data: serde_json::map::Map<String, Value> = serde_json::from_str(&data_str).unwrap();
+
+    Ok(Event {
+        id: Some(id),
+        timestamp: DateTime::<Utc>::from_utc(
+            NaiveDateTime::from_timestamp_opt(time_seconds, time_subnanos).unwrap(),
+            Utc,
+        ),
+        duration: Duration::nanoseconds(duration_ns),
+        data,
+    })
 }
@@ -26,6 +26,8 @@ pub use self::worker::Datastore;
 pub enum DatastoreMethod {
     Memory(),
     File(String),
+    /// Opened with SQLite's `mode=ro`, so all writes fail
+    FileReadOnly(String),
 }
 
 /* TODO: Implement this as a proper error */
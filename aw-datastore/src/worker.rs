@@ -8,6 +8,7 @@ use chrono::Utc;
 
 use rusqlite::Connection;
 use rusqlite::DropBehavior;
+use rusqlite::OpenFlags;
 use rusqlite::Transaction;
 use rusqlite::TransactionBehavior;
 
@@ -15,6 +16,9 @@ use aw_models::Bucket;
 use aw_models::Event;
 use aw_models::KeyValue;
 
+use serde_json::value::Value;
+use serde_json::Map;
+
 use crate::DatastoreError;
 use crate::DatastoreInstance;
 use crate::DatastoreMethod;
@@ -48,6 +52,7 @@ pub enum Response {
     Empty(),
     Bucket(Bucket),
     BucketMap(HashMap<String, Bucket>),
+    BucketList(Vec<Bucket>),
     Event(Event),
     EventList(Vec<Event>),
     Count(i64),
@@ -62,6 +67,8 @@ pub enum Command {
     DeleteBucket(String),
     GetBucket(String),
     GetBuckets(),
+    GetBucketsPage(Option<String>, usize),
+    UpdateBucketData(String, Map<String, Value>),
     InsertEvents(String, Vec<Event>),
     Heartbeat(String, Event, f64),
     GetEvent(String, i64),
@@ -71,6 +78,13 @@ pub enum Command {
         Option<DateTime<Utc>>,
         Option<u64>,
     ),
+    GetEventsPage(
+        String,
+        Option<DateTime<Utc>>,
+        Option<DateTime<Utc>>,
+        Option<(DateTime<Utc>, i64)>,
+        u64,
+    ),
     GetEventCount(String, Option<DateTime<Utc>>, Option<DateTime<Utc>>),
     DeleteEventsById(String, Vec<i64>),
     ForceCommit(),
@@ -78,6 +92,11 @@ pub enum Command {
     GetKeyValue(String),
     GetKeysStarting(String),
     DeleteKeyValue(String),
+    IntegrityCheck(),
+    BeginTransaction(),
+    CommitTransaction(),
+    RollbackTransaction(),
+    Vacuum(),
     Close(),
 }
 
@@ -99,6 +118,8 @@ struct DatastoreWorker {
     quit: bool,
     uncommitted_events: usize,
     commit: bool,
+    /// Whether an explicit transaction was begun, see [`Datastore::transaction`]
+    in_transaction: bool,
     last_heartbeat: HashMap<String, Option<Event>>,
 }
 
@@ -113,24 +134,47 @@ impl DatastoreWorker {
             quit: false,
             uncommitted_events: 0,
             commit: false,
+            in_transaction: false,
             last_heartbeat: HashMap::new(),
         }
     }
 
-    fn work_loop(&mut self, method: DatastoreMethod) {
+    /// Opens the SQLite connection and loads the datastore from it
+    ///
+    /// Only read-only datastores fail with an error, the others panic like they always have.
+    fn open(method: &DatastoreMethod) -> Result<(Connection, DatastoreInstance), DatastoreError> {
+        let read_only = matches!(method, DatastoreMethod::FileReadOnly(_));
+
         // Open SQLite connection
-        let mut conn = match &method {
+        let conn = match method {
             DatastoreMethod::Memory() => {
                 Connection::open_in_memory().expect("Failed to create in-memory datastore")
             }
             DatastoreMethod::File(path) => {
                 Connection::open(path).expect("Failed to create datastore")
             }
+            DatastoreMethod::FileReadOnly(path) => {
+                Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(
+                    |err| {
+                        DatastoreError::InternalError(format!(
+                            "Failed to open read-only datastore {path}: {err}"
+                        ))
+                    },
+                )?
+            }
+        };
+        // Read-only datastores can't be migrated, so they have to be of the newest version already
+        let ds = match DatastoreInstance::new(&conn, !read_only) {
+            Ok(ds) => ds,
+            Err(err) if read_only => return Err(err),
+            Err(err) => panic!("Failed to open datastore: {err:?}"),
         };
-        let mut ds = DatastoreInstance::new(&conn, true).unwrap();
+        Ok((conn, ds))
+    }
 
+    fn work_loop(&mut self, mut conn: Connection, mut ds: DatastoreInstance, read_only: bool) {
         // Ensure legacy import
-        if self.legacy_import {
+        if self.legacy_import && !read_only {
             let transaction = match conn.transaction_with_behavior(TransactionBehavior::Immediate) {
                 Ok(transaction) => transaction,
                 Err(err) => {
@@ -148,19 +192,39 @@ impl DatastoreWorker {
         }
 
         // Start handling and respond to requests
+        // Immediate transactions take a write lock, which read-only connections can't
+        let behavior = if read_only {
+            TransactionBehavior::Deferred
+        } else {
+            TransactionBehavior::Immediate
+        };
+        // VACUUM can't run within a transaction, so vacuum requests are responded to after the
+        // current transaction is committed
+        let mut vacuum_response_sender = None;
+        // Close requests are responded to once the connection is closed, so that the database
+        // file is complete when the datastore is closed
+        let mut close_response_sender = None;
+        // Explicit transactions are begun by starting a new transaction right after committing
+        // the current one, and ended by committing or rolling back the transaction they're in
+        let mut begin_response_sender = None;
+        let mut end_response_sender = None;
+        let mut rollback = false;
         loop {
             let last_commit_time: DateTime<Utc> = Utc::now();
-            let mut tx: Transaction =
-                match conn.transaction_with_behavior(TransactionBehavior::Immediate) {
-                    Ok(tx) => tx,
-                    Err(err) => {
-                        error!("Unable to start transaction! {:?}", err);
-                        // Wait 1s before retrying
-                        std::thread::sleep(std::time::Duration::from_millis(1000));
-                        continue;
-                    }
-                };
+            let mut tx: Transaction = match conn.transaction_with_behavior(behavior) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    error!("Unable to start transaction! {:?}", err);
+                    // Wait 1s before retrying
+                    std::thread::sleep(std::time::Duration::from_millis(1000));
+                    continue;
+                }
+            };
             tx.set_drop_behavior(DropBehavior::Commit);
+            if let Some(response_sender) = begin_response_sender.take() {
+                self.in_transaction = true;
+                response_sender.respond(Ok(Response::Empty()));
+            }
 
             self.uncommitted_events = 0;
             self.commit = false;
@@ -174,9 +238,55 @@ impl DatastoreWorker {
                         break;
                     }
                 };
+                match (&request, self.in_transaction) {
+                    (Command::BeginTransaction(), false) => {
+                        begin_response_sender = Some(response_sender);
+                        break;
+                    }
+                    (Command::CommitTransaction(), true)
+                    | (Command::RollbackTransaction(), true) => {
+                        rollback = matches!(request, Command::RollbackTransaction());
+                        end_response_sender = Some(response_sender);
+                        break;
+                    }
+                    (Command::BeginTransaction(), true) => {
+                        response_sender.respond(Err(DatastoreError::InternalError(
+                            "Already in a transaction".to_string(),
+                        )));
+                        continue;
+                    }
+                    // Vacuuming commits the current transaction
+                    (Command::Vacuum(), true) => {
+                        response_sender.respond(Err(DatastoreError::InternalError(
+                            "Can't vacuum within a transaction".to_string(),
+                        )));
+                        continue;
+                    }
+                    (Command::CommitTransaction(), false)
+                    | (Command::RollbackTransaction(), false) => {
+                        response_sender.respond(Err(DatastoreError::InternalError(
+                            "Not in a transaction".to_string(),
+                        )));
+                        continue;
+                    }
+                    _ => (),
+                }
+                if let Command::Vacuum() = request {
+                    vacuum_response_sender = Some(response_sender);
+                    break;
+                }
+                if let Command::Close() = request {
+                    close_response_sender = Some(response_sender);
+                    self.quit = true;
+                    break;
+                }
                 let response = self.handle_request(request, &mut ds, &tx);
                 response_sender.respond(response);
 
+                // Explicit transactions are only committed once they end
+                if self.in_transaction {
+                    continue;
+                }
                 let now: DateTime<Utc> = Utc::now();
                 let commit_interval_passed: bool = (now - last_commit_time) > Duration::seconds(15);
                 if self.commit
@@ -187,18 +297,46 @@ impl DatastoreWorker {
                     break;
                 };
             }
-            debug!(
-                "Committing DB! Force commit {}, {} uncommitted events",
-                self.commit, self.uncommitted_events
-            );
-            match tx.commit() {
-                Ok(_) => (),
-                Err(err) => panic!("Failed to commit datastore transaction! {err}"),
+            // Transactions which weren't ended when the datastore is closed are rolled back too
+            if rollback || (self.quit && self.in_transaction) {
+                debug!("Rolling back DB transaction");
+                if let Err(err) = tx.rollback() {
+                    panic!("Failed to roll back datastore transaction! {err}");
+                }
+                // The caches may have changes which were rolled back
+                self.last_heartbeat.clear();
+                if let Err(err) = ds.reload_buckets(&conn) {
+                    error!("Failed to reload buckets after rolling back: {:?}", err);
+                }
+            } else {
+                debug!(
+                    "Committing DB! Force commit {}, {} uncommitted events",
+                    self.commit, self.uncommitted_events
+                );
+                match tx.commit() {
+                    Ok(_) => (),
+                    Err(err) => panic!("Failed to commit datastore transaction! {err}"),
+                }
+            }
+            rollback = false;
+            self.in_transaction = false;
+            if let Some(response_sender) = end_response_sender.take() {
+                response_sender.respond(Ok(Response::Empty()));
+            }
+            if let Some(response_sender) = vacuum_response_sender.take() {
+                let response = ds.vacuum(&conn).map(|()| Response::Empty());
+                response_sender.respond(response);
             }
             if self.quit {
                 break;
             };
         }
+        if let Err((_, err)) = conn.close() {
+            error!("Failed to close datastore connection: {err}");
+        }
+        if let Some(response_sender) = close_response_sender {
+            response_sender.respond(Ok(Response::Empty()));
+        }
         info!("DB Worker thread finished");
     }
 
@@ -228,6 +366,18 @@ impl DatastoreWorker {
                 Err(e) => Err(e),
             },
             Command::GetBuckets() => Ok(Response::BucketMap(ds.get_buckets())),
+            Command::GetBucketsPage(after, limit) => Ok(Response::BucketList(
+                ds.get_buckets_page(after.as_deref(), limit),
+            )),
+            Command::UpdateBucketData(bucketname, data) => {
+                match ds.update_bucket_data(tx, &bucketname, data) {
+                    Ok(_) => {
+                        self.commit = true;
+                        Ok(Response::Empty())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
             Command::InsertEvents(bucketname, events) => {
                 match ds.insert_events(tx, &bucketname, events) {
                     Ok(events) => {
@@ -259,6 +409,13 @@ impl DatastoreWorker {
                     Err(e) => Err(e),
                 }
             }
+            Command::GetEventsPage(bucketname, starttime_opt, endtime_opt, after, limit) => {
+                match ds.get_events_page(tx, &bucketname, starttime_opt, endtime_opt, after, limit)
+                {
+                    Ok(el) => Ok(Response::EventList(el)),
+                    Err(e) => Err(e),
+                }
+            }
             Command::GetEventCount(bucketname, starttime_opt, endtime_opt) => {
                 match ds.get_event_count(tx, &bucketname, starttime_opt, endtime_opt) {
                     Ok(n) => Ok(Response::Count(n)),
@@ -291,10 +448,17 @@ impl DatastoreWorker {
                 Ok(()) => Ok(Response::Empty()),
                 Err(e) => Err(e),
             },
-            Command::Close() => {
-                self.quit = true;
-                Ok(Response::Empty())
+            Command::IntegrityCheck() => match ds.integrity_check(tx) {
+                Ok(result) => Ok(Response::StringVec(result)),
+                Err(e) => Err(e),
+            },
+            Command::BeginTransaction()
+            | Command::CommitTransaction()
+            | Command::RollbackTransaction() => {
+                unreachable!("Transaction requests are handled by the work loop")
             }
+            Command::Vacuum() => unreachable!("Vacuum requests are handled by the work loop"),
+            Command::Close() => unreachable!("Close requests are handled by the work loop"),
         }
     }
 }
@@ -305,19 +469,42 @@ impl Datastore {
         Datastore::_new_internal(method, legacy_import)
     }
 
+    /// Opens an existing datastore without allowing any writes to it
+    ///
+    /// Fails if the datastore doesn't exist or isn't of the newest version, as it can't be
+    /// created or migrated.
+    pub fn new_readonly(dbpath: String) -> Result<Self, DatastoreError> {
+        let method = DatastoreMethod::FileReadOnly(dbpath);
+        Datastore::_try_new_internal(method, false)
+    }
+
     pub fn new_in_memory(legacy_import: bool) -> Self {
         let method = DatastoreMethod::Memory();
         Datastore::_new_internal(method, legacy_import)
     }
 
     fn _new_internal(method: DatastoreMethod, legacy_import: bool) -> Self {
+        match Datastore::_try_new_internal(method, legacy_import) {
+            Ok(ds) => ds,
+            Err(err) => panic!("Failed to open datastore: {err:?}"),
+        }
+    }
+
+    /// Opens the datastore before starting its worker, so that failing to open it is an error
+    /// instead of a worker which quits right away
+    fn _try_new_internal(
+        method: DatastoreMethod,
+        legacy_import: bool,
+    ) -> Result<Self, DatastoreError> {
+        let read_only = matches!(method, DatastoreMethod::FileReadOnly(_));
+        let (conn, ds) = DatastoreWorker::open(&method)?;
         let (requester, responder) =
             mpsc_requests::channel::<Command, Result<Response, DatastoreError>>();
         let _thread = thread::spawn(move || {
             let mut di = DatastoreWorker::new(responder, legacy_import);
-            di.work_loop(method);
+            di.work_loop(conn, ds, read_only);
         });
-        Datastore { requester }
+        Ok(Datastore { requester })
     }
 
     pub fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
@@ -341,6 +528,23 @@ impl Datastore {
         }
     }
 
+    /// Replaces the data attributes of a bucket
+    pub fn update_bucket_data(
+        &self,
+        bucket_id: &str,
+        data: Map<String, Value>,
+    ) -> Result<(), DatastoreError> {
+        let cmd = Command::UpdateBucketData(bucket_id.to_string(), data);
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Empty() => Ok(()),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
         let cmd = Command::GetBucket(bucket_id.to_string());
         let receiver = self.requester.request(cmd).unwrap();
@@ -367,6 +571,26 @@ impl Datastore {
         }
     }
 
+    /// Returns up to `limit` buckets, ordered by ID, with IDs after `after`
+    ///
+    /// For listing many buckets without copying all of them at once: pass the ID of the last
+    /// bucket of a page to get the next one.
+    pub fn get_buckets_page(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Bucket>, DatastoreError> {
+        let cmd = Command::GetBucketsPage(after.map(str::to_string), limit);
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::BucketList(bl) => Ok(bl),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn insert_events(
         &self,
         bucket_id: &str,
@@ -430,6 +654,35 @@ impl Datastore {
         }
     }
 
+    /// Returns up to `limit` events in the time range, oldest first, after the event `after`
+    ///
+    /// For paging through large buckets without loading all events at once: pass the timestamp
+    /// and ID of the last event of a page to get the next one.
+    pub fn get_events_page(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        after: Option<(DateTime<Utc>, i64)>,
+        limit: u64,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        let cmd = Command::GetEventsPage(
+            bucket_id.to_string(),
+            starttime_opt,
+            endtime_opt,
+            after,
+            limit,
+        );
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::EventList(el) => Ok(el),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn get_event_count(
         &self,
         bucket_id: &str,
@@ -515,6 +768,73 @@ impl Datastore {
         }
     }
 
+    /// Runs SQLite's integrity check on the database, returning an error describing the problems
+    /// found if it fails
+    ///
+    /// Also fails if the database couldn't be opened in the first place.
+    pub fn integrity_check(&self) -> Result<(), DatastoreError> {
+        let cmd = Command::IntegrityCheck();
+        let receiver = match self.requester.request(cmd) {
+            Ok(receiver) => receiver,
+            Err(_) => return Err(DatastoreError::MpscError),
+        };
+
+        match receiver.collect() {
+            Ok(Ok(Response::StringVec(result))) => {
+                if result == ["ok"] {
+                    Ok(())
+                } else {
+                    Err(DatastoreError::InternalError(result.join("\n")))
+                }
+            }
+            Ok(Ok(_)) => panic!("Invalid response"),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(DatastoreError::MpscError),
+        }
+    }
+
+    /// Runs `f` in a transaction, which is rolled back if `f` fails
+    ///
+    /// Either all or none of the changes made by `f` through this datastore are kept. Changes
+    /// made through clones of it while `f` runs are part of the transaction as well, as all of
+    /// them go through the same connection. Transactions can't be nested.
+    pub fn transaction<T, E: From<DatastoreError>>(
+        &self,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.transaction_request(Command::BeginTransaction())?;
+        match f() {
+            Ok(result) => {
+                self.transaction_request(Command::CommitTransaction())?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.transaction_request(Command::RollbackTransaction())?;
+                Err(e)
+            }
+        }
+    }
+
+    fn transaction_request(&self, cmd: Command) -> Result<(), DatastoreError> {
+        let receiver = self.requester.request(cmd).unwrap();
+        _unwrap_response(receiver)
+    }
+
+    /// Rebuilds the database file, reclaiming the space of deleted events
+    ///
+    /// Blocks until done, which can take a while for big databases.
+    pub fn vacuum(&self) -> Result<(), DatastoreError> {
+        let cmd = Command::Vacuum();
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Empty() => Ok(()),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
     // Should block until worker has stopped
     pub fn close(&self) {
         info!("Sending close request to database");
@@ -14,6 +14,7 @@ mod datastore_tests {
     use serde_json::json;
 
     use aw_datastore::Datastore;
+    use aw_datastore::DatastoreError;
 
     use aw_models::Bucket;
     use aw_models::BucketMetadata;
@@ -246,6 +247,72 @@ mod datastore_tests {
         assert_eq!(event_count, 1);
     }
 
+    #[test]
+    fn test_events_get_page() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        // Five events, of which the middle three have the same timestamp
+        let now = Utc::now();
+        let events: Vec<Event> = [0, 1, 1, 1, 2]
+            .iter()
+            .enumerate()
+            .map(|(n, seconds)| Event {
+                id: None,
+                timestamp: now + Duration::seconds(*seconds),
+                duration: Duration::seconds(1),
+                data: json_map! {"n": json!(n)},
+            })
+            .collect();
+        ds.insert_events(&bucket.id, &events).unwrap();
+
+        info!("Page through events two at a time");
+        let mut fetched = Vec::new();
+        let mut after = None;
+        loop {
+            let page = ds
+                .get_events_page(&bucket.id, None, None, after, 2)
+                .unwrap();
+            if page.is_empty() {
+                break;
+            }
+            let last = page.last().unwrap();
+            after = Some((last.timestamp, last.id.unwrap()));
+            fetched.extend(page);
+        }
+        // Oldest first, and every event exactly once
+        assert_eq!(fetched.len(), 5);
+        assert!(fetched
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        let mut ns: Vec<u64> = fetched
+            .iter()
+            .map(|e| e.data["n"].as_u64().unwrap())
+            .collect();
+        ns.sort();
+        assert_eq!(ns, vec![0, 1, 2, 3, 4]);
+
+        info!("Get a page with starttime filter");
+        // The first event is clamped to the start of the range
+        let start = now + Duration::milliseconds(500);
+        let page = ds
+            .get_events_page(&bucket.id, Some(start), None, None, 10)
+            .unwrap();
+        assert_eq!(page.len(), 5);
+        assert_eq!(page[0].timestamp, start);
+        let page = ds
+            .get_events_page(
+                &bucket.id,
+                Some(start),
+                None,
+                Some((page[0].timestamp, page[0].id.unwrap())),
+                10,
+            )
+            .unwrap();
+        assert_eq!(page.len(), 4);
+    }
+
     #[test]
     fn test_events_delete() {
         // Setup datastore
@@ -298,6 +365,27 @@ mod datastore_tests {
         }
     }
 
+    #[test]
+    fn test_integrity_check() {
+        let ds = Datastore::new_in_memory(false);
+        create_test_bucket(&ds);
+        ds.integrity_check().unwrap();
+    }
+
+    #[test]
+    fn test_empty_bucket_metadata_end() {
+        let ds = Datastore::new_in_memory(false);
+
+        // Metadata given on creation should be ignored, as the bucket has no events yet
+        let mut bucket = test_bucket();
+        bucket.metadata.end = Some(Utc::now());
+        ds.create_bucket(&bucket).unwrap();
+
+        let bucket_fetched = ds.get_bucket(&bucket.id).unwrap();
+        assert_eq!(bucket_fetched.metadata.start, None);
+        assert_eq!(bucket_fetched.metadata.end, None);
+    }
+
     #[test]
     fn test_bucket_metadata_start_end() {
         // Setup datastore
@@ -433,6 +521,196 @@ mod datastore_tests {
         }
     }
 
+    #[test]
+    fn test_datastore_readonly() {
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-readonly.db");
+        let db_path_str = db_path.to_str().unwrap().to_string();
+
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest-readonly.db file");
+        }
+
+        let bucket = test_bucket();
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(0),
+            data: json_map! {"key": json!("value")},
+        };
+        {
+            let ds = Datastore::new(db_path_str.clone(), false);
+            ds.create_bucket(&bucket).unwrap();
+            ds.insert_events(&bucket.id, &[e1.clone()]).unwrap();
+            ds.force_commit().unwrap();
+            ds.close();
+        }
+        // Give the worker some time to commit before opening the db again
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let ds = Datastore::new_readonly(db_path_str).unwrap();
+        // Reads work as usual
+        assert!(ds.get_buckets().unwrap().contains_key(&bucket.id));
+        assert_eq!(
+            ds.get_events(&bucket.id, None, None, None).unwrap().len(),
+            1
+        );
+
+        // Writes fail
+        let mut bucket2 = test_bucket();
+        bucket2.id = "testid2".to_string();
+        assert!(ds.create_bucket(&bucket2).is_err());
+        assert!(ds.insert_events(&bucket.id, &[e1]).is_err());
+        assert!(ds.delete_bucket(&bucket.id).is_err());
+        ds.force_commit().unwrap();
+        assert_eq!(
+            ds.get_events(&bucket.id, None, None, None).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_readonly_open_failed() {
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-readonly-invalid.db");
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest-readonly-invalid.db file");
+        }
+        let db_path_str = db_path.to_str().unwrap().to_string();
+
+        // Missing datastores can't be created
+        assert!(Datastore::new_readonly(db_path_str.clone()).is_err());
+
+        // Neither can files which aren't datastores be read
+        std::fs::write(&db_path, "not a datastore").unwrap();
+        assert!(Datastore::new_readonly(db_path_str).is_err());
+    }
+
+    #[test]
+    fn test_update_bucket_data() {
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-update-bucket.db");
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest-update-bucket.db file");
+        }
+
+        let ds = Datastore::new(db_path.to_str().unwrap().to_string(), false);
+        let bucket = create_test_bucket(&ds);
+        ds.update_bucket_data(&bucket.id, json_map! {"color": json!("red")})
+            .unwrap();
+        let fetched = ds.get_bucket(&bucket.id).unwrap();
+        assert_eq!(fetched.data.get("color"), Some(&json!("red")));
+        assert!(ds
+            .update_bucket_data("no-such-bucket", json_map! {})
+            .is_err());
+        ds.close();
+
+        // The update is persisted, not only cached
+        let ds = Datastore::new(db_path.to_str().unwrap().to_string(), false);
+        let fetched = ds.get_bucket(&bucket.id).unwrap();
+        assert_eq!(fetched.data.get("color"), Some(&json!("red")));
+        ds.close();
+    }
+
+    #[test]
+    fn test_close_commits() {
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-close.db");
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest-close.db file");
+        }
+
+        let ds = Datastore::new(db_path.to_str().unwrap().to_string(), false);
+        let bucket = create_test_bucket(&ds);
+        let e = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        ds.insert_events(&bucket.id, &[e]).unwrap();
+        ds.close();
+
+        // Close blocks until everything is committed, so the file can be used right away
+        let ds = Datastore::new_readonly(db_path.to_str().unwrap().to_string()).unwrap();
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+        ds.close();
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-vacuum.db");
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest-vacuum.db file");
+        }
+
+        let ds = Datastore::new(db_path.to_str().unwrap().to_string(), false);
+        let bucket = create_test_bucket(&ds);
+        let events: Vec<Event> = (0..1000)
+            .map(|i| Event {
+                id: None,
+                timestamp: Utc::now() + Duration::seconds(i),
+                duration: Duration::seconds(1),
+                data: json_map! {"key": json!("a value which takes up some space")},
+            })
+            .collect();
+        ds.insert_events(&bucket.id, &events).unwrap();
+
+        // Vacuum blocks until the events are committed and the file is rebuilt
+        ds.vacuum().unwrap();
+        let size_full = std::fs::metadata(&db_path).unwrap().len();
+
+        ds.delete_bucket(&bucket.id).unwrap();
+        ds.vacuum().unwrap();
+        let size_vacuumed = std::fs::metadata(&db_path).unwrap().len();
+        ds.close();
+
+        assert!(size_vacuumed < size_full);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+        let event = |seconds: i64| Event {
+            id: None,
+            timestamp: Utc::now() + Duration::seconds(seconds),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let first = event(0);
+        ds.insert_events(&bucket.id, &[first.clone()]).unwrap();
+
+        info!("Roll back a failed transaction");
+        let result: Result<(), DatastoreError> = ds.transaction(|| {
+            ds.insert_events(&bucket.id, &[event(10), event(20)])?;
+            // Not committed even if asked to, as the transaction isn't done yet
+            ds.force_commit()?;
+            Err(DatastoreError::InternalError("disk full".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+        // The cached bucket end is rolled back too
+        let cached = ds.get_bucket(&bucket.id).unwrap();
+        assert_eq!(cached.metadata.end, Some(first.calculate_endtime()));
+
+        info!("Commit a succeeding transaction");
+        let result: Result<(), DatastoreError> = ds.transaction(|| {
+            ds.insert_events(&bucket.id, &[event(10), event(20)])?;
+            // Transactions can't be nested
+            assert!(ds.transaction(|| Ok::<(), DatastoreError>(())).is_err());
+            Ok(())
+        });
+        result.unwrap();
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 3);
+    }
+
     #[test]
     fn test_datastore_reload() {
         // Create tmp datastore path
@@ -494,4 +772,29 @@ mod datastore_tests {
             );
         }
     }
+
+    #[test]
+    fn test_get_buckets_page() {
+        let ds = Datastore::new_in_memory(false);
+        for id in ["b", "d", "a", "c", "e"] {
+            let mut bucket = test_bucket();
+            bucket.id = id.to_string();
+            ds.create_bucket(&bucket).unwrap();
+        }
+
+        info!("Page through buckets two at a time");
+        let mut fetched = Vec::new();
+        let mut after: Option<String> = None;
+        loop {
+            let page = ds.get_buckets_page(after.as_deref(), 2).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 2);
+            after = Some(page.last().unwrap().id.clone());
+            fetched.extend(page.into_iter().map(|b| b.id));
+        }
+        // Ordered by ID, and every bucket exactly once
+        assert_eq!(fetched, vec!["a", "b", "c", "d", "e"]);
+    }
 }
@@ -70,39 +70,61 @@ pub fn bucket_new(
     }
 }
 
-#[get("/<bucket_id>/events?<start>&<end>&<limit>")]
-pub fn bucket_events_get(
+/// Update the data attributes of a bucket
+///
+/// Only `data` is updated, the other fields of the bucket can't be changed after it was created.
+/// The `id` of the body may be left out, but has to match the one in the path if given.
+#[put("/<bucket_id>", data = "<message>", format = "application/json")]
+pub fn bucket_update(
     bucket_id: String,
-    start: Option<String>,
-    end: Option<String>,
-    limit: Option<u64>,
+    message: Json<Bucket>,
     state: &State<ServerState>,
-) -> Result<Json<Vec<Event>>, HttpErrorJson> {
-    let starttime: Option<DateTime<Utc>> = match start {
-        Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
-            Ok(dt) => Some(dt.with_timezone(&Utc)),
-            Err(e) => {
-                let err_msg = format!(
-                    "Failed to parse starttime, datetime needs to be in rfc3339 format: {e}"
-                );
-                warn!("{}", err_msg);
-                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
-            }
-        },
-        None => None,
-    };
-    let endtime: Option<DateTime<Utc>> = match end {
+) -> Result<(), HttpErrorJson> {
+    let bucket = message.into_inner();
+    if !bucket.id.is_empty() && bucket.id != bucket_id {
+        let err_msg = format!(
+            "Bucket ID '{}' in the body doesn't match '{bucket_id}' in the path",
+            bucket.id
+        );
+        warn!("{}", err_msg);
+        return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+    }
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.update_bucket_data(&bucket_id, bucket.data) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Parses the `start` or `end` query parameter, `name` is used in the error message
+fn parse_time_param(
+    name: &str,
+    dt_str: Option<String>,
+) -> Result<Option<DateTime<Utc>>, HttpErrorJson> {
+    match dt_str {
         Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
-            Ok(dt) => Some(dt.with_timezone(&Utc)),
+            Ok(dt) => Ok(Some(dt.with_timezone(&Utc))),
             Err(e) => {
                 let err_msg =
-                    format!("Failed to parse endtime, datetime needs to be in rfc3339 format: {e}");
+                    format!("Failed to parse {name}, datetime needs to be in rfc3339 format: {e}");
                 warn!("{}", err_msg);
-                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+                Err(HttpErrorJson::new(Status::BadRequest, err_msg))
             }
         },
-        None => None,
-    };
+        None => Ok(None),
+    }
+}
+
+#[get("/<bucket_id>/events?<start>&<end>&<limit>")]
+pub fn bucket_events_get(
+    bucket_id: String,
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<u64>,
+    state: &State<ServerState>,
+) -> Result<Json<Vec<Event>>, HttpErrorJson> {
+    let starttime = parse_time_param("starttime", start)?;
+    let endtime = parse_time_param("endtime", end)?;
     let datastore = endpoints_get_lock!(state.datastore);
     let res = datastore.get_events(&bucket_id, starttime, endtime, limit);
     match res {
@@ -111,6 +133,40 @@ pub fn bucket_events_get(
     }
 }
 
+/// Returns up to `limit` events in the time range, oldest first, to page through them
+///
+/// Pages continue after the event with the timestamp `after` and the ID `after_id`, which are
+/// those of the last event of the previous page.
+#[get("/<bucket_id>/events/page?<start>&<end>&<after>&<after_id>&<limit>")]
+pub fn bucket_events_page(
+    bucket_id: String,
+    start: Option<String>,
+    end: Option<String>,
+    after: Option<String>,
+    after_id: Option<i64>,
+    limit: u64,
+    state: &State<ServerState>,
+) -> Result<Json<Vec<Event>>, HttpErrorJson> {
+    let starttime = parse_time_param("starttime", start)?;
+    let endtime = parse_time_param("endtime", end)?;
+    let after = match (parse_time_param("after", after)?, after_id) {
+        (Some(timestamp), Some(id)) => Some((timestamp, id)),
+        (None, None) => None,
+        _ => {
+            return Err(HttpErrorJson::new(
+                Status::BadRequest,
+                "after and after_id have to be given together".to_string(),
+            ))
+        }
+    };
+    let datastore = endpoints_get_lock!(state.datastore);
+    let res = datastore.get_events_page(&bucket_id, starttime, endtime, after, limit);
+    match res {
+        Ok(events) => Ok(Json(events)),
+        Err(err) => Err(err.into()),
+    }
+}
+
 // Needs unused parameter, otherwise there'll be a route collision
 // See: https://api.rocket.rs/master/rocket/struct.Route.html#resolving-collisions
 #[get("/<bucket_id>/events/<event_id>?<_unused..>")]
@@ -161,13 +217,17 @@ pub fn bucket_events_heartbeat(
     }
 }
 
-#[get("/<bucket_id>/events/count")]
+#[get("/<bucket_id>/events/count?<start>&<end>")]
 pub fn bucket_event_count(
     bucket_id: String,
+    start: Option<String>,
+    end: Option<String>,
     state: &State<ServerState>,
 ) -> Result<Json<u64>, HttpErrorJson> {
+    let starttime = parse_time_param("starttime", start)?;
+    let endtime = parse_time_param("endtime", end)?;
     let datastore = endpoints_get_lock!(state.datastore);
-    let res = datastore.get_event_count(&bucket_id, None, None);
+    let res = datastore.get_event_count(&bucket_id, starttime, endtime);
     match res {
         Ok(eventcount) => Ok(Json(eventcount as u64)),
         Err(err) => Err(err.into()),
@@ -117,10 +117,12 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
             "/api/0/buckets",
             routes![
                 bucket::bucket_new,
+                bucket::bucket_update,
                 bucket::bucket_delete,
                 bucket::buckets_get,
                 bucket::bucket_get,
                 bucket::bucket_events_get,
+                bucket::bucket_events_page,
                 bucket::bucket_events_create,
                 bucket::bucket_events_heartbeat,
                 bucket::bucket_event_count,
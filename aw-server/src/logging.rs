@@ -2,10 +2,45 @@ use std::fs;
 use std::path::PathBuf;
 
 use fern::colors::{Color, ColoredLevelConfig};
+use log::kv::{Key, Source, Value, VisitSource};
 
 use crate::dirs;
 
+/// Formats the structured fields of a log record as ` key=value` pairs
+fn format_key_values(record: &log::Record) -> String {
+    struct Collect(String);
+
+    impl<'kvs> VisitSource<'kvs> for Collect {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+            self.0.push_str(&format!(" {key}={value}"));
+            Ok(())
+        }
+    }
+
+    let mut collect = Collect(String::new());
+    // Collecting never fails
+    let _ = record.key_values().visit(&mut collect);
+    collect.0
+}
+
 pub fn setup_logger(testing: bool, verbose: bool) -> Result<(), fern::InitError> {
+    let default_log_level = if testing || verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    setup_logger_with_level(testing, default_log_level, false)
+}
+
+/// Sets up logging like [`setup_logger`], with the given level unless overridden by `LOG_LEVEL`
+///
+/// If `structured_fields` is set, the structured fields of log records are appended to their
+/// lines as ` key=value` pairs.
+pub fn setup_logger_with_level(
+    testing: bool,
+    default_log_level: log::LevelFilter,
+    structured_fields: bool,
+) -> Result<(), fern::InitError> {
     let mut logfile_path: PathBuf =
         dirs::get_log_dir().expect("Unable to get log dir to store logs in");
     fs::create_dir_all(logfile_path.clone()).expect("Unable to create folder for logs");
@@ -27,12 +62,6 @@ pub fn setup_logger(testing: bool, verbose: bool) -> Result<(), fern::InitError>
         .warn(Color::Yellow)
         .error(Color::Red);
 
-    let default_log_level = if testing || verbose {
-        log::LevelFilter::Debug
-    } else {
-        log::LevelFilter::Info
-    };
-
     let log_level = std::env::var("LOG_LEVEL").map_or(default_log_level, |level| {
         match level.to_lowercase().as_str() {
             "trace" => log::LevelFilter::Trace,
@@ -58,12 +87,18 @@ pub fn setup_logger(testing: bool, verbose: bool) -> Result<(), fern::InitError>
     dispatch
         // Formatting
         .format(move |out, message, record| {
+            let fields = if structured_fields {
+                format_key_values(record)
+            } else {
+                String::new()
+            };
             out.finish(format_args!(
-                "[{}][{}][{}]: {}",
+                "[{}][{}][{}]: {}{}",
                 chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
                 colors.color(record.level()),
                 record.target(),
                 message,
+                fields,
             ))
         })
         // Color and higher log levels to stdout
@@ -128,6 +128,55 @@ mod api_tests {
         assert_eq!(bucket.metadata.start, None);
         assert_eq!(bucket.metadata.end, None);
 
+        // Update data of bucket
+        res = client
+            .put("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "id": "id",
+                "type": "type",
+                "client": "client",
+                "hostname": "hostname",
+                "data": {"color": "red"}
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        res = client
+            .get("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let bucket: Bucket = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(bucket.data.get("color"), Some(&json!("red")));
+
+        // Update data of bucket with another ID in the body
+        res = client
+            .put("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "id": "other-id",
+                "type": "type",
+                "client": "client",
+                "hostname": "hostname",
+                "data": {"color": "blue"}
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        res = client
+            .get("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let bucket: Bucket = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(bucket.data.get("color"), Some(&json!("red")));
+
         // Get non-existing bucket
         res = client
             .get("/api/0/buckets/invalid_bucket")
@@ -247,6 +296,52 @@ mod api_tests {
             r#"[{"id":1,"timestamp":"2018-01-01T01:01:01Z","duration":2.0,"data":{}}]"#
         );
 
+        // Get eventcount within a time range, which only the heartbeat event is in
+        let count = |query: &str| {
+            let res = client
+                .get(format!("/api/0/buckets/id/events/count?{query}"))
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            (res.status(), res.into_string().unwrap())
+        };
+        assert_eq!(
+            count("start=2018-01-01T00:00:00Z&end=2018-01-02T00:00:00Z"),
+            (rocket::http::Status::Ok, "1".to_string())
+        );
+        assert_eq!(
+            count("start=2018-01-02T00:00:00Z"),
+            (rocket::http::Status::Ok, "0".to_string())
+        );
+        // Invalid times are rejected
+        assert_eq!(count("start=yesterday").0, rocket::http::Status::BadRequest);
+
+        // Get events a page at a time, continuing after the last event of the previous page
+        let page = |query: &str| {
+            let res = client
+                .get(format!("/api/0/buckets/id/events/page?{query}"))
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            (res.status(), res.into_string().unwrap())
+        };
+        assert_eq!(
+            page("limit=10"),
+            (
+                rocket::http::Status::Ok,
+                r#"[{"id":1,"timestamp":"2018-01-01T01:01:01Z","duration":2.0,"data":{}}]"#
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            page("after=2018-01-01T01:01:01Z&after_id=1&limit=10"),
+            (rocket::http::Status::Ok, "[]".to_string())
+        );
+        assert_eq!(
+            page("after=2018-01-01T01:01:01Z&limit=10").0,
+            rocket::http::Status::BadRequest
+        );
+
         // Delete event
         client
             .delete("/api/0/buckets/id/events/1")
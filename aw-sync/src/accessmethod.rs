@@ -7,11 +7,37 @@ use reqwest::StatusCode;
 use aw_datastore::{Datastore, DatastoreError};
 use aw_models::{Bucket, Event};
 
+use crate::error::SyncError;
+use crate::retry::{is_transient, with_retry_if, RetryPolicy};
+
 // This trait should be implemented by both AwClient and Datastore, unifying them under a single API
 pub trait AccessMethod: std::fmt::Debug {
     fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String>;
+    /// Returns the buckets in pages of at most `page_size`, ordered by ID
+    ///
+    /// Unlike [`AccessMethod::get_buckets`], implementations can page through the buckets, so
+    /// that callers only keeping some of them don't hold all of them in memory at once. The
+    /// default implementation doesn't, it fetches all buckets and splits them up.
+    fn get_buckets_paged<'a>(
+        &'a self,
+        page_size: usize,
+    ) -> Box<dyn Iterator<Item = Result<Vec<Bucket>, String>> + 'a> {
+        let mut buckets: Vec<Bucket> = match self.get_buckets() {
+            Ok(buckets) => buckets.into_values().collect(),
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        buckets.sort_by(|a, b| a.id.cmp(&b.id));
+        let pages: Vec<Vec<Bucket>> = buckets.chunks(page_size).map(|c| c.to_vec()).collect();
+        Box::new(pages.into_iter().map(Ok))
+    }
     fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError>;
     fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError>;
+    fn delete_bucket(&self, bucket_id: &str) -> Result<(), DatastoreError>;
+    /// Updates the data attributes of a bucket, other fields can't be changed
+    fn update_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError>;
+    /// Returns the events overlapping the time range, newest first
+    ///
+    /// Both bounds are inclusive: events ending at `start` or starting at `end` are included.
     fn get_events(
         &self,
         bucket_id: &str,
@@ -19,22 +45,90 @@ pub trait AccessMethod: std::fmt::Debug {
         end: Option<DateTime<Utc>>,
         limit: Option<u64>,
     ) -> Result<Vec<Event>, String>;
+    /// Returns the events in the time range in batches of at most `batch_size`, oldest first
+    ///
+    /// Unlike [`AccessMethod::get_events`], implementations can page through the events so that
+    /// not all of them are held in memory at once. The default implementation doesn't, it
+    /// fetches all events and splits them up.
+    fn get_events_batched<'a>(
+        &'a self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        batch_size: usize,
+    ) -> Box<dyn Iterator<Item = Result<Vec<Event>, String>> + 'a> {
+        split_into_batches(self.get_events(bucket_id, start, end, None), batch_size)
+    }
     fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String>;
-    fn get_event_count(&self, bucket_id: &str) -> Result<i64, String>;
+    fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String>;
+    /// Counts the events in a bucket which overlap the given time range
+    fn get_event_count_range(
+        &self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<i64, String>;
+    fn get_event_count(&self, bucket_id: &str) -> Result<i64, String> {
+        self.get_event_count_range(bucket_id, None, None)
+    }
     fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String>;
+    /// Like [`AccessMethod::heartbeat`], but retries transient failures with `policy`
+    ///
+    /// Heartbeats of the same event merge into it, so they are safe to repeat. Only requests to
+    /// servers fail transiently, so the default implementation doesn't retry.
+    fn heartbeat_retrying(
+        &self,
+        bucket_id: &str,
+        event: Event,
+        duration: f64,
+        _policy: &RetryPolicy,
+    ) -> Result<(), String> {
+        self.heartbeat(bucket_id, event, duration)
+    }
+    /// Whether this is an aw-server accessed over HTTP, which inserting into is rate limited
+    fn is_server(&self) -> bool {
+        false
+    }
+    /// Runs `f` in a transaction, so that either all or none of its writes are kept
+    ///
+    /// The default implementation just runs `f`, for access methods without transactions.
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<(), SyncError>) -> Result<(), SyncError> {
+        f()
+    }
     fn close(&self);
 }
 
 impl AccessMethod for Datastore {
     fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
-        Ok(Datastore::get_buckets(self).unwrap())
+        Datastore::get_buckets(self).map_err(|e| format!("{e:?}"))
+    }
+    fn get_buckets_paged<'a>(
+        &'a self,
+        page_size: usize,
+    ) -> Box<dyn Iterator<Item = Result<Vec<Bucket>, String>> + 'a> {
+        Box::new(BucketPages {
+            ds: self,
+            page_size,
+            after: None,
+            done: false,
+        })
     }
     fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
         Datastore::get_bucket(self, bucket_id)
     }
     fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
         Datastore::create_bucket(self, bucket)?;
-        self.force_commit().unwrap();
+        self.force_commit()?;
+        Ok(())
+    }
+    fn delete_bucket(&self, bucket_id: &str) -> Result<(), DatastoreError> {
+        Datastore::delete_bucket(self, bucket_id)?;
+        self.force_commit()?;
+        Ok(())
+    }
+    fn update_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+        Datastore::update_bucket_data(self, &bucket.id, bucket.data.clone())?;
+        self.force_commit()?;
         Ok(())
     }
     fn get_events(
@@ -44,43 +138,175 @@ impl AccessMethod for Datastore {
         end: Option<DateTime<Utc>>,
         limit: Option<u64>,
     ) -> Result<Vec<Event>, String> {
-        Ok(Datastore::get_events(self, bucket_id, start, end, limit).unwrap())
+        Datastore::get_events(self, bucket_id, start, end, limit).map_err(|e| format!("{e:?}"))
+    }
+    fn get_events_batched<'a>(
+        &'a self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        batch_size: usize,
+    ) -> Box<dyn Iterator<Item = Result<Vec<Event>, String>> + 'a> {
+        let bucket_id = bucket_id.to_string();
+        Box::new(EventPages::new(batch_size, move |after| {
+            self.get_events_page(&bucket_id, start, end, after, batch_size as u64)
+                .map_err(|e| format!("{e:?}"))
+        }))
     }
     fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String> {
-        Datastore::heartbeat(self, bucket_id, event, duration).unwrap();
-        self.force_commit().unwrap();
+        Datastore::heartbeat(self, bucket_id, event, duration).map_err(|e| format!("{e:?}"))?;
+        self.force_commit().map_err(|e| format!("{e:?}"))?;
         Ok(())
     }
     fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String> {
-        Datastore::insert_events(self, bucket_id, &events[..]).unwrap();
-        self.force_commit().unwrap();
+        Datastore::insert_events(self, bucket_id, &events[..]).map_err(|e| format!("{e:?}"))?;
+        self.force_commit().map_err(|e| format!("{e:?}"))?;
         Ok(())
     }
-    fn get_event_count(&self, bucket_id: &str) -> Result<i64, String> {
-        Ok(Datastore::get_event_count(self, bucket_id, None, None).unwrap())
+    fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String> {
+        Datastore::delete_events_by_id(self, bucket_id, event_ids).map_err(|e| format!("{e:?}"))?;
+        self.force_commit().map_err(|e| format!("{e:?}"))?;
+        Ok(())
+    }
+    fn get_event_count_range(
+        &self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<i64, String> {
+        Datastore::get_event_count(self, bucket_id, start, end).map_err(|e| format!("{e:?}"))
+    }
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<(), SyncError>) -> Result<(), SyncError> {
+        Datastore::transaction(self, f)
     }
     fn close(&self) {
         Datastore::close(self);
     }
 }
 
+/// Pages through the buckets of a datastore, see [`Datastore::get_buckets_page`]
+struct BucketPages<'a> {
+    ds: &'a Datastore,
+    page_size: usize,
+    /// ID of the last bucket returned
+    after: Option<String>,
+    done: bool,
+}
+
+impl Iterator for BucketPages<'_> {
+    type Item = Result<Vec<Bucket>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self
+            .ds
+            .get_buckets_page(self.after.as_deref(), self.page_size)
+        {
+            Ok(buckets) => {
+                // A short page is the last one
+                self.done = buckets.len() < self.page_size;
+                let last = buckets.last()?;
+                self.after = Some(last.id.clone());
+                Some(Ok(buckets))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(format!("{e:?}")))
+            }
+        }
+    }
+}
+
+/// Splits all events of a time range into batches, oldest first
+fn split_into_batches<'a>(
+    events: Result<Vec<Event>, String>,
+    batch_size: usize,
+) -> Box<dyn Iterator<Item = Result<Vec<Event>, String>> + 'a> {
+    let mut events = match events {
+        Ok(events) => events,
+        Err(e) => return Box::new(std::iter::once(Err(e))),
+    };
+    // Newest first, stably sorted so events with the same timestamp keep their order
+    events.reverse();
+    events.sort_by_key(|e| e.timestamp);
+    let batches: Vec<Vec<Event>> = events.chunks(batch_size).map(|c| c.to_vec()).collect();
+    Box::new(batches.into_iter().map(Ok))
+}
+
+type PageFetcher<'a> =
+    Box<dyn FnMut(Option<(DateTime<Utc>, i64)>) -> Result<Vec<Event>, String> + 'a>;
+
+/// Pages through the events of a bucket, see [`Datastore::get_events_page`]
+struct EventPages<'a> {
+    /// Fetches the page after the event with the given timestamp and ID
+    fetch_page: PageFetcher<'a>,
+    batch_size: usize,
+    /// Timestamp and ID of the last event returned
+    after: Option<(DateTime<Utc>, i64)>,
+    done: bool,
+}
+
+impl<'a> EventPages<'a> {
+    fn new(
+        batch_size: usize,
+        fetch_page: impl FnMut(Option<(DateTime<Utc>, i64)>) -> Result<Vec<Event>, String> + 'a,
+    ) -> EventPages<'a> {
+        EventPages {
+            fetch_page: Box::new(fetch_page),
+            batch_size,
+            after: None,
+            done: false,
+        }
+    }
+
+    /// Continues after `page`, the page returned last
+    fn advance(&mut self, page: &[Event]) -> Result<(), String> {
+        // A short page is the last one
+        self.done = page.len() < self.batch_size;
+        if let Some(last) = page.last() {
+            let id = last.id.ok_or("paged event has no ID")?;
+            self.after = Some((last.timestamp, id));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for EventPages<'_> {
+    type Item = Result<Vec<Event>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page = (self.fetch_page)(self.after).and_then(|events| {
+            self.advance(&events)?;
+            Ok(events)
+        });
+        match page {
+            Ok(events) if events.is_empty() => None,
+            Ok(events) => Some(Ok(events)),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 impl AccessMethod for AwClient {
     fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
-        Ok(AwClient::get_buckets(self).unwrap())
+        AwClient::get_buckets(self).map_err(|e| e.to_string())
     }
     fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
-        let bucket = AwClient::get_bucket(self, bucket_id);
-        match bucket {
+        match AwClient::get_bucket(self, bucket_id) {
             Ok(bucket) => Ok(bucket),
-            Err(e) => {
-                warn!("{:?}", e);
-                let code = e.status().unwrap();
-                if code == StatusCode::NOT_FOUND {
-                    Err(DatastoreError::NoSuchBucket(bucket_id.into()))
-                } else {
-                    panic!("Unexpected error");
-                }
+            // Such as when the bucket was deleted on the server meanwhile
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
+                Err(DatastoreError::NoSuchBucket(bucket_id.into()))
             }
+            Err(e) => Err(DatastoreError::InternalError(e.to_string())),
         }
     }
     fn get_events(
@@ -90,21 +316,88 @@ impl AccessMethod for AwClient {
         end: Option<DateTime<Utc>>,
         limit: Option<u64>,
     ) -> Result<Vec<Event>, String> {
-        Ok(AwClient::get_events(self, bucket_id, start, end, limit).unwrap())
+        AwClient::get_events(self, bucket_id, start, end, limit).map_err(|e| e.to_string())
+    }
+    fn get_events_batched<'a>(
+        &'a self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        batch_size: usize,
+    ) -> Box<dyn Iterator<Item = Result<Vec<Event>, String>> + 'a> {
+        let bucket = bucket_id.to_string();
+        let fetch_page = move |after| {
+            AwClient::get_events_page(self, &bucket, start, end, after, batch_size as u64)
+        };
+        let first_page = match fetch_page(None) {
+            Ok(page) => page,
+            // Servers of older versions can't page through events, so all of them are fetched
+            // at once instead (which fails the same for buckets which don't exist)
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
+                let events = AccessMethod::get_events(self, bucket_id, start, end, None);
+                return split_into_batches(events, batch_size);
+            }
+            Err(e) => return Box::new(std::iter::once(Err(e.to_string()))),
+        };
+        let mut pages = EventPages::new(batch_size, move |after| {
+            fetch_page(after).map_err(|e| e.to_string())
+        });
+        if let Err(e) = pages.advance(&first_page) {
+            return Box::new(std::iter::once(Err(e)));
+        }
+        if first_page.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+        Box::new(std::iter::once(Ok(first_page)).chain(pages))
     }
     fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String> {
         AwClient::insert_events(self, bucket_id, events).map_err(|e| e.to_string())
     }
-    fn get_event_count(&self, bucket_id: &str) -> Result<i64, String> {
-        Ok(AwClient::get_event_count(self, bucket_id).unwrap())
+    fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String> {
+        // The REST API deletes one event at a time
+        for event_id in event_ids {
+            AwClient::delete_event(self, bucket_id, event_id).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+    fn get_event_count_range(
+        &self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<i64, String> {
+        AwClient::get_event_count_range(self, bucket_id, start, end).map_err(|e| e.to_string())
     }
     fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
-        AwClient::create_bucket(self, bucket).unwrap();
-        Ok(())
+        AwClient::create_bucket(self, bucket)
+            .map_err(|e| DatastoreError::InternalError(e.to_string()))
+    }
+    fn delete_bucket(&self, bucket_id: &str) -> Result<(), DatastoreError> {
+        AwClient::delete_bucket(self, bucket_id)
+            .map_err(|e| DatastoreError::InternalError(e.to_string()))
+    }
+    fn update_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+        AwClient::update_bucket(self, bucket)
+            .map_err(|e| DatastoreError::InternalError(e.to_string()))
     }
     fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String> {
         AwClient::heartbeat(self, bucket_id, &event, duration).map_err(|e| format!("{e:?}"))
     }
+    fn heartbeat_retrying(
+        &self,
+        bucket_id: &str,
+        event: Event,
+        duration: f64,
+        policy: &RetryPolicy,
+    ) -> Result<(), String> {
+        with_retry_if(policy, "insert event", is_transient, || {
+            AwClient::heartbeat(self, bucket_id, &event, duration)
+        })
+        .map_err(|e| format!("{e:?}"))
+    }
+    fn is_server(&self) -> bool {
+        true
+    }
     fn close(&self) {
         // NOP
     }
@@ -0,0 +1,159 @@
+//! Consistency audit of the buckets in the sync folder, for `aw-sync audit`
+//!
+//! Catches misconfigurations which syncing itself can't tell apart from real data, such as two
+//! devices pushing the same bucket (cloned machines, or a `device_id` shared by accident) or a
+//! device whose clock is far ahead. Only the bucket metadata is read, so auditing is cheap even
+//! for big remotes.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::accessmethod::AccessMethod;
+use crate::crypt::open_remote;
+use crate::error::{io_context, SyncError};
+use crate::naming::BucketNameTemplate;
+use crate::sync::{bucket_origin, find_remotes, remote_device_id};
+use crate::version::filter_compatible_remotes;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditIssue {
+    /// Several remotes have the same bucket with overlapping time ranges, which can only happen
+    /// if more than one device recorded it at the same time
+    Overlap,
+    /// The bucket has events in the future
+    Future,
+}
+
+/// A suspicious bucket found by [`audit`]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditWarning {
+    pub issue: AuditIssue,
+    /// ID of the bucket on the device it was recorded on
+    pub bucket: String,
+    /// Device IDs of the remotes with the bucket
+    pub remotes: Vec<String>,
+    pub detail: String,
+}
+
+/// Time range a remote claims for a bucket
+struct Claim {
+    remote: String,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+}
+
+fn fmt_time(time: Option<DateTime<Utc>>) -> String {
+    time.map_or("-".to_string(), |t| {
+        t.format("%Y-%m-%d %H:%M:%S").to_string()
+    })
+}
+
+/// Audits the buckets of `remotes`, pairs of device IDs and their datastores
+///
+/// Buckets are grouped by the ID they have on the device they were recorded on, so the same
+/// bucket is found in remotes with different device IDs. Events ending more than `tolerance`
+/// after `now` are reported as in the future, to allow for some clock skew.
+pub fn audit_buckets(
+    remotes: &[(String, &dyn AccessMethod)],
+    names: &BucketNameTemplate,
+    now: DateTime<Utc>,
+    tolerance: Duration,
+) -> Result<Vec<AuditWarning>, SyncError> {
+    let mut warnings = Vec::new();
+    let mut claims: BTreeMap<String, Vec<Claim>> = BTreeMap::new();
+    for (remote, ds) in remotes {
+        for bucket in ds.get_buckets().map_err(SyncError::Access)?.values() {
+            let origin = bucket_origin(bucket, Some(remote));
+            let source_id = names.source_id(&bucket.id, origin).to_string();
+            let (start, end) = (bucket.metadata.start, bucket.metadata.end);
+            if let Some(end) = end.filter(|end| *end > now + tolerance) {
+                warnings.push(AuditWarning {
+                    issue: AuditIssue::Future,
+                    bucket: source_id.clone(),
+                    remotes: vec![remote.clone()],
+                    detail: format!(
+                        "'{}' has events until {}, is the clock of {origin} wrong?",
+                        bucket.id,
+                        fmt_time(Some(end))
+                    ),
+                });
+            }
+            claims.entry(source_id).or_default().push(Claim {
+                remote: remote.clone(),
+                start,
+                end,
+            });
+        }
+    }
+
+    for (bucket, mut claims) in claims {
+        claims.sort_by(|a, b| a.remote.cmp(&b.remote));
+        for (i, a) in claims.iter().enumerate() {
+            for b in &claims[i + 1..] {
+                // Empty buckets don't claim any time
+                let (Some(a_start), Some(a_end), Some(b_start), Some(b_end)) =
+                    (a.start, a.end, b.start, b.end)
+                else {
+                    continue;
+                };
+                if a_start < b_end && b_start < a_end {
+                    warnings.push(AuditWarning {
+                        issue: AuditIssue::Overlap,
+                        bucket: bucket.clone(),
+                        remotes: vec![a.remote.clone(), b.remote.clone()],
+                        detail: format!(
+                            "{} has it from {} to {}, {} from {} to {}",
+                            a.remote,
+                            fmt_time(a.start),
+                            fmt_time(a.end),
+                            b.remote,
+                            fmt_time(b.start),
+                            fmt_time(b.end)
+                        ),
+                    });
+                }
+            }
+        }
+    }
+    warnings.sort_by(|a, b| (&a.bucket, a.issue).cmp(&(&b.bucket, b.issue)));
+    Ok(warnings)
+}
+
+/// Audits the buckets of all remotes in the sync folder, including the one of this device
+///
+/// Encrypted remotes are decrypted with `passphrase`, remotes which can't be opened are skipped.
+pub fn audit(
+    sync_directory: &Path,
+    passphrase: Option<&str>,
+    names: &BucketNameTemplate,
+) -> Result<Vec<AuditWarning>, SyncError> {
+    let dbfiles =
+        find_remotes(sync_directory).map_err(|e| io_context(e, "read sync dir", sync_directory))?;
+    // Declared first so the copies are deleted after their datastores are closed
+    let mut plaintext_copies = Vec::new();
+    let mut datastores = Vec::new();
+    for path in filter_compatible_remotes(dbfiles) {
+        match open_remote(&path, passphrase) {
+            Ok((ds, copy)) => {
+                plaintext_copies.extend(copy);
+                datastores.push((remote_device_id(sync_directory, &path), ds));
+            }
+            Err(e) => warn!("Skipping remote {}: {e}", path.display()),
+        }
+    }
+
+    let remotes: Vec<(String, &dyn AccessMethod)> = datastores
+        .iter()
+        .map(|(device, ds)| (device.clone(), ds as &dyn AccessMethod))
+        .collect();
+    // Allows for clocks being slightly off, as usual between devices
+    let result = audit_buckets(&remotes, names, Utc::now(), Duration::minutes(5));
+    for (_, ds) in &datastores {
+        ds.close();
+    }
+    result
+}
@@ -0,0 +1,313 @@
+//! Config file for aw-sync
+//!
+//! Read from `~/.config/activitywatch/aw-sync/config.toml` (or the platform equivalent), so that
+//! scheduled runs don't need to pass everything on the command line. Options given on the command
+//! line override the values from the config file.
+//!
+//! Example:
+//!
+//! ```toml
+//! sync_directory = "/home/user/ActivityWatchSync"
+//! buckets_regex = "aw-watcher-(afk|window)_.*"
+//! # Salt for fields redacted by hashing, see [redact] below
+//! redact_salt = "keep this secret"
+//! # Encrypts the staging datastore, all devices have to use the same passphrase
+//! passphrase = "correct horse battery staple"
+//! # IDs of pulled buckets, "{id}-synced-from-{origin}" by default
+//! bucket_name_template = "{id}@{origin}"
+//!
+//! # Overrides for the device with hostname "laptop"
+//! [hosts.laptop]
+//! sync_directory = "/mnt/sync/ActivityWatch"
+//! # Keeps syncing to the folder of the previous aw-server installation
+//! device_id = "3ec4a1f2-8c3a-4b5e-9d3c-1f2e3d4c5b6a"
+//!
+//! # Events never to sync, keyed by bucket ID
+//! [[exclude."aw-watcher-window_laptop"]]
+//! field = "title"
+//! regex = "(?i)online banking"
+//!
+//! [[exclude."aw-watcher-window_laptop"]]
+//! field = "app"
+//! equals = "KeePassXC"
+//!
+//! # Fields to scrub before syncing, keyed by bucket ID and field
+//! # "replace" replaces the value with "REDACTED", "hash" with a hash salted with redact_salt
+//! [redact."aw-watcher-window_laptop"]
+//! title = "replace"
+//!
+//! [redact."aw-watcher-web-firefox"]
+//! url = "hash"
+//!
+//! # Replaces the hostname and device ID of this device by pseudonyms in what it pushes
+//! pseudonym_salt = "also keep this secret"
+//!
+//! # Fields to keep when syncing, keyed by bucket ID, the others are dropped (all for [])
+//! [keep_fields]
+//! "aw-watcher-window_laptop" = ["app", "title"]
+//!
+//! # Merges contiguous events with the same data when syncing, keyed by bucket ID and in seconds
+//! [pulsetime]
+//! "aw-watcher-afk_laptop" = 185.0
+//!
+//! # Drops events shorter than this when syncing, keyed by bucket ID and in seconds
+//! [min_duration]
+//! "aw-watcher-input_laptop" = 1.0
+//!
+//! # Pulls the buckets of a reinstalled device, which got a new device ID, into the same buckets
+//! # as before, keyed by the old device ID
+//! [origin_aliases]
+//! "1b6d3f2a-old" = "9c4e8a7d-new"
+//!
+//! # Runs after every successful push, with the summary of the pass in AW_SYNC_* variables
+//! post_hook = "notify-send aw-sync \"Pushed $AW_SYNC_EVENTS_PUSHED events\""
+//!
+//! # Syncs through a WebDAV server instead of a synchronized folder, see the store module
+//! [remote_store]
+//! kind = "webdav"
+//! url = "https://cloud.example.com/remote.php/dav/files/user/aw-sync"
+//! username = "user"
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::error::{io_context, SyncError};
+use crate::filter::{EventRule, EventRuleConfig};
+use crate::naming::BucketNameTemplate;
+use crate::redact::Redaction;
+use crate::store::RemoteStoreConfig;
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct SyncConfig {
+    /// Path of sync folder
+    pub sync_directory: Option<PathBuf>,
+    /// Bucket IDs to sync
+    pub buckets: Option<Vec<String>>,
+    /// Regex matching bucket IDs to sync, matched against the whole bucket ID
+    pub buckets_regex: Option<String>,
+    /// URLs of remote aw-server instances to push local buckets to
+    pub remote_servers: Option<Vec<String>>,
+    /// Overrides of the above, keyed by the hostname of the device they apply to
+    #[serde(default)]
+    pub hosts: HashMap<String, HostConfig>,
+    /// Rules for events to exclude from syncing, keyed by bucket ID
+    #[serde(default)]
+    pub exclude: HashMap<String, Vec<EventRuleConfig>>,
+    /// Data fields to redact before syncing, keyed by bucket ID and field
+    #[serde(default)]
+    pub redact: HashMap<String, HashMap<String, Redaction>>,
+    /// Salt for redacting fields by hashing, required if any are
+    pub redact_salt: Option<String>,
+    /// Salt for replacing the hostname and device ID by pseudonyms, see
+    /// [`SyncSpec::pseudonym_salt`]
+    ///
+    /// [`SyncSpec::pseudonym_salt`]: crate::sync::SyncSpec::pseudonym_salt
+    pub pseudonym_salt: Option<String>,
+    /// Data fields to keep when syncing, keyed by bucket ID
+    #[serde(default)]
+    pub keep_fields: HashMap<String, Vec<String>>,
+    /// Pulsetimes to merge events with, in seconds, keyed by bucket ID
+    #[serde(default)]
+    pub pulsetime: HashMap<String, f64>,
+    /// Minimum durations of events to sync, in seconds, keyed by bucket ID
+    #[serde(default)]
+    pub min_duration: HashMap<String, f64>,
+    /// Origins to pull into the buckets of another origin, see [`SyncSpec::origin_aliases`]
+    ///
+    /// [`SyncSpec::origin_aliases`]: crate::sync::SyncSpec::origin_aliases
+    #[serde(default)]
+    pub origin_aliases: HashMap<String, String>,
+    /// Passphrase to encrypt the staging datastore with, has to be the same on all devices
+    ///
+    /// The `AW_SYNC_PASSPHRASE` environment variable takes precedence.
+    pub passphrase: Option<String>,
+    /// Template of the IDs of pulled buckets, see [`BucketNameTemplate`]
+    pub bucket_name_template: Option<String>,
+    /// Device ID to use instead of the one of the local aw-server, see [`SyncSpec::device_id`]
+    ///
+    /// [`SyncSpec::device_id`]: crate::sync::SyncSpec::device_id
+    pub device_id: Option<String>,
+    /// Store to sync the staging datastores through instead of a synchronized folder
+    pub remote_store: Option<RemoteStoreConfig>,
+    /// Shell command to run after a successful push, see the hook module
+    pub post_hook: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct HostConfig {
+    pub sync_directory: Option<PathBuf>,
+    pub buckets: Option<Vec<String>>,
+    pub buckets_regex: Option<String>,
+    pub remote_servers: Option<Vec<String>>,
+    pub device_id: Option<String>,
+}
+
+/// Checks that a device ID given by the user can be used as the name of its folder
+///
+/// Device IDs are UUIDs usually, so only ASCII letters, digits, `-`, `_` and `.` are allowed.
+/// Hidden folders are skipped when looking for remotes, so they can't start with a `.`.
+pub fn validate_device_id(device_id: &str) -> Result<(), SyncError> {
+    let invalid =
+        |reason: &str| SyncError::Config(format!("invalid device ID '{device_id}': {reason}"));
+    if device_id.is_empty() || device_id.len() > 64 {
+        return Err(invalid("has to be 1 to 64 characters long"));
+    }
+    if device_id.starts_with('.') {
+        return Err(invalid("can't start with a '.'"));
+    }
+    if !device_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(invalid(
+            "only ASCII letters, digits, '-', '_' and '.' are allowed",
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the path of the config file
+pub fn default_config_path() -> Option<PathBuf> {
+    let dir = appdirs::user_config_dir(Some("activitywatch"), None, false).ok()?;
+    Some(dir.join("aw-sync").join("config.toml"))
+}
+
+/// Returns the default sync directory, `aw-sync` in the ActivityWatch data directory
+///
+/// `$XDG_DATA_HOME` takes precedence over the platform's data directory. The directory is
+/// created if it doesn't exist.
+pub fn default_sync_directory() -> Result<PathBuf, SyncError> {
+    let dir = data_directory()?.join("aw-sync");
+    fs::create_dir_all(&dir).map_err(|e| io_context(e, "create sync directory", &dir))?;
+    Ok(dir)
+}
+
+/// Returns the directory of the local sync state, `aw-sync-state` in the ActivityWatch data
+/// directory
+///
+/// Unlike the sync directory, it's never synced to other devices. The directory is created if it
+/// doesn't exist.
+pub fn default_state_directory() -> Result<PathBuf, SyncError> {
+    let dir = data_directory()?.join("aw-sync-state");
+    fs::create_dir_all(&dir).map_err(|e| io_context(e, "create state directory", &dir))?;
+    Ok(dir)
+}
+
+/// Returns the ActivityWatch data directory, `$XDG_DATA_HOME` taking precedence over the
+/// platform's data directory
+fn data_directory() -> Result<PathBuf, SyncError> {
+    let xdg_data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute());
+    match xdg_data_home {
+        Some(dir) => Ok(dir.join("activitywatch")),
+        None => appdirs::user_data_dir(Some("activitywatch"), None, false)
+            .map_err(|_| SyncError::Config("couldn't determine the data directory".to_string())),
+    }
+}
+
+impl SyncConfig {
+    /// Loads the config from a file, falling back to an empty config if it doesn't exist
+    pub fn load(path: &Path) -> Result<SyncConfig, SyncError> {
+        if !path.exists() {
+            return Ok(SyncConfig::default());
+        }
+        let content =
+            fs::read_to_string(path).map_err(|e| io_context(e, "read config file", path))?;
+        toml::from_str(&content)
+            .map_err(|e| SyncError::Config(format!("failed to parse {}: {e}", path.display())))
+    }
+
+    /// Returns the config with the overrides for the given host applied
+    pub fn for_host(&self, hostname: &str) -> SyncConfig {
+        let mut config = self.clone();
+        if let Some(host) = self.hosts.get(hostname) {
+            let host = host.clone();
+            config.sync_directory = host.sync_directory.or(config.sync_directory);
+            config.buckets = host.buckets.or(config.buckets);
+            config.buckets_regex = host.buckets_regex.or(config.buckets_regex);
+            config.remote_servers = host.remote_servers.or(config.remote_servers);
+            config.device_id = host.device_id.or(config.device_id);
+        }
+        config
+    }
+
+    /// Returns the template of the IDs of pulled buckets
+    pub fn bucket_names(&self) -> Result<BucketNameTemplate, SyncError> {
+        match &self.bucket_name_template {
+            Some(template) => BucketNameTemplate::parse(template),
+            None => Ok(BucketNameTemplate::default()),
+        }
+    }
+
+    /// Returns the exclusion rules, keyed by bucket ID
+    pub fn exclude_rules(&self) -> Result<HashMap<String, Vec<EventRule>>, SyncError> {
+        self.exclude
+            .iter()
+            .map(|(bucket_id, rules)| {
+                let rules = rules
+                    .iter()
+                    .map(EventRuleConfig::to_rule)
+                    .collect::<Result<_, _>>()?;
+                Ok((bucket_id.clone(), rules))
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), SyncError> {
+        if let Some(sync_directory) = &self.sync_directory {
+            if !sync_directory.is_dir() {
+                return Err(SyncError::Config(format!(
+                    "sync directory {} does not exist",
+                    sync_directory.display()
+                )));
+            }
+        }
+        if let Some(buckets_regex) = &self.buckets_regex {
+            Regex::new(buckets_regex)
+                .map_err(|e| SyncError::Config(format!("invalid buckets_regex: {e}")))?;
+        } else if self.buckets.as_ref().is_some_and(|b| b.is_empty()) {
+            return Err(SyncError::Config(
+                "buckets is empty, so nothing would be synced".to_string(),
+            ));
+        }
+        self.exclude_rules()?;
+        if let Some((bucket_id, _)) = self
+            .min_duration
+            .iter()
+            .find(|(_, min)| !min.is_finite() || **min < 0.0)
+        {
+            return Err(SyncError::Config(format!(
+                "min_duration of '{bucket_id}' has to be a number of seconds, at least 0"
+            )));
+        }
+        if let Some((origin, _)) = self
+            .origin_aliases
+            .iter()
+            .find(|(origin, alias)| origin == alias || self.origin_aliases.contains_key(*alias))
+        {
+            return Err(SyncError::Config(format!(
+                "origin_aliases of '{origin}' has to be an origin which isn't aliased itself"
+            )));
+        }
+        self.bucket_names()?;
+        if let Some(device_id) = &self.device_id {
+            validate_device_id(device_id)?;
+        }
+        let hashes = self
+            .redact
+            .values()
+            .any(|fields| fields.values().any(|r| *r == Redaction::Hash));
+        if hashes && self.redact_salt.as_ref().map_or(true, |s| s.is_empty()) {
+            return Err(SyncError::Config(
+                "redact_salt is required for redacting fields by hashing".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
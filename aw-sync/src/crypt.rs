@@ -0,0 +1,175 @@
+//! Encryption of staging datastores with a passphrase
+//!
+//! Meant for syncing through folders stored by untrusted parties, such as cloud storage. When a
+//! passphrase is set, the staging datastore of the device is encrypted as a whole before it is
+//! written to the sync folder, and encrypted remotes are decrypted to a temporary plaintext copy
+//! outside the sync folder before they are opened. The local aw-server datastore is not affected.
+//!
+//! All devices syncing through a folder have to use the same passphrase, as remotes encrypted
+//! with a different one can't be decrypted and are skipped. Remotes which aren't encrypted are
+//! still read as is, so devices can switch to encryption one at a time.
+//!
+//! The file format is a magic header, followed by the salt the key was derived from with
+//! Argon2id, the nonce, and the datastore encrypted with XChaCha20-Poly1305.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+use aw_datastore::Datastore;
+
+use crate::error::{io_context, SyncError};
+use crate::sync::create_datastore;
+
+/// Environment variable the passphrase is read from, takes precedence over the config file
+pub const PASSPHRASE_ENV: &str = "AW_SYNC_PASSPHRASE";
+
+const MAGIC: &[u8; 8] = b"AWSYNCE1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN;
+
+/// Returns true if the file exists and is an encrypted datastore
+pub fn is_encrypted(path: &Path) -> bool {
+    let mut magic = [0u8; MAGIC.len()];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok_and(|_| &magic == MAGIC)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, SyncError> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut_slice())
+        .map_err(|e| SyncError::Crypto(format!("failed to derive key: {e}")))?;
+    Ok(key)
+}
+
+/// Encrypts the datastore at `src` to `dest`
+pub fn encrypt_file(src: &Path, dest: &Path, passphrase: &str) -> Result<(), SyncError> {
+    let plaintext = fs::read(src).map_err(|e| io_context(e, "read", src))?;
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let payload = Payload {
+        msg: &plaintext,
+        aad: MAGIC,
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| SyncError::Crypto(format!("failed to encrypt {}", src.display())))?;
+
+    let mut encrypted = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    encrypted.extend_from_slice(MAGIC);
+    encrypted.extend_from_slice(&salt);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    fs::write(dest, encrypted).map_err(|e| io_context(e, "write", dest))
+}
+
+/// Decrypts the encrypted datastore at `src` to `dest`
+///
+/// `dest` is created readable by the current user only, and must not exist yet. Fails if `src`
+/// isn't encrypted, or was encrypted with a different passphrase.
+pub fn decrypt_file(src: &Path, dest: &Path, passphrase: &str) -> Result<(), SyncError> {
+    let encrypted = fs::read(src).map_err(|e| io_context(e, "read", src))?;
+    if encrypted.len() < HEADER_LEN || &encrypted[..MAGIC.len()] != MAGIC {
+        return Err(SyncError::Crypto(format!(
+            "{} is not an encrypted datastore",
+            src.display()
+        )));
+    }
+    let salt = &encrypted[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let nonce = XNonce::from_slice(&encrypted[MAGIC.len() + SALT_LEN..HEADER_LEN]);
+    let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, salt)?);
+    let payload = Payload {
+        msg: &encrypted[HEADER_LEN..],
+        aad: MAGIC,
+    };
+    let plaintext = cipher.decrypt(nonce, payload).map_err(|_| {
+        SyncError::Crypto(format!(
+            "failed to decrypt {}, was it encrypted with a different passphrase?",
+            src.display()
+        ))
+    })?;
+    write_private(dest, &plaintext).map_err(|e| io_context(e, "write", dest))
+}
+
+/// Writes `data` to a new file only the current user can read
+///
+/// Fails rather than following a symlink or overwriting a file someone else created at `path`.
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
+
+/// Plaintext copy of an encrypted datastore outside the sync folder, removed when dropped
+///
+/// Lives in a new directory in the temp dir with an unpredictable name, which only the current
+/// user can access, so neither the copy nor the journal files SQLite creates next to it can be
+/// read or replaced by other users. Has to be kept until the datastore opened from it is closed.
+#[derive(Debug)]
+pub struct PlaintextCopy {
+    path: PathBuf,
+    // Removes the copy along with the directory when dropped
+    _dir: tempfile::TempDir,
+}
+
+impl PlaintextCopy {
+    /// Creates the private directory for a copy of the datastore at `path`, without the copy
+    pub(crate) fn new(path: &Path) -> Result<PlaintextCopy, SyncError> {
+        let temp_dir = std::env::temp_dir();
+        let dir = tempfile::Builder::new()
+            .prefix("aw-sync-")
+            .tempdir_in(&temp_dir)
+            .map_err(|e| io_context(e, "create temporary directory in", &temp_dir))?;
+        let file = path.file_name().unwrap_or("remote.db".as_ref());
+        Ok(PlaintextCopy {
+            path: dir.path().join(file),
+            _dir: dir,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+}
+
+/// Decrypts the encrypted datastore at `path` to a plaintext copy
+pub fn decrypt_to_temp(path: &Path, passphrase: &str) -> Result<PlaintextCopy, SyncError> {
+    let copy = PlaintextCopy::new(path)?;
+    decrypt_file(path, copy.path(), passphrase)?;
+    Ok(copy)
+}
+
+pub(crate) fn missing_passphrase(path: &Path) -> SyncError {
+    SyncError::Config(format!(
+        "{} is encrypted, but no passphrase is set (set {PASSPHRASE_ENV} or passphrase in the \
+         config file)",
+        path.display()
+    ))
+}
+
+/// Opens a remote datastore read-only, decrypting it first if it's encrypted
+///
+/// Returns the plaintext copy along with the datastore if it was decrypted.
+pub fn open_remote(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<(Datastore, Option<PlaintextCopy>), SyncError> {
+    if !is_encrypted(path) {
+        return Ok((create_datastore(path)?, None));
+    }
+    let passphrase = passphrase.ok_or_else(|| missing_passphrase(path))?;
+    let copy = decrypt_to_temp(path, passphrase)?;
+    Ok((create_datastore(copy.path())?, Some(copy)))
+}
@@ -0,0 +1,169 @@
+//! Self-check of the sync setup, for `aw-sync doctor`
+//!
+//! Checks the things most often misconfigured when setting up syncing: the sync directory, the
+//! folder of this device in it, whether other devices sync to it, the local aw-server and the
+//! staging datastore. Every check is run even if earlier ones failed, unless it depends on them.
+
+use std::fs;
+use std::path::Path;
+
+use aw_client_rust::AwClient;
+
+use crate::crypt::open_remote;
+use crate::sync::{find_remotes_nonlocal, local_remote_dbfile};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    /// Not necessarily a problem, such as no other device having synced yet
+    Warn,
+    Fail,
+}
+
+/// Result of a single check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Check {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Checks the sync setup, including that the local aw-server is reachable through `client`
+///
+/// The sync directory is checked for `device_id` if given, else for the device ID of aw-server.
+pub fn doctor(
+    sync_directory: &Path,
+    client: &AwClient,
+    device_id: Option<&str>,
+    instance: Option<&str>,
+    db_filename: Option<&str>,
+    passphrase: Option<&str>,
+) -> Vec<Check> {
+    let name = "aw-server reachable";
+    match client.get_info() {
+        Ok(info) => {
+            let detail = match device_id {
+                Some(device_id) => {
+                    format!("device ID {}, syncing as {device_id}", info.device_id)
+                }
+                None => format!("device ID {}", info.device_id),
+            };
+            let mut checks = vec![Check::new(name, CheckStatus::Pass, detail)];
+            checks.extend(check_sync_directory(
+                sync_directory,
+                device_id.unwrap_or(&info.device_id),
+                instance,
+                db_filename,
+                passphrase,
+            ));
+            checks
+        }
+        Err(e) => vec![Check::new(
+            name,
+            CheckStatus::Fail,
+            format!("{e}, is aw-server running?"),
+        )],
+    }
+}
+
+/// Checks the sync directory of the device with ID `device_id`
+///
+/// Only reads the sync directory, apart from a temporary file to check it's writable.
+pub fn check_sync_directory(
+    sync_directory: &Path,
+    device_id: &str,
+    instance: Option<&str>,
+    db_filename: Option<&str>,
+    passphrase: Option<&str>,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let name = "sync directory exists";
+    if !sync_directory.is_dir() {
+        let detail = format!("{} is not a directory", sync_directory.display());
+        checks.push(Check::new(name, CheckStatus::Fail, detail));
+        return checks;
+    }
+    let detail = sync_directory.display().to_string();
+    checks.push(Check::new(name, CheckStatus::Pass, detail));
+
+    // Hidden, so remotes looking for dbs at the same time ignore it
+    let name = "sync directory writable";
+    let probe = sync_directory.join(format!(".aw-sync-doctor-{}", std::process::id()));
+    match fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe)) {
+        Ok(()) => checks.push(Check::new(name, CheckStatus::Pass, "")),
+        Err(e) => checks.push(Check::new(name, CheckStatus::Fail, e.to_string())),
+    }
+
+    let name = "remotes found";
+    match find_remotes_nonlocal(sync_directory, device_id, None) {
+        Ok(remotes) if remotes.is_empty() => checks.push(Check::new(
+            name,
+            CheckStatus::Warn,
+            "no other devices synced yet, is the folder synced to them?",
+        )),
+        Ok(remotes) => checks.push(Check::new(
+            name,
+            CheckStatus::Pass,
+            format!("{} remote dbs", remotes.len()),
+        )),
+        Err(e) => checks.push(Check::new(name, CheckStatus::Fail, e.to_string())),
+    }
+
+    let name = "device folder exists";
+    let mut device_dir = sync_directory.join(device_id);
+    if let Some(instance) = instance {
+        device_dir.push(instance);
+    }
+    if !device_dir.is_dir() {
+        let detail = format!(
+            "{} doesn't exist, nothing was pushed yet",
+            device_dir.display()
+        );
+        checks.push(Check::new(name, CheckStatus::Fail, detail));
+        return checks;
+    }
+    let detail = device_dir.display().to_string();
+    checks.push(Check::new(name, CheckStatus::Pass, detail));
+
+    let name = "staging datastore opens";
+    let dbfile = match local_remote_dbfile(sync_directory, device_id, instance, db_filename) {
+        Ok(dbfile) if dbfile.exists() => dbfile,
+        Ok(dbfile) => {
+            let detail = format!("{} doesn't exist", dbfile.display());
+            checks.push(Check::new(name, CheckStatus::Fail, detail));
+            return checks;
+        }
+        Err(e) => {
+            checks.push(Check::new(name, CheckStatus::Fail, e.to_string()));
+            return checks;
+        }
+    };
+    let check = match open_remote(&dbfile, passphrase) {
+        Ok((ds, _plaintext)) => {
+            let result = ds.integrity_check().and_then(|_| ds.get_buckets());
+            ds.close();
+            match result {
+                Ok(buckets) => Check::new(
+                    name,
+                    CheckStatus::Pass,
+                    format!("{} buckets in {}", buckets.len(), dbfile.display()),
+                ),
+                Err(e) => Check::new(name, CheckStatus::Fail, format!("{e:?}")),
+            }
+        }
+        Err(e) => Check::new(name, CheckStatus::Fail, e.to_string()),
+    };
+    checks.push(check);
+    checks
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use aw_datastore::DatastoreError;
+
+use crate::sync::SyncRunSummary;
+
+const MB: u64 = 1_000_000;
+
+#[derive(Debug)]
+pub enum SyncError {
+    /// Reading or writing the sync directory failed
+    Io(std::io::Error),
+    /// A staging datastore returned an error
+    Datastore(DatastoreError),
+    /// The local aw-server couldn't be reached or returned an error
+    Client(reqwest::Error),
+    /// A datastore or server accessed through an `AccessMethod` returned an error
+    Access(String),
+    /// (De)serializing a sync state or version file failed
+    Json(serde_json::Error),
+    /// A path in the sync directory isn't valid UTF-8
+    InvalidPath(PathBuf),
+    /// A remote was written by a newer, incompatible, version of aw-sync
+    Incompatible { found: u32, expected: u32 },
+    /// The local aw-server is older than the oldest version supported
+    IncompatibleServer { found: String, minimum: String },
+    /// The config file is invalid
+    Config(String),
+    /// Encrypting or decrypting a staging datastore failed
+    Crypto(String),
+    /// The deadline of a sync pass passed before it was done, with what was synced until then
+    Timeout(Box<SyncRunSummary>),
+    /// A sync pass was cancelled before it was done, with what was synced until then
+    Interrupted(Box<SyncRunSummary>),
+    /// Another sync pass holds the lock file at the path, see the lock module
+    Locked(PathBuf),
+    /// Listing, downloading or uploading files of a remote store failed
+    Store(String),
+    /// An aw-server JSON export to sync from is malformed
+    InvalidExport(String),
+    /// Pushing would leave less than the minimum free space on the disk of the sync folder, with
+    /// the free and needed space in bytes
+    DiskFull {
+        path: PathBuf,
+        available: u64,
+        required: u64,
+    },
+    /// The number of events of a destination bucket dropped while syncing into it, which syncing
+    /// never does. Only returned in strict mode, see [`SyncSpec::strict`](crate::SyncSpec::strict)
+    CountDecreased {
+        bucket: String,
+        before: i64,
+        after: i64,
+    },
+    /// A thread syncing with a remote in parallel to others panicked, with the panic message
+    Panicked { remote: String, message: String },
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Io(e) => write!(f, "{e}"),
+            SyncError::Datastore(e) => write!(f, "datastore error: {e:?}"),
+            SyncError::Client(e) => write!(f, "aw-server request failed: {e}"),
+            SyncError::Access(e) => write!(f, "{e}"),
+            SyncError::Json(e) => write!(f, "{e}"),
+            SyncError::InvalidPath(path) => {
+                write!(f, "path is not valid UTF-8: {}", path.display())
+            }
+            SyncError::Incompatible { found, expected } => write!(
+                f,
+                "written by a newer version of aw-sync (version {found}, expected at most {expected}), please upgrade"
+            ),
+            SyncError::IncompatibleServer { found, minimum } => write!(
+                f,
+                "aw-server {found} is too old, aw-sync needs at least {minimum}, please upgrade"
+            ),
+            SyncError::Config(msg) => write!(f, "invalid config: {msg}"),
+            SyncError::Crypto(msg) => write!(f, "{msg}"),
+            SyncError::Timeout(summary) => write!(
+                f,
+                "sync timed out after {:.0}s, {} buckets were synced before stopping",
+                summary.duration_secs, summary.buckets_synced
+            ),
+            SyncError::Interrupted(summary) => write!(
+                f,
+                "sync interrupted after {} buckets were synced",
+                summary.buckets_synced
+            ),
+            SyncError::Locked(path) => write!(
+                f,
+                "another aw-sync is already syncing, as it holds the lock {}",
+                path.display()
+            ),
+            SyncError::Store(msg) => write!(f, "remote store: {msg}"),
+            SyncError::InvalidExport(msg) => write!(f, "invalid aw-server export: {msg}"),
+            SyncError::DiskFull {
+                path,
+                available,
+                required,
+            } => write!(
+                f,
+                "not pushing, as only {} MB are free in {} and pushing needs {} MB to keep the \
+                minimum free space",
+                available / MB,
+                path.display(),
+                required / MB
+            ),
+            SyncError::CountDecreased {
+                bucket,
+                before,
+                after,
+            } => write!(
+                f,
+                "events of '{bucket}' dropped from {before} to {after} while syncing, something \
+                else is deleting events"
+            ),
+            SyncError::Panicked { remote, message } => {
+                write!(f, "pulling from {remote} panicked: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SyncError::Io(e) => Some(e),
+            SyncError::Client(e) => Some(e),
+            SyncError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SyncError {
+    fn from(e: std::io::Error) -> Self {
+        SyncError::Io(e)
+    }
+}
+
+impl From<DatastoreError> for SyncError {
+    fn from(e: DatastoreError) -> Self {
+        SyncError::Datastore(e)
+    }
+}
+
+impl From<reqwest::Error> for SyncError {
+    fn from(e: reqwest::Error) -> Self {
+        SyncError::Client(e)
+    }
+}
+
+impl From<serde_json::Error> for SyncError {
+    fn from(e: serde_json::Error) -> Self {
+        SyncError::Json(e)
+    }
+}
+
+/// Adds the path that was being accessed to an IO error
+pub(crate) fn io_context(e: std::io::Error, action: &str, path: &Path) -> SyncError {
+    SyncError::Io(std::io::Error::new(
+        e.kind(),
+        format!("failed to {action} {}: {e}", path.display()),
+    ))
+}
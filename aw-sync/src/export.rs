@@ -0,0 +1,59 @@
+//! Export of a remote's buckets to JSON, for `aw-sync export`
+//!
+//! Written in the export format of aw-server, so exports can be inspected to see exactly what a
+//! device contributed, or be imported into an aw-server as a backup of it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use aw_models::{BucketsExport, TryVec};
+
+use crate::accessmethod::AccessMethod;
+use crate::crypt::open_remote;
+use crate::error::{io_context, SyncError};
+
+/// Returns all buckets of `ds` along with their events
+pub fn export_buckets(ds: &dyn AccessMethod) -> Result<BucketsExport, SyncError> {
+    let mut buckets = HashMap::new();
+    for (bucket_id, mut bucket) in ds.get_buckets().map_err(SyncError::Access)? {
+        let events = ds
+            .get_events(&bucket_id, None, None, None)
+            .map_err(SyncError::Access)?;
+        bucket.events = Some(TryVec::new(events));
+        buckets.insert(bucket_id, bucket);
+    }
+    Ok(BucketsExport { buckets })
+}
+
+/// Exports the buckets of the remote datastore at `remote_path` to `out_path`
+///
+/// The remote is opened read-only, `passphrase` is needed if it's encrypted. Returns what was
+/// written, such as to report how many buckets and events were exported.
+pub fn export(
+    remote_path: &Path,
+    out_path: &Path,
+    passphrase: Option<&str>,
+) -> Result<BucketsExport, SyncError> {
+    // The datastore only logs failing to open it, so a wrong path is reported here instead
+    if !remote_path.is_file() {
+        return Err(io_context(
+            std::io::ErrorKind::NotFound.into(),
+            "export",
+            remote_path,
+        ));
+    }
+    let (ds, _plaintext) = open_remote(remote_path, passphrase)?;
+    let result = export_buckets(&ds);
+    ds.close();
+    let export = result?;
+
+    let file = File::create(out_path).map_err(|e| io_context(e, "write export", out_path))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &export)?;
+    writer
+        .flush()
+        .map_err(|e| io_context(e, "write export", out_path))?;
+    Ok(export)
+}
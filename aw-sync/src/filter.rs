@@ -0,0 +1,67 @@
+//! Exclusion of events from syncing by their data
+//!
+//! Meant for keeping sensitive events (such as windows with certain titles) from ever leaving
+//! the device. Rules are configured per bucket in the config file, see [`crate::SyncConfig`],
+//! and events matching any of the rules of their bucket are never written to the destination.
+
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use aw_models::Event;
+
+use crate::error::SyncError;
+
+/// A rule matching events by a field in their data
+#[derive(Debug, Clone)]
+pub enum EventRule {
+    /// Matches events where the field equals the value
+    Equals { field: String, value: Value },
+    /// Matches events where the field is a string matching the regex, anywhere in the string
+    Matches { field: String, regex: Regex },
+}
+
+impl EventRule {
+    pub fn matches(&self, event: &Event) -> bool {
+        match self {
+            EventRule::Equals { field, value } => event.data.get(field) == Some(value),
+            EventRule::Matches { field, regex } => event
+                .data
+                .get(field)
+                .and_then(|v| v.as_str())
+                .is_some_and(|v| regex.is_match(v)),
+        }
+    }
+}
+
+/// An [`EventRule`] as written in the config file
+///
+/// Exactly one of `equals` and `regex` has to be set.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct EventRuleConfig {
+    pub field: String,
+    pub equals: Option<Value>,
+    pub regex: Option<String>,
+}
+
+impl EventRuleConfig {
+    pub fn to_rule(&self) -> Result<EventRule, SyncError> {
+        let field = self.field.clone();
+        match (&self.equals, &self.regex) {
+            (Some(value), None) => Ok(EventRule::Equals {
+                field,
+                value: value.clone(),
+            }),
+            (None, Some(regex)) => {
+                let regex = Regex::new(regex).map_err(|e| {
+                    SyncError::Config(format!("invalid regex for field '{}': {e}", self.field))
+                })?;
+                Ok(EventRule::Matches { field, regex })
+            }
+            _ => Err(SyncError::Config(format!(
+                "rule for field '{}' needs exactly one of 'equals' and 'regex'",
+                self.field
+            ))),
+        }
+    }
+}
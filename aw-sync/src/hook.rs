@@ -0,0 +1,71 @@
+//! Command run after a successful push, for `aw-sync sync --post-hook`
+//!
+//! Lets other automation follow up on a sync pass, such as committing the sync folder to git or
+//! sending a notification. The command is run by the shell (`sh -c`, or `cmd /C` on Windows),
+//! with the summary of the pass in these environment variables:
+//!
+//! - `AW_SYNC_EVENTS_PUSHED`: number of new events pushed
+//! - `AW_SYNC_EVENTS_PULLED`: number of new events pulled, from all remotes
+//! - `AW_SYNC_BUCKETS_SYNCED`: number of buckets synced without errors
+//! - `AW_SYNC_ERRORS`: number of buckets which failed to sync
+//! - `AW_SYNC_DURATION_SECS`: how long the pass took, in seconds
+//! - `AW_SYNC_STAGING_DB`: path of the staging datastore pushed to
+//!
+//! A failing hook is logged, but doesn't fail the sync pass, as the pass itself succeeded.
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::sync::SyncRunSummary;
+
+/// Returns the environment variables the hook is run with
+fn hook_env(summary: &SyncRunSummary, staging_db: &Path) -> Vec<(&'static str, String)> {
+    vec![
+        ("AW_SYNC_EVENTS_PUSHED", summary.events_pushed.to_string()),
+        (
+            "AW_SYNC_EVENTS_PULLED",
+            summary.events_pulled.values().sum::<usize>().to_string(),
+        ),
+        ("AW_SYNC_BUCKETS_SYNCED", summary.buckets_synced.to_string()),
+        ("AW_SYNC_ERRORS", summary.errors.len().to_string()),
+        (
+            "AW_SYNC_DURATION_SECS",
+            format!("{:.3}", summary.duration_secs),
+        ),
+        (
+            "AW_SYNC_STAGING_DB",
+            staging_db.to_string_lossy().into_owned(),
+        ),
+    ]
+}
+
+/// Runs `command` with the summary of the pass, returning whether it succeeded
+///
+/// Waits for the command to exit, so long-running commands should background themselves.
+pub fn run_post_hook(command: &str, summary: &SyncRunSummary, staging_db: &Path) -> bool {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    info!("Running post-hook: {command}");
+    match shell
+        .arg(command)
+        .envs(hook_env(summary, staging_db))
+        .status()
+    {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            warn!("Post-hook '{command}' failed with {status}");
+            false
+        }
+        Err(e) => {
+            warn!("Failed to run post-hook '{command}': {e}");
+            false
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Remote aw-server instances accessed directly over HTTP
+//!
+//! Complements the staging datastores in the sync folder for setups with a central aw-server,
+//! which local buckets can be pushed to without a shared folder.
+
+use std::collections::HashMap;
+
+use aw_client_rust::AwClient;
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+
+use aw_datastore::DatastoreError;
+use aw_models::{Bucket, Event};
+
+use crate::accessmethod::AccessMethod;
+use crate::retry::{is_transient, with_retry_if, RetryPolicy};
+
+/// A remote aw-server, identified by its URL (such as `https://aw.example.com:5600`)
+pub struct HttpRemote {
+    client: AwClient,
+}
+
+impl HttpRemote {
+    pub fn new(url: &str) -> HttpRemote {
+        // The host and port are only used to build the base URL, which is replaced right away
+        let mut client = AwClient::new("127.0.0.1", "5600", "aw-sync");
+        client.baseurl = url.trim_end_matches('/').to_string();
+        HttpRemote { client }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.client.baseurl
+    }
+
+    pub fn get_info(&self) -> Result<aw_models::Info, reqwest::Error> {
+        self.client.get_info()
+    }
+}
+
+impl std::fmt::Debug for HttpRemote {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "HttpRemote(url={:?})", self.client.baseurl)
+    }
+}
+
+fn to_datastore_error(e: reqwest::Error) -> DatastoreError {
+    DatastoreError::InternalError(e.to_string())
+}
+
+impl AccessMethod for HttpRemote {
+    fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
+        self.client.get_buckets().map_err(|e| e.to_string())
+    }
+    fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
+        match self.client.get_bucket(bucket_id) {
+            Ok(bucket) => Ok(bucket),
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
+                Err(DatastoreError::NoSuchBucket(bucket_id.into()))
+            }
+            Err(e) => Err(to_datastore_error(e)),
+        }
+    }
+    fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+        self.client
+            .create_bucket(bucket)
+            .map_err(to_datastore_error)
+    }
+    fn delete_bucket(&self, bucket_id: &str) -> Result<(), DatastoreError> {
+        self.client
+            .delete_bucket(bucket_id)
+            .map_err(to_datastore_error)
+    }
+    fn update_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+        self.client
+            .update_bucket(bucket)
+            .map_err(to_datastore_error)
+    }
+    fn get_events(
+        &self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        limit: Option<u64>,
+    ) -> Result<Vec<Event>, String> {
+        self.client
+            .get_events(bucket_id, start, end, limit)
+            .map_err(|e| e.to_string())
+    }
+    fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String> {
+        self.client
+            .insert_events(bucket_id, events)
+            .map_err(|e| e.to_string())
+    }
+    fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String> {
+        for event_id in event_ids {
+            self.client
+                .delete_event(bucket_id, event_id)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+    fn get_event_count_range(
+        &self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<i64, String> {
+        self.client
+            .get_event_count_range(bucket_id, start, end)
+            .map_err(|e| e.to_string())
+    }
+    fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String> {
+        self.client
+            .heartbeat(bucket_id, &event, duration)
+            .map_err(|e| e.to_string())
+    }
+    fn heartbeat_retrying(
+        &self,
+        bucket_id: &str,
+        event: Event,
+        duration: f64,
+        policy: &RetryPolicy,
+    ) -> Result<(), String> {
+        with_retry_if(policy, "insert event", is_transient, || {
+            self.client.heartbeat(bucket_id, &event, duration)
+        })
+        .map_err(|e| e.to_string())
+    }
+    fn is_server(&self) -> bool {
+        true
+    }
+    fn close(&self) {
+        // NOP
+    }
+}
@@ -0,0 +1,165 @@
+//! aw-server JSON exports as a sync source, for `aw-sync import`
+//!
+//! Lets the history of a device be brought into syncing from an export of its aw-server (such as
+//! an old aw-server being migrated from), without importing it into a running aw-server first.
+//! The export is loaded into memory and is read-only, it's only meant to be pushed from.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use aw_datastore::DatastoreError;
+use aw_models::{Bucket, Event};
+
+use crate::accessmethod::AccessMethod;
+use crate::error::{io_context, SyncError};
+
+const READ_ONLY: &str = "aw-server exports are read-only";
+
+/// The buckets and events of an aw-server JSON export
+#[derive(Debug, Clone, Default)]
+pub struct JsonExport {
+    buckets: HashMap<String, Bucket>,
+    /// Events of each bucket, oldest first
+    events: HashMap<String, Vec<Event>>,
+}
+
+impl JsonExport {
+    /// Loads the export at `path`, such as a file exported from the settings of aw-server
+    pub fn load(path: &Path) -> Result<JsonExport, SyncError> {
+        let content = fs::read_to_string(path).map_err(|e| io_context(e, "read export", path))?;
+        JsonExport::from_json(&content).map_err(|e| match e {
+            SyncError::InvalidExport(msg) => {
+                SyncError::InvalidExport(format!("{}: {msg}", path.display()))
+            }
+            e => e,
+        })
+    }
+
+    /// Parses an export, with all of its events
+    ///
+    /// Unlike when importing into aw-server, events which can't be parsed are an error instead of
+    /// being dropped, so a malformed export isn't only partially synced.
+    pub fn from_json(content: &str) -> Result<JsonExport, SyncError> {
+        let invalid = SyncError::InvalidExport;
+        let root: Value = serde_json::from_str(content).map_err(|e| invalid(e.to_string()))?;
+        let buckets = match root.get("buckets") {
+            Some(Value::Object(buckets)) => buckets,
+            _ => {
+                return Err(invalid(
+                    "expected an object with a \"buckets\" object".into(),
+                ))
+            }
+        };
+
+        let mut export = JsonExport::default();
+        for (key, value) in buckets {
+            let Value::Object(fields) = value else {
+                return Err(invalid(format!("bucket '{key}' isn't an object")));
+            };
+            let mut fields = fields.clone();
+            let events = match fields.remove("events") {
+                None | Some(Value::Null) => Vec::new(),
+                Some(Value::Array(events)) => events,
+                Some(_) => return Err(invalid(format!("events of bucket '{key}' aren't a list"))),
+            };
+            let mut bucket: Bucket = serde_json::from_value(Value::Object(fields))
+                .map_err(|e| invalid(format!("bucket '{key}': {e}")))?;
+            // The ID is optional in buckets, as they're keyed by it
+            if bucket.id.is_empty() {
+                bucket.id = key.clone();
+            } else if bucket.id != *key {
+                return Err(invalid(format!(
+                    "bucket '{key}' has a different ID '{}'",
+                    bucket.id
+                )));
+            }
+
+            let mut events: Vec<Event> = events
+                .into_iter()
+                .enumerate()
+                .map(|(i, event)| {
+                    serde_json::from_value(event)
+                        .map_err(|e| invalid(format!("bucket '{key}', event {i}: {e}")))
+                })
+                .collect::<Result<_, _>>()?;
+            events.sort_by_key(|e| e.timestamp);
+            // Like in the datastore, the metadata is the range of the events
+            bucket.metadata.start = events.iter().map(|e| e.timestamp).min();
+            bucket.metadata.end = events.iter().map(|e| e.calculate_endtime()).max();
+            bucket.events = None;
+
+            export.events.insert(key.clone(), events);
+            export.buckets.insert(key.clone(), bucket);
+        }
+        Ok(export)
+    }
+
+    fn bucket_events(&self, bucket_id: &str) -> Result<&Vec<Event>, String> {
+        self.events
+            .get(bucket_id)
+            .ok_or_else(|| format!("no bucket '{bucket_id}' in export"))
+    }
+}
+
+impl AccessMethod for JsonExport {
+    fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
+        Ok(self.buckets.clone())
+    }
+    fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
+        self.buckets
+            .get(bucket_id)
+            .cloned()
+            .ok_or_else(|| DatastoreError::NoSuchBucket(bucket_id.to_string()))
+    }
+    fn create_bucket(&self, _bucket: &Bucket) -> Result<(), DatastoreError> {
+        Err(DatastoreError::InternalError(READ_ONLY.to_string()))
+    }
+    fn delete_bucket(&self, _bucket_id: &str) -> Result<(), DatastoreError> {
+        Err(DatastoreError::InternalError(READ_ONLY.to_string()))
+    }
+    fn update_bucket(&self, _bucket: &Bucket) -> Result<(), DatastoreError> {
+        Err(DatastoreError::InternalError(READ_ONLY.to_string()))
+    }
+    fn get_events(
+        &self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        limit: Option<u64>,
+    ) -> Result<Vec<Event>, String> {
+        // Newest first and overlapping the range, like in the datastore
+        Ok(self
+            .bucket_events(bucket_id)?
+            .iter()
+            .rev()
+            .filter(|e| start.map_or(true, |start| e.calculate_endtime() >= start))
+            .filter(|e| end.map_or(true, |end| e.timestamp <= end))
+            .take(limit.map_or(usize::MAX, |l| l as usize))
+            .cloned()
+            .collect())
+    }
+    fn insert_events(&self, _bucket_id: &str, _events: Vec<Event>) -> Result<(), String> {
+        Err(READ_ONLY.to_string())
+    }
+    fn delete_events(&self, _bucket_id: &str, _event_ids: Vec<i64>) -> Result<(), String> {
+        Err(READ_ONLY.to_string())
+    }
+    fn get_event_count_range(
+        &self,
+        bucket_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<i64, String> {
+        Ok(self.get_events(bucket_id, start, end, None)?.len() as i64)
+    }
+    fn heartbeat(&self, _bucket_id: &str, _event: Event, _duration: f64) -> Result<(), String> {
+        Err(READ_ONLY.to_string())
+    }
+    fn close(&self) {
+        // NOP
+    }
+}
@@ -6,9 +6,123 @@ extern crate serde_json;
 
 mod sync;
 pub use sync::create_datastore;
+pub use sync::find_remotes;
+pub use sync::find_stale_remotes;
+pub use sync::list_remotes;
+pub use sync::origin_bucket_id;
+pub use sync::prune_remotes;
+pub use sync::push_to_staging;
 pub use sync::sync_datastores;
 pub use sync::sync_run;
+pub use sync::sync_run_datastore;
+pub use sync::unsynced_buckets;
+pub use sync::verify_remote_integrity;
+pub use sync::BucketSyncSummary;
+pub use sync::BucketTimings;
+pub use sync::RemoteInfo;
+pub use sync::SyncMode;
+pub use sync::SyncRunSummary;
+pub use sync::SyncRunner;
 pub use sync::SyncSpec;
+pub use sync::SyncStats;
+pub use sync::ORIGIN_DEVICE_KEY;
+pub use sync::SYNC_ENABLED_KEY;
 
 mod accessmethod;
 pub use accessmethod::AccessMethod;
+
+mod state;
+pub use state::{
+    checkpoints_file, local_state_dir, state_file, BucketSyncState, Checkpoints, SyncState,
+};
+
+mod version;
+pub use version::{
+    check_schema_version, check_server_version, check_version_compatible,
+    filter_compatible_remotes, parse_server_version, read_schema_version, write_schema_version,
+    write_version_marker, MIN_SERVER_VERSION, SCHEMA_VERSION_KEY, SYNC_VERSION,
+};
+
+mod error;
+pub use error::SyncError;
+
+mod config;
+pub use config::{
+    default_config_path, default_state_directory, default_sync_directory, validate_device_id,
+    HostConfig, SyncConfig,
+};
+
+mod tombstone;
+pub use tombstone::{apply_tombstones, record_deletions};
+
+mod http;
+pub use http::HttpRemote;
+
+mod retry;
+pub use retry::{is_transient, with_retry, with_retry_if, RetryPolicy};
+
+mod filter;
+pub use filter::{EventRule, EventRuleConfig};
+
+mod redact;
+pub use redact::{keep_fields, pseudonym, redact_event, Redaction, REDACTED};
+
+mod verify;
+pub use verify::{verify, BucketVerification};
+
+mod migrate;
+pub use migrate::{migrate_synced_bucket_ids, repair_naming, NamingRepair};
+
+mod crypt;
+pub use crypt::{
+    decrypt_file, decrypt_to_temp, encrypt_file, is_encrypted, open_remote, PlaintextCopy,
+    PASSPHRASE_ENV,
+};
+
+mod merge;
+pub use merge::{event_revision, REVISION_KEY};
+
+mod doctor;
+pub use doctor::{check_sync_directory, doctor, Check, CheckStatus};
+
+mod naming;
+pub use naming::{BucketNameTemplate, DEFAULT_BUCKET_NAME_TEMPLATE};
+
+mod progress;
+pub use progress::{ConsoleProgress, NoProgress, SyncProgress, SyncProgressEvent, TotalProgress};
+
+mod lock;
+pub use lock::SyncLock;
+
+mod longpath;
+pub use longpath::{extended_length_path, MAX_PATH};
+
+mod metrics;
+pub use metrics::{parse_metrics, write_metrics};
+
+mod repair;
+pub use repair::{repair, RepairSummary};
+
+mod audit;
+pub use audit::{audit, audit_buckets, AuditIssue, AuditWarning};
+
+mod export;
+pub use export::{export, export_buckets};
+
+mod hook;
+pub use hook::run_post_hook;
+
+mod import;
+pub use import::JsonExport;
+
+mod pending;
+pub use pending::{pending_counts, PendingCount};
+
+mod ratelimit;
+pub use ratelimit::RateLimiter;
+
+mod store;
+pub use store::{
+    download_remotes, open_store, upload_staging, FolderStore, RemoteStore, RemoteStoreConfig,
+    S3Store, WebDavStore, S3_ACCESS_KEY_ID_ENV, S3_SECRET_ACCESS_KEY_ENV, WEBDAV_PASSWORD_ENV,
+};
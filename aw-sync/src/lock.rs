@@ -0,0 +1,63 @@
+//! Lock against concurrent sync passes
+//!
+//! Two passes syncing the same staging datastore at the same time, such as a scheduled run
+//! overlapping with a manual one, would write to it concurrently and pull the same events twice.
+//! A pass therefore holds an advisory lock on a `sync.lock` file in the local state directory of
+//! the staging datastore (see [`local_state_dir`]), which unlike the sync folder isn't synced to
+//! other devices. The OS releases it once the process exits, so a crashed pass never leaves a
+//! stale lock behind.
+//!
+//! Locks are per staging datastore, so instances syncing to staging datastores of their own (see
+//! [`SyncSpec::instance`]) don't block each other.
+//!
+//! [`SyncSpec::instance`]: crate::sync::SyncSpec::instance
+//! [`local_state_dir`]: crate::state::local_state_dir
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+
+use crate::error::{io_context, SyncError};
+use crate::state::local_state_dir;
+
+/// Lock on a staging datastore, held until dropped
+#[derive(Debug)]
+pub struct SyncLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl SyncLock {
+    /// Locks the staging datastore at `dbfile`
+    ///
+    /// Fails right away with [`SyncError::Locked`] if another pass holds the lock.
+    pub fn acquire(dbfile: &Path) -> Result<SyncLock, SyncError> {
+        let path = local_state_dir(dbfile)?.join("sync.lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| io_context(e, "open lock file", &path))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => {
+                debug!("Locked {}", path.display());
+                Ok(SyncLock { file, path })
+            }
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Err(SyncError::Locked(path))
+            }
+            Err(e) => Err(io_context(e, "lock", &path)),
+        }
+    }
+}
+
+impl Drop for SyncLock {
+    // Also run when unwinding from a panic
+    fn drop(&mut self) {
+        // The file is kept, as deleting it could race with another pass locking it
+        if let Err(e) = self.file.unlock() {
+            warn!("Failed to unlock {}: {e}", self.path.display());
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Paths exceeding the path length limit of Windows, for deeply nested sync folders
+//!
+//! Windows limits paths to 260 characters (MAX_PATH) unless they have the `\\?\` extended-length
+//! prefix, so a long sync folder path combined with a long device ID fails to be created or
+//! opened, with errors which don't tell why. Folders of staging datastores which would exceed the
+//! limit are given the prefix instead. The limits of other platforms are long enough not to
+//! matter.
+
+use std::path::{Path, PathBuf};
+
+/// Path length limit of Windows for paths without the extended-length prefix
+pub const MAX_PATH: usize = 260;
+
+/// Room for the files kept next to a staging datastore, such as its state and temporary copy,
+/// whose names are longer than the name of the datastore
+const SIBLING_MARGIN: usize = 32;
+
+/// Returns the extended-length form of an absolute Windows path, such as `\\?\C:\sync` for
+/// `C:\sync` and `\\?\UNC\nas\sync` for `\\nas\sync`
+///
+/// Slashes are replaced by backslashes, as extended-length paths are passed to the filesystem
+/// as they are. Returns None for relative paths and paths which already have the prefix.
+pub fn extended_length_path(path: &str) -> Option<String> {
+    if path.starts_with(r"\\?\") {
+        return None;
+    }
+    let path = path.replace('/', "\\");
+    if let Some(unc) = path.strip_prefix(r"\\") {
+        return Some(format!(r"\\?\UNC\{unc}"));
+    }
+    let bytes = path.as_bytes();
+    let has_drive = bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && &bytes[1..3] == b":\\";
+    has_drive.then(|| format!(r"\\?\{path}"))
+}
+
+/// Returns the extended-length form of `path`, made absolute first
+pub(crate) fn to_extended_length(path: &Path) -> Option<PathBuf> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().ok()?.join(path)
+    };
+    absolute
+        .to_str()
+        .and_then(extended_length_path)
+        .map(PathBuf::from)
+}
+
+/// Returns `dir` in a form the files in it can be opened by on Windows, such as the folder of a
+/// staging datastore
+///
+/// `file_name_len` is the length of the longest file name which will be in it. Elsewhere, and
+/// for paths short enough, `dir` is returned as it is.
+pub(crate) fn long_path_safe(dir: &Path, file_name_len: usize) -> PathBuf {
+    let len = dir.as_os_str().len() + 1 + file_name_len + SIBLING_MARGIN;
+    if !cfg!(windows) || len <= MAX_PATH {
+        return dir.to_path_buf();
+    }
+    match to_extended_length(dir) {
+        Some(extended) => {
+            info!(
+                "{} is too long for Windows, using {} instead",
+                dir.display(),
+                extended.display()
+            );
+            extended
+        }
+        None => dir.to_path_buf(),
+    }
+}
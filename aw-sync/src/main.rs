@@ -14,16 +14,47 @@ extern crate serde;
 extern crate serde_json;
 
 use std::error::Error;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use chrono::{DateTime, Datelike, TimeZone, Utc};
 use clap::{Parser, Subcommand};
+use regex::Regex;
 
 use aw_client_rust::AwClient;
 
 mod accessmethod;
+mod audit;
+mod config;
+mod crypt;
+mod doctor;
+mod error;
+mod export;
+mod filter;
+mod hook;
+mod http;
+mod import;
+mod lock;
+mod longpath;
+mod merge;
+mod metrics;
+mod migrate;
+mod naming;
+mod pending;
+mod progress;
+mod ratelimit;
+mod redact;
+mod repair;
+mod retry;
+mod state;
+mod store;
 mod sync;
+mod tombstone;
+mod verify;
+mod version;
 
 const DEFAULT_PORT: &str = "5600";
 
@@ -45,20 +76,58 @@ struct Opts {
     #[clap(long)]
     testing: bool,
 
-    /// Enable debug logging.
+    /// Enable debug logging, including why buckets are (not) synced.
+    /// Can be given twice (-vv) for trace logging.
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log warnings and errors, and don't print progress, such as for cron jobs.
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log level, one of "error", "warn", "info", "debug" and "trace".
+    /// Takes precedence over --verbose and --quiet. The LOG_LEVEL environment variable takes
+    /// precedence over all of them, like for aw-server.
+    #[clap(long)]
+    log_level: Option<log::LevelFilter>,
+
+    /// Append the remote and bucket of log lines to them as key=value pairs, for log processing.
+    #[clap(long)]
+    log_fields: bool,
+
+    /// Path to the config file.
+    /// Defaults to "aw-sync/config.toml" in the ActivityWatch config directory.
     #[clap(long)]
-    verbose: bool,
+    config: Option<PathBuf>,
 
     /// Full path to sync directory.
-    /// If not specified, exit.
+    /// If not specified here or in the config file, aw-sync in the ActivityWatch data directory
+    /// is used.
     #[clap(long)]
-    sync_dir: String,
+    sync_dir: Option<PathBuf>,
 
     /// Full path to sync db file
     /// Useful for syncing buckets from a specific db file in the sync directory.
     /// Must be a valid absolute path to a file in the sync directory.
     #[clap(long)]
     sync_db: Option<String>,
+
+    /// Filename of the staging db for this device in the sync directory.
+    /// Useful for keeping several aw-server instances on one device in separate dbs.
+    /// Defaults to "<device_id>.db", or "test.db" if that is the only existing db.
+    #[clap(long)]
+    sync_db_filename: Option<String>,
+
+    /// Name of the aw-server instance to sync, for hosts running several (such as a testing
+    /// instance). Each instance gets its own staging db, in "{device_id}/{instance}/".
+    #[clap(long)]
+    instance: Option<String>,
+
+    /// Device ID to sync this device as, instead of the one of the local aw-server.
+    /// Useful after reinstalling aw-server (which gets a new device ID), to keep syncing to the
+    /// folder of the previous device ID, so other devices don't see it as a new device.
+    #[clap(long)]
+    device_id: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -76,55 +145,464 @@ enum Commands {
         /// Format: YYYY-MM-DD
         #[clap(long)]
         start_date: Option<String>,
+        /// Time to start syncing from, as an RFC3339 timestamp.
+        /// More precise alternative to --start-date.
+        #[clap(long, conflicts_with = "start_date")]
+        start: Option<String>,
+        /// Time to stop syncing at, as an RFC3339 timestamp.
+        /// If not specified, sync up until now.
+        #[clap(long)]
+        end: Option<String>,
         /// Specify buckets to sync using a comma-separated list.
-        /// If not specified, all buckets will be synced.
+        /// If not specified, all buckets will be synced. An empty list is an error.
         #[clap(long)]
         buckets: Option<String>,
+        /// Specify buckets to sync using a regex, matched against the whole bucket ID.
+        /// If given together with --buckets, buckets matching either will be synced.
+        #[clap(long)]
+        buckets_regex: Option<String>,
+        /// Sync all buckets, ignoring the buckets and buckets_regex of the config file.
+        #[clap(long, conflicts_with_all = ["buckets", "buckets_regex"])]
+        all: bool,
+        /// Sync the buckets with "$aw.sync.enabled": true in their data, instead of the ones of
+        /// --buckets and --buckets-regex. These are only used if no bucket has the flag.
+        #[clap(long, conflicts_with = "all")]
+        bucket_flags: bool,
+        /// Only sync buckets of these types, as a comma-separated list (such as
+        /// "afkstatus,currentwindow"). Applies in addition to --buckets and --buckets-regex.
+        #[clap(long)]
+        include_types: Option<String>,
+        /// Never sync buckets of these types, as a comma-separated list.
+        #[clap(long)]
+        exclude_types: Option<String>,
+        /// Only sync buckets with events within this long before now, such as "30m" or "1h".
+        /// Supports the units s, m, h and d.
+        #[clap(long)]
+        changed_within: Option<String>,
         /// Mode to sync in. Can be "push", "pull", or "both".
         /// Defaults to "both".
-        #[clap(long, default_value = "both")]
+        #[clap(long, default_value = "both", value_parser = ["push", "pull", "both"])]
         mode: String,
+        /// Only report what would be synced, without writing anything.
+        #[clap(long)]
+        dry_run: bool,
+        /// Ignore the sync state from previous passes and check all buckets for new events.
+        #[clap(long)]
+        force_full: bool,
+        /// Maximum number of remotes to pull from in parallel.
+        #[clap(long, default_value = "4")]
+        pull_concurrency: usize,
+        /// Only pull from the remote with this device ID, such as for troubleshooting the data
+        /// of one device. Much faster than pulling from all remotes.
+        #[clap(long)]
+        only_remote: Option<String>,
+        /// Sync at most this many events per bucket and run, such as 100000, so that a big
+        /// backlog is synced over several runs instead of all at once.
+        #[clap(long)]
+        max_events_per_bucket: Option<usize>,
+        /// Skip buckets with more events than this, such as for quick syncs of the small buckets
+        /// with the big ones left to a scheduled full sync.
+        #[clap(long)]
+        skip_buckets_larger_than: Option<usize>,
+        /// Insert at most this many events per second when pulling into aw-server, such as 500,
+        /// so it stays responsive to its watchers during big pulls.
+        #[clap(long)]
+        insert_rate: Option<u32>,
+        /// Don't push if it would leave less than this many MB free on the disk of the sync
+        /// folder. 0 disables the check.
+        #[clap(long, default_value = "500")]
+        min_free_space: u64,
+        /// Delete synced buckets whose source bucket was deleted.
+        /// Deleted buckets are recorded as tombstones when pushing, and deleted when pulling.
+        /// If a bucket is re-created with the same ID, the re-created bucket takes precedence.
+        #[clap(long)]
+        propagate_deletions: bool,
+        /// Check the integrity of remote dbs before syncing from them, skipping corrupt ones.
+        /// Can take a while for big dbs.
+        #[clap(long)]
+        verify_integrity: bool,
+        /// Maximum number of attempts for requests to the local aw-server.
+        #[clap(long, default_value = "5")]
+        retry_attempts: u32,
+        /// Delay before retrying a failed request to the local aw-server, in milliseconds.
+        /// Doubled after every retry.
+        #[clap(long, default_value = "500")]
+        retry_delay_ms: u64,
+        /// URL of a remote aw-server to push local buckets to directly, without a sync folder.
+        /// Can be given several times.
+        #[clap(long = "remote-server")]
+        remote_servers: Vec<String>,
+        /// Compare all events in the time range against the destination, and only sync the
+        /// missing ones. Slower, but fills in gaps and never duplicates events.
+        #[clap(long)]
+        dedup_strict: bool,
+        /// Fail syncing a bucket if its number of events in the destination drops while syncing
+        /// into it, instead of only logging an error.
+        #[clap(long)]
+        strict: bool,
+        /// How far the clocks of devices may be off from each other, in seconds.
+        /// Syncing resumes this long before the last synced event, so events from devices with
+        /// a clock that's behind aren't missed. Off by default, as the events in between are
+        /// read again on every pass.
+        #[clap(long, default_value = "0")]
+        clock_skew_tolerance: i64,
+        /// Print a summary of the sync pass as JSON, for use in scripts.
+        #[clap(long)]
+        json: bool,
+        /// Print how many events each bucket has to sync before syncing, and the progress of
+        /// the pass towards the total. Only counts events, so is quick.
+        #[clap(long, conflicts_with = "json")]
+        show_pending: bool,
+        /// Write metrics of the sync pass to this file in the Prometheus text format, such as
+        /// for the textfile collector of node_exporter. Counters are kept across runs.
+        #[clap(long)]
+        metrics_file: Option<PathBuf>,
+        /// Update events which were edited after they were synced, in both directions.
+        /// Edited events need to have an incremented "$aw.sync.revision" in their data.
+        #[clap(long)]
+        merge_edits: bool,
+        /// Vacuum the staging db after pushing, so peers have less to transfer.
+        #[clap(long)]
+        vacuum: bool,
+        /// Warn about remotes which weren't synced without errors for this long, such as "7d".
+        #[clap(long, default_value = "7d")]
+        stale_after: String,
+        /// Stop syncing after this long, such as "5m", so scheduled runs can't pile up.
+        /// Buckets being synced are finished first, the rest are synced on the next run.
+        #[clap(long)]
+        timeout: Option<String>,
+        /// Only push new events to a bucket once at least this many accumulated (or
+        /// --min-interval passed), so the staging db changes less often.
+        #[clap(long, default_value = "0")]
+        min_batch: usize,
+        /// Only push new events to a bucket once this long passed since it was last pushed to
+        /// (or --min-batch events accumulated), such as "10m".
+        #[clap(long)]
+        min_interval: Option<String>,
+        /// Push all new events regardless of --min-batch and --min-interval, such as on the
+        /// last run before shutting down.
+        #[clap(long)]
+        flush: bool,
+        /// Merge contiguous events with the same data in AFK and window buckets, with the
+        /// default pulsetimes of their watchers. Pulsetimes of other buckets can be set in the
+        /// config file.
+        #[clap(long)]
+        merge_heartbeats: bool,
+        /// Merge contiguous events with the same data in all buckets with this pulsetime in
+        /// seconds, instead of those of --merge-heartbeats and the config file, such as to
+        /// experiment with merging. 0 disables merging.
+        #[clap(long)]
+        pulsetime: Option<f64>,
+        /// Print how long the slowest buckets took to sync at the end, split up into fetching,
+        /// inserting and counting events. Printed to stderr, so it can be used with --json.
+        #[clap(long)]
+        profile: bool,
+        /// Shell command to run after a successful push, such as committing the sync folder.
+        /// The summary of the pass is passed in environment variables, such as
+        /// AW_SYNC_EVENTS_PUSHED, see the README. A failing command doesn't fail the sync.
+        #[clap(long)]
+        post_hook: Option<String>,
     },
     /// List buckets and their sync status.
     List {},
+    /// List remote devices in the sync folder and when they were last synced.
+    ListRemotes {},
+    /// List the buckets of aw-server which aren't synced, such as those of a new watcher missing
+    /// from the buckets of the config.
+    ListUnsynced {
+        /// Buckets which are synced, as a comma-separated list, like for sync.
+        #[clap(long)]
+        buckets: Option<String>,
+        /// Regex matching the buckets which are synced, like for sync.
+        #[clap(long)]
+        buckets_regex: Option<String>,
+        /// Buckets flagged with "$aw.sync.enabled" are synced, like for sync.
+        #[clap(long)]
+        bucket_flags: bool,
+    },
+    /// Compare the event counts of synced buckets with their source buckets.
+    /// Exits with a nonzero exit code if any differ, for use in health checks.
+    Verify {
+        /// Only verify these source buckets, as a comma-separated list.
+        #[clap(long)]
+        buckets: Option<String>,
+        /// Number of events the counts may differ by, as duplicate and excluded events aren't
+        /// synced.
+        #[clap(long, default_value = "0")]
+        tolerance: i64,
+        /// Print the results as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check the buckets of all remotes for signs of misconfiguration, such as the same bucket
+    /// being recorded by several devices at the same time, or events in the future.
+    Audit {
+        /// Print the warnings as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check the sync setup, such as whether the sync directory and the local aw-server work.
+    /// Exits with a nonzero exit code if any check fails.
+    Doctor {},
+    /// Delete the folders of remotes which weren't synced for a long time, such as
+    /// decommissioned devices. The folder of this device is never deleted.
+    Prune {
+        /// Delete remotes which weren't touched for this long, such as "90d".
+        #[clap(long, default_value = "90d")]
+        older_than: String,
+        /// Delete without asking for confirmation.
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Pull the events of a time window into a pulled bucket again, such as to fill a gap
+    /// after restoring a backup. The events of the bucket in the window are replaced by those
+    /// of its source bucket, events outside of it aren't touched.
+    Repair {
+        /// ID of the pulled bucket, such as "aw-watcher-window_laptop-synced-from-<device ID>".
+        #[clap(long)]
+        bucket: String,
+        /// Start of the window (inclusive), as an RFC 3339 timestamp.
+        #[clap(long)]
+        start: String,
+        /// End of the window (exclusive), as an RFC 3339 timestamp.
+        #[clap(long)]
+        end: String,
+        /// Replace the events without asking for confirmation.
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Repair the pulled buckets of aw-server named by old versions: fill in their missing
+    /// "$aw.sync.origin", and collapse repeated "-synced-from-" suffixes. Changes nothing when
+    /// run again.
+    RepairNaming {
+        /// Only print what would be repaired.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Export the buckets and events of a remote db to a JSON file, in the export format of
+    /// aw-server. The remote db is only read.
+    Export {
+        /// Path to the remote db, such as "<sync dir>/<device ID>/<device ID>.db".
+        #[clap(long)]
+        remote: PathBuf,
+        /// Path to write the JSON export to.
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Push the buckets and events of an aw-server JSON export to the staging db of this device,
+    /// such as to bring the history of an old aw-server into syncing without importing it into
+    /// aw-server first.
+    Import {
+        /// Path to the JSON export, as exported from the settings of aw-server.
+        #[clap(long)]
+        file: PathBuf,
+    },
+}
+
+/// Exit code when a sync is interrupted, as usual for processes stopped by SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+
+fn parse_rfc3339(timestamp: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(timestamp).map(|dt| dt.with_timezone(&Utc))
+}
+
+/// Parses a comma-separated list of bucket IDs, falling back to the buckets of the config
+fn parse_buckets(buckets: Option<&str>, config: &config::SyncConfig) -> Option<Vec<String>> {
+    buckets
+        .map(|b| {
+            b.split(',')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .or_else(|| config.buckets.clone())
+}
+
+/// Parses a regex matching whole bucket IDs, falling back to the buckets_regex of the config
+fn parse_buckets_regex(
+    regex: Option<&str>,
+    config: &config::SyncConfig,
+) -> Result<Option<Regex>, regex::Error> {
+    regex
+        .or(config.buckets_regex.as_deref())
+        .map(|re| Regex::new(&format!("^(?:{re})$")))
+        .transpose()
+}
+
+/// Parses a duration such as "90s", "30m", "1h" or "7d"
+fn parse_duration(duration: &str) -> Result<chrono::Duration, String> {
+    let invalid = || format!("invalid duration '{duration}', expected something like '1h'");
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (amount, unit) = duration.split_at(unit_start);
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(amount)),
+        "m" => Ok(chrono::Duration::minutes(amount)),
+        "h" => Ok(chrono::Duration::hours(amount)),
+        "d" => Ok(chrono::Duration::days(amount)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Prints the slowest buckets of a sync pass and where their time went, to stderr
+fn print_profile(summary: &sync::SyncRunSummary) {
+    const SLOWEST: usize = 10;
+    let mut buckets: Vec<&sync::BucketSyncSummary> = summary.buckets.iter().collect();
+    buckets.sort_by(|a, b| b.timings.total_secs().total_cmp(&a.timings.total_secs()));
+    eprintln!(
+        "{:<60} {:>9} {:>11} {:>9} {:>9}",
+        "BUCKET", "TOTAL", "GET EVENTS", "INSERT", "COUNT"
+    );
+    for bucket in buckets.into_iter().take(SLOWEST) {
+        let t = &bucket.timings;
+        eprintln!(
+            "{:<60} {:>8.2}s {:>10.2}s {:>8.2}s {:>8.2}s",
+            bucket.bucket_id,
+            t.total_secs(),
+            t.get_events_secs,
+            t.insert_secs,
+            t.count_secs
+        );
+    }
+}
+
+/// Prints the buckets with events to sync, and the total
+fn print_pending(pending: &[pending::PendingCount]) {
+    println!("{:<38} {:<60} {:>10}", "REMOTE", "BUCKET", "PENDING");
+    for count in pending.iter().filter(|c| c.events > 0) {
+        println!(
+            "{:<38} {:<60} {:>10}",
+            count.remote, count.source_bucket, count.events
+        );
+    }
+    let total: i64 = pending.iter().map(|c| c.events).sum();
+    println!("{total} events to sync");
+}
+
+/// Returns the device ID to sync this device as, `device_id` if set
+fn local_device_id(client: &AwClient, device_id: Option<&str>) -> Result<String, Box<dyn Error>> {
+    match device_id {
+        Some(device_id) => Ok(device_id.to_string()),
+        None => Ok(client.get_info()?.device_id),
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opts: Opts = Opts::parse();
     let verbose = opts.verbose;
 
-    info!("Started aw-sync...");
+    let log_level = match verbose {
+        _ if opts.quiet => log::LevelFilter::Warn,
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    let log_level = opts.log_level.unwrap_or(log_level);
+    aw_server::logging::setup_logger_with_level(true, log_level, opts.log_fields)
+        .expect("Failed to setup logging");
 
-    aw_server::logging::setup_logger(true, verbose).expect("Failed to setup logging");
+    info!("Started aw-sync...");
 
-    let sync_directory = if opts.sync_dir.is_empty() {
-        println!("No sync directory specified, exiting...");
-        std::process::exit(1);
+    let port = if opts.testing && opts.port == DEFAULT_PORT {
+        "5666"
     } else {
-        Path::new(&opts.sync_dir)
+        &opts.port
+    };
+
+    let client = AwClient::new(opts.host.as_str(), port, "aw-sync");
+
+    let config_path = opts.config.clone().or_else(config::default_config_path);
+    let config = match &config_path {
+        Some(path) => {
+            debug!("Reading config at {}", path.display());
+            config::SyncConfig::load(path)?.for_host(&client.hostname)
+        }
+        None => config::SyncConfig::default(),
+    };
+    config.validate()?;
+
+    let default_sync_directory;
+    let sync_directory = match opts.sync_dir.as_ref().or(config.sync_directory.as_ref()) {
+        Some(sync_dir) => sync_dir.as_path(),
+        None => {
+            default_sync_directory = config::default_sync_directory()?;
+            default_sync_directory.as_path()
+        }
     };
     info!("Using sync dir: {}", sync_directory.display());
 
+    let passphrase = std::env::var(crypt::PASSPHRASE_ENV)
+        .ok()
+        .or_else(|| config.passphrase.clone())
+        .filter(|p| !p.is_empty());
+
     if let Some(sync_db) = &opts.sync_db {
         info!("Using sync db: {}", sync_db);
     }
 
-    let port = if opts.testing && opts.port == DEFAULT_PORT {
-        "5666"
-    } else {
-        &opts.port
+    let device_id = opts.device_id.clone().or_else(|| config.device_id.clone());
+    if let Some(device_id) = &device_id {
+        config::validate_device_id(device_id)?;
+    }
+    // Sync pseudonymizes the device ID itself, the other commands need to be given it
+    let sync_as = || -> Result<Option<String>, Box<dyn Error>> {
+        match &config.pseudonym_salt {
+            Some(salt) => {
+                let device_id = local_device_id(&client, device_id.as_deref())?;
+                Ok(Some(redact::pseudonym(&device_id, salt)))
+            }
+            None => Ok(device_id.clone()),
+        }
     };
 
-    let client = AwClient::new(opts.host.as_str(), port, "aw-sync");
-
     match &opts.command {
         // Perform two-way sync
         Commands::Sync {
             start_date,
+            start,
+            end,
             buckets,
+            buckets_regex,
+            all,
+            bucket_flags,
+            include_types,
+            exclude_types,
+            changed_within,
             mode,
+            dry_run,
+            force_full,
+            pull_concurrency,
+            only_remote,
+            max_events_per_bucket,
+            skip_buckets_larger_than,
+            insert_rate,
+            min_free_space,
+            propagate_deletions,
+            verify_integrity,
+            retry_attempts,
+            retry_delay_ms,
+            remote_servers,
+            dedup_strict,
+            strict,
+            clock_skew_tolerance,
+            json,
+            show_pending,
+            metrics_file,
+            merge_edits,
+            vacuum,
+            stale_after,
+            timeout,
+            min_batch,
+            min_interval,
+            flush,
+            merge_heartbeats,
+            pulsetime,
+            profile,
+            post_hook,
         } => {
-            let start: Option<DateTime<Utc>> = start_date.as_ref().map(|date| {
+            let start_from_date: Option<DateTime<Utc>> = start_date.as_ref().map(|date| {
                 println!("{}", date.clone());
                 chrono::NaiveDate::parse_from_str(&date.clone(), "%Y-%m-%d")
                     .map(|nd| {
@@ -134,11 +612,36 @@ fn main() -> Result<(), Box<dyn Error>> {
                     })
                     .expect("Date was not on the format YYYY-MM-DD")
             });
+            let start = match start {
+                Some(start) => Some(parse_rfc3339(start)?),
+                None => start_from_date,
+            };
+            let end = end.as_deref().map(parse_rfc3339).transpose()?;
+
+            let buckets_vec = parse_buckets(buckets.as_deref(), &config);
+            let buckets_regex = parse_buckets_regex(buckets_regex.as_deref(), &config)?;
 
-            // Parse comma-separated list
-            let buckets_vec: Option<Vec<String>> = buckets
+            let include_types: Option<Vec<String>> = include_types
                 .as_ref()
-                .map(|b| b.split(',').map(|s| s.to_string()).collect());
+                .map(|t| t.split(',').map(|s| s.to_string()).collect());
+            let exclude_types: Vec<String> = exclude_types
+                .as_ref()
+                .map(|t| t.split(',').map(|s| s.to_string()).collect())
+                .unwrap_or_default();
+
+            let changed_within = changed_within.as_deref().map(parse_duration).transpose()?;
+            let stale_threshold = parse_duration(stale_after)?;
+            let min_interval = min_interval.as_deref().map(parse_duration).transpose()?;
+            let timeout = timeout
+                .as_deref()
+                .map(|timeout| parse_duration(timeout)?.to_std().map_err(|e| e.to_string()))
+                .transpose()?;
+
+            let remote_servers = if remote_servers.is_empty() {
+                config.remote_servers.clone().unwrap_or_default()
+            } else {
+                remote_servers.clone()
+            };
 
             let sync_db: Option<PathBuf> = opts.sync_db.as_ref().map(|db| {
                 let db_path = Path::new(db);
@@ -151,25 +654,486 @@ fn main() -> Result<(), Box<dyn Error>> {
                 db_path.to_path_buf()
             });
 
-            let sync_spec = sync::SyncSpec {
-                path: sync_directory.to_path_buf(),
-                path_db: sync_db,
-                buckets: buckets_vec,
-                start,
-            };
-
             let mode_enum = match mode.as_str() {
                 "push" => sync::SyncMode::Push,
                 "pull" => sync::SyncMode::Pull,
                 "both" => sync::SyncMode::Both,
-                _ => panic!("Invalid mode"),
+                _ => unreachable!("mode is validated by clap"),
             };
 
-            sync::sync_run(client, &sync_spec, mode_enum)
+            let exclude = config.exclude_rules()?;
+            let bucket_names = config.bucket_names()?;
+            let remote_store = config
+                .remote_store
+                .as_ref()
+                .map(store::open_store)
+                .transpose()?;
+            let mut runner = sync::SyncRunner::new(client, sync_directory)
+                .mode(mode_enum)
+                .configure(|spec| {
+                    spec.path_db = sync_db;
+                    spec.db_filename = opts.sync_db_filename.clone();
+                    spec.instance = opts.instance.clone();
+                    spec.device_id = device_id.clone();
+                    spec.include_types = include_types;
+                    spec.exclude_types = exclude_types;
+                    spec.changed_within = changed_within;
+                    spec.start = start;
+                    spec.end = end;
+                    spec.dry_run = *dry_run;
+                    spec.force_full = *force_full;
+                    spec.pull_concurrency = *pull_concurrency;
+                    spec.only_remote = only_remote.clone();
+                    spec.max_events_per_bucket = *max_events_per_bucket;
+                    spec.skip_buckets_larger_than = *skip_buckets_larger_than;
+                    spec.insert_rate = insert_rate.map(ratelimit::RateLimiter::new);
+                    spec.min_free_space = min_free_space.saturating_mul(1_000_000);
+                    spec.propagate_deletions = *propagate_deletions;
+                    spec.retry = retry::RetryPolicy {
+                        max_attempts: *retry_attempts,
+                        base_delay: std::time::Duration::from_millis(*retry_delay_ms),
+                    };
+                    spec.verify_integrity = *verify_integrity;
+                    spec.remote_servers = remote_servers;
+                    spec.dedup_strict = *dedup_strict;
+                    spec.strict = *strict;
+                    spec.clock_skew_tolerance = chrono::Duration::seconds(*clock_skew_tolerance);
+                    spec.merge_edits = *merge_edits;
+                    spec.vacuum = *vacuum;
+                    spec.stale_threshold = stale_threshold;
+                    spec.exclude = exclude;
+                    spec.redact = config.redact.clone();
+                    spec.redact_salt = config.redact_salt.clone().unwrap_or_default();
+                    spec.keep_fields = config.keep_fields.clone();
+                    spec.pseudonym_salt = config.pseudonym_salt.clone();
+                    spec.passphrase = passphrase.clone();
+                    spec.bucket_names = bucket_names;
+                    spec.min_batch = *min_batch;
+                    spec.min_interval = min_interval;
+                    spec.flush = *flush;
+                    spec.pulsetime = config.pulsetime.clone();
+                    spec.min_duration = config.min_duration.clone();
+                    spec.origin_aliases = config.origin_aliases.clone();
+                    spec.merge_heartbeats = *merge_heartbeats;
+                    spec.all_buckets = *all;
+                    spec.bucket_flags = *bucket_flags;
+                    spec.remote_store = remote_store;
+                    spec.post_hook = post_hook.clone().or_else(|| config.post_hook.clone());
+                });
+            if let Some(buckets) = buckets_vec {
+                runner = runner.buckets(buckets);
+            }
+            if let Some(buckets_regex) = buckets_regex {
+                runner = runner.buckets_regex(buckets_regex);
+            }
+            if let Some(timeout) = timeout {
+                runner = runner.timeout(timeout);
+            }
+            if let Some(pulsetime) = pulsetime {
+                runner = runner.pulsetime(*pulsetime);
+            }
+
+            let total_progress = if *show_pending {
+                let pending = runner.pending_counts()?;
+                print_pending(&pending);
+                let total = pending.iter().map(|p| p.events).sum();
+                Some(progress::TotalProgress::new(total))
+            } else {
+                None
+            };
+            // Progress messages would end up in the JSON output
+            let progress: &dyn progress::SyncProgress = match &total_progress {
+                _ if *json || opts.quiet => &progress::NoProgress,
+                Some(total_progress) => total_progress,
+                None => &progress::ConsoleProgress,
+            };
+            runner = runner.progress(progress);
+
+            // Stop after the current batch of events on Ctrl-C, so the staging db is left intact
+            let cancel = Arc::new(AtomicBool::new(false));
+            let cancel_handler = cancel.clone();
+            ctrlc::set_handler(move || {
+                if cancel_handler.swap(true, Ordering::SeqCst) {
+                    std::process::exit(EXIT_INTERRUPTED);
+                }
+                eprintln!(
+                    "Interrupted, stopping after the current batch of events (press Ctrl-C \
+                    again to exit immediately)"
+                );
+            })?;
+            runner = runner.cancel(cancel);
+
+            let result = runner.run();
+            // Also what was synced before a timeout or interruption
+            let partial_summary = match &result {
+                Ok(summary) => Some(summary),
+                Err(error::SyncError::Timeout(summary))
+                | Err(error::SyncError::Interrupted(summary)) => Some(summary.as_ref()),
+                Err(_) => None,
+            };
+            if let Some(metrics_file) = metrics_file {
+                // Not worth failing the sync pass for
+                if let Err(e) = metrics::write_metrics(metrics_file, partial_summary) {
+                    warn!("Failed to write metrics: {e}");
+                }
+            }
+            if let (true, Some(summary)) = (*profile, partial_summary) {
+                print_profile(summary);
+            }
+            let summary = match result {
+                Err(error::SyncError::Interrupted(summary)) => {
+                    if *json {
+                        println!("{}", serde_json::to_string_pretty(&summary)?);
+                    }
+                    eprintln!(
+                        "Interrupted after {} buckets, the next sync continues where this one \
+                        stopped",
+                        summary.buckets_synced
+                    );
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    std::process::exit(EXIT_INTERRUPTED);
+                }
+                result => result?,
+            };
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else if *dry_run {
+                println!("Dry run, no changes were made. Would sync:");
+                for summary in summary.buckets {
+                    if summary.deleted {
+                        println!(" - {}: bucket would be deleted", summary.bucket_id);
+                        continue;
+                    }
+                    println!(
+                        " - {}: {} new events{}",
+                        summary.bucket_id,
+                        summary.events,
+                        if summary.created {
+                            " (bucket would be created)"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+            Ok(())
         }
 
         // List all buckets
-        Commands::List {} => sync::list_buckets(&client, sync_directory),
+        Commands::List {} => sync::list_buckets(
+            &client,
+            sync_directory,
+            sync_as()?.as_deref(),
+            opts.instance.as_deref(),
+            opts.sync_db_filename.as_deref(),
+            passphrase.as_deref(),
+        ),
+        // List remote devices
+        Commands::ListRemotes {} => {
+            let device_id = local_device_id(&client, sync_as()?.as_deref())?;
+            let remotes = sync::list_remotes(sync_directory, &device_id)?;
+            let fmt_time = |time: Option<DateTime<Utc>>| {
+                time.map_or("-".to_string(), |t| {
+                    t.format("%Y-%m-%d %H:%M:%S").to_string()
+                })
+            };
+            println!(
+                "{:<38} {:<20} {:<20} DB",
+                "DEVICE", "MODIFIED", "LAST EVENT"
+            );
+            for remote in remotes {
+                println!(
+                    "{:<38} {:<20} {:<20} {}",
+                    remote.device,
+                    fmt_time(remote.modified),
+                    fmt_time(remote.last_event),
+                    remote.path.display()
+                );
+            }
+            Ok(())
+        }
+        // List buckets not covered by the filters
+        Commands::ListUnsynced {
+            buckets,
+            buckets_regex,
+            bucket_flags,
+        } => {
+            let sync_spec = sync::SyncSpec {
+                buckets: parse_buckets(buckets.as_deref(), &config),
+                buckets_regex: parse_buckets_regex(buckets_regex.as_deref(), &config)?,
+                bucket_flags: *bucket_flags,
+                bucket_names: config.bucket_names()?,
+                ..Default::default()
+            };
+            let unsynced = sync::unsynced_buckets(&client, &sync_spec)?;
+            if unsynced.is_empty() {
+                eprintln!("All buckets are synced");
+            }
+            for bucket_id in unsynced {
+                println!("{bucket_id}");
+            }
+            Ok(())
+        }
+        // Compare event counts
+        Commands::Verify {
+            buckets,
+            tolerance,
+            json,
+        } => {
+            let device_id = local_device_id(&client, sync_as()?.as_deref())?;
+            let buckets: Option<Vec<String>> = buckets
+                .as_ref()
+                .map(|b| b.split(',').map(|s| s.to_string()).collect());
+            let results = verify::verify(
+                sync_directory,
+                &client,
+                &device_id,
+                buckets.as_deref(),
+                opts.instance.as_deref(),
+                opts.sync_db_filename.as_deref(),
+                passphrase.as_deref(),
+                &config.bucket_names()?,
+            )?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                println!(
+                    "{:<38} {:<50} {:>10} {:>10} {:>8}",
+                    "REMOTE", "BUCKET", "SOURCE", "SYNCED", "DELTA"
+                );
+                for result in &results {
+                    let synced = result
+                        .synced_count
+                        .map_or("missing".to_string(), |c| c.to_string());
+                    println!(
+                        "{:<38} {:<50} {:>10} {:>10} {:>8}",
+                        result.remote,
+                        result.source_bucket,
+                        result.source_count,
+                        synced,
+                        result.delta
+                    );
+                }
+            }
+            let mismatches = results.iter().filter(|r| r.is_mismatch(*tolerance)).count();
+            if mismatches > 0 {
+                eprintln!("{mismatches} buckets have missing events");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        // Check the buckets of remotes
+        Commands::Audit { json } => {
+            let warnings = audit::audit(
+                sync_directory,
+                passphrase.as_deref(),
+                &config.bucket_names()?,
+            )?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&warnings)?);
+            } else if warnings.is_empty() {
+                println!("No issues found");
+            } else {
+                for warning in &warnings {
+                    let issue = match warning.issue {
+                        audit::AuditIssue::Overlap => "OVERLAP",
+                        audit::AuditIssue::Future => "FUTURE",
+                    };
+                    println!("[{issue}] {}: {}", warning.bucket, warning.detail);
+                }
+            }
+            Ok(())
+        }
+        // Check the sync setup
+        Commands::Doctor {} => {
+            let checks = doctor::doctor(
+                sync_directory,
+                &client,
+                sync_as()?.as_deref(),
+                opts.instance.as_deref(),
+                opts.sync_db_filename.as_deref(),
+                passphrase.as_deref(),
+            );
+            for check in &checks {
+                let status = match check.status {
+                    doctor::CheckStatus::Pass => "PASS",
+                    doctor::CheckStatus::Warn => "WARN",
+                    doctor::CheckStatus::Fail => "FAIL",
+                };
+                if check.detail.is_empty() {
+                    println!("[{status}] {}", check.name);
+                } else {
+                    println!("[{status}] {}: {}", check.name, check.detail);
+                }
+            }
+            let failed = checks
+                .iter()
+                .filter(|c| c.status == doctor::CheckStatus::Fail)
+                .count();
+            if failed > 0 {
+                eprintln!("{failed} checks failed");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        // Delete stale remotes
+        Commands::Prune { older_than, yes } => {
+            let older_than = parse_duration(older_than)?;
+            let device_id = local_device_id(&client, sync_as()?.as_deref())?;
+            let stale = sync::find_stale_remotes(sync_directory, &device_id, older_than)?;
+            if stale.is_empty() {
+                println!("No stale remotes found");
+                return Ok(());
+            }
+            println!("Stale remotes:");
+            for dir in &stale {
+                println!("  {}", dir.display());
+            }
+            if !yes {
+                print!("Delete {} remote folders? [y/N] ", stale.len());
+                std::io::stdout().flush()?;
+                let mut answer = String::new();
+                std::io::stdin().read_line(&mut answer)?;
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    println!("Aborted");
+                    return Ok(());
+                }
+            }
+            for dir in sync::prune_remotes(sync_directory, &device_id, older_than)? {
+                println!("Deleted {}", dir.display());
+            }
+            Ok(())
+        }
+        // Pull a time window of a bucket again
+        Commands::Repair {
+            bucket,
+            start,
+            end,
+            yes,
+        } => {
+            let (start, end) = (parse_rfc3339(start)?, parse_rfc3339(end)?);
+            let device_id = local_device_id(&client, sync_as()?.as_deref())?;
+            let mut sync_spec = sync::SyncSpec {
+                path: sync_directory.to_path_buf(),
+                exclude: config.exclude_rules()?,
+                redact: config.redact.clone(),
+                redact_salt: config.redact_salt.clone().unwrap_or_default(),
+                keep_fields: config.keep_fields.clone(),
+                min_duration: config.min_duration.clone(),
+                origin_aliases: config.origin_aliases.clone(),
+                passphrase: passphrase.clone(),
+                bucket_names: config.bucket_names()?,
+                dry_run: true,
+                ..Default::default()
+            };
+            let planned = repair::repair(&client, &device_id, bucket, start, end, &sync_spec)?;
+            println!(
+                "Would delete {} events of {} from {start} until {end}, and pull {} events from \
+                {} of {} again",
+                planned.deleted, bucket, planned.pulled, planned.source_bucket, planned.remote
+            );
+            if !yes {
+                print!("Replace the events? [y/N] ");
+                std::io::stdout().flush()?;
+                let mut answer = String::new();
+                std::io::stdin().read_line(&mut answer)?;
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    println!("Aborted");
+                    return Ok(());
+                }
+            }
+            sync_spec.dry_run = false;
+            let summary = repair::repair(&client, &device_id, bucket, start, end, &sync_spec)?;
+            println!(
+                "Deleted {} events and pulled {} events",
+                summary.deleted, summary.pulled
+            );
+            Ok(())
+        }
+        // Fix the names of buckets pulled by old versions
+        Commands::RepairNaming { dry_run } => {
+            let device_id = local_device_id(&client, sync_as()?.as_deref())?;
+            let dbfile = sync::local_remote_dbfile(
+                sync_directory,
+                &device_id,
+                opts.instance.as_deref(),
+                opts.sync_db_filename.as_deref(),
+            )?;
+            // Sync passes would pull into the buckets being renamed
+            let _lock = lock::SyncLock::acquire(&dbfile)?;
+            let state_path = state::state_file(&dbfile)?;
+            let mut state = state::SyncState::load(&state_path);
+            let repairs = migrate::repair_naming(&client, *dry_run, &mut state)?;
+            if !dry_run {
+                state.save(&state_path)?;
+            }
+            match (repairs.len(), *dry_run) {
+                (0, _) => println!("No buckets to repair"),
+                (n, true) => println!("Would repair {n} buckets"),
+                (n, false) => println!("Repaired {n} buckets"),
+            }
+            Ok(())
+        }
+        // Dump a remote to JSON
+        Commands::Export { remote, output } => {
+            let exported = export::export(remote, output, passphrase.as_deref())?;
+            let buckets = exported.buckets.len();
+            let events: usize = exported
+                .buckets
+                .into_values()
+                .filter_map(|b| b.events)
+                .map(|events| events.take_inner().len())
+                .sum();
+            println!(
+                "Exported {buckets} buckets with {events} events to {}",
+                output.display()
+            );
+            Ok(())
+        }
+        // Push a JSON export to the staging db
+        Commands::Import { file } => {
+            let source = import::JsonExport::load(file)?;
+            let device_id = local_device_id(&client, sync_as()?.as_deref())?;
+            let dbfile = sync::local_remote_dbfile(
+                sync_directory,
+                &device_id,
+                opts.instance.as_deref(),
+                opts.sync_db_filename.as_deref(),
+            )?;
+            let _lock = lock::SyncLock::acquire(&dbfile)?;
+            let sync_spec = sync::SyncSpec {
+                path: sync_directory.to_path_buf(),
+                exclude: config.exclude_rules()?,
+                redact: config.redact.clone(),
+                redact_salt: config.redact_salt.clone().unwrap_or_default(),
+                keep_fields: config.keep_fields.clone(),
+                pseudonym_salt: config.pseudonym_salt.clone(),
+                min_duration: config.min_duration.clone(),
+                passphrase: passphrase.clone(),
+                bucket_names: config.bucket_names()?,
+                ..Default::default()
+            };
+            // Not the state of sync passes, which is about the buckets of aw-server
+            let stats = sync::push_to_staging(
+                &source,
+                &dbfile,
+                &device_id,
+                &sync_spec,
+                &mut state::SyncState::default(),
+                if opts.quiet {
+                    &progress::NoProgress
+                } else {
+                    &progress::ConsoleProgress
+                },
+            )?;
+            println!(
+                "Pushed {} events in {} buckets to {}",
+                stats.events_inserted,
+                stats.buckets_processed,
+                dbfile.display()
+            );
+            Ok(())
+        }
     }?;
 
     // Needed to give the datastores some time to commit before program is shut down.
@@ -0,0 +1,103 @@
+//! Merging of edited events, enabled with `--merge-edits`
+//!
+//! Events are mostly append-only, so by default events which were already synced are never
+//! synced again, even if they were edited afterwards. Tools which edit events (such as to
+//! correct their categorization) can have their edits synced by following this convention:
+//!
+//!  - Every edit of an event increments the integer at [`REVISION_KEY`] in its data. Events
+//!    without a revision are at revision 0, and are never merged.
+//!  - Edits only change the data of an event, not its timestamp or duration, as events are
+//!    matched by those. Event IDs can't be used, as they differ between datastores.
+//!
+//! When merging, the destination version of an event is replaced by the source version if the
+//! source version has a higher revision (last writer wins). As edits are merged in every sync
+//! pass, they propagate both ways: pushing merges local edits into the staging datastore, and
+//! pulling merges the edits of other devices into the local server.
+
+use std::collections::HashMap;
+
+use aw_models::Event;
+
+use crate::accessmethod::AccessMethod;
+use crate::error::SyncError;
+use crate::sync::SyncSpec;
+
+/// Key in the event data of the revision of an edited event
+pub const REVISION_KEY: &str = "$aw.sync.revision";
+
+/// Returns the revision of an event, 0 if it was never edited
+pub fn event_revision(event: &Event) -> u64 {
+    event
+        .data
+        .get(REVISION_KEY)
+        .and_then(|revision| revision.as_u64())
+        .unwrap_or(0)
+}
+
+/// Returns the key edited events are matched by between datastores
+pub(crate) fn match_key(event: &Event) -> (i64, i64) {
+    (
+        event.timestamp.timestamp_millis(),
+        event.duration.num_milliseconds(),
+    )
+}
+
+/// Replaces the events in `bucket_to` which have a newer revision in `bucket_from`
+///
+/// Returns the number of replaced events. If `sync_spec.dry_run` is set, nothing is written and
+/// the number of events which would be replaced is returned.
+pub(crate) fn merge_edits(
+    ds_from: &dyn AccessMethod,
+    ds_to: &dyn AccessMethod,
+    bucket_from: &str,
+    bucket_to: &str,
+    sync_spec: &SyncSpec,
+    log_prefix: &str,
+) -> Result<usize, SyncError> {
+    let edited: Vec<Event> = ds_from
+        .get_events(bucket_from, sync_spec.start, sync_spec.end, None)
+        .map_err(SyncError::Access)?
+        .into_iter()
+        .filter(|e| event_revision(e) > 0)
+        // Events may be edited to match an exclusion rule after they were synced
+        .filter(|e| !sync_spec.excludes_event(bucket_from, e))
+        .collect();
+    // Nothing to do for append-only buckets
+    if edited.is_empty() {
+        return Ok(0);
+    }
+
+    let existing: HashMap<(i64, i64), Event> = ds_to
+        .get_events(bucket_to, sync_spec.start, sync_spec.end, None)
+        .map_err(SyncError::Access)?
+        .into_iter()
+        .map(|e| (match_key(&e), e))
+        .collect();
+
+    let mut replacements = Vec::new();
+    for mut event in edited {
+        // Events not in the destination yet are synced as usual
+        if let Some(old) = existing.get(&match_key(&event)) {
+            if event_revision(&event) > event_revision(old) {
+                // Setting the ID makes the insert replace the old version
+                event.id = old.id;
+                sync_spec.redact_event(bucket_from, &mut event);
+                replacements.push(event);
+            }
+        }
+    }
+
+    if replacements.is_empty() {
+        return Ok(0);
+    }
+    let count = replacements.len();
+    if sync_spec.dry_run {
+        info!("{log_prefix}  ~ Would update {} edited events", count);
+    } else {
+        ds_to
+            .insert_events(bucket_to, replacements)
+            .map_err(SyncError::Access)?;
+        info!("{log_prefix}  = Updated {} edited events", count);
+    }
+    Ok(count)
+}
@@ -0,0 +1,159 @@
+//! Metrics of sync passes, for `aw-sync sync --metrics-file`
+//!
+//! Written in the text format of Prometheus, to be scraped by the textfile collector of
+//! node_exporter, so that stale or failing syncs can be alerted on. Counters are totals over all
+//! runs, and are carried over from the metrics file written by the previous run.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use chrono::Utc;
+
+use crate::error::{io_context, SyncError};
+use crate::sync::SyncRunSummary;
+
+const LAST_RUN: &str = "aw_sync_last_run_timestamp";
+const LAST_SUCCESS: &str = "aw_sync_last_success_timestamp";
+const LAST_DURATION: &str = "aw_sync_last_run_duration_seconds";
+const LAST_RUN_SUCCESS: &str = "aw_sync_last_run_success";
+const RUNS: &str = "aw_sync_runs_total";
+const PULLED: &str = "aw_sync_events_pulled_total";
+const PUSHED: &str = "aw_sync_events_pushed_total";
+const ERRORS: &str = "aw_sync_errors_total";
+
+/// Escapes a label value, as required by the text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn pulled_series(remote: &str) -> String {
+    format!("{PULLED}{{remote=\"{}\"}}", escape_label(remote))
+}
+
+/// Parses the samples of a metrics file, keyed by series (the metric name and its labels)
+///
+/// Only needs to read files written by [`write_metrics`], so timestamps aren't supported.
+pub fn parse_metrics(content: &str) -> BTreeMap<String, f64> {
+    content
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            Some((series.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Writes the metrics of a sync pass to `path`
+///
+/// `summary` is None if the pass failed before syncing anything, such as when aw-server wasn't
+/// reachable. A pass counts as successful if no bucket failed and it wasn't stopped early.
+pub fn write_metrics(path: &Path, summary: Option<&SyncRunSummary>) -> Result<(), SyncError> {
+    let previous = match fs::read_to_string(path) {
+        Ok(content) => parse_metrics(&content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            warn!(
+                "Failed to read metrics {}, resetting them: {e}",
+                path.display()
+            );
+            BTreeMap::new()
+        }
+    };
+    let total =
+        |series: &str, increment: f64| previous.get(series).copied().unwrap_or(0.0) + increment;
+
+    let now = Utc::now().timestamp() as f64;
+    let succeeded = summary.is_some_and(|s| s.errors.is_empty() && !s.timed_out && !s.interrupted);
+    let last_success = if succeeded {
+        Some(now)
+    } else {
+        previous.get(LAST_SUCCESS).copied()
+    };
+    let errors = summary.map_or(1, |s| s.errors.len());
+
+    let mut pulled: BTreeMap<String, f64> = previous
+        .iter()
+        .filter(|(series, _)| series.starts_with(&format!("{PULLED}{{")))
+        .map(|(series, value)| (series.clone(), *value))
+        .collect();
+    for (remote, events) in summary.map(|s| &s.events_pulled).into_iter().flatten() {
+        *pulled.entry(pulled_series(remote)).or_default() += *events as f64;
+    }
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} {kind}").unwrap();
+        for (series, value) in samples {
+            writeln!(out, "{series} {value}").unwrap();
+        }
+    };
+    metric(
+        LAST_RUN,
+        "gauge",
+        "Unix time of the last sync pass",
+        &[(LAST_RUN.to_string(), now)],
+    );
+    if let Some(last_success) = last_success {
+        metric(
+            LAST_SUCCESS,
+            "gauge",
+            "Unix time of the last sync pass without errors",
+            &[(LAST_SUCCESS.to_string(), last_success)],
+        );
+    }
+    if let Some(summary) = summary {
+        metric(
+            LAST_DURATION,
+            "gauge",
+            "Duration of the last sync pass in seconds",
+            &[(LAST_DURATION.to_string(), summary.duration_secs)],
+        );
+    }
+    metric(
+        LAST_RUN_SUCCESS,
+        "gauge",
+        "Whether the last sync pass was without errors",
+        &[(
+            LAST_RUN_SUCCESS.to_string(),
+            if succeeded { 1.0 } else { 0.0 },
+        )],
+    );
+    metric(
+        RUNS,
+        "counter",
+        "Number of sync passes",
+        &[(RUNS.to_string(), total(RUNS, 1.0))],
+    );
+    let pulled: Vec<(String, f64)> = pulled.into_iter().collect();
+    metric(
+        PULLED,
+        "counter",
+        "Number of new events pulled, by device ID of the remote",
+        &pulled,
+    );
+    let pushed = summary.map_or(0, |s| s.events_pushed) as f64;
+    metric(
+        PUSHED,
+        "counter",
+        "Number of new events pushed",
+        &[(PUSHED.to_string(), total(PUSHED, pushed))],
+    );
+    metric(
+        ERRORS,
+        "counter",
+        "Number of buckets which failed to sync, and of sync passes which failed altogether",
+        &[(ERRORS.to_string(), total(ERRORS, errors as f64))],
+    );
+
+    // Written to a temporary file first, so the collector never reads a partial file
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, out).map_err(|e| io_context(e, "write metrics", &tmp))?;
+    fs::rename(&tmp, path).map_err(|e| io_context(e, "write metrics", path))
+}
@@ -0,0 +1,207 @@
+//! Migration of pulled buckets to IDs keyed by device ID
+//!
+//! Older versions suffixed pulled buckets with the hostname of the device they were pulled
+//! from, so devices sharing a hostname had their events merged into the same bucket. Pulled
+//! buckets are now suffixed with the device ID instead (see [`crate::sync`]), and this renames
+//! the buckets pulled by older versions, as long as they came from a single device.
+//!
+//! Buckets which several remotes were pulled into can't be split apart, so they are left as is,
+//! and the remotes are pulled into new buckets from then on.
+//!
+//! Even older versions pulled buckets without recording their `$aw.sync.origin`, and sometimes
+//! suffixed them more than once (`-synced-from-a-synced-from-a`). [`repair_naming`] fixes those,
+//! for `aw-sync repair-naming`.
+
+use std::collections::HashMap;
+
+use aw_datastore::Datastore;
+use aw_models::Bucket;
+
+use crate::accessmethod::AccessMethod;
+use crate::error::SyncError;
+use crate::naming::BucketNameTemplate;
+use crate::state::SyncState;
+use crate::sync::{legacy_origin, pulled_bucket_id, synced_bucket_id, SyncSpec, ORIGIN_DEVICE_KEY};
+
+/// A pulled bucket fixed by [`repair_naming`]
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamingRepair {
+    /// ID of the bucket before the repair
+    pub bucket_id: String,
+    /// ID the bucket was renamed to, if it was
+    pub renamed_to: Option<String>,
+    /// `$aw.sync.origin` filled in, if the bucket had none
+    pub origin: Option<String>,
+}
+
+/// Renames the buckets in `ds_to` pulled from `remotes` by older versions
+///
+/// `remotes` are the remote datastores with their device IDs. Returns the renamed buckets as
+/// (old ID, new ID) pairs. If `sync_spec.dry_run` is set, nothing is renamed and the buckets
+/// which would be renamed are returned.
+pub fn migrate_synced_bucket_ids(
+    remotes: &[(&Datastore, String)],
+    ds_to: &dyn AccessMethod,
+    sync_spec: &SyncSpec,
+    state: &mut SyncState,
+) -> Result<Vec<(String, String)>, SyncError> {
+    // Legacy bucket IDs with the new IDs of the remote buckets which were pulled into them
+    let mut legacy: HashMap<String, Vec<String>> = HashMap::new();
+    for (ds_from, did) in remotes {
+        let buckets = ds_from.get_buckets().map_err(SyncError::Datastore)?;
+        for bucket in buckets.values() {
+            let old_id = synced_bucket_id(bucket, legacy_origin(bucket), &sync_spec.bucket_names);
+            let new_id = pulled_bucket_id(bucket, Some(did), &sync_spec.bucket_names);
+            if old_id != new_id {
+                legacy.entry(old_id).or_default().push(new_id);
+            }
+        }
+    }
+
+    let mut legacy: Vec<(String, Vec<String>)> = legacy.into_iter().collect();
+    legacy.sort();
+    let mut renamed = Vec::new();
+    for (old_id, new_ids) in legacy {
+        let old_bucket = match ds_to.get_bucket(&old_id) {
+            Ok(bucket) => bucket,
+            Err(_) => continue,
+        };
+        if new_ids.len() > 1 {
+            warn!(
+                "Bucket {old_id} has events pulled from {} devices with the same hostname, \
+                 leaving it as is and pulling them into {:?} from now on",
+                new_ids.len(),
+                new_ids
+            );
+            continue;
+        }
+        let new_id = &new_ids[0];
+        if ds_to.get_bucket(new_id).is_ok() {
+            warn!("Not renaming bucket {old_id}, {new_id} already exists");
+            continue;
+        }
+        if sync_spec.dry_run {
+            info!("Would rename bucket {old_id} to {new_id}");
+        } else {
+            rename_bucket(ds_to, &old_bucket, new_id)?;
+            if let Some(bucket_state) = state.buckets.remove(&old_id) {
+                state.buckets.insert(new_id.clone(), bucket_state);
+            }
+            info!("Renamed bucket {old_id} to {new_id}");
+        }
+        renamed.push((old_id, new_id.clone()));
+    }
+    Ok(renamed)
+}
+
+/// Repairs the pulled buckets in `ds` named by old versions
+///
+/// Buckets named `{id}-synced-from-{origin}` without a `$aw.sync.origin` get the origin from
+/// their ID, and IDs with the suffix repeated are collapsed to a single one, unless a bucket with
+/// that ID already exists. Only the default naming is repaired, as it's the only one old versions
+/// had. Repaired buckets are fine already, so running it again changes nothing. If `dry_run` is
+/// set, nothing is changed and the buckets which would be repaired are returned.
+pub fn repair_naming(
+    ds: &dyn AccessMethod,
+    dry_run: bool,
+    state: &mut SyncState,
+) -> Result<Vec<NamingRepair>, SyncError> {
+    let names = BucketNameTemplate::default();
+    let mut buckets: Vec<Bucket> = ds
+        .get_buckets()
+        .map_err(SyncError::Access)?
+        .into_values()
+        .filter(|bucket| bucket.id.contains(names.separator()))
+        .collect();
+    buckets.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut repairs = Vec::new();
+    for mut bucket in buckets {
+        let Some((_, origin)) = bucket.id.rsplit_once(names.separator()) else {
+            continue;
+        };
+        if origin.is_empty() {
+            warn!("Not repairing bucket {}, it has no origin", bucket.id);
+            continue;
+        }
+        let origin = origin.to_string();
+        let normalized = names.format(names.source_id(&bucket.id, &origin), &origin);
+
+        // Suffixes which are device IDs are recorded as such, then the origin is the hostname
+        let origin_device = bucket.data.get(ORIGIN_DEVICE_KEY).and_then(|o| o.as_str());
+        let backfilled = if bucket.data.contains_key("$aw.sync.origin") {
+            None
+        } else if origin_device == Some(origin.as_str()) {
+            Some(bucket.hostname.clone())
+        } else {
+            Some(origin.clone())
+        };
+        let renamed_to = if normalized == bucket.id {
+            None
+        } else if ds.get_bucket(&normalized).is_ok() {
+            warn!(
+                "Not renaming bucket {}, {normalized} already exists",
+                bucket.id
+            );
+            None
+        } else {
+            Some(normalized)
+        };
+        if backfilled.is_none() && renamed_to.is_none() {
+            continue;
+        }
+
+        let action = if dry_run { "Would set" } else { "Setting" };
+        if let Some(backfilled) = &backfilled {
+            info!(
+                "{action} $aw.sync.origin of bucket {} to {backfilled}",
+                bucket.id
+            );
+            bucket
+                .data
+                .insert("$aw.sync.origin".to_string(), serde_json::json!(backfilled));
+        }
+        match &renamed_to {
+            Some(new_id) if dry_run => info!("Would rename bucket {} to {new_id}", bucket.id),
+            Some(new_id) => {
+                rename_bucket(ds, &bucket, new_id)?;
+                if let Some(bucket_state) = state.buckets.remove(&bucket.id) {
+                    state.buckets.insert(new_id.clone(), bucket_state);
+                }
+                info!("Renamed bucket {} to {new_id}", bucket.id);
+            }
+            None if dry_run => {}
+            None => ds.update_bucket(&bucket).map_err(SyncError::Datastore)?,
+        }
+        repairs.push(NamingRepair {
+            bucket_id: bucket.id,
+            renamed_to,
+            origin: backfilled,
+        });
+    }
+    Ok(repairs)
+}
+
+/// Copies a bucket with its events to `new_id` and deletes the old bucket
+fn rename_bucket(ds: &dyn AccessMethod, bucket: &Bucket, new_id: &str) -> Result<(), SyncError> {
+    let mut bucket_new = bucket.clone();
+    bucket_new.id = new_id.to_string();
+    // Events are copied separately, not as part of the bucket
+    bucket_new.events = None;
+    ds.create_bucket(&bucket_new)
+        .map_err(SyncError::Datastore)?;
+
+    let mut events = ds
+        .get_events(&bucket.id, None, None, None)
+        .map_err(SyncError::Access)?;
+    for event in events.iter_mut() {
+        event.id = None;
+    }
+    for chunk in events.chunks(1000) {
+        ds.insert_events(new_id, chunk.to_vec())
+            .map_err(SyncError::Access)?;
+    }
+    ds.delete_bucket(&bucket.id).map_err(SyncError::Datastore)?;
+    Ok(())
+}
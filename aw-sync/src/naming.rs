@@ -0,0 +1,122 @@
+//! Naming of the buckets pulled from other devices
+//!
+//! Pulled buckets are named after their source bucket and the device they were pulled from, by
+//! default `{id}-synced-from-{origin}`. Tools expecting another convention can configure a
+//! template of their own with `bucket_name_template` in the config file, such as `{id}@{origin}`.
+//!
+//! Changing the template doesn't rename buckets which were already pulled, they stay as they are
+//! and the events are pulled again into buckets named by the new template.
+
+use crate::error::SyncError;
+
+/// Default template of pulled bucket IDs
+pub const DEFAULT_BUCKET_NAME_TEMPLATE: &str = "{id}-synced-from-{origin}";
+
+/// Text between the placeholders of the default template
+const DEFAULT_SEPARATOR: &str = "-synced-from-";
+
+/// Template of the IDs of pulled buckets, with `{id}` and `{origin}` placeholders
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketNameTemplate {
+    template: String,
+    /// Text between the placeholders
+    separator: String,
+}
+
+impl Default for BucketNameTemplate {
+    fn default() -> BucketNameTemplate {
+        BucketNameTemplate::parse(DEFAULT_BUCKET_NAME_TEMPLATE).unwrap()
+    }
+}
+
+impl BucketNameTemplate {
+    /// Parses a template
+    ///
+    /// Both placeholders have to occur exactly once, and be separated by some text, so that the
+    /// origin can be told apart from the bucket ID. Characters which break the bucket URLs of the
+    /// REST API (`/`, `?`, `#` and whitespace) are rejected.
+    pub fn parse(template: &str) -> Result<BucketNameTemplate, SyncError> {
+        let invalid = |reason: &str| {
+            SyncError::Config(format!(
+                "invalid bucket_name_template '{template}': {reason}"
+            ))
+        };
+        for placeholder in ["{id}", "{origin}"] {
+            if template.matches(placeholder).count() != 1 {
+                return Err(invalid(&format!("needs {placeholder} exactly once")));
+            }
+        }
+        let literal = template.replace("{id}", "").replace("{origin}", "");
+        if literal.contains(['{', '}']) {
+            return Err(invalid(
+                "only {id} and {origin} are supported as placeholders",
+            ));
+        }
+        if literal
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '/' | '?' | '#'))
+        {
+            return Err(invalid("contains characters not allowed in bucket IDs"));
+        }
+        let id_at = template.find("{id}").unwrap();
+        let origin_at = template.find("{origin}").unwrap();
+        let separator = if id_at < origin_at {
+            &template[id_at + "{id}".len()..origin_at]
+        } else {
+            &template[origin_at + "{origin}".len()..id_at]
+        };
+        if separator.is_empty() {
+            return Err(invalid(
+                "{id} and {origin} have to be separated by some text",
+            ));
+        }
+        Ok(BucketNameTemplate {
+            template: template.to_string(),
+            separator: separator.to_string(),
+        })
+    }
+
+    /// Returns the text between the placeholders, such as `-synced-from-`
+    pub fn separator(&self) -> &str {
+        &self.separator
+    }
+
+    /// Returns the text before and after `{id}`, for the given origin
+    fn around(&self, origin: &str) -> (String, String) {
+        let (before, after) = self.template.split_once("{id}").unwrap();
+        (
+            before.replace("{origin}", origin),
+            after.replace("{origin}", origin),
+        )
+    }
+
+    /// Returns the ID of the bucket with ID `bucket_id` when pulled from `origin`
+    pub fn format(&self, bucket_id: &str, origin: &str) -> String {
+        let (before, after) = self.around(origin);
+        format!("{before}{bucket_id}{after}")
+    }
+
+    /// Returns the ID of the source bucket of a bucket pulled from `origin`
+    ///
+    /// Bucket IDs which aren't named after `origin` are returned as they are. Repeated names
+    /// (`-synced-from-a-synced-from-a`), as created by earlier versions, are collapsed.
+    pub fn source_id<'a>(&self, bucket_id: &'a str, origin: &str) -> &'a str {
+        let (before, after) = self.around(origin);
+        let mut source_id = bucket_id;
+        while let Some(stripped) = source_id
+            .strip_prefix(&before)
+            .and_then(|id| id.strip_suffix(&after))
+        {
+            source_id = stripped;
+        }
+        source_id
+    }
+
+    /// Returns true if a bucket ID looks like the ID of a pulled bucket
+    ///
+    /// Only checks for the text between the placeholders. IDs named by the default template
+    /// always count, so that buckets pulled before the template was changed are never pushed.
+    pub fn is_pulled(&self, bucket_id: &str) -> bool {
+        bucket_id.contains(&self.separator) || bucket_id.contains(DEFAULT_SEPARATOR)
+    }
+}
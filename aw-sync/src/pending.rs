@@ -0,0 +1,142 @@
+//! Counts of the events a sync pass would sync, for `aw-sync sync --show-pending`
+//!
+//! Only counts the events after the point each bucket resumes at, which is much cheaper than a
+//! dry run, as no events are read. Counts can be a bit higher than what's synced in the end, as
+//! excluded and duplicate events are counted too.
+
+use aw_models::Bucket;
+use serde::Serialize;
+
+use crate::accessmethod::AccessMethod;
+use crate::crypt::open_remote;
+use crate::error::SyncError;
+use crate::sync::{
+    filter_only_remote, find_remotes_nonlocal, local_remote_dbfile, remote_device_id, resume_point,
+    SyncSpec,
+};
+use crate::version::filter_compatible_remotes;
+
+/// Number of events of a bucket which would be synced
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PendingCount {
+    /// Device ID of the remote the bucket is pulled from, or the local device ID if pushed
+    pub remote: String,
+    pub source_bucket: String,
+    pub synced_bucket: String,
+    pub events: i64,
+}
+
+fn count_pending(
+    remote: &str,
+    ds_from: &dyn AccessMethod,
+    source_bucket: &str,
+    ds_to: &dyn AccessMethod,
+    synced_bucket: String,
+    sync_spec: &SyncSpec,
+) -> Result<PendingCount, SyncError> {
+    let resume_at = match ds_to.get_bucket(&synced_bucket) {
+        Ok(bucket_to) => resume_point(&bucket_to, sync_spec),
+        Err(_) => sync_spec.start,
+    };
+    let events = ds_from
+        .get_event_count_range(source_bucket, resume_at, sync_spec.end)
+        .map_err(SyncError::Access)?;
+    Ok(PendingCount {
+        remote: remote.to_string(),
+        source_bucket: source_bucket.to_string(),
+        synced_bucket,
+        events,
+    })
+}
+
+/// Counts the events of the buckets `sync_spec` syncs which would be pulled into `local` or
+/// pushed from it, without syncing anything
+///
+/// `local` is the local aw-server (or a datastore standing in for it), pushing to the staging
+/// datastore of `device_id`. Buckets merged by [`SyncSpec::origin_aliases`] are counted from the
+/// end of the merged bucket, so their older events aren't counted.
+pub fn pending_counts(
+    local: &dyn AccessMethod,
+    device_id: &str,
+    sync_spec: &SyncSpec,
+) -> Result<Vec<PendingCount>, SyncError> {
+    let included =
+        |b: &Bucket| sync_spec.may_include_bucket(b) && sync_spec.includes_bucket_type(&b._type);
+    let passphrase = sync_spec.passphrase.as_deref();
+    let mut counts = Vec::new();
+
+    // Pulled buckets
+    let remote_dbfiles = find_remotes_nonlocal(&sync_spec.path, device_id, None)?;
+    let only_remote = sync_spec.only_remote.as_deref();
+    let remote_dbfiles = filter_only_remote(&sync_spec.path, remote_dbfiles, only_remote)?;
+    for path in filter_compatible_remotes(remote_dbfiles) {
+        let remote = remote_device_id(&sync_spec.path, &path);
+        let (ds, _plaintext) = match open_remote(&path, passphrase) {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("Skipping remote {}: {e}", path.display());
+                continue;
+            }
+        };
+        let mut buckets: Vec<_> = ds
+            .get_buckets()
+            .map_err(SyncError::Datastore)?
+            .into_values()
+            .filter(included)
+            .collect();
+        sync_spec.select_flagged(&mut buckets);
+        buckets.sort_by(|a, b| a.id.cmp(&b.id));
+        for bucket in buckets {
+            let synced_bucket = sync_spec.pulled_bucket_id(&bucket, Some(&remote));
+            let result = count_pending(&remote, &ds, &bucket.id, local, synced_bucket, sync_spec);
+            counts.push(result?);
+        }
+        ds.close();
+    }
+
+    // Pushed buckets, all of their events are pending if nothing was pushed yet
+    let dbfile = local_remote_dbfile(
+        &sync_spec.path,
+        device_id,
+        sync_spec.instance.as_deref(),
+        sync_spec.db_filename.as_deref(),
+    )?;
+    let staging = if dbfile.exists() {
+        Some(open_remote(&dbfile, passphrase)?)
+    } else {
+        None
+    };
+    let mut buckets: Vec<_> = local
+        .get_buckets()
+        .map_err(SyncError::Access)?
+        .into_values()
+        .filter(|b| !sync_spec.bucket_names.is_pulled(&b.id) && included(b))
+        .collect();
+    sync_spec.select_flagged(&mut buckets);
+    buckets.sort_by(|a, b| a.id.cmp(&b.id));
+    for bucket in buckets {
+        let count = match &staging {
+            Some((ds_staging, _)) => count_pending(
+                device_id,
+                local,
+                &bucket.id,
+                ds_staging,
+                bucket.id.clone(),
+                sync_spec,
+            )?,
+            None => PendingCount {
+                remote: device_id.to_string(),
+                source_bucket: bucket.id.clone(),
+                synced_bucket: bucket.id.clone(),
+                events: local
+                    .get_event_count_range(&bucket.id, sync_spec.start, sync_spec.end)
+                    .map_err(SyncError::Access)?,
+            },
+        };
+        counts.push(count);
+    }
+    if let Some((ds_staging, _plaintext)) = staging {
+        ds_staging.close();
+    }
+    Ok(counts)
+}
@@ -0,0 +1,108 @@
+//! Progress reporting for sync passes
+//!
+//! Embedders (like aw-qt) can implement [`SyncProgress`], or pass a closure, to render progress
+//! without scraping the log output.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncProgressEvent {
+    /// Syncing of a bucket started
+    BucketStarted { bucket_id: String },
+    /// A batch of events was inserted into a bucket
+    EventsInserted {
+        bucket_id: String,
+        /// Number of events inserted into the bucket so far
+        inserted: usize,
+        /// Number of events which will be inserted into the bucket in total
+        total: usize,
+    },
+    /// Syncing of a bucket finished
+    BucketFinished {
+        bucket_id: String,
+        /// Number of new events in the bucket
+        new_events: usize,
+    },
+}
+
+/// Receives progress events during a sync pass
+///
+/// Must be `Sync`, as remotes are pulled from in parallel.
+pub trait SyncProgress: Sync {
+    fn report(&self, event: SyncProgressEvent);
+}
+
+impl<F: Fn(SyncProgressEvent) + Sync> SyncProgress for F {
+    fn report(&self, event: SyncProgressEvent) {
+        self(event)
+    }
+}
+
+/// Prints the progress of inserting events to stdout, overwriting the line as it goes
+pub struct ConsoleProgress;
+
+impl SyncProgress for ConsoleProgress {
+    fn report(&self, event: SyncProgressEvent) {
+        // TODO: Don't print progress messages if not in a suitable terminal environment (such as a
+        // pipe or systemd journal)
+        if let SyncProgressEvent::EventsInserted {
+            bucket_id,
+            inserted,
+            total,
+        } = event
+        {
+            print!("{bucket_id} ({inserted}/{total})\r");
+            std::io::stdout().flush().ok();
+        }
+    }
+}
+
+/// Prints the number of events inserted into all buckets out of `total` to stdout, such as the
+/// total of [`pending_counts`](crate::pending_counts)
+pub struct TotalProgress {
+    total: i64,
+    /// Events inserted into finished buckets, and so far into each unfinished bucket
+    inserted: Mutex<(usize, HashMap<String, usize>)>,
+}
+
+impl TotalProgress {
+    pub fn new(total: i64) -> TotalProgress {
+        TotalProgress {
+            total,
+            inserted: Mutex::new((0, HashMap::new())),
+        }
+    }
+}
+
+impl SyncProgress for TotalProgress {
+    fn report(&self, event: SyncProgressEvent) {
+        let mut inserted = self.inserted.lock().unwrap();
+        let (finished, unfinished) = &mut *inserted;
+        match event {
+            SyncProgressEvent::EventsInserted {
+                bucket_id,
+                inserted,
+                ..
+            } => {
+                unfinished.insert(bucket_id, inserted);
+            }
+            // Buckets are synced more than once when pushing to remote servers too
+            SyncProgressEvent::BucketFinished { bucket_id, .. } => {
+                *finished += unfinished.remove(&bucket_id).unwrap_or(0);
+            }
+            SyncProgressEvent::BucketStarted { .. } => return,
+        }
+        let total_inserted = *finished + unfinished.values().sum::<usize>();
+        print!("Synced {total_inserted}/{} events\r", self.total);
+        std::io::stdout().flush().ok();
+    }
+}
+
+/// Ignores all progress events
+pub struct NoProgress;
+
+impl SyncProgress for NoProgress {
+    fn report(&self, _event: SyncProgressEvent) {}
+}
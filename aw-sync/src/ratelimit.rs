@@ -0,0 +1,50 @@
+//! Rate limiting of inserting events into aw-server, for `aw-sync sync --insert-rate`
+//!
+//! Pulling a big backlog into aw-server as fast as possible can keep it busy enough that the
+//! heartbeats of the watchers feeding it time out. The limit is a token bucket shared by all
+//! remotes being pulled from, so pulling in parallel doesn't multiply it.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Events per second
+    rate: f64,
+    /// Tokens available and when they were last refilled. Negative while events taken ahead of
+    /// the rate are paid back.
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// Limits to `rate` events per second, allowing bursts of up to a second's worth of events
+    ///
+    /// A rate of 0 is taken as 1, as nothing would ever be inserted otherwise.
+    pub fn new(rate: u32) -> RateLimiter {
+        let rate = f64::from(rate.max(1));
+        RateLimiter {
+            rate,
+            tokens: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Takes `n` events worth of tokens, sleeping until they'd have been available
+    ///
+    /// Batches bigger than a burst are let through after waiting for the time they take at the
+    /// rate, so bulk inserts don't have to be split up.
+    pub fn acquire(&self, n: usize) {
+        let wait = {
+            let mut tokens = self.tokens.lock().unwrap();
+            let (available, refilled) = *tokens;
+            let now = Instant::now();
+            let refill = now.duration_since(refilled).as_secs_f64() * self.rate;
+            let available = (available + refill).min(self.rate) - n as f64;
+            *tokens = (available, now);
+            // The lock isn't held while sleeping, so others queue up behind the debt instead
+            Duration::from_secs_f64((-available).max(0.0) / self.rate)
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
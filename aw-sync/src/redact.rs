@@ -0,0 +1,76 @@
+//! Redaction of sensitive event data before syncing
+//!
+//! Unlike exclusion (see the `filter` module), redacted events are still synced, so the timeline
+//! and time stats stay complete, but the configured fields of their data are scrubbed before
+//! they are written to the destination. Timestamps and durations are never changed.
+//!
+//! Hashing is deterministic for a given salt, so redacted events still deduplicate, and the same
+//! URL gets the same hash on every device sharing a salt.
+//!
+//! Buckets can also be synced with only some of the fields of their data, such as the app and
+//! title of window events without the rest, to keep big data out of the synced buckets.
+//!
+//! The hostname and device ID of a device can be replaced by pseudonyms in what it pushes, for
+//! sync folders shared with others. Pseudonyms are salted hashes, so each device keeps the same
+//! one across runs, and the devices stay apart without revealing which machines they are.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use aw_models::Event;
+
+use crate::merge::REVISION_KEY;
+
+/// Value redacted fields are replaced with by [`Redaction::Replace`]
+pub const REDACTED: &str = "REDACTED";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+    /// Replace the value with [`REDACTED`]
+    Replace,
+    /// Replace the value with a salted SHA-256 hash of it, as hex
+    Hash,
+}
+
+/// Redacts the given fields of the event's data, fields it doesn't have are ignored
+pub fn redact_event(event: &mut Event, fields: &HashMap<String, Redaction>, salt: &str) {
+    for (field, redaction) in fields {
+        if let Some(value) = event.data.get_mut(field) {
+            *value = match redaction {
+                Redaction::Replace => Value::String(REDACTED.to_string()),
+                Redaction::Hash => Value::String(salted_hash(value, salt)),
+            };
+        }
+    }
+}
+
+/// Drops the fields of the event's data other than `fields`, all of them if it is empty
+///
+/// The revision of edited events is always kept, as merging edits relies on it.
+pub fn keep_fields(event: &mut Event, fields: &[String]) {
+    event
+        .data
+        .retain(|field, _| field == REVISION_KEY || fields.contains(field));
+}
+
+/// Returns the pseudonym of a hostname or device ID, such as `anon-3f1c9a0e5b7d2468`
+///
+/// The same ID and salt always give the same pseudonym.
+pub fn pseudonym(id: &str, salt: &str) -> String {
+    let hash = salted_hash(&Value::String(id.to_string()), salt);
+    format!("anon-{}", &hash[..16])
+}
+
+fn salted_hash(value: &Value, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    match value {
+        Value::String(s) => hasher.update(s.as_bytes()),
+        other => hasher.update(other.to_string().as_bytes()),
+    }
+    format!("{:x}", hasher.finalize())
+}
@@ -0,0 +1,173 @@
+//! Repair of gaps in pulled buckets, for `aw-sync repair`
+//!
+//! Pulls resume after the last event in the destination bucket, so events missing from earlier
+//! on (such as after restoring a backup of aw-server) are never pulled again. Repairing a time
+//! window deletes the events of the pulled bucket which start in it and pulls them again from the
+//! source bucket. Events outside of the window are never touched.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use aw_models::{Bucket, Event};
+
+use crate::accessmethod::AccessMethod;
+use crate::crypt::open_remote;
+use crate::error::SyncError;
+use crate::sync::{bucket_origin, find_remotes_nonlocal, remote_device_id, SyncSpec};
+use crate::version::filter_compatible_remotes;
+
+/// Result of repairing a time window of a pulled bucket
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RepairSummary {
+    /// Device ID of the remote the bucket is pulled from
+    pub remote: String,
+    pub source_bucket: String,
+    pub synced_bucket: String,
+    /// Number of events deleted from the pulled bucket (or which would be, in a dry run)
+    pub deleted: usize,
+    /// Number of events pulled again (or which would be, in a dry run)
+    pub pulled: usize,
+}
+
+fn in_window(event: &Event, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+    event.timestamp >= start && event.timestamp < end
+}
+
+/// Pulls the events starting in `[start, end)` into the pulled bucket `bucket_id` again
+///
+/// `local` is the local aw-server (or a datastore standing in for it), with the device ID
+/// `device_id`. The source bucket is looked up in the remotes in the sync folder of `sync_spec`,
+/// and events are excluded and redacted as in a sync pass. If `sync_spec.dry_run` is set, nothing
+/// is written and the numbers of events which would be deleted and pulled are returned.
+pub fn repair(
+    local: &dyn AccessMethod,
+    device_id: &str,
+    bucket_id: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    sync_spec: &SyncSpec,
+) -> Result<RepairSummary, SyncError> {
+    if !sync_spec.bucket_names.is_pulled(bucket_id) {
+        return Err(SyncError::Config(format!(
+            "'{bucket_id}' isn't a pulled bucket, only pulled buckets can be repaired"
+        )));
+    }
+    if start >= end {
+        return Err(SyncError::Config(format!(
+            "the start of the window ({start}) has to be before its end ({end})"
+        )));
+    }
+    local
+        .get_bucket(bucket_id)
+        .map_err(|e| SyncError::Access(format!("{e:?}")))?;
+
+    let remote_dbfiles =
+        filter_compatible_remotes(find_remotes_nonlocal(&sync_spec.path, device_id, None)?);
+    for path in remote_dbfiles {
+        let remote = remote_device_id(&sync_spec.path, &path);
+        let (ds, _plaintext) = match open_remote(&path, sync_spec.passphrase.as_deref()) {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("Skipping remote {}: {e}", path.display());
+                continue;
+            }
+        };
+        let source: Option<Bucket> = ds
+            .get_buckets()
+            .map_err(SyncError::Datastore)?
+            .into_values()
+            .find(|b| sync_spec.pulled_bucket_id(b, Some(&remote)) == bucket_id);
+        let Some(source) = source else {
+            ds.close();
+            continue;
+        };
+        // The window would only be pulled again from one of the origins
+        if sync_spec.merges_origin(bucket_origin(&source, Some(&remote))) {
+            ds.close();
+            return Err(SyncError::Config(format!(
+                "'{bucket_id}' is merged from several origins by origin_aliases, \
+                which repairing isn't supported for"
+            )));
+        }
+        let result = repair_window(&ds, local, &source, bucket_id, start, end, sync_spec);
+        ds.close();
+        let (deleted, pulled) = result?;
+        return Ok(RepairSummary {
+            remote,
+            source_bucket: source.id,
+            synced_bucket: bucket_id.to_string(),
+            deleted,
+            pulled,
+        });
+    }
+    Err(SyncError::Access(format!(
+        "no remote in {} has the source bucket of '{bucket_id}'",
+        sync_spec.path.display()
+    )))
+}
+
+/// Replaces the events of `bucket_to` in the window by those of `bucket_from`
+///
+/// Returns the numbers of deleted and pulled events.
+fn repair_window(
+    ds_from: &dyn AccessMethod,
+    ds_to: &dyn AccessMethod,
+    bucket_from: &Bucket,
+    bucket_to: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    sync_spec: &SyncSpec,
+) -> Result<(usize, usize), SyncError> {
+    let stale_ids: Vec<i64> = ds_to
+        .get_events(bucket_to, Some(start), Some(end), None)
+        .map_err(SyncError::Access)?
+        .into_iter()
+        .filter(|e| in_window(e, start, end))
+        .filter_map(|e| e.id)
+        .collect();
+
+    let mut events: Vec<Event> = ds_from
+        .get_events(&bucket_from.id, Some(start), Some(end), None)
+        .map_err(SyncError::Access)?
+        .into_iter()
+        .filter(|e| in_window(e, start, end))
+        .filter(|e| !sync_spec.excludes_event(&bucket_from.id, e))
+        .filter(|e| !sync_spec.drops_short_event(&bucket_from.id, e))
+        .collect();
+    for event in &mut events {
+        // Unset ID on events, as they are not globally unique
+        event.id = None;
+        sync_spec.redact_event(&bucket_from.id, event);
+    }
+    // Oldest first, like in a sync pass
+    events.sort_by_key(|e| e.timestamp);
+
+    let (deleted, pulled) = (stale_ids.len(), events.len());
+    if sync_spec.dry_run {
+        info!(
+            "  ~ Would replace {deleted} events in '{bucket_to}' by {pulled} events from '{}'",
+            bucket_from.id
+        );
+        return Ok((deleted, pulled));
+    }
+    // Either both or neither are kept, so a failed repair doesn't leave a bigger gap
+    let mut replace = || {
+        if !stale_ids.is_empty() {
+            ds_to
+                .delete_events(bucket_to, stale_ids.clone())
+                .map_err(SyncError::Access)?;
+        }
+        if !events.is_empty() {
+            ds_to
+                .insert_events(bucket_to, events.clone())
+                .map_err(SyncError::Access)?;
+        }
+        Ok(())
+    };
+    ds_to.transaction(&mut replace)?;
+    info!(
+        "  = Replaced {deleted} events in '{bucket_to}' by {pulled} events from '{}'",
+        bucket_from.id
+    );
+    Ok((deleted, pulled))
+}
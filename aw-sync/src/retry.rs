@@ -0,0 +1,68 @@
+//! Retrying of calls which can fail transiently
+//!
+//! Mostly meant for requests to the local aw-server, which may not be up yet when a scheduled
+//! sync runs right after boot. Only requests which are safe to repeat are retried, as a request
+//! timing out may still have been carried out by the server.
+
+use std::fmt::Display;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every retry after it
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Calls `op` until it succeeds, with exponential backoff between attempts
+///
+/// Returns the error of the last attempt if all of them failed.
+pub fn with_retry<T, E: Display>(
+    policy: &RetryPolicy,
+    what: &str,
+    op: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    with_retry_if(policy, what, |_| true, op)
+}
+
+/// Like [`with_retry`], but only retries the errors `retryable` returns true for
+pub fn with_retry_if<T, E: Display>(
+    policy: &RetryPolicy,
+    what: &str,
+    retryable: impl Fn(&E) -> bool,
+    mut op: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut delay = policy.base_delay;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_attempts && retryable(&e) => {
+                warn!(
+                    "Failed to {} (attempt {}/{}), retrying in {:?}: {}",
+                    what, attempt, policy.max_attempts, delay, e
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns true if a request to aw-server may succeed when repeated, as it couldn't connect or
+/// the server failed
+pub fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.status().is_some_and(|status| status.is_server_error())
+}
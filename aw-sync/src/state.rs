@@ -0,0 +1,210 @@
+//! Persisted state from previous sync passes
+//!
+//! Stored as a JSON file in a local directory of the staging datastore of the local device (see
+//! [`local_state_dir`]), and used to skip buckets which haven't changed since they were last
+//! synced, and to warn about remotes which haven't been synced in a while.
+//!
+//! Checkpoints of buckets being pulled are stored in a separate file, which is written after every
+//! committed batch of events rather than at the end of a pass, so an interrupted pass can resume
+//! where it stopped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::default_state_directory;
+use crate::error::{io_context, SyncError};
+
+/// Returns the local directory of the state of the staging datastore at `dbfile`, creating it
+///
+/// The state is only of use to this device and rewritten by every pass, so it's kept out of the
+/// sync folder, where it would be synced to every other device. Each staging datastore has a
+/// directory of its own, named after its file and a hash of its path.
+pub fn local_state_dir(dbfile: &Path) -> Result<PathBuf, SyncError> {
+    let parent = dbfile.parent().unwrap_or_else(|| Path::new("."));
+    // The same staging datastore gets the same directory however its path is spelled
+    let parent = fs::canonicalize(parent).unwrap_or_else(|_| parent.to_path_buf());
+    let path = parent.join(dbfile.file_name().unwrap_or_default());
+    let hash = format!("{:x}", Sha256::digest(path.to_string_lossy().as_bytes()));
+    let stem = dbfile.file_stem().unwrap_or_default().to_string_lossy();
+    let dir = default_state_directory()?.join(format!("{stem}-{}", &hash[..16]));
+    fs::create_dir_all(&dir).map_err(|e| io_context(e, "create state directory", &dir))?;
+    Ok(dir)
+}
+
+/// Returns the path of the sync state of the staging datastore at `dbfile`
+pub fn state_file(dbfile: &Path) -> Result<PathBuf, SyncError> {
+    local_state_file(dbfile, "state.json")
+}
+
+/// Returns the path of the checkpoints of the staging datastore at `dbfile`
+pub fn checkpoints_file(dbfile: &Path) -> Result<PathBuf, SyncError> {
+    local_state_file(dbfile, "checkpoint.json")
+}
+
+fn local_state_file(dbfile: &Path, name: &str) -> Result<PathBuf, SyncError> {
+    let path = local_state_dir(dbfile)?.join(name);
+    // Older versions kept the state next to the staging datastore, in the sync folder
+    let legacy = dbfile.with_extension(name);
+    if legacy.exists() {
+        if !path.exists() {
+            info!("Moving {} to {}", legacy.display(), path.display());
+            fs::copy(&legacy, &path).map_err(|e| io_context(e, "move state file", &legacy))?;
+        }
+        fs::remove_file(&legacy).map_err(|e| io_context(e, "remove state file", &legacy))?;
+    }
+    Ok(path)
+}
+
+/// State of a single bucket after it was last synced
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BucketSyncState {
+    /// End of the last event in the source bucket
+    pub end: DateTime<Utc>,
+    /// Number of events in the destination bucket from where syncing resumes after `end`, which
+    /// only change if the destination did
+    pub eventcount: i64,
+    /// When new events were last written to the destination bucket
+    #[serde(default)]
+    pub flushed: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SyncState {
+    /// State of each synced bucket, keyed by destination bucket ID
+    #[serde(default)]
+    pub buckets: HashMap<String, BucketSyncState>,
+    /// When each remote was last pulled from without errors, keyed by device ID
+    #[serde(default)]
+    pub remotes: HashMap<String, DateTime<Utc>>,
+    /// Checkpoints of buckets being pulled, shared by all clones of the state
+    #[serde(skip)]
+    pub checkpoints: Option<Arc<Checkpoints>>,
+}
+
+impl SyncState {
+    /// Loads the state from a file, falling back to an empty state if it can't be read
+    pub fn load(path: &Path) -> SyncState {
+        if !path.exists() {
+            return SyncState::default();
+        }
+        let parsed = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(state) => state,
+            Err(e) => {
+                warn!(
+                    "Failed to read sync state {}, ignoring it: {e}",
+                    path.display()
+                );
+                SyncState::default()
+            }
+        }
+    }
+
+    /// Returns the remotes which weren't pulled from without errors within `threshold` before
+    /// now, with when they last were, oldest first
+    pub fn stale_remotes(&self, threshold: Duration) -> Vec<(&str, DateTime<Utc>)> {
+        let cutoff = Utc::now() - threshold;
+        let mut stale: Vec<(&str, DateTime<Utc>)> = self
+            .remotes
+            .iter()
+            .filter(|(_, last_sync)| **last_sync < cutoff)
+            .map(|(did, last_sync)| (did.as_str(), *last_sync))
+            .collect();
+        stale.sort_by_key(|(_, last_sync)| *last_sync);
+        stale
+    }
+
+    /// Returns the bucket states which differ from `before`, `None` for ones which were removed
+    ///
+    /// Remotes pulled from at the same time each sync with a clone of the same state, so only
+    /// what each of them changed can be merged back, see [`SyncState::apply_bucket_changes`].
+    pub fn bucket_changes(&self, before: &SyncState) -> HashMap<String, Option<BucketSyncState>> {
+        let mut changes: HashMap<String, Option<BucketSyncState>> = self
+            .buckets
+            .iter()
+            .filter(|(bucket_id, bucket)| before.buckets.get(*bucket_id) != Some(*bucket))
+            .map(|(bucket_id, bucket)| (bucket_id.clone(), Some(bucket.clone())))
+            .collect();
+        for bucket_id in before.buckets.keys() {
+            if !self.buckets.contains_key(bucket_id) {
+                changes.insert(bucket_id.clone(), None);
+            }
+        }
+        changes
+    }
+
+    pub fn apply_bucket_changes(&mut self, changes: HashMap<String, Option<BucketSyncState>>) {
+        for (bucket_id, change) in changes {
+            match change {
+                Some(bucket) => self.buckets.insert(bucket_id, bucket),
+                None => self.buckets.remove(&bucket_id),
+            };
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SyncError> {
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(path, content).map_err(|e| io_context(e, "write sync state", path))
+    }
+}
+
+/// Timestamps of the last committed events of buckets which weren't fully synced yet, keyed by
+/// destination bucket ID
+///
+/// A bucket has a checkpoint from when its first batch of events is committed until all of its
+/// events are, so a checkpoint left on startup means the previous pass was interrupted.
+#[derive(Debug)]
+pub struct Checkpoints {
+    path: PathBuf,
+    committed: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl Checkpoints {
+    /// Loads the checkpoints from a file, falling back to none if it can't be read
+    pub fn load(path: &Path) -> Checkpoints {
+        let committed = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Checkpoints {
+            path: path.to_path_buf(),
+            committed: Mutex::new(committed),
+        }
+    }
+
+    pub fn get(&self, bucket_id: &str) -> Option<DateTime<Utc>> {
+        self.committed.lock().unwrap().get(bucket_id).copied()
+    }
+
+    /// Records that the events of a bucket until `timestamp` were committed
+    pub fn advance(&self, bucket_id: &str, timestamp: DateTime<Utc>) -> Result<(), SyncError> {
+        let mut committed = self.committed.lock().unwrap();
+        committed.insert(bucket_id.to_string(), timestamp);
+        self.save(&committed)
+    }
+
+    /// Removes the checkpoint of a bucket once all of its events were committed
+    pub fn finish(&self, bucket_id: &str) -> Result<(), SyncError> {
+        let mut committed = self.committed.lock().unwrap();
+        if committed.remove(bucket_id).is_none() {
+            return Ok(());
+        }
+        self.save(&committed)
+    }
+
+    fn save(&self, committed: &HashMap<String, DateTime<Utc>>) -> Result<(), SyncError> {
+        // Written to a temporary file first, so being interrupted never leaves a partial file
+        let tmp = self.path.with_extension("json.tmp");
+        let content = serde_json::to_string(committed)?;
+        fs::write(&tmp, content).map_err(|e| io_context(e, "write checkpoints", &tmp))?;
+        fs::rename(&tmp, &self.path).map_err(|e| io_context(e, "write checkpoints", &self.path))
+    }
+}
@@ -0,0 +1,685 @@
+//! Remote stores to sync staging datastores through, instead of a synchronized folder
+//!
+//! Staging datastores are self-contained SQLite files, so they can be kept in object storage or
+//! on a WebDAV server just as well as in a folder synchronized by Syncthing or the like. With a
+//! remote store configured, the sync directory is a local mirror of the store: the staging
+//! datastores of other devices are downloaded into it before pulling, and the own staging
+//! datastore is uploaded from it after pushing. Everything else works on the mirror as usual.
+//!
+//! Files are identified by their path relative to the root of the store, with `/` separators,
+//! such as `{device}/{device}.db`.
+//!
+//! Configured with `[remote_store]` in the config file:
+//!
+//! ```toml
+//! [remote_store]
+//! kind = "s3"
+//! endpoint = "https://s3.eu-central-1.amazonaws.com"
+//! bucket = "my-activitywatch"
+//! region = "eu-central-1"
+//! prefix = "aw-sync/"
+//! ```
+//!
+//! Credentials are best passed in environment variables rather than the config file, see
+//! [`open_store`].
+
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::Utc;
+use regex::Regex;
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use reqwest::{Method, StatusCode, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::error::{io_context, SyncError};
+use crate::longpath::to_extended_length;
+use crate::sync::{find_remotes, is_device_folder};
+use crate::version::version_path;
+
+/// Environment variable with the password of a WebDAV store
+pub const WEBDAV_PASSWORD_ENV: &str = "AW_SYNC_WEBDAV_PASSWORD";
+/// Environment variables with the credentials of an S3 store, `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` are used if they aren't set
+pub const S3_ACCESS_KEY_ID_ENV: &str = "AW_SYNC_S3_ACCESS_KEY_ID";
+pub const S3_SECRET_ACCESS_KEY_ENV: &str = "AW_SYNC_S3_SECRET_ACCESS_KEY";
+
+/// Folders nested deeper than this in a store aren't looked into, like [`find_remotes`] does
+const MAX_DEPTH: usize = 8;
+
+/// Storage of the staging datastores of all devices
+pub trait RemoteStore: std::fmt::Debug + Send + Sync {
+    /// Returns the paths of all files in the store, skipping hidden ones
+    fn list(&self) -> Result<Vec<String>, SyncError>;
+    /// Downloads the file at `key` to `dest`
+    fn download(&self, key: &str, dest: &Path) -> Result<(), SyncError>;
+    /// Uploads the file at `src` to `key`, replacing the file there
+    fn upload(&self, src: &Path, key: &str) -> Result<(), SyncError>;
+}
+
+/// Configuration of a remote store, the `[remote_store]` section of the config file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum RemoteStoreConfig {
+    /// A folder, such as a network share which isn't mounted as the sync directory
+    Folder { path: PathBuf },
+    /// A folder on a WebDAV server, such as Nextcloud
+    Webdav {
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// A bucket in S3 or a service compatible with it, addressed path-style
+    S3 {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        /// Prefix of the keys of all files, such as `aw-sync/`
+        #[serde(default)]
+        prefix: String,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+    },
+}
+
+/// Opens the store of a config
+///
+/// Passwords and keys are read from [`WEBDAV_PASSWORD_ENV`], [`S3_ACCESS_KEY_ID_ENV`] and
+/// [`S3_SECRET_ACCESS_KEY_ENV`] in preference to the config.
+pub fn open_store(config: &RemoteStoreConfig) -> Result<Box<dyn RemoteStore>, SyncError> {
+    let env = |names: &[&str]| names.iter().find_map(|name| std::env::var(name).ok());
+    let store: Box<dyn RemoteStore> = match config {
+        RemoteStoreConfig::Folder { path } => Box::new(FolderStore::new(path.clone())),
+        RemoteStoreConfig::Webdav {
+            url,
+            username,
+            password,
+        } => Box::new(WebDavStore::new(
+            url,
+            username.clone(),
+            env(&[WEBDAV_PASSWORD_ENV]).or_else(|| password.clone()),
+        )?),
+        RemoteStoreConfig::S3 {
+            endpoint,
+            bucket,
+            region,
+            prefix,
+            access_key_id,
+            secret_access_key,
+        } => {
+            let missing =
+                |what: &str| SyncError::Config(format!("remote_store: no {what} for the S3 store"));
+            let credentials = S3Credentials {
+                access_key_id: env(&[S3_ACCESS_KEY_ID_ENV, "AWS_ACCESS_KEY_ID"])
+                    .or_else(|| access_key_id.clone())
+                    .ok_or_else(|| missing("access_key_id"))?,
+                secret_access_key: env(&[S3_SECRET_ACCESS_KEY_ENV, "AWS_SECRET_ACCESS_KEY"])
+                    .or_else(|| secret_access_key.clone())
+                    .ok_or_else(|| missing("secret_access_key"))?,
+                session_token: env(&["AWS_SESSION_TOKEN"]),
+            };
+            Box::new(S3Store::new(endpoint, bucket, region, prefix, credentials)?)
+        }
+    };
+    Ok(store)
+}
+
+/// Returns the path of a file in a folder mirroring a store
+///
+/// Keys with `..` or absolute components are rejected, so that a store can't make files be
+/// written outside of the folder.
+fn mirror_path(root: &Path, key: &str) -> Result<PathBuf, SyncError> {
+    let relative = Path::new(key);
+    let safe = !key.is_empty()
+        && relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if !safe {
+        return Err(SyncError::Store(format!(
+            "invalid file name '{key}' in store"
+        )));
+    }
+    Ok(root.join(relative))
+}
+
+/// Returns the key of a file in a folder mirroring a store
+fn mirror_key(root: &Path, path: &Path) -> Result<String, SyncError> {
+    // Paths of staging datastores may have the extended-length prefix, see the longpath module
+    let extended_root = to_extended_length(root);
+    let relative = path
+        .strip_prefix(root)
+        .or_else(|e| match &extended_root {
+            Some(extended_root) => path.strip_prefix(extended_root),
+            None => Err(e),
+        })
+        .map_err(|_| SyncError::InvalidPath(path.to_path_buf()))?;
+    let components: Option<Vec<&str>> = relative.iter().map(|c| c.to_str()).collect();
+    components
+        .map(|c| c.join("/"))
+        .ok_or_else(|| SyncError::InvalidPath(path.to_path_buf()))
+}
+
+/// Downloads the staging datastores of other devices into the sync directory
+///
+/// Their version markers are downloaded along with them. Files are downloaded to a temporary
+/// file first, so remotes are never opened half downloaded. Returns the number of datastores
+/// downloaded.
+pub fn download_remotes(
+    store: &dyn RemoteStore,
+    sync_directory: &Path,
+    device_id: &str,
+) -> Result<usize, SyncError> {
+    let mut downloaded = 0;
+    for key in store.list()? {
+        let is_db = key.ends_with(".db");
+        if !is_db && !key.ends_with(".version.json") {
+            continue;
+        }
+        // The own staging datastore is only ever uploaded
+        let device_dir = key.split('/').next().unwrap_or_default();
+        if is_device_folder(device_dir, device_id) {
+            continue;
+        }
+        let dest = mirror_path(sync_directory, &key)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|e| io_context(e, "create dir", parent))?;
+        }
+        let mut partial = dest.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        debug!("Downloading {key} to {}", dest.display());
+        store.download(&key, &partial)?;
+        fs::rename(&partial, &dest).map_err(|e| io_context(e, "download", &dest))?;
+        if is_db {
+            downloaded += 1;
+        }
+    }
+    info!("Downloaded {downloaded} remote datastores from {store:?}");
+    Ok(downloaded)
+}
+
+/// Uploads the staging datastore at `dbfile` in the sync directory, with its version marker
+pub fn upload_staging(
+    store: &dyn RemoteStore,
+    sync_directory: &Path,
+    dbfile: &Path,
+) -> Result<(), SyncError> {
+    let marker = version_path(dbfile);
+    // The marker goes first, so the datastore is never downloaded without it
+    for path in [marker.as_path(), dbfile] {
+        if path.exists() {
+            let key = mirror_key(sync_directory, path)?;
+            debug!("Uploading {} to {key}", path.display());
+            store.upload(path, &key)?;
+        }
+    }
+    info!("Uploaded {} to {store:?}", dbfile.display());
+    Ok(())
+}
+
+/// A folder used as a store
+#[derive(Debug)]
+pub struct FolderStore {
+    root: PathBuf,
+}
+
+impl FolderStore {
+    pub fn new(root: PathBuf) -> FolderStore {
+        FolderStore { root }
+    }
+}
+
+impl RemoteStore for FolderStore {
+    fn list(&self) -> Result<Vec<String>, SyncError> {
+        let dbs = find_remotes(&self.root).map_err(|e| io_context(e, "read store", &self.root))?;
+        let mut keys = Vec::new();
+        for db in dbs {
+            let marker = version_path(&db);
+            if marker.exists() {
+                keys.push(mirror_key(&self.root, &marker)?);
+            }
+            keys.push(mirror_key(&self.root, &db)?);
+        }
+        Ok(keys)
+    }
+
+    fn download(&self, key: &str, dest: &Path) -> Result<(), SyncError> {
+        let src = mirror_path(&self.root, key)?;
+        fs::copy(&src, dest).map_err(|e| io_context(e, "download", &src))?;
+        Ok(())
+    }
+
+    fn upload(&self, src: &Path, key: &str) -> Result<(), SyncError> {
+        let dest = mirror_path(&self.root, key)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|e| io_context(e, "create dir", parent))?;
+        }
+        // Copied next to the destination first, so the folder never has a partial file
+        let mut partial = dest.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        fs::copy(src, &partial).map_err(|e| io_context(e, "upload", &partial))?;
+        fs::rename(&partial, &dest).map_err(|e| io_context(e, "upload", &dest))
+    }
+}
+
+fn store_error(what: &str, key: &str, e: impl std::fmt::Display) -> SyncError {
+    SyncError::Store(format!("failed to {what} {key}: {e}"))
+}
+
+/// Writes the body of a successful response to `dest`
+fn save_response(response: Response, key: &str, dest: &Path) -> Result<(), SyncError> {
+    let mut response = response
+        .error_for_status()
+        .map_err(|e| store_error("download", key, e))?;
+    let mut file = File::create(dest).map_err(|e| io_context(e, "download", dest))?;
+    response
+        .copy_to(&mut file)
+        .map_err(|e| store_error("download", key, e))?;
+    Ok(())
+}
+
+/// Returns the body of a file to upload, with its length, as S3 doesn't take chunked uploads
+fn upload_body(src: &Path) -> Result<Body, SyncError> {
+    let file = File::open(src).map_err(|e| io_context(e, "upload", src))?;
+    let len = file
+        .metadata()
+        .map_err(|e| io_context(e, "upload", src))?
+        .len();
+    Ok(Body::sized(file, len))
+}
+
+/// Decodes the `%XX` escapes of a URL path
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = s
+            .get(i + 1..i + 3)
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parses the base URL of a store, which has to end with a `/` for joining keys to it
+fn parse_base_url(url: &str) -> Result<Url, SyncError> {
+    let url = format!("{}/", url.trim_end_matches('/'));
+    Url::parse(&url).map_err(|e| SyncError::Config(format!("remote_store: invalid url {url}: {e}")))
+}
+
+/// A folder on a WebDAV server
+pub struct WebDavStore {
+    client: Client,
+    url: Url,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl std::fmt::Debug for WebDavStore {
+    // Without the password
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WebDavStore(url={:?})", self.url.as_str())
+    }
+}
+
+impl WebDavStore {
+    pub fn new(
+        url: &str,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Result<WebDavStore, SyncError> {
+        Ok(WebDavStore {
+            client: Client::new(),
+            url: parse_base_url(url)?,
+            username,
+            password,
+        })
+    }
+
+    fn request(&self, method: Method, key: &str) -> Result<RequestBuilder, SyncError> {
+        let url = self
+            .url
+            .join(key)
+            .map_err(|e| store_error("access", key, e))?;
+        let request = self.client.request(method, url);
+        Ok(match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        })
+    }
+
+    /// Returns the paths of the files and folders in a folder, folders ending with a `/`
+    fn list_dir(&self, dir: &str) -> Result<Vec<String>, SyncError> {
+        const PROPFIND: &str = r#"<?xml version="1.0"?>
+<propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#;
+        let response = self
+            .request(Method::from_bytes(b"PROPFIND").unwrap(), dir)?
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(PROPFIND)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text())
+            .map_err(|e| store_error("list", dir, e))?;
+        // Servers differ in the namespace prefix they use
+        let href = Regex::new(r"<(?:[A-Za-z]+:)?href>([^<]*)</(?:[A-Za-z]+:)?href>").unwrap();
+        let base = self.url.path();
+        let mut entries = Vec::new();
+        for captures in href.captures_iter(&response) {
+            // Hrefs are either absolute paths or full URLs
+            let Ok(url) = self.url.join(&captures[1]) else {
+                continue;
+            };
+            let Some(relative) = url.path().strip_prefix(base) else {
+                continue;
+            };
+            let relative = percent_decode(relative);
+            // The folder itself is listed too
+            if relative != dir {
+                entries.push(relative);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl RemoteStore for WebDavStore {
+    fn list(&self) -> Result<Vec<String>, SyncError> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![(String::new(), 0)];
+        while let Some((dir, depth)) = dirs.pop() {
+            for entry in self.list_dir(&dir)? {
+                let name = entry
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or_default();
+                if name.starts_with('.') {
+                    continue;
+                }
+                if entry.ends_with('/') {
+                    if depth < MAX_DEPTH {
+                        dirs.push((entry, depth + 1));
+                    }
+                } else {
+                    keys.push(entry);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn download(&self, key: &str, dest: &Path) -> Result<(), SyncError> {
+        let response = self
+            .request(Method::GET, key)?
+            .send()
+            .map_err(|e| store_error("download", key, e))?;
+        save_response(response, key, dest)
+    }
+
+    fn upload(&self, src: &Path, key: &str) -> Result<(), SyncError> {
+        // Folders have to be created one at a time
+        let components: Vec<&str> = key.split('/').collect();
+        let mut dir = String::new();
+        for component in &components[..components.len() - 1] {
+            dir.push_str(component);
+            dir.push('/');
+            let response = self
+                .request(Method::from_bytes(b"MKCOL").unwrap(), &dir)?
+                .send()
+                .map_err(|e| store_error("create", &dir, e))?;
+            // Already exists
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                response
+                    .error_for_status()
+                    .map_err(|e| store_error("create", &dir, e))?;
+            }
+        }
+        self.request(Method::PUT, key)?
+            .body(upload_body(src)?)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| store_error("upload", key, e))?;
+        Ok(())
+    }
+}
+
+struct S3Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+/// A bucket in S3, or a service compatible with it such as MinIO
+pub struct S3Store {
+    client: Client,
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    prefix: String,
+    credentials: S3Credentials,
+}
+
+impl std::fmt::Debug for S3Store {
+    // Without the credentials
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "S3Store(endpoint={:?}, bucket={:?}, prefix={:?})",
+            self.endpoint.as_str(),
+            self.bucket,
+            self.prefix
+        )
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Percent-encodes everything but unreserved characters, as required by signature version 4
+fn aws_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+impl S3Store {
+    fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        prefix: &str,
+        credentials: S3Credentials,
+    ) -> Result<S3Store, SyncError> {
+        Ok(S3Store {
+            client: Client::new(),
+            endpoint: parse_base_url(endpoint)?,
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            prefix: prefix.to_string(),
+            credentials,
+        })
+    }
+
+    /// Returns the path of the object of a file, relative to the endpoint
+    fn object_path(&self, key: &str) -> String {
+        format!("{}/{}{key}", self.bucket, self.prefix)
+    }
+
+    /// Returns a request to `path` (relative to the endpoint) signed with signature version 4
+    ///
+    /// Payloads are left unsigned, so that files can be streamed rather than hashed first.
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<RequestBuilder, SyncError> {
+        let encoded: Vec<String> = path.split('/').map(aws_encode).collect();
+        let mut url = self
+            .endpoint
+            .join(&encoded.join("/"))
+            .map_err(|e| store_error("access", path, e))?;
+        let mut query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", aws_encode(name), aws_encode(value)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+        url.set_query(Some(&query).filter(|q| !q.is_empty()).map(|q| q.as_str()));
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        const PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", PAYLOAD.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{method}\n{}\n{query}\n{canonical_headers}\n{signed_headers}\n{PAYLOAD}",
+            url.path()
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let secret = format!("AWS4{}", self.credentials.secret_access_key);
+        let mut signing_key = hmac_sha256(secret.as_bytes(), date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+            Signature={signature}",
+            self.credentials.access_key_id
+        );
+
+        // The host header is set by reqwest, from the same URL
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("Authorization", authorization);
+        for (name, value) in headers.into_iter().skip(1) {
+            request = request.header(name, value);
+        }
+        Ok(request)
+    }
+}
+
+impl RemoteStore for S3Store {
+    fn list(&self) -> Result<Vec<String>, SyncError> {
+        let key_re = Regex::new(r"<Key>([^<]*)</Key>").unwrap();
+        let token_re =
+            Regex::new(r"<NextContinuationToken>([^<]*)</NextContinuationToken>").unwrap();
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let body = self
+                .request(Method::GET, &format!("{}/", self.bucket), &query)?
+                .send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.text())
+                .map_err(|e| store_error("list", &self.bucket, e))?;
+            for captures in key_re.captures_iter(&body) {
+                let key = xml_unescape(&captures[1]);
+                let Some(key) = key.strip_prefix(&self.prefix) else {
+                    continue;
+                };
+                let hidden = key.split('/').any(|c| c.starts_with('.'));
+                if !key.is_empty() && !hidden {
+                    keys.push(key.to_string());
+                }
+            }
+            token = token_re.captures(&body).map(|c| xml_unescape(&c[1]));
+            if token.is_none() {
+                break;
+            }
+        }
+        Ok(keys)
+    }
+
+    fn download(&self, key: &str, dest: &Path) -> Result<(), SyncError> {
+        let response = self
+            .request(Method::GET, &self.object_path(key), &[])?
+            .send()
+            .map_err(|e| store_error("download", key, e))?;
+        save_response(response, key, dest)
+    }
+
+    fn upload(&self, src: &Path, key: &str) -> Result<(), SyncError> {
+        self.request(Method::PUT, &self.object_path(key), &[])?
+            .body(upload_body(src)?)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| store_error("upload", key, e))?;
+        Ok(())
+    }
+}
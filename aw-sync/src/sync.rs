@@ -9,35 +9,362 @@ extern crate chrono;
 extern crate reqwest;
 extern crate serde_json;
 
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 use aw_client_rust::AwClient;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use regex::Regex;
+use serde::Serialize;
 
 use aw_datastore::{Datastore, DatastoreError};
-use aw_models::{Bucket, Event};
+use aw_models::{Bucket, BucketMetadata, Event};
 
 use crate::accessmethod::AccessMethod;
+use crate::config::validate_device_id;
+use crate::crypt::{
+    decrypt_file, decrypt_to_temp, encrypt_file, is_encrypted, missing_passphrase, open_remote,
+    PlaintextCopy,
+};
+use crate::error::{io_context, SyncError};
+use crate::filter::EventRule;
+use crate::hook::run_post_hook;
+use crate::http::HttpRemote;
+use crate::lock::SyncLock;
+use crate::longpath::long_path_safe;
+use crate::merge::{event_revision, match_key, merge_edits};
+use crate::migrate::migrate_synced_bucket_ids;
+use crate::naming::BucketNameTemplate;
+use crate::pending::{pending_counts, PendingCount};
+use crate::progress::{NoProgress, SyncProgress, SyncProgressEvent};
+use crate::ratelimit::RateLimiter;
+use crate::redact::{keep_fields, pseudonym, redact_event, Redaction};
+use crate::retry::{with_retry, RetryPolicy};
+use crate::state::{checkpoints_file, state_file, BucketSyncState, Checkpoints, SyncState};
+use crate::store::{download_remotes, upload_staging, RemoteStore};
+use crate::tombstone::{apply_tombstones, record_deletions};
+use crate::version::{
+    check_schema_version, check_server_version, filter_compatible_remotes, write_schema_version,
+    write_version_marker,
+};
 
-#[derive(PartialEq, Eq)]
+/// Which phases of a sync pass to run
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum SyncMode {
+    /// Only push local buckets, for devices which shouldn't import the data of others
     Push,
+    /// Only pull from remotes, for devices which only aggregate the data of others
     Pull,
     Both,
 }
 
+impl SyncMode {
+    pub fn pulls(self) -> bool {
+        self != SyncMode::Push
+    }
+
+    pub fn pushes(self) -> bool {
+        self != SyncMode::Pull
+    }
+}
+
 pub struct SyncSpec {
     /// Path of sync folder
     pub path: PathBuf,
     /// Path of sync db
     /// If None, will use all
     pub path_db: Option<PathBuf>,
+    /// Device ID of the only remote to sync with, for troubleshooting a single peer
+    /// If None, will use all
+    pub only_remote: Option<String>,
+    /// Filename of the staging db for the local device
+    /// If None, will use `{device_id}.db`
+    pub db_filename: Option<String>,
+    /// Device ID to use for the local device, instead of the one of the local aw-server
+    ///
+    /// Names the folder of the staging db, and is the origin of the pushed buckets, so a device
+    /// whose aw-server was reinstalled (which gets a new device ID) can keep syncing as the same
+    /// peer. Other devices then keep pulling into their existing `-synced-from-` buckets, which
+    /// they wouldn't if it synced as a new peer.
+    pub device_id: Option<String>,
+    /// Name of the local aw-server instance, for hosts running several of them
+    /// If set, the staging db is put in `{device_id}/{instance}/` instead of `{device_id}/`, so
+    /// that every instance gets a staging db of its own
+    pub instance: Option<String>,
     /// Bucket IDs to sync
+    /// An empty list (without `buckets_regex`) is rejected unless `all_buckets` is set, as it
+    /// would sync nothing
     pub buckets: Option<Vec<String>>,
+    /// Regex matching bucket IDs to sync
+    /// If both this and `buckets` are set, buckets matching either will be synced
+    pub buckets_regex: Option<Regex>,
+    /// If true, all buckets are synced, regardless of `buckets` and `buckets_regex`
+    pub all_buckets: bool,
+    /// If true, the buckets to sync are the ones with [`SYNC_ENABLED_KEY`] set to true in their
+    /// data, instead of those of `buckets` and `buckets_regex`
+    ///
+    /// Lets the buckets themselves decide whether they're synced. Falls back to `buckets` and
+    /// `buckets_regex` if no bucket of the source has the flag, true or false.
+    pub bucket_flags: bool,
+    /// Bucket types to sync
+    /// If None, buckets of all types will be synced
+    pub include_types: Option<Vec<String>>,
+    /// Bucket types to never sync, even if in `include_types`
+    pub exclude_types: Vec<String>,
+    /// If set, only buckets with events ending within this long before now are synced
+    /// Buckets without events, or whose end isn't known, are always synced
+    pub changed_within: Option<Duration>,
     /// Start of time range to sync
     pub start: Option<DateTime<Utc>>,
+    /// End of time range to sync
+    pub end: Option<DateTime<Utc>>,
+    /// If true, only report what would be synced without writing anything
+    pub dry_run: bool,
+    /// If true, ignore the sync state from previous passes and check all buckets for new events
+    pub force_full: bool,
+    /// Maximum number of remotes to pull from in parallel
+    pub pull_concurrency: usize,
+    /// If set, at most this many events are synced per bucket and pass
+    /// The oldest events are synced first, and the rest on the next passes, so that big
+    /// backlogs (such as of a runaway watcher) are synced gradually instead of all at once
+    pub max_events_per_bucket: Option<usize>,
+    /// If set, source buckets with more events than this are skipped, such as for quick passes
+    /// syncing only small buckets, with the big ones left to a scheduled full pass
+    pub skip_buckets_larger_than: Option<usize>,
+    /// Pushing is refused if it would leave less than this many bytes free on the disk of the
+    /// sync folder, as a full disk breaks the folder synchronizer for everything else too.
+    /// 0 disables the check.
+    pub min_free_space: u64,
+    /// If set, limits how fast events are inserted into aw-server over HTTP, so it stays
+    /// responsive to its watchers during big pulls. Inserting into datastores isn't limited.
+    pub insert_rate: Option<RateLimiter>,
+    /// If true, buckets deleted on their source are deleted on the destination as well
+    pub propagate_deletions: bool,
+    /// How to retry requests to the local aw-server which fail transiently, see the retry module
+    pub retry: RetryPolicy,
+    /// If true, remote datastores failing SQLite's integrity check are skipped
+    pub verify_integrity: bool,
+    /// URLs of remote aw-server instances to push local buckets to over HTTP, in addition to
+    /// the staging datastore in the sync folder
+    pub remote_servers: Vec<String>,
+    /// If true, all events in the time range are compared against the destination by their
+    /// [`event_dedup_key`], and only the missing ones are synced, instead of resuming after the
+    /// last event in the destination bucket
+    pub dedup_strict: bool,
+    /// If true, syncing a bucket fails if the number of events in the destination dropped while
+    /// syncing into it, instead of only logging an error
+    ///
+    /// Syncing only ever adds events to the destination, so a drop means something else deleted
+    /// events at the same time, or the destination lost them.
+    pub strict: bool,
+    /// How far the clocks of devices may be off from each other, zero by default
+    ///
+    /// Syncing resumes this long before the last event in the destination bucket, so events
+    /// created on a device with a clock that's behind aren't missed. The overlapping events are
+    /// read again on every pass, and deduplicated by their [`event_dedup_key`], so it's only
+    /// worth setting for devices whose clocks are known to be off.
+    pub clock_skew_tolerance: Duration,
+    /// If true, events edited since they were synced are updated in the destination
+    /// See the `merge` module for how edits need to be marked
+    pub merge_edits: bool,
+    /// If true, the staging datastore is vacuumed after pushing, to reduce its size
+    pub vacuum: bool,
+    /// Remotes which weren't pulled from without errors for this long are warned about
+    pub stale_threshold: Duration,
+    /// If set, no more buckets, remotes or remote servers are started after this time, and
+    /// [`sync_run`] fails with [`SyncError::Timeout`]
+    /// Buckets being synced when it passes are finished first
+    pub deadline: Option<Instant>,
+    /// If set, setting the flag stops the pass like the deadline passing, and [`sync_run`] fails
+    /// with [`SyncError::Interrupted`], such as when the user presses Ctrl-C
+    /// The bucket being synced is stopped after the batch of events being inserted, and synced
+    /// further on the next pass
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Rules for events never to sync, keyed by the ID of their source bucket
+    pub exclude: HashMap<String, Vec<EventRule>>,
+    /// Data fields to redact before writing events to the destination, keyed by the ID of their
+    /// source bucket and the field
+    pub redact: HashMap<String, HashMap<String, Redaction>>,
+    /// Salt for fields redacted by hashing
+    pub redact_salt: String,
+    /// Data fields to keep of the events of buckets, keyed by the ID of their source bucket
+    ///
+    /// Other fields are dropped before writing events to the destination, all of them for an
+    /// empty list. Buckets without one keep all fields.
+    pub keep_fields: HashMap<String, Vec<String>>,
+    /// If set, the hostname and device ID of this device are replaced by pseudonyms salted with
+    /// this in what is pushed, see the redact module
+    ///
+    /// The staging datastore is in the folder of the pseudonymized device ID. IDs of buckets are
+    /// kept, so those named after the hostname (like those of most watchers) still reveal it.
+    pub pseudonym_salt: Option<String>,
+    /// If set, new events are only pushed to existing buckets once at least this many have
+    /// accumulated, or [`SyncSpec::min_interval`] passed, to reduce churn of the staging
+    /// datastore. 0 disables it
+    pub min_batch: usize,
+    /// If set, new events are only pushed to existing buckets once this long passed since
+    /// events were last pushed to them, or [`SyncSpec::min_batch`] events accumulated
+    pub min_interval: Option<Duration>,
+    /// If true, all new events are pushed regardless of `min_batch` and `min_interval`, such as
+    /// on the last pass before shutting down
+    pub flush: bool,
+    /// Pulsetimes to merge the events of buckets with, keyed by the ID of their source bucket
+    ///
+    /// Events of these buckets are all sent as heartbeats, so contiguous events with the same
+    /// data are merged in the destination like the watcher merges them.
+    pub pulsetime: HashMap<String, f64>,
+    /// If set, the pulsetime to merge the events of all buckets with, instead of those of
+    /// `pulsetime` and `merge_heartbeats`
+    ///
+    /// Nonzero pulsetimes merge contiguous events with the same data, 0 disables merging.
+    pub pulsetime_override: Option<f64>,
+    /// Minimum durations of events to sync in seconds, keyed by the ID of their source bucket
+    ///
+    /// Shorter events are dropped, such as zero-duration pings. Buckets without one sync all of
+    /// their events, as instantaneous events are meaningful in some.
+    pub min_duration: HashMap<String, f64>,
+    /// Origins whose buckets are pulled into the buckets of another origin, such as the old
+    /// device ID of a reinstalled device mapped to its new one, so both end up in one timeline
+    ///
+    /// Only a single alias is followed, origins aliased to are never aliased themselves.
+    pub origin_aliases: HashMap<String, String>,
+    /// If true, the events of AFK and window buckets are merged with the default pulsetimes of
+    /// their watchers, unless `pulsetime` has one for them
+    pub merge_heartbeats: bool,
+    /// If set, the staging datastore is encrypted with this passphrase, and encrypted remotes are
+    /// decrypted with it, see the crypt module
+    pub passphrase: Option<String>,
+    /// Template of the IDs of pulled buckets, `{id}-synced-from-{origin}` by default
+    pub bucket_names: BucketNameTemplate,
+    /// If set, the staging datastores are synced through this store, and the sync folder is a
+    /// local mirror of it, see the store module
+    pub remote_store: Option<Box<dyn RemoteStore>>,
+    /// Shell command to run after a successful push, see the hook module
+    pub post_hook: Option<String>,
+}
+
+/// Summary of what a sync pass did (or would do, in a dry run) to a single bucket
+#[derive(Serialize, Debug, Clone)]
+pub struct BucketSyncSummary {
+    /// ID of the destination bucket
+    pub bucket_id: String,
+    /// Whether the destination bucket was (or would be) created
+    pub created: bool,
+    /// Number of new events in the destination bucket
+    pub events: usize,
+    /// Whether the destination bucket was (or would be) deleted, as its source was deleted
+    pub deleted: bool,
+    /// Why syncing the bucket failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Where the time syncing the bucket went, for finding slow buckets
+    pub timings: BucketTimings,
+}
+
+/// Wall-clock time spent in the phases of syncing a bucket, in seconds
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct BucketTimings {
+    /// Fetching events from the source and destination buckets
+    pub get_events_secs: f64,
+    /// Inserting events into the destination bucket
+    pub insert_secs: f64,
+    /// Counting the events of the destination bucket
+    pub count_secs: f64,
+}
+
+impl BucketTimings {
+    pub fn total_secs(&self) -> f64 {
+        self.get_events_secs + self.insert_secs + self.count_secs
+    }
+}
+
+/// Result of syncing all buckets from one datastore to another
+#[derive(Debug, Clone, Default)]
+pub struct SyncStats {
+    /// Number of buckets synced (or attempted to), including failed ones
+    pub buckets_processed: usize,
+    /// Number of destination buckets created (or which would be, in a dry run)
+    pub buckets_created: usize,
+    /// Number of new events in the destination buckets
+    pub events_inserted: usize,
+    /// Summaries of the buckets
+    pub buckets: Vec<BucketSyncSummary>,
+    /// Whether the deadline passed before all buckets were synced
+    pub timed_out: bool,
+    /// Whether the pass was cancelled before all buckets were synced
+    pub interrupted: bool,
+}
+
+impl SyncStats {
+    fn push(&mut self, bucket: BucketSyncSummary) {
+        self.buckets_processed += 1;
+        if bucket.created {
+            self.buckets_created += 1;
+        }
+        self.events_inserted += bucket.events;
+        self.buckets.push(bucket);
+    }
+}
+
+/// Summary of a whole sync pass, printed as JSON by `aw-sync sync --json`
+///
+/// Meant to be parsed by scripts, so fields should only ever be added, not renamed or removed.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SyncRunSummary {
+    /// Number of buckets synced without errors
+    pub buckets_synced: usize,
+    /// Number of new events pulled from each remote, keyed by device ID
+    pub events_pulled: BTreeMap<String, usize>,
+    /// Number of new events pushed to the sync folder and remote servers
+    pub events_pushed: usize,
+    /// How long the sync pass took, in seconds
+    pub duration_secs: f64,
+    /// Errors of buckets which failed to sync, keyed by destination bucket ID, and of remotes
+    /// which failed to be pulled from as a whole, keyed by their device ID
+    pub errors: BTreeMap<String, String>,
+    /// Summaries of all synced buckets
+    pub buckets: Vec<BucketSyncSummary>,
+    /// Whether the sync pass was stopped early, as its deadline passed
+    pub timed_out: bool,
+    /// Whether the sync pass was stopped early, as it was cancelled (such as by Ctrl-C)
+    pub interrupted: bool,
+}
+
+impl SyncRunSummary {
+    /// Adds the stats of pulling from a remote
+    pub fn record_pull(&mut self, remote_did: &str, stats: SyncStats) {
+        *self
+            .events_pulled
+            .entry(remote_did.to_string())
+            .or_default() += stats.events_inserted;
+        self.timed_out |= stats.timed_out;
+        self.interrupted |= stats.interrupted;
+        self.record(stats.buckets);
+    }
+
+    /// Adds the stats of pushing
+    pub fn record_push(&mut self, stats: SyncStats) {
+        self.events_pushed += stats.events_inserted;
+        self.timed_out |= stats.timed_out;
+        self.interrupted |= stats.interrupted;
+        self.record(stats.buckets);
+    }
+
+    fn record(&mut self, buckets: Vec<BucketSyncSummary>) {
+        for bucket in &buckets {
+            match &bucket.error {
+                Some(error) => {
+                    self.errors.insert(bucket.bucket_id.clone(), error.clone());
+                }
+                None => self.buckets_synced += 1,
+            }
+        }
+        self.buckets.extend(buckets);
+    }
 }
 
 impl Default for SyncSpec {
@@ -47,28 +374,470 @@ impl Default for SyncSpec {
         SyncSpec {
             path,
             path_db: None,
+            only_remote: None,
+            db_filename: None,
+            device_id: None,
+            instance: None,
             buckets: None,
+            buckets_regex: None,
+            all_buckets: false,
+            bucket_flags: false,
+            include_types: None,
+            exclude_types: Vec::new(),
+            changed_within: None,
             start: None,
+            end: None,
+            dry_run: false,
+            force_full: false,
+            pull_concurrency: 4,
+            max_events_per_bucket: None,
+            skip_buckets_larger_than: None,
+            min_free_space: 500_000_000,
+            insert_rate: None,
+            propagate_deletions: false,
+            retry: RetryPolicy::default(),
+            verify_integrity: false,
+            remote_servers: Vec::new(),
+            dedup_strict: false,
+            strict: false,
+            clock_skew_tolerance: Duration::zero(),
+            merge_edits: false,
+            vacuum: false,
+            stale_threshold: Duration::days(7),
+            deadline: None,
+            cancel: None,
+            exclude: HashMap::new(),
+            redact: HashMap::new(),
+            redact_salt: String::new(),
+            keep_fields: HashMap::new(),
+            pseudonym_salt: None,
+            passphrase: None,
+            min_batch: 0,
+            min_interval: None,
+            flush: false,
+            pulsetime: HashMap::new(),
+            pulsetime_override: None,
+            min_duration: HashMap::new(),
+            origin_aliases: HashMap::new(),
+            merge_heartbeats: false,
+            bucket_names: BucketNameTemplate::default(),
+            remote_store: None,
+            post_hook: None,
+        }
+    }
+}
+
+impl SyncSpec {
+    /// Returns true if the bucket with the given ID should be synced
+    pub fn includes_bucket(&self, bucket_id: &str) -> bool {
+        if self.all_buckets || (self.buckets.is_none() && self.buckets_regex.is_none()) {
+            return true;
+        }
+        let in_list = self
+            .buckets
+            .as_ref()
+            .is_some_and(|buckets| buckets.iter().any(|b_id| b_id == bucket_id));
+        let matches_regex = self
+            .buckets_regex
+            .as_ref()
+            .is_some_and(|re| re.is_match(bucket_id));
+        in_list || matches_regex
+    }
+
+    /// Returns true if the bucket may be synced, by its ID or as it has a flag
+    ///
+    /// Which flagged buckets are synced is only known once all buckets are listed, see
+    /// [`SyncSpec::select_flagged`].
+    pub(crate) fn may_include_bucket(&self, bucket: &Bucket) -> bool {
+        self.includes_bucket(&bucket.id)
+            || (self.bucket_flags && bucket.data.contains_key(SYNC_ENABLED_KEY))
+    }
+
+    /// Keeps the buckets flagged to be synced, if `bucket_flags` is set and any bucket has the
+    /// flag, returning whether it did
+    ///
+    /// Otherwise only keeps the buckets included by their ID, see [`SyncSpec::includes_bucket`].
+    pub(crate) fn select_flagged(&self, buckets: &mut Vec<Bucket>) -> bool {
+        let flagged = self.bucket_flags
+            && buckets
+                .iter()
+                .any(|b| b.data.contains_key(SYNC_ENABLED_KEY));
+        if flagged {
+            buckets
+                .retain(|b| b.data.get(SYNC_ENABLED_KEY).and_then(|v| v.as_bool()) == Some(true));
+        } else {
+            buckets.retain(|b| self.includes_bucket(&b.id));
+        }
+        flagged
+    }
+
+    /// Returns an error if the bucket filters would sync nothing, see [`SyncSpec::buckets`]
+    pub fn check_buckets(&self) -> Result<(), SyncError> {
+        let empty = self.buckets.as_ref().is_some_and(|b| b.is_empty());
+        if empty && self.buckets_regex.is_none() && !self.all_buckets && !self.bucket_flags {
+            return Err(SyncError::Config(
+                "the list of buckets to sync is empty, so nothing would be synced (use --all \
+                to sync all buckets)"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns true if buckets of the given type should be synced
+    pub fn includes_bucket_type(&self, bucket_type: &str) -> bool {
+        let included = self
+            .include_types
+            .as_ref()
+            .map_or(true, |types| types.iter().any(|t| t == bucket_type));
+        included && !self.exclude_types.iter().any(|t| t == bucket_type)
+    }
+
+    /// Returns true if the event matches any of the exclusion rules of its bucket
+    pub fn excludes_event(&self, bucket_id: &str, event: &Event) -> bool {
+        self.exclude
+            .get(bucket_id)
+            .is_some_and(|rules| rules.iter().any(|rule| rule.matches(event)))
+    }
+
+    /// Returns true if the event is shorter than the minimum duration of its bucket
+    pub fn drops_short_event(&self, bucket_id: &str, event: &Event) -> bool {
+        self.min_duration.get(bucket_id).is_some_and(|min| {
+            let duration = event.duration.num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6;
+            duration < *min
+        })
+    }
+
+    /// Returns the origin the buckets of `origin` are pulled into, see
+    /// [`SyncSpec::origin_aliases`]
+    pub fn aliased_origin<'a>(&'a self, origin: &'a str) -> &'a str {
+        self.origin_aliases
+            .get(origin)
+            .map_or(origin, String::as_str)
+    }
+
+    /// Returns true if the buckets of `origin` are pulled into buckets shared with another origin
+    pub(crate) fn merges_origin(&self, origin: &str) -> bool {
+        self.origin_aliases.contains_key(origin)
+            || self.origin_aliases.values().any(|alias| alias == origin)
+    }
+
+    /// Returns the ID of the bucket `bucket_from` is pulled into from the remote `src_did`
+    pub(crate) fn pulled_bucket_id(&self, bucket_from: &Bucket, src_did: Option<&str>) -> String {
+        let origin = self.aliased_origin(bucket_origin(bucket_from, src_did));
+        synced_bucket_id(bucket_from, origin, &self.bucket_names)
+    }
+
+    /// Returns the pseudonym of a hostname or device ID of this device if pseudonymizing them,
+    /// see [`SyncSpec::pseudonym_salt`], or the ID as it is
+    pub fn pseudonymize(&self, id: &str) -> String {
+        match &self.pseudonym_salt {
+            Some(salt) => pseudonym(id, salt),
+            None => id.to_string(),
+        }
+    }
+
+    /// Returns the device ID this device syncs as, whose folder has its staging datastore
+    pub(crate) fn local_device_id(&self, server_device_id: &str) -> String {
+        self.pseudonymize(self.device_id.as_deref().unwrap_or(server_device_id))
+    }
+
+    /// Redacts the data fields configured for the event's bucket, and drops those not kept
+    pub fn redact_event(&self, bucket_id: &str, event: &mut Event) {
+        if let Some(fields) = self.keep_fields.get(bucket_id) {
+            keep_fields(event, fields);
+        }
+        if let Some(fields) = self.redact.get(bucket_id) {
+            redact_event(event, fields, &self.redact_salt);
+        }
+    }
+
+    /// Returns the pulsetime to merge the events of a bucket with, if they should be merged
+    pub fn pulsetime(&self, bucket: &Bucket) -> Option<f64> {
+        if let Some(pulsetime) = self.pulsetime_override {
+            return (pulsetime > 0.0).then_some(pulsetime);
+        }
+        self.pulsetime.get(&bucket.id).copied().or_else(|| {
+            if !self.merge_heartbeats {
+                return None;
+            }
+            // The defaults of aw-watcher-afk (timeout + poll time) and aw-watcher-window
+            // (poll time + 1s)
+            match bucket._type.as_str() {
+                "afkstatus" => Some(185.0),
+                "currentwindow" => Some(2.0),
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns true if pushing `new_events` events to a bucket which was last pushed to at
+    /// `flushed` should wait for more events to accumulate
+    pub fn defers_push(&self, new_events: usize, flushed: Option<DateTime<Utc>>) -> bool {
+        if self.flush || (self.min_batch == 0 && self.min_interval.is_none()) {
+            return false;
+        }
+        let batch_reached = self.min_batch > 0 && new_events >= self.min_batch;
+        let interval_passed = self
+            .min_interval
+            .is_some_and(|interval| flushed.map_or(true, |t| Utc::now() - t >= interval));
+        !batch_reached && !interval_passed
+    }
+
+    /// Returns true if the deadline is set and has passed
+    pub fn deadline_passed(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Returns true if the pass was cancelled, see [`SyncSpec::cancel`]
+    pub fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+}
+
+/// Builder for a sync pass, for embedding aw-sync in other programs
+///
+/// Options without a method of their own can be set on the [`SyncSpec`] with
+/// [`SyncRunner::configure`].
+///
+/// ```no_run
+/// # use aw_sync::{SyncMode, SyncRunner};
+/// let client = aw_client_rust::AwClient::new("127.0.0.1", "5600", "my-daemon");
+/// let summary = SyncRunner::new(client, "/home/user/ActivityWatchSync")
+///     .buckets(vec!["aw-watcher-afk_laptop".to_string()])
+///     .mode(SyncMode::Pull)
+///     .timeout(std::time::Duration::from_secs(300))
+///     .run()?;
+/// # Ok::<(), aw_sync::SyncError>(())
+/// ```
+pub struct SyncRunner<'a> {
+    local: LocalSource,
+    spec: SyncSpec,
+    mode: SyncMode,
+    timeout: Option<std::time::Duration>,
+    progress: &'a dyn SyncProgress,
+}
+
+/// Where the local buckets are synced from and to
+enum LocalSource {
+    Server(AwClient),
+    /// An open datastore, with the device ID of its aw-server
+    Datastore(Arc<Datastore>, String),
+}
+
+impl<'a> SyncRunner<'a> {
+    /// Returns a runner syncing all buckets in both directions through `sync_directory`
+    pub fn new(client: AwClient, sync_directory: impl Into<PathBuf>) -> SyncRunner<'a> {
+        SyncRunner::with_local(LocalSource::Server(client), sync_directory)
+    }
+
+    /// Returns a runner syncing the buckets of an already open datastore, see
+    /// [`sync_run_datastore`]
+    #[allow(dead_code)]
+    pub fn from_datastore(
+        datastore: Arc<Datastore>,
+        device_id: &str,
+        sync_directory: impl Into<PathBuf>,
+    ) -> SyncRunner<'a> {
+        let local = LocalSource::Datastore(datastore, device_id.to_string());
+        SyncRunner::with_local(local, sync_directory)
+    }
+
+    fn with_local(local: LocalSource, sync_directory: impl Into<PathBuf>) -> SyncRunner<'a> {
+        SyncRunner {
+            local,
+            spec: SyncSpec {
+                path: sync_directory.into(),
+                ..SyncSpec::default()
+            },
+            mode: SyncMode::Both,
+            timeout: None,
+            progress: &NoProgress,
+        }
+    }
+
+    /// Only syncs these buckets (and those matching [`SyncRunner::buckets_regex`], if set)
+    pub fn buckets(mut self, buckets: Vec<String>) -> SyncRunner<'a> {
+        self.spec.buckets = Some(buckets);
+        self
+    }
+
+    /// Only syncs buckets matching this regex (and those in [`SyncRunner::buckets`], if set)
+    pub fn buckets_regex(mut self, regex: Regex) -> SyncRunner<'a> {
+        self.spec.buckets_regex = Some(regex);
+        self
+    }
+
+    pub fn mode(mut self, mode: SyncMode) -> SyncRunner<'a> {
+        self.mode = mode;
+        self
+    }
+
+    /// Stops the pass once it ran for this long, counted from when it's started with
+    /// [`SyncRunner::run`], see [`SyncSpec::deadline`]
+    pub fn timeout(mut self, timeout: std::time::Duration) -> SyncRunner<'a> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Merges the events of all buckets with `pulsetime`, see [`SyncSpec::pulsetime_override`]
+    pub fn pulsetime(mut self, pulsetime: f64) -> SyncRunner<'a> {
+        self.spec.pulsetime_override = Some(pulsetime);
+        self
+    }
+
+    /// Stops the pass once `cancel` is set, see [`SyncSpec::cancel`]
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> SyncRunner<'a> {
+        self.spec.cancel = Some(cancel);
+        self
+    }
+
+    pub fn progress(mut self, progress: &'a dyn SyncProgress) -> SyncRunner<'a> {
+        self.progress = progress;
+        self
+    }
+
+    /// Changes any other option of the pass
+    pub fn configure(mut self, f: impl FnOnce(&mut SyncSpec)) -> SyncRunner<'a> {
+        f(&mut self.spec);
+        self
+    }
+
+    /// Returns the options the pass will run with
+    #[allow(dead_code)]
+    pub fn spec(&self) -> &SyncSpec {
+        &self.spec
+    }
+
+    /// Counts the events the pass would sync, see [`pending_counts`]
+    pub fn pending_counts(&self) -> Result<Vec<PendingCount>, SyncError> {
+        let (local, server_device_id): (&dyn AccessMethod, String) = match &self.local {
+            LocalSource::Server(client) => {
+                let info = with_retry(&self.spec.retry, "get server info", || client.get_info())?;
+                (client, info.device_id)
+            }
+            LocalSource::Datastore(datastore, device_id) => (datastore.as_ref(), device_id.clone()),
+        };
+        let device_id = self.spec.local_device_id(&server_device_id);
+        pending_counts(local, &device_id, &self.spec)
+    }
+
+    /// Performs the sync pass, see [`sync_run`]
+    pub fn run(mut self) -> Result<SyncRunSummary, SyncError> {
+        if let Some(timeout) = self.timeout {
+            self.spec.deadline = Some(Instant::now() + timeout);
+        }
+        match self.local {
+            LocalSource::Server(client) => sync_run(client, &self.spec, self.mode, self.progress),
+            LocalSource::Datastore(datastore, device_id) => {
+                sync_run_datastore(datastore, &device_id, &self.spec, self.mode, self.progress)
+            }
         }
     }
 }
 
 /// Performs a single sync pass
-pub fn sync_run(client: AwClient, sync_spec: &SyncSpec, mode: SyncMode) -> Result<(), String> {
-    let info = client.get_info().map_err(|e| e.to_string())?;
+pub fn sync_run(
+    client: AwClient,
+    sync_spec: &SyncSpec,
+    mode: SyncMode,
+    progress: &dyn SyncProgress,
+) -> Result<SyncRunSummary, SyncError> {
+    let started = Instant::now();
+    sync_spec.check_buckets()?;
+    let info = with_retry(&sync_spec.retry, "get server info", || client.get_info())?;
+    check_server_version(&info.version)?;
 
     // FIXME: Here it is assumed that the device_id for the local server is the one used by
     // aw-server-rust, which is not necessarily true (aw-server-python has seperate device_id).
     // Therefore, this may sometimes fail to pick up the correct local datastore.
-    let device_id = info.device_id.as_str();
+    sync_run_local(&client, &info.device_id, started, sync_spec, mode, progress)
+}
+
+/// Performs a single sync pass with an already open datastore instead of aw-server
+///
+/// For running the sync in the same process as aw-server, so it doesn't go through its HTTP API.
+/// `device_id` is the device ID of the aw-server the datastore belongs to. Otherwise the same as
+/// [`sync_run`], including pushing through the staging datastore in the sync folder.
+pub fn sync_run_datastore(
+    datastore: Arc<Datastore>,
+    device_id: &str,
+    sync_spec: &SyncSpec,
+    mode: SyncMode,
+    progress: &dyn SyncProgress,
+) -> Result<SyncRunSummary, SyncError> {
+    let started = Instant::now();
+    sync_spec.check_buckets()?;
+    sync_run_local(
+        datastore.as_ref(),
+        device_id,
+        started,
+        sync_spec,
+        mode,
+        progress,
+    )
+}
+
+/// Performs a sync pass of `local`, whose device ID is `server_device_id`
+fn sync_run_local(
+    local: &(dyn AccessMethod + Sync),
+    server_device_id: &str,
+    started: Instant,
+    sync_spec: &SyncSpec,
+    mode: SyncMode,
+    progress: &dyn SyncProgress,
+) -> Result<SyncRunSummary, SyncError> {
+    if sync_spec.all_buckets {
+        info!("Syncing all buckets");
+    }
+    let device_id = match &sync_spec.device_id {
+        Some(device_id) => {
+            validate_device_id(device_id)?;
+            info!(
+                "Using device ID {device_id} instead of the one of aw-server ({server_device_id})"
+            );
+            device_id.as_str()
+        }
+        None => server_device_id,
+    };
+    let pseudonymized = sync_spec.local_device_id(server_device_id);
+    if sync_spec.pseudonym_salt.is_some() {
+        info!("Syncing as {pseudonymized} instead of {device_id}");
+    }
+    let device_id = pseudonymized.as_str();
 
     // FIXME: Bad device_id assumption?
-    let ds_localremote = setup_local_remote(sync_spec.path.as_path(), device_id)?;
+    let dbfile = local_remote_dbfile(
+        sync_spec.path.as_path(),
+        device_id,
+        sync_spec.instance.as_deref(),
+        sync_spec.db_filename.as_deref(),
+    )?;
+    // Held until the pass is done, including when it fails
+    let _lock = SyncLock::acquire(&dbfile)?;
+
+    if let Some(store) = &sync_spec.remote_store {
+        let downloading = || download_remotes(store.as_ref(), &sync_spec.path, device_id);
+        with_retry(&sync_spec.retry, "download remotes", downloading)?;
+    }
+
+    let state_path = state_file(&dbfile)?;
+    let mut state = SyncState::load(&state_path);
+    state.checkpoints = Some(Arc::new(Checkpoints::load(&checkpoints_file(&dbfile)?)));
+
     let remote_dbfiles = find_remotes_nonlocal(
         sync_spec.path.as_path(),
         device_id,
         sync_spec.path_db.as_ref(),
-    );
+    )?;
+    let remote_dbfiles = filter_only_remote(
+        sync_spec.path.as_path(),
+        remote_dbfiles,
+        sync_spec.only_remote.as_deref(),
+    )?;
 
     // Log if remotes found
     // TODO: Only log remotes of interest
@@ -80,12 +849,29 @@ pub fn sync_run(client: AwClient, sync_spec: &SyncSpec, mode: SyncMode) -> Resul
         );
     }
 
-    // TODO: Check for compatible remote db version before opening
-    let ds_remotes: Vec<Datastore> = remote_dbfiles
-        .iter()
-        .map(|p| p.as_path())
-        .map(create_datastore)
-        .collect();
+    let remote_dbfiles = filter_compatible_remotes(remote_dbfiles);
+    // Plaintext copies of encrypted remotes, which have to outlive their datastores
+    let mut plaintext_copies: Vec<PlaintextCopy> = Vec::new();
+    let mut opened = Vec::new();
+    for path in remote_dbfiles {
+        match open_remote(&path, sync_spec.passphrase.as_deref()) {
+            Ok((ds, copy)) => {
+                plaintext_copies.extend(copy);
+                // Catches remotes of newer versions whose version marker file was lost
+                if let Err(e) = check_schema_version(&ds) {
+                    warn!("Skipping remote {}: {e}", path.display());
+                    ds.close();
+                    continue;
+                }
+                opened.push((path, ds));
+            }
+            Err(e) => warn!("Skipping remote {}: {e}", path.display()),
+        }
+    }
+    let (remote_dbfiles, ds_remotes): (Vec<PathBuf>, Vec<Datastore>) = opened
+        .into_iter()
+        .filter(|(path, ds)| !sync_spec.verify_integrity || verify_remote_integrity(path, ds))
+        .unzip();
 
     if !ds_remotes.is_empty() {
         info!(
@@ -95,55 +881,391 @@ pub fn sync_run(client: AwClient, sync_spec: &SyncSpec, mode: SyncMode) -> Resul
         );
     }
 
+    let mut summary = SyncRunSummary::default();
+    let mut pushed_staging = false;
+
     // Pull
-    if mode == SyncMode::Pull || mode == SyncMode::Both {
+    if mode.pulls() {
         info!("Pulling...");
-        for ds_from in &ds_remotes {
-            sync_datastores(ds_from, &client, false, None, sync_spec);
+        let remotes: Vec<(&Datastore, String)> = ds_remotes
+            .iter()
+            .zip(remote_dbfiles.iter())
+            .map(|(ds, path)| (ds, remote_device_id(sync_spec.path.as_path(), path)))
+            .collect();
+
+        // Buckets pulled by older versions are suffixed with hostnames instead of device IDs
+        migrate_synced_bucket_ids(&remotes, local, sync_spec, &mut state)?;
+
+        // Remotes are independent of each other, so pull from several of them at a time
+        for chunk in remotes.chunks(sync_spec.pull_concurrency.max(1)) {
+            if sync_spec.cancelled() {
+                warn!("Interrupted, not pulling from the remaining remotes");
+                summary.interrupted = true;
+                break;
+            }
+            if sync_spec.deadline_passed() {
+                warn!("Timed out, not pulling from the remaining remotes");
+                summary.timed_out = true;
+                break;
+            }
+            type BucketChanges = HashMap<String, Option<BucketSyncState>>;
+            type PullResult = Result<(String, SyncStats, BucketChanges), SyncError>;
+            let state_before = &state;
+            let results: Vec<(&String, PullResult)> = std::thread::scope(|s| {
+                let handles: Vec<_> = chunk
+                    .iter()
+                    .map(|(ds_from, remote_did)| {
+                        let mut remote_state = state_before.clone();
+                        let handle = s.spawn(move || -> PullResult {
+                            let mut remote_stats = sync_datastores(
+                                *ds_from,
+                                local,
+                                false,
+                                Some(remote_did.as_str()),
+                                sync_spec,
+                                &mut remote_state,
+                                progress,
+                            )?;
+                            if sync_spec.propagate_deletions {
+                                let deleted = apply_tombstones(
+                                    ds_from,
+                                    local,
+                                    sync_spec,
+                                    &mut remote_state,
+                                    &format!("[{remote_did}] "),
+                                )?;
+                                remote_stats
+                                    .buckets
+                                    .extend(deleted.into_iter().map(deleted_summary));
+                            }
+                            let changes = remote_state.bucket_changes(state_before);
+                            Ok((remote_did.clone(), remote_stats, changes))
+                        });
+                        (remote_did, handle)
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|(remote_did, h)| {
+                        let result = h.join().unwrap_or_else(|panic| {
+                            Err(SyncError::Panicked {
+                                remote: remote_did.clone(),
+                                message: panic_message(panic.as_ref()),
+                            })
+                        });
+                        (remote_did, result)
+                    })
+                    .collect()
+            });
+            for (remote_did, result) in results {
+                // What the other remotes of the chunk changed is kept even if some of them failed
+                let (remote_did, remote_stats, changes) = match result {
+                    Ok(pulled) => pulled,
+                    Err(e) => {
+                        warn!("Failed to pull from remote {remote_did}: {e}");
+                        summary.errors.insert(remote_did.clone(), e.to_string());
+                        continue;
+                    }
+                };
+                // Whole state clones would undo what the other remotes of the chunk changed
+                state.apply_bucket_changes(changes);
+                if !sync_spec.dry_run && remote_stats.buckets.iter().all(|b| b.error.is_none()) {
+                    state.remotes.insert(remote_did.clone(), Utc::now());
+                }
+                summary.record_pull(&remote_did, remote_stats);
+            }
+        }
+
+        // Remotes which are skipped or fail every time never get their time updated
+        for (remote_did, last_sync) in state.stale_remotes(sync_spec.stale_threshold) {
+            warn!(
+                "Remote {} was last synced without errors {} days ago, at {}",
+                remote_did,
+                (Utc::now() - last_sync).num_days(),
+                last_sync
+            );
         }
     }
 
     // Push local server buckets to sync folder
-    if mode == SyncMode::Push || mode == SyncMode::Both {
+    if mode.pushes() && sync_spec.cancelled() {
+        warn!("Interrupted, not pushing");
+        summary.interrupted = true;
+    } else if mode.pushes() && sync_spec.deadline_passed() {
+        warn!("Timed out, not pushing");
+        summary.timed_out = true;
+    } else if mode.pushes() {
         info!("Pushing...");
-        sync_datastores(&client, &ds_localremote, true, Some(device_id), sync_spec);
+        let pushed = push_to_staging(local, &dbfile, device_id, sync_spec, &mut state, progress)?;
+        summary.record_push(pushed);
+        pushed_staging = !sync_spec.dry_run;
+        if let (Some(store), false) = (&sync_spec.remote_store, sync_spec.dry_run) {
+            let uploading = || upload_staging(store.as_ref(), &sync_spec.path, &dbfile);
+            with_retry(&sync_spec.retry, "upload staging datastore", uploading)?;
+        }
+
+        for url in &sync_spec.remote_servers {
+            if sync_spec.cancelled() {
+                warn!("Interrupted, not pushing to the remaining remote servers");
+                summary.interrupted = true;
+                break;
+            }
+            if sync_spec.deadline_passed() {
+                warn!("Timed out, not pushing to the remaining remote servers");
+                summary.timed_out = true;
+                break;
+            }
+            let remote = HttpRemote::new(url);
+            if let Err(e) = remote.get_info() {
+                warn!("Skipping remote server {}: {e}", remote.url());
+                continue;
+            }
+            info!("Pushing to remote server {}...", remote.url());
+            // Buckets keep their IDs when pushed, which would collide with the state of the
+            // staging datastore, so remote servers are always synced without state.
+            match sync_datastores(
+                local,
+                &remote,
+                true,
+                Some(device_id),
+                sync_spec,
+                &mut SyncState::default(),
+                progress,
+            ) {
+                Ok(stats) => summary.record_push(stats),
+                Err(e) => warn!("Failed to push to remote server {}: {e}", remote.url()),
+            }
+        }
+    }
+
+    if !sync_spec.dry_run {
+        state.save(&state_path)?;
     }
 
     // Close open database connections
     for ds_from in &ds_remotes {
         ds_from.close();
     }
-    ds_localremote.close();
 
     // Dropping also works to close the database connections, weirdly enough.
     // Probably because once the database is dropped, the thread will stop,
     // and then the Connection will be dropped, which closes the connection.
     std::mem::drop(ds_remotes);
-    std::mem::drop(ds_localremote);
+    std::mem::drop(plaintext_copies);
 
     // NOTE: Will fail if db connections not closed (as it will open them again)
     //list_buckets(&client, sync_spec.path.as_path());
 
+    if let Ok(metadata) = fs::metadata(&dbfile) {
+        info!(
+            "Staging datastore {} is {} MB",
+            dbfile.display(),
+            metadata.len() / 1_000_000
+        );
+    }
+
+    summary.duration_secs = started.elapsed().as_secs_f64();
+    // The state is saved above, so the next pass picks up where this one stopped
+    // Also cancelled while syncing the last bucket, after all the checks above
+    summary.interrupted |= sync_spec.cancelled();
+    if summary.interrupted {
+        return Err(SyncError::Interrupted(Box::new(summary)));
+    }
+    // Not run for passes stopped early, as they didn't push everything
+    if let Some(command) = sync_spec.post_hook.as_deref() {
+        if pushed_staging && !summary.timed_out {
+            run_post_hook(command, &summary, &dbfile);
+        }
+    }
+    if summary.timed_out {
+        return Err(SyncError::Timeout(Box::new(summary)));
+    }
+    Ok(summary)
+}
+
+/// Pushes the buckets of `ds_from` to the staging datastore at `dbfile`
+///
+/// The push is atomic: the staging datastore is copied to a temporary file next to it, which is
+/// pushed to and only renamed over the staging datastore once the push succeeded. That way the
+/// folder synchronizer never picks up a half-written datastore, and a failed push leaves the
+/// previous one intact (`state` should be discarded then, as it may include the failed push).
+///
+/// If `sync_spec.passphrase` is set, the push goes to a plaintext copy outside the sync folder
+/// instead, which is encrypted to the temporary file afterwards.
+pub fn push_to_staging(
+    ds_from: &dyn AccessMethod,
+    dbfile: &Path,
+    device_id: &str,
+    sync_spec: &SyncSpec,
+    state: &mut SyncState,
+    progress: &dyn SyncProgress,
+) -> Result<SyncStats, SyncError> {
+    let push = |ds_to: &Datastore, state: &mut SyncState| -> Result<SyncStats, SyncError> {
+        let mut pushed = sync_datastores(
+            ds_from,
+            ds_to,
+            true,
+            Some(device_id),
+            sync_spec,
+            state,
+            progress,
+        )?;
+        if sync_spec.propagate_deletions {
+            let deleted = record_deletions(ds_from, ds_to, sync_spec, state)?;
+            pushed
+                .buckets
+                .extend(deleted.into_iter().map(deleted_summary));
+        }
+        if sync_spec.vacuum && !sync_spec.dry_run {
+            info!("Vacuuming staging datastore...");
+            ds_to.vacuum()?;
+        }
+        Ok(pushed)
+    };
+
+    let passphrase = sync_spec.passphrase.as_deref();
+    let encrypted = is_encrypted(dbfile);
+    if encrypted && passphrase.is_none() {
+        return Err(missing_passphrase(dbfile));
+    }
+
+    // Nothing is written in a dry run, so the staging datastore can be used as is
+    if sync_spec.dry_run {
+        let plaintext = match passphrase {
+            Some(passphrase) if encrypted => Some(decrypt_to_temp(dbfile, passphrase)?),
+            _ => None,
+        };
+        let ds_localremote = setup_local_remote(plaintext.as_ref().map_or(dbfile, |c| c.path()))?;
+        let result = push(&ds_localremote, state);
+        ds_localremote.close();
+        return result;
+    }
+
+    check_free_space(dbfile, sync_spec.min_free_space)?;
+
+    // Not a .db file, so never picked up as a remote
+    let tmpfile = dbfile.with_extension("db.tmp");
+    let plaintext = passphrase.map(|_| PlaintextCopy::new(dbfile)).transpose()?;
+    let workfile = plaintext.as_ref().map_or(tmpfile.as_path(), |c| c.path());
+    if dbfile.exists() {
+        match passphrase {
+            Some(passphrase) if encrypted => decrypt_file(dbfile, workfile, passphrase)?,
+            // Unencrypted staging datastores are encrypted once a passphrase is set
+            _ => {
+                fs::copy(dbfile, workfile)
+                    .map_err(|e| io_context(e, "copy staging db", workfile))?;
+            }
+        }
+    } else if workfile.exists() {
+        // Left over from an interrupted push
+        fs::remove_file(workfile).map_err(|e| io_context(e, "remove staging db", workfile))?;
+    }
+    let ds_tmp = setup_local_remote(workfile)?;
+    let result = push(&ds_tmp, state).and_then(|pushed| {
+        write_schema_version(&ds_tmp)?;
+        Ok(pushed)
+    });
+    // Blocks until everything is committed
+    ds_tmp.close();
+
+    match result {
+        Ok(pushed) => {
+            if let Some(passphrase) = passphrase {
+                encrypt_file(workfile, &tmpfile, passphrase)?;
+            }
+            fs::rename(&tmpfile, dbfile)
+                .map_err(|e| io_context(e, "replace staging db", dbfile))?;
+            write_version_marker(dbfile)?;
+            Ok(pushed)
+        }
+        Err(e) => {
+            warn!("Push failed, keeping the previous staging datastore");
+            if let Err(e) = fs::remove_file(workfile) {
+                warn!("Failed to remove {}: {e}", workfile.display());
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Checks that pushing to the staging datastore at `dbfile` leaves at least `min_free` bytes free
+///
+/// Pushing copies the staging datastore, so it's estimated to need as much space as the staging
+/// datastore takes. The new events are usually small in comparison.
+fn check_free_space(dbfile: &Path, min_free: u64) -> Result<(), SyncError> {
+    if min_free == 0 {
+        return Ok(());
+    }
+    let size = fs::metadata(dbfile).map_or(0, |m| m.len());
+    let dir = dbfile.parent().unwrap_or(dbfile);
+    let available =
+        fs2::available_space(dir).map_err(|e| io_context(e, "check free space in", dir))?;
+    let required = size.saturating_add(min_free);
+    if available < required {
+        return Err(SyncError::DiskFull {
+            path: dir.to_path_buf(),
+            available,
+            required,
+        });
+    }
     Ok(())
 }
 
+/// Returns the message a thread panicked with
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+fn deleted_summary(bucket_id: String) -> BucketSyncSummary {
+    BucketSyncSummary {
+        bucket_id,
+        created: false,
+        events: 0,
+        deleted: true,
+        error: None,
+        timings: BucketTimings::default(),
+    }
+}
+
 #[allow(dead_code)]
-pub fn list_buckets(client: &AwClient, sync_directory: &Path) -> Result<(), String> {
-    let info = client.get_info().map_err(|e| e.to_string())?;
+pub fn list_buckets(
+    client: &AwClient,
+    sync_directory: &Path,
+    device_id: Option<&str>,
+    instance: Option<&str>,
+    db_filename: Option<&str>,
+    passphrase: Option<&str>,
+) -> Result<(), SyncError> {
+    let info = client.get_info()?;
 
     // FIXME: Incorrect device_id assumption?
-    let device_id = info.device_id.as_str();
-    let ds_localremote = setup_local_remote(sync_directory, device_id)?;
+    let device_id = device_id.unwrap_or(info.device_id.as_str());
+    let dbfile = local_remote_dbfile(sync_directory, device_id, instance, db_filename)?;
+    let (ds_localremote, _plaintext) = if is_encrypted(&dbfile) {
+        open_remote(&dbfile, passphrase)?
+    } else {
+        (setup_local_remote(&dbfile)?, None)
+    };
 
-    let remote_dbfiles = find_remotes_nonlocal(sync_directory, device_id, None);
+    let remote_dbfiles = find_remotes_nonlocal(sync_directory, device_id, None)?;
     info!("Found remotes: {:?}", remote_dbfiles);
 
-    // TODO: Check for compatible remote db version before opening
-    let ds_remotes: Vec<Datastore> = remote_dbfiles
-        .iter()
-        .map(|p| p.as_path())
-        .map(create_datastore)
-        .collect();
+    let remote_dbfiles = filter_compatible_remotes(remote_dbfiles);
+    let mut plaintext_copies = Vec::new();
+    let mut ds_remotes: Vec<Datastore> = Vec::new();
+    for path in remote_dbfiles {
+        match open_remote(&path, passphrase) {
+            Ok((ds, copy)) => {
+                plaintext_copies.extend(copy);
+                ds_remotes.push(ds);
+            }
+            Err(e) => warn!("Skipping remote {}: {e}", path.display()),
+        }
+    }
 
     log_buckets(client);
     log_buckets(&ds_localremote);
@@ -154,53 +1276,368 @@ pub fn list_buckets(client: &AwClient, sync_directory: &Path) -> Result<(), Stri
     Ok(())
 }
 
-fn setup_local_remote(path: &Path, device_id: &str) -> Result<Datastore, String> {
+/// Returns the IDs of the buckets of `ds` which aren't synced, such as new watchers missing from
+/// `sync_spec.buckets`
+///
+/// Pulled buckets aren't listed, as they're never pushed.
+pub fn unsynced_buckets(
+    ds: &dyn AccessMethod,
+    sync_spec: &SyncSpec,
+) -> Result<Vec<String>, SyncError> {
+    let buckets: Vec<Bucket> = ds
+        .get_buckets()
+        .map_err(SyncError::Access)?
+        .into_values()
+        .filter(|b| !sync_spec.bucket_names.is_pulled(&b.id))
+        .collect();
+    let mut synced: Vec<Bucket> = buckets
+        .iter()
+        .filter(|b| sync_spec.includes_bucket_type(&b._type))
+        .cloned()
+        .collect();
+    sync_spec.select_flagged(&mut synced);
+    let mut unsynced: Vec<String> = buckets
+        .into_iter()
+        .filter(|b| !synced.iter().any(|s| s.id == b.id))
+        .map(|b| b.id)
+        .collect();
+    unsynced.sort();
+    Ok(unsynced)
+}
+
+/// Summary of a remote staging db in the sync folder
+#[derive(Debug, Clone)]
+pub struct RemoteInfo {
+    /// Name of the device folder the db is in, usually the device ID of the remote
+    pub device: String,
+    pub path: PathBuf,
+    /// When the db file was last modified, usually when it was last pushed to
+    pub modified: Option<DateTime<Utc>>,
+    /// End of the newest event across all buckets in the db
+    pub last_event: Option<DateTime<Utc>>,
+}
+
+/// Returns info about all remotes in the sync folder, excluding the local one
+pub fn list_remotes(sync_directory: &Path, device_id: &str) -> Result<Vec<RemoteInfo>, SyncError> {
+    let mut remotes = Vec::new();
+    for path in find_remotes_nonlocal(sync_directory, device_id, None)? {
+        let device = remote_device_id(sync_directory, &path);
+        let modified = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(DateTime::<Utc>::from);
+
+        // Encrypted remotes aren't decrypted just for this, their modification time still tells
+        // when they were last pushed to
+        // Remotes which can't be opened are still listed, only without their last event
+        let last_event = if is_encrypted(&path) {
+            None
+        } else {
+            match create_datastore(&path) {
+                Ok(ds) => {
+                    let last_event = ds
+                        .get_buckets()
+                        .map_err(SyncError::Datastore)?
+                        .values()
+                        .filter_map(|bucket| bucket.metadata.end)
+                        .max();
+                    ds.close();
+                    last_event
+                }
+                Err(e) => {
+                    warn!("Failed to open remote {}: {e}", path.display());
+                    None
+                }
+            }
+        };
+
+        remotes.push(RemoteInfo {
+            device,
+            path,
+            modified,
+            last_event,
+        });
+    }
+    remotes.sort_by(|a, b| a.device.cmp(&b.device));
+    Ok(remotes)
+}
+
+/// Returns the folders of remotes which weren't touched for longer than `older_than`
+///
+/// A remote was touched when its db file was last modified, or at the end of its newest event,
+/// whichever is later. Remotes with several dbs (see [`SyncSpec::instance`]) are only returned
+/// if all of them are stale. The folder of the local device is never returned.
+pub fn find_stale_remotes(
+    sync_directory: &Path,
+    device_id: &str,
+    older_than: Duration,
+) -> Result<Vec<PathBuf>, SyncError> {
+    // Keyed by folder, as device folders may be nested
+    let mut last_touched: BTreeMap<PathBuf, Option<DateTime<Utc>>> = BTreeMap::new();
+    for remote in list_remotes(sync_directory, device_id)? {
+        let Some(dir) = remote_device_dir(sync_directory, &remote.path) else {
+            continue;
+        };
+        let touched = remote.modified.max(remote.last_event);
+        let entry = last_touched.entry(dir).or_insert(touched);
+        *entry = (*entry).max(touched);
+    }
+
+    let cutoff = Utc::now() - older_than;
+    let local_dir = sync_directory.join(device_id);
+    Ok(last_touched
+        .into_iter()
+        .filter(|(dir, _)| *dir != local_dir)
+        .filter(|(_, touched)| touched.map_or(true, |t| t < cutoff))
+        .map(|(dir, _)| dir)
+        .collect())
+}
+
+/// Deletes the folders of remotes which weren't touched for longer than `older_than`
+///
+/// Meant for cleaning up after decommissioned devices, whose buckets would otherwise be pulled
+/// forever. See [`find_stale_remotes`] for which remotes are deleted. Returns the deleted folders.
+pub fn prune_remotes(
+    sync_directory: &Path,
+    device_id: &str,
+    older_than: Duration,
+) -> Result<Vec<PathBuf>, SyncError> {
+    let stale = find_stale_remotes(sync_directory, device_id, older_than)?;
+    for dir in &stale {
+        info!("Deleting stale remote {}", dir.display());
+        fs::remove_dir_all(dir).map_err(|e| io_context(e, "delete remote dir", dir))?;
+    }
+    Ok(stale)
+}
+
+/// Returns the path of the staging db for the local device, creating its directory if needed
+pub(crate) fn local_remote_dbfile(
+    path: &Path,
+    device_id: &str,
+    instance: Option<&str>,
+    db_filename: Option<&str>,
+) -> Result<PathBuf, SyncError> {
     // FIXME: Don't run twice if already exists
-    fs::create_dir_all(path).unwrap();
+    fs::create_dir_all(path).map_err(|e| io_context(e, "create sync dir", path))?;
 
-    let remotedir = path.join(device_id);
-    fs::create_dir_all(&remotedir).unwrap();
+    let mut remotedir = path.join(device_id);
+    if let Some(instance) = instance {
+        remotedir.push(instance);
+    }
+    // Long sync directories and device IDs may exceed the path length limit of Windows
+    let file_name_len = db_filename.map_or(device_id.len() + ".db".len(), str::len);
+    let remotedir = long_path_safe(&remotedir, file_name_len);
+    fs::create_dir_all(&remotedir).map_err(|e| io_context(e, "create remote dir", &remotedir))?;
 
-    let dbfile = remotedir.join("test.db");
+    match db_filename {
+        Some(db_filename) => Ok(remotedir.join(db_filename)),
+        None => default_local_dbfile(&remotedir, device_id),
+    }
+}
 
+fn setup_local_remote(dbfile: &Path) -> Result<Datastore, SyncError> {
     // Print a message if dbfile doesn't already exist
     if !dbfile.exists() {
         info!("Creating new database file: {}", dbfile.display());
     }
 
-    let ds_localremote = create_datastore(&dbfile);
+    let ds_localremote = Datastore::new(datastore_path(dbfile)?, false);
     Ok(ds_localremote)
 }
 
+/// Returns the path of a datastore as the string the datastore is opened with
+///
+/// Datastores can only be opened by UTF-8 paths, so other paths are an error instead of a panic.
+fn datastore_path(path: &Path) -> Result<String, SyncError> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| SyncError::InvalidPath(path.to_path_buf()))
+}
+
+/// Returns the path of the staging db to use in the local remote dir if none was specified.
+///
+/// Defaults to `{device_id}.db`, but keeps using the legacy `test.db` if it is the only db present.
+fn default_local_dbfile(remotedir: &Path, device_id: &str) -> Result<PathBuf, SyncError> {
+    let legacy_dbfile = remotedir.join("test.db");
+    let dbfiles: Vec<PathBuf> = fs::read_dir(remotedir)
+        .map_err(|e| io_context(e, "read remote dir", remotedir))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("db"))
+        .collect();
+    if dbfiles.len() == 1 && dbfiles[0] == legacy_dbfile {
+        info!("Using legacy database file: {}", legacy_dbfile.display());
+        return Ok(legacy_dbfile);
+    }
+    Ok(remotedir.join(format!("{device_id}.db")))
+}
+
 /// Returns a list of all remote dbs
-fn find_remotes(sync_directory: &Path) -> std::io::Result<Vec<PathBuf>> {
-    let dbs = fs::read_dir(sync_directory)?
-        .map(|res| res.ok().unwrap().path())
-        .filter(|p| p.is_dir())
-        .flat_map(|d| fs::read_dir(d).unwrap())
-        .map(|res| res.ok().unwrap().path())
-        .filter(|path| path.extension().unwrap_or_else(|| OsStr::new("")) == "db")
+///
+/// Looks for dbs in the device folders, and in the folders within them, such as instance folders
+/// (see [`SyncSpec::instance`]) or the device folders of a sync folder organized into more
+/// levels, such as `{os}/{device}/{device}.db`. Files without a `.db` extension (such as partial
+/// downloads left by the folder synchronizer) are skipped, as are hidden folders (such as
+/// Syncthing's `.stversions`) and conflict copies created by the folder synchronizer.
+///
+/// The dbs are sorted by path, so by device ID first, as the order of `fs::read_dir` depends on
+/// the filesystem. Dbs which are the same file as a db before them (symlinks, hardlinks or bind
+/// mounts of a folder appearing twice) are skipped, so that every db is pulled at most once.
+pub fn find_remotes(sync_directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    // Folders linked into themselves would be walked forever otherwise
+    const MAX_DEPTH: usize = 8;
+
+    fn find_dbs(dir: &Path, dbs: &mut Vec<PathBuf>, depth: usize) -> std::io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                if depth > 0 && !is_hidden(&path) {
+                    find_dbs(&path, dbs, depth - 1)?;
+                }
+            } else if path.extension().and_then(|e| e.to_str()) == Some("db") {
+                if is_conflict_copy(&path) {
+                    warn!(
+                        "Ignoring conflict copy {}! Two devices wrote to the same staging db, \
+                        resolve the conflict by deleting the copy, and make sure every device \
+                        has a device ID of its own",
+                        path.display()
+                    );
+                    continue;
+                }
+                dbs.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut dbs = Vec::new();
+    for entry in fs::read_dir(sync_directory)? {
+        let dir = entry?.path();
+        if dir.is_dir() && !is_hidden(&dir) {
+            find_dbs(&dir, &mut dbs, MAX_DEPTH)?;
+        }
+    }
+    dbs.sort();
+    Ok(dedup_remotes(dbs))
+}
+
+/// Drops the dbs which are the same file as a db before them
+fn dedup_remotes(dbs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut canonical_paths = HashSet::new();
+    // Hardlinks and bind mounts have paths of their own, but share the inode
+    #[cfg(unix)]
+    let mut inodes = HashSet::new();
+    dbs.into_iter()
+        .filter(|path| {
+            // Such as broken symlinks, which fail to open later on
+            let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+            let mut unique = canonical_paths.insert(canonical);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                if let Ok(metadata) = fs::metadata(path) {
+                    unique &= inodes.insert((metadata.dev(), metadata.ino()));
+                }
+            }
+            if !unique {
+                warn!(
+                    "Ignoring {}, as it's the same db as another remote (linked twice)",
+                    path.display()
+                );
+            }
+            unique
+        })
+        .collect()
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Returns true if a file is a copy created by the folder synchronizer to resolve a conflict
+///
+/// Such as `test.sync-conflict-20240101-120000-ABCDEF.db` (Syncthing) or
+/// `test (Laptop's conflicted copy 2024-01-01).db` (Dropbox).
+fn is_conflict_copy(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        name.contains(".sync-conflict-") || name.contains("conflicted copy")
+    })
+}
+
+/// Returns the device folder of the remote a db belongs to
+///
+/// That's the innermost folder the db is in that is named like the db, as for `{os}/{device}/
+/// {device}.db` in sync folders organized into more levels, or else the folder in the sync folder
+/// the db is in, as for `{device}/{instance}/{device}.db` or `{device}/test.db`. Dbs in nested
+/// folders have to be named after their device to be told apart.
+pub(crate) fn remote_device_dir(sync_directory: &Path, dbfile: &Path) -> Option<PathBuf> {
+    let dirs: Vec<&Path> = dbfile
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| *dir != sync_directory && dir.starts_with(sync_directory))
         .collect();
-    Ok(dbs)
+    let stem = dbfile.file_stem();
+    dirs.iter()
+        .find(|dir| dir.file_name() == stem)
+        .or(dirs.last())
+        .map(|dir| dir.to_path_buf())
+}
+
+/// Returns the device ID of the remote a db belongs to, which is the name of the device folder
+/// it is in
+pub(crate) fn remote_device_id(sync_directory: &Path, dbfile: &Path) -> String {
+    remote_device_dir(sync_directory, dbfile)
+        .and_then(|dir| {
+            dir.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default()
+}
+
+/// Returns true if a device folder is named after the device with ID `device_id`
+///
+/// Names differing only by case are the same folder on the case-insensitive filesystems of
+/// Windows and macOS.
+pub(crate) fn is_device_folder(name: &str, device_id: &str) -> bool {
+    if cfg!(any(windows, target_os = "macos")) {
+        name.eq_ignore_ascii_case(device_id)
+    } else {
+        name == device_id
+    }
 }
 
 /// Returns a list of all remotes, excluding local ones
-fn find_remotes_nonlocal(
+///
+/// Sorted alphabetically by device ID, which is the order remotes are pulled in, so that sync
+/// passes don't depend on the filesystem.
+pub(crate) fn find_remotes_nonlocal(
     sync_directory: &Path,
     device_id: &str,
     sync_db: Option<&PathBuf>,
-) -> Vec<PathBuf> {
-    let remotes_all = find_remotes(sync_directory).unwrap();
-    remotes_all
+) -> Result<Vec<PathBuf>, SyncError> {
+    let remotes_all =
+        find_remotes(sync_directory).map_err(|e| io_context(e, "read sync dir", sync_directory))?;
+    // Links to the own db from another folder are named after another device
+    let local_dir = fs::canonicalize(sync_directory.join(device_id)).ok();
+    let remotes = remotes_all
         .into_iter()
-        // Filter out own remote
+        // Filter out own remote, by folder name rather than by substring, as the IDs of other
+        // devices may start with the own one
+        .filter(|path| {
+            let top_level = path
+                .strip_prefix(sync_directory)
+                .ok()
+                .and_then(|relative| relative.components().next());
+            let in_local_dir = top_level
+                .is_some_and(|dir| is_device_folder(&dir.as_os_str().to_string_lossy(), device_id));
+            !in_local_dir && !is_device_folder(&remote_device_id(sync_directory, path), device_id)
+        })
         .filter(|path| {
-            !(path
-                .clone()
-                .into_os_string()
-                .into_string()
-                .unwrap()
-                .contains(device_id))
+            let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+            local_dir
+                .as_ref()
+                .map_or(true, |local_dir| !canonical.starts_with(local_dir))
         })
         // If sync_db is Some, return only remotes in that path
         .filter(|path| {
@@ -210,206 +1647,1050 @@ fn find_remotes_nonlocal(
                 true
             }
         })
-        .collect()
+        .collect();
+    Ok(remotes)
 }
 
-pub fn create_datastore(path: &Path) -> Datastore {
-    let pathstr = path.as_os_str().to_str().unwrap();
-    Datastore::new(pathstr.to_string(), false)
+/// Returns the remotes of `remote_dbfiles` with the device ID `only_remote`, or all of them if
+/// it is None
+///
+/// Fails if none match, listing the remotes there are, as a mistyped device ID would otherwise
+/// silently sync with nothing.
+pub(crate) fn filter_only_remote(
+    sync_directory: &Path,
+    remote_dbfiles: Vec<PathBuf>,
+    only_remote: Option<&str>,
+) -> Result<Vec<PathBuf>, SyncError> {
+    let Some(only_remote) = only_remote else {
+        return Ok(remote_dbfiles);
+    };
+    let (matching, others): (Vec<PathBuf>, Vec<PathBuf>) = remote_dbfiles
+        .into_iter()
+        .partition(|path| is_device_folder(&remote_device_id(sync_directory, path), only_remote));
+    if matching.is_empty() {
+        let mut available: Vec<String> = others
+            .iter()
+            .map(|path| remote_device_id(sync_directory, path))
+            .collect();
+        available.dedup();
+        let available = if available.is_empty() {
+            "none".to_string()
+        } else {
+            available.join(", ")
+        };
+        return Err(SyncError::Config(format!(
+            "no remote '{only_remote}' in the sync folder, available remotes: {available}"
+        )));
+    }
+    Ok(matching)
 }
 
-/// Returns the sync-destination bucket for a given bucket, creates it if it doesn't exist.
+/// Opens the db of a remote
+///
+/// Remote dbs are only ever read from, as they belong to other devices, so they are opened
+/// read-only to guard against accidental writes (which would cause conflicts in the sync folder).
+/// Paths which aren't valid UTF-8 can't be opened, and are a [`SyncError::InvalidPath`]. Dbs
+/// which can't be opened, such as ones which are corrupted or of another schema version, are a
+/// [`SyncError::Datastore`].
+pub fn create_datastore(path: &Path) -> Result<Datastore, SyncError> {
+    Ok(Datastore::new_readonly(datastore_path(path)?)?)
+}
+
+/// Returns true if a remote datastore passes SQLite's integrity check, warns if it doesn't
+///
+/// Guards against importing from dbs only partially written by the folder synchronizer.
+pub fn verify_remote_integrity(path: &Path, ds: &Datastore) -> bool {
+    match ds.integrity_check() {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "Skipping remote {}: integrity check failed: {:?}",
+                path.display(),
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Returns the ID of a bucket without its `-synced-from-{origin}` suffix
+///
+/// Only suffixes naming `origin` are stripped, so origins and bucket IDs which themselves contain
+/// `-synced-from-` are handled. Repeated suffixes (`-synced-from-a-synced-from-a`), as created by
+/// earlier versions, are collapsed. See [`BucketNameTemplate::source_id`] for other templates.
+#[allow(dead_code)]
+pub fn origin_bucket_id<'a>(bucket_id: &'a str, origin: &str) -> &'a str {
+    BucketNameTemplate::default().source_id(bucket_id, origin)
+}
+
+/// Key in the bucket data of the device ID of the device a bucket was pushed from
+///
+/// Unlike hostnames (`$aw.sync.origin`), device IDs are unique, so buckets from devices sharing
+/// a hostname are kept apart.
+pub const ORIGIN_DEVICE_KEY: &str = "$aw.sync.origin_device";
+
+/// Key in the bucket data flagging whether a bucket is synced, see [`SyncSpec::bucket_flags`]
+pub const SYNC_ENABLED_KEY: &str = "$aw.sync.enabled";
+
+/// Returns the legacy origin of a bucket, the hostname it was synced from
+pub(crate) fn legacy_origin(bucket: &Bucket) -> &str {
+    bucket
+        .data
+        .get("$aw.sync.origin")
+        .and_then(|origin| origin.as_str())
+        .unwrap_or(&bucket.hostname)
+}
+
+/// Returns the origin pulled buckets are suffixed with
+///
+/// Prefers the device ID recorded when the bucket was pushed, then the device folder of the
+/// remote the bucket is pulled from (`src_did`), and falls back to the hostname for buckets
+/// which were pushed by older versions and aren't pulled from a device folder.
+pub(crate) fn bucket_origin<'a>(bucket: &'a Bucket, src_did: Option<&'a str>) -> &'a str {
+    bucket
+        .data
+        .get(ORIGIN_DEVICE_KEY)
+        .and_then(|origin| origin.as_str())
+        .or(src_did)
+        .unwrap_or_else(|| legacy_origin(bucket))
+}
+
+/// Returns the ID of the bucket a bucket is synced to when pulled from a remote
+pub(crate) fn pulled_bucket_id(
+    bucket_from: &Bucket,
+    src_did: Option<&str>,
+    names: &BucketNameTemplate,
+) -> String {
+    synced_bucket_id(bucket_from, bucket_origin(bucket_from, src_did), names)
+}
+
+/// Returns the ID of a bucket named after the given origin
+pub(crate) fn synced_bucket_id(
+    bucket_from: &Bucket,
+    origin: &str,
+    names: &BucketNameTemplate,
+) -> String {
+    // Ensure the bucket ID is named after its origin, such as "{id}-synced-from-{device id}"
+    let orig_bucketid = names.source_id(&bucket_from.id, origin);
+    if orig_bucketid.contains(names.separator()) {
+        warn!(
+            "Bucket '{}' is named as synced from another origin than '{origin}', \
+            keeping that as part of the bucket ID",
+            bucket_from.id
+        );
+    }
+    names.format(orig_bucketid, origin)
+}
+
+/// Returns the sync-destination bucket `new_id` for a given bucket, creates it if it doesn't
+/// exist.
+///
+/// The returned bool is true if the bucket was created (or would be, in a dry run).
 fn get_or_create_sync_bucket(
     bucket_from: &Bucket,
+    new_id: &str,
     ds_to: &dyn AccessMethod,
     is_push: bool,
-) -> Bucket {
-    let new_id = if is_push {
-        bucket_from.id.clone()
-    } else {
-        // Ensure the bucket ID ends in "-synced-from-{device id}"
-        let orig_bucketid = bucket_from.id.split("-synced-from-").next().unwrap();
-        let fallback = serde_json::to_value(&bucket_from.hostname).unwrap();
-        let origin = bucket_from
-            .data
-            .get("$aw.sync.origin")
-            .unwrap_or(&fallback)
-            .as_str()
-            .unwrap();
-        format!("{orig_bucketid}-synced-from-{origin}")
-    };
-
-    match ds_to.get_bucket(new_id.as_str()) {
-        Ok(bucket) => bucket,
+    src_did: Option<&str>,
+    sync_spec: &SyncSpec,
+) -> Result<(Bucket, bool), SyncError> {
+    let dry_run = sync_spec.dry_run;
+    match ds_to.get_bucket(new_id) {
+        Ok(bucket) => {
+            let bucket = sync_bucket_data(bucket_from, bucket, ds_to, is_push, src_did, dry_run);
+            Ok((bucket, false))
+        }
         Err(DatastoreError::NoSuchBucket(_)) => {
             let mut bucket_new = bucket_from.clone();
-            bucket_new.id = new_id.clone();
-            // TODO: Replace sync origin with hostname/GUID and discuss how we will treat the data
-            // attributes for internal use.
+            bucket_new.id = new_id.to_string();
+            // Keeps when the bucket was created on its source device, not when it was synced.
+            // If the source doesn't know, its first event is the earliest it can have been.
+            bucket_new.created = bucket_from.created.or(bucket_from.metadata.start);
+            // Those of the destination are those of the events synced to it
+            bucket_new.metadata = BucketMetadata::default();
+            bucket_new.events = None;
+            // TODO: Discuss how we will treat the data attributes for internal use.
+            if is_push {
+                bucket_new.hostname = sync_spec.pseudonymize(&bucket_from.hostname);
+            }
             bucket_new.data.insert(
                 "$aw.sync.origin".to_string(),
-                serde_json::json!(bucket_from.hostname),
+                serde_json::json!(bucket_new.hostname),
             );
-            ds_to.create_bucket(&bucket_new).unwrap();
-            match ds_to.get_bucket(new_id.as_str()) {
-                Ok(bucket) => bucket,
-                Err(e) => panic!("{e:?}"),
+            if let (true, Some(did)) = (is_push, src_did) {
+                bucket_new
+                    .data
+                    .insert(ORIGIN_DEVICE_KEY.to_string(), serde_json::json!(did));
             }
+            if dry_run {
+                return Ok((bucket_new, true));
+            }
+            let created = match ds_to.create_bucket(&bucket_new) {
+                Ok(()) => true,
+                // Created by another process since it was looked up
+                Err(DatastoreError::BucketAlreadyExists(_)) => false,
+                Err(e) => return Err(SyncError::Datastore(e)),
+            };
+            Ok((ds_to.get_bucket(new_id)?, created))
         }
-        Err(e) => panic!("{e:?}"),
+        Err(e) => Err(SyncError::Datastore(e)),
+    }
+}
+
+/// Updates the data attributes of an already synced bucket to those of its source bucket
+///
+/// The `$aw.sync.origin` of the synced bucket is kept, and pushed buckets get their
+/// [`ORIGIN_DEVICE_KEY`] added if they were pushed by an older version. Returns the updated
+/// bucket, failing to update it is only logged, as the events can still be synced.
+fn sync_bucket_data(
+    bucket_from: &Bucket,
+    mut bucket_to: Bucket,
+    ds_to: &dyn AccessMethod,
+    is_push: bool,
+    src_did: Option<&str>,
+    dry_run: bool,
+) -> Bucket {
+    let mut data = bucket_from.data.clone();
+    if let Some(origin) = bucket_to.data.get("$aw.sync.origin") {
+        data.insert("$aw.sync.origin".to_string(), origin.clone());
+    }
+    if let (true, Some(did)) = (is_push, src_did) {
+        data.insert(ORIGIN_DEVICE_KEY.to_string(), serde_json::json!(did));
     }
+    if data == bucket_to.data {
+        return bucket_to;
+    }
+
+    bucket_to.data = data;
+    if dry_run {
+        info!(" ~ Would update data of bucket '{}'", bucket_to.id);
+        return bucket_to;
+    }
+    match ds_to.update_bucket(&bucket_to) {
+        Ok(()) => info!(" = Updated data of bucket '{}'", bucket_to.id),
+        Err(e) => warn!(
+            "Failed to update data of bucket '{}': {:?}",
+            bucket_to.id, e
+        ),
+    }
+    bucket_to
 }
 
 /// Syncs all buckets from `ds_from` to `ds_to` with `-synced` appended to the ID of the destination bucket.
 ///
 /// is_push: a bool indicating if we're pushing local buckets to the sync dir
 ///          (as opposed to pulling from remotes)
-/// src_did: source device ID
+/// src_did: source device ID, also used to prefix log lines
+///
+/// state: sync state from previous passes, updated with the newly synced buckets
+/// progress: receives progress events as buckets are synced
+///
+/// Returns stats with a summary for each synced bucket. If `sync_spec.dry_run` is set, nothing is
+/// written to `ds_to` and the stats describe what would have been synced.
+///
+/// Buckets which fail to sync are included in the stats with their error, only failing to list
+/// the buckets of `ds_from` fails the whole call.
 pub fn sync_datastores(
     ds_from: &dyn AccessMethod,
     ds_to: &dyn AccessMethod,
     is_push: bool,
     src_did: Option<&str>,
     sync_spec: &SyncSpec,
-) {
+    state: &mut SyncState,
+    progress: &dyn SyncProgress,
+) -> Result<SyncStats, SyncError> {
     // FIXME: "-synced" should only be appended when synced to the local database, not to the
     // staging area for local buckets.
-    info!("Syncing {:?} to {:?}", ds_from, ds_to);
+    // Prefix log lines with the source device, to keep them readable when pulling in parallel
+    let log_prefix = src_did.map(|did| format!("[{did}] ")).unwrap_or_default();
+    // Also attached to log records as fields, for log processors to group them by
+    let remote = src_did.unwrap_or_default();
+    info!(remote = remote; "{log_prefix}Syncing {:?} to {:?}", ds_from, ds_to);
+    sync_spec.check_buckets()?;
 
-    let mut buckets_from: Vec<Bucket> = ds_from
-        .get_buckets()
-        .unwrap()
-        .iter_mut()
-        // If buckets vec isn't empty, filter out buckets not in the buckets vec
-        .filter(|tup| {
-            let bucket = &tup.1;
-            if let Some(buckets) = &sync_spec.buckets {
-                buckets.iter().any(|b_id| b_id == &bucket.id)
-            } else {
-                true
-            }
-        })
-        .map(|tup| {
-            // TODO: Refuse to sync buckets without hostname/device ID set, or if set to 'unknown'
-            if tup.1.hostname == "unknown" {
-                warn!(" ! Bucket hostname/device ID was invalid, setting to device ID/hostname");
-                tup.1.hostname = src_did.unwrap().to_string();
-            }
-            tup.1.clone()
-        })
-        .collect();
+    // Buckets are listed a page at a time, so that only the ones to sync are kept, however many
+    // buckets were pulled from other devices
+    const BUCKET_PAGE_SIZE: usize = 100;
+    let mut buckets_from: Vec<Bucket> = Vec::new();
+    for page in ds_from.get_buckets_paged(BUCKET_PAGE_SIZE) {
+        let page = page.map_err(SyncError::Access)?;
+        let included = page
+            .into_iter()
+            // Filter out buckets not in the buckets vec or matching the buckets regex, if set,
+            // unless they may be flagged to be synced
+            .filter(|bucket| sync_spec.may_include_bucket(bucket))
+            // Filter out buckets of types not included, or excluded
+            .filter(|bucket| sync_spec.includes_bucket_type(&bucket._type))
+            // Never push buckets pulled from other devices back into the sync folder
+            .filter(|bucket| !(is_push && sync_spec.bucket_names.is_pulled(&bucket.id)))
+            .filter_map(|mut bucket| {
+                // TODO: Refuse to sync buckets without hostname/device ID set, or if set to
+                // 'unknown'
+                if bucket.hostname == "unknown" {
+                    let Some(did) = src_did else {
+                        warn!(
+                            "{log_prefix} ! Bucket '{}' has no valid hostname/device ID, and the source device is unknown, skipping",
+                            bucket.id
+                        );
+                        return None;
+                    };
+                    warn!(
+                        "{log_prefix} ! Bucket hostname/device ID was invalid, setting to device ID/hostname"
+                    );
+                    bucket.hostname = did.to_string();
+                }
+                Some(bucket)
+            });
+        buckets_from.extend(included);
+    }
+    let flagged = sync_spec.select_flagged(&mut buckets_from);
+    if flagged {
+        info!(remote = remote; "{log_prefix}Syncing the buckets flagged with {SYNC_ENABLED_KEY}");
+    }
 
     // Log warning for buckets requested but not found
-    if let Some(buckets) = &sync_spec.buckets {
+    if let Some(buckets) = sync_spec.buckets.as_ref().filter(|_| !flagged) {
         for b_id in buckets {
             if !buckets_from.iter().any(|b| b.id == *b_id) {
-                error!(" ! Bucket \"{}\" not found in source datastore", b_id);
+                error!(
+                    "{log_prefix} ! Bucket \"{}\" not found in source datastore",
+                    b_id
+                );
             }
         }
     }
 
+    // Skip dormant buckets
+    if let Some(changed_within) = sync_spec.changed_within {
+        let cutoff = Utc::now() - changed_within;
+        buckets_from.retain(|b| b.metadata.end.map_or(true, |end| end >= cutoff));
+    }
+
     // Sync buckets in order of most recently updated
     buckets_from.sort_by_key(|b| b.metadata.end);
 
-    for bucket_from in buckets_from {
-        let bucket_to = get_or_create_sync_bucket(&bucket_from, ds_to, is_push);
-        sync_one(ds_from, ds_to, bucket_from, bucket_to);
+    let mut stats = SyncStats::default();
+    let bucket_count = buckets_from.len();
+    for (i, bucket_from) in buckets_from.into_iter().enumerate() {
+        if sync_spec.cancelled() {
+            warn!(
+                remote = remote;
+                "{log_prefix} ! Interrupted, not syncing the remaining {} buckets",
+                bucket_count - i
+            );
+            stats.interrupted = true;
+            break;
+        }
+        if sync_spec.deadline_passed() {
+            warn!(
+                remote = remote;
+                "{log_prefix} ! Timed out, not syncing the remaining {} buckets",
+                bucket_count - i
+            );
+            stats.timed_out = true;
+            break;
+        }
+        // Not recorded in the state, so the bucket is synced once it's no longer skipped
+        if let Some(max) = sync_spec.skip_buckets_larger_than {
+            match ds_from.get_event_count(&bucket_from.id) {
+                Ok(count) if count > max as i64 => {
+                    warn!(
+                        remote = remote, bucket = bucket_from.id.as_str(), count = count;
+                        "{log_prefix} - Deferring bucket '{}', as it has {count} events (more \
+                        than {max})", bucket_from.id
+                    );
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "{log_prefix} ! Failed to count the events of '{}', syncing it anyway: {e}",
+                    bucket_from.id
+                ),
+            }
+        }
+        let bucket_id_to = if is_push {
+            bucket_from.id.clone()
+        } else {
+            sync_spec.pulled_bucket_id(&bucket_from, src_did)
+        };
+        let synced_bucket = get_or_create_sync_bucket(
+            &bucket_from,
+            &bucket_id_to,
+            ds_to,
+            is_push,
+            src_did,
+            sync_spec,
+        );
+        let (bucket_to, created) = match synced_bucket {
+            Ok(synced_bucket) => synced_bucket,
+            Err(e) => {
+                // Not recorded in the state either, so creating it is retried on the next pass
+                let error = e.to_string();
+                error!(
+                    remote = remote, bucket = bucket_id_to.as_str(), error = error.as_str();
+                    "{log_prefix} ! Failed to get or create bucket '{bucket_id_to}': {error}"
+                );
+                stats.push(BucketSyncSummary {
+                    bucket_id: bucket_id_to,
+                    created: false,
+                    events: 0,
+                    deleted: false,
+                    error: Some(error),
+                    timings: BucketTimings::default(),
+                });
+                continue;
+            }
+        };
+        if created && sync_spec.dry_run {
+            info!(
+                remote = remote, bucket = bucket_to.id.as_str();
+                "{log_prefix} + Would create bucket '{}'", bucket_to.id
+            );
+        }
+        progress.report(SyncProgressEvent::BucketStarted {
+            bucket_id: bucket_to.id.clone(),
+        });
+        let bucket_id_from = bucket_from.id.clone();
+        let mut events = 0;
+        let mut timings = BucketTimings::default();
+        let mut sync_bucket = || -> Result<(), SyncError> {
+            events = sync_one(
+                ds_from,
+                ds_to,
+                bucket_from.clone(),
+                &bucket_to,
+                created,
+                is_push,
+                sync_spec,
+                state,
+                remote,
+                &log_prefix,
+                progress,
+                &mut timings,
+            )?;
+            Ok(())
+        };
+        // Pushes are all-or-nothing per bucket, as the next pass resumes after the last event in
+        // the staging datastore, and would skip the events missing from a partially pushed batch
+        // Pulls keep what was inserted, as they resume at their checkpoint instead
+        let result = if is_push {
+            ds_to.transaction(&mut sync_bucket)
+        } else {
+            sync_bucket()
+        };
+        let events = match result.map(|()| events) {
+            Ok(events) => events,
+            // Watchers may delete and recreate their buckets at any time, which isn't an error
+            Err(_)
+                if bucket_deleted(ds_from, &bucket_id_from)
+                    || (!(created && sync_spec.dry_run)
+                        && bucket_deleted(ds_to, &bucket_to.id)) =>
+            {
+                warn!(
+                    remote = remote, bucket = bucket_to.id.as_str();
+                    "{log_prefix} ! Bucket '{}' was deleted while syncing, skipping it",
+                    bucket_id_from
+                );
+                continue;
+            }
+            Err(e) => {
+                // Not recorded in the state, so the bucket is synced again on the next pass
+                let error = e.to_string();
+                let rolled_back = if is_push { ", rolled back" } else { "" };
+                error!(
+                    remote = remote, bucket = bucket_to.id.as_str(), error = error.as_str();
+                    "{log_prefix} ! Failed to sync bucket '{}'{rolled_back}: {error}", bucket_to.id
+                );
+                stats.push(BucketSyncSummary {
+                    bucket_id: bucket_to.id,
+                    created,
+                    events: 0,
+                    deleted: false,
+                    error: Some(error),
+                    timings,
+                });
+                continue;
+            }
+        };
+        progress.report(SyncProgressEvent::BucketFinished {
+            bucket_id: bucket_to.id.clone(),
+            new_events: events,
+        });
+        stats.push(BucketSyncSummary {
+            bucket_id: bucket_to.id,
+            created,
+            events,
+            deleted: false,
+            error: None,
+            timings,
+        });
     }
+    Ok(stats)
+}
+
+/// Time ranges of events by their data, for finding the events they cover
+///
+/// An event is covered if it's within an event with the same data, such as the events a merged
+/// event was merged from.
+struct Coverage {
+    /// Start and end of the events of each data, sorted by start, with each end being the latest
+    /// one of the events up to it
+    ranges: HashMap<String, Vec<(DateTime<Utc>, DateTime<Utc>)>>,
+}
+
+impl Coverage {
+    fn new(events: &[Event]) -> Coverage {
+        let mut ranges: HashMap<String, Vec<(DateTime<Utc>, DateTime<Utc>)>> = HashMap::new();
+        for event in events {
+            let data = serde_json::Value::Object(event.data.clone()).to_string();
+            let range = (event.timestamp, event.calculate_endtime());
+            ranges.entry(data).or_default().push(range);
+        }
+        for ranges in ranges.values_mut() {
+            ranges.sort_by_key(|(start, _)| *start);
+            // Any event starting earlier may reach further than the ones after it
+            for i in 1..ranges.len() {
+                ranges[i].1 = ranges[i].1.max(ranges[i - 1].1);
+            }
+        }
+        Coverage { ranges }
+    }
+
+    /// Returns true if `event` is within one of the events with the same data
+    fn covers(&self, event: &Event) -> bool {
+        let data = serde_json::Value::Object(event.data.clone()).to_string();
+        let Some(ranges) = self.ranges.get(&data) else {
+            return false;
+        };
+        // The latest end of the events starting at or before the event
+        match ranges.partition_point(|(start, _)| *start <= event.timestamp) {
+            0 => false,
+            n => ranges[n - 1].1 >= event.calculate_endtime(),
+        }
+    }
+}
+
+/// Returns true if the bucket doesn't exist (anymore)
+fn bucket_deleted(ds: &dyn AccessMethod, bucket_id: &str) -> bool {
+    matches!(
+        ds.get_bucket(bucket_id),
+        Err(DatastoreError::NoSuchBucket(_))
+    )
+}
+
+/// Numbers of events read from a source bucket, and of those which weren't synced
+#[derive(Default)]
+struct EventCounts {
+    read: usize,
+    /// Seconds spent fetching the events
+    fetch_secs: f64,
+    excluded: usize,
+    /// Shorter than the minimum duration of the bucket
+    short: usize,
+    skipped: usize,
 }
 
 /// Syncs a single bucket from one datastore to another
+///
+/// `created` should be true if `bucket_to` was just created (or would be, in a dry run), in which
+/// case it is known to be empty and is not queried.
+///
+/// Returns the number of new events in the destination bucket, or an error if inserting events
+/// still failed after retrying.
+#[allow(clippy::too_many_arguments)]
 fn sync_one(
     ds_from: &dyn AccessMethod,
     ds_to: &dyn AccessMethod,
     bucket_from: Bucket,
-    bucket_to: Bucket,
-) {
-    let eventcount_to_old = ds_to.get_event_count(bucket_to.id.as_str()).unwrap();
-    info!(" ⟳  Syncing bucket '{}'", bucket_to.id);
+    bucket_to: &Bucket,
+    created: bool,
+    is_push: bool,
+    sync_spec: &SyncSpec,
+    state: &mut SyncState,
+    remote: &str,
+    log_prefix: &str,
+    progress: &dyn SyncProgress,
+    timings: &mut BucketTimings,
+) -> Result<usize, SyncError> {
+    let bucket = bucket_to.id.as_str();
+    // Buckets merged from several origins (see SyncSpec::origin_aliases) keep a state for each
+    // of them, as the end of the destination may be that of another origin
+    let origin = bucket_origin(&bucket_from, Some(remote));
+    let merged = !is_push && sync_spec.merges_origin(origin);
+    let state_key = if merged {
+        format!("{bucket}@{origin}")
+    } else {
+        bucket.to_string()
+    };
+    info!(remote = remote, bucket = bucket; "{log_prefix} ⟳  Syncing bucket '{bucket}'");
+
+    // Changes to the destination are noticed by the events from where syncing resumes after the
+    // end of the source, as counting whole buckets can be slow
+    let count_resumed = |end: DateTime<Utc>, timings: &mut BucketTimings| {
+        let counting = Instant::now();
+        let count = ds_to
+            .get_event_count_range(
+                bucket_to.id.as_str(),
+                resume_after(Some(end), sync_spec),
+                sync_spec.end,
+            )
+            .map_err(SyncError::Access);
+        timings.count_secs += counting.elapsed().as_secs_f64();
+        count
+    };
+
+    // Edits don't change the end or the event count of a bucket, so they have to be merged
+    // before checking whether the bucket changed
+    if sync_spec.merge_edits && !created {
+        merge_edits(
+            ds_from,
+            ds_to,
+            &bucket_from.id,
+            &bucket_to.id,
+            sync_spec,
+            log_prefix,
+        )?;
+    }
+
+    // Skip the bucket if neither it nor the destination changed since it was last synced
+    // Events after the end of the time range aren't synced, so they don't count as changes
+    let end_from = bucket_from.metadata.end;
+    if let Some(end) = end_from {
+        let skew = end - Utc::now();
+        // Events being a bit in the future is common enough not to warn about
+        if skew > sync_spec.clock_skew_tolerance.max(Duration::minutes(5)) {
+            warn!(
+                remote = remote, bucket = bucket, skew_secs = skew.num_seconds();
+                "{log_prefix}  ! Last event in '{}' is {}s in the future, is the clock ahead?",
+                bucket_from.id,
+                skew.num_seconds()
+            );
+        }
+    }
+    let end_from = end_from.map(|end| sync_spec.end.map_or(end, |range_end| end.min(range_end)));
+    if !created && !sync_spec.force_full {
+        if let (Some(end), Some(bucket_state)) = (end_from, state.buckets.get(&state_key)) {
+            if bucket_state.end == end && bucket_state.eventcount == count_resumed(end, timings)? {
+                info!(
+                    remote = remote, bucket = bucket, count = 0;
+                    "{log_prefix}  ✓ Already up to date! (unchanged since last sync)"
+                );
+                return Ok(0);
+            }
+        }
+    }
 
     // Sync events
-    // FIXME: This should use bucket_to.metadata.end, but it doesn't because it doesn't work
-    // for empty buckets (Should be None, is Some(unknown_time))
-    // let resume_sync_at = bucket_to.metadata.end;
-    let most_recent_events = ds_to
-        .get_events(bucket_to.id.as_str(), None, None, Some(1))
-        .unwrap();
-    let resume_sync_at = most_recent_events.first().map(|e| e.timestamp + e.duration);
+    // Merged buckets resume after the end of the origin when it was last synced instead
+    let resume_sync_at = if created {
+        sync_spec.start
+    } else if merged {
+        resume_after(state.buckets.get(&state_key).map(|s| s.end), sync_spec)
+    } else {
+        resume_point(bucket_to, sync_spec)
+    };
 
     if let Some(resume_time) = resume_sync_at {
-        info!("   + Resuming at {:?}", resume_time);
+        info!("{log_prefix}   + Resuming at {:?}", resume_time);
     } else {
-        info!("   + Starting from beginning");
+        info!("{log_prefix}   + Starting from beginning");
     }
 
-    // Fetch events
-    // Unset ID on events, as they are not globally unique
-    // TODO: Fetch at most ~5,000 events at a time (or so, to avoid timeout from huge buckets)
-    let mut events: Vec<Event> = ds_from
-        .get_events(bucket_from.id.as_str(), resume_sync_at, None, None)
-        .unwrap()
-        .iter()
-        .map(|e| {
-            let mut new_e = e.clone();
-            new_e.id = None;
-            new_e
-        })
-        .collect();
+    // Checkpoints are only kept for pulls, as pushes go to a copy which is discarded if the push
+    // is interrupted
+    let checkpoints = state.checkpoints.clone().filter(|_| !is_push);
+    let checkpoint = checkpoints
+        .as_ref()
+        .and_then(|c| c.get(&state_key))
+        .filter(|_| !created);
 
-    // Sort ascending
-    // FIXME: What happens here if two events have the same timestamp?
-    events.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    let fetch_from = match checkpoint {
+        // An interrupted pass committed all events until the checkpoint, so there's no need to
+        // check earlier ones again, even in strict mode
+        Some(checkpoint) => {
+            info!("{log_prefix}   + Resuming at checkpoint {:?}", checkpoint);
+            Some(
+                sync_spec
+                    .start
+                    .map_or(checkpoint, |start| checkpoint.max(start)),
+            )
+        }
+        // In strict mode, events from before the resume point are checked too, as they may be
+        // missing from the destination even though later events aren't
+        None if sync_spec.dedup_strict && !created => sync_spec.start,
+        None => resume_sync_at,
+    };
+    debug!(
+        remote = remote, bucket = bucket;
+        "{log_prefix}   Computed resume point {:?}, fetching events from {:?}",
+        resume_sync_at,
+        fetch_from
+    );
+
+    let pulsetime = sync_spec.pulsetime(&bucket_from);
+
+    // Skip events which were already synced, as the events fetched may overlap with the events
+    // in the destination
+    // Events at the checkpoint may have been committed already
+    // The events of other origins of merged buckets may overlap too
+    let dedup = sync_spec.dedup_strict
+        || sync_spec.clock_skew_tolerance > Duration::zero()
+        || checkpoint.is_some()
+        || merged;
+    // Events are streamed from the source in batches, so that big buckets don't have to fit in
+    // memory. The destination events each batch may duplicate are fetched along with it.
+    const BATCH_SIZE: usize = 1000;
+    let for_each_batch = |counts: &mut EventCounts,
+                          f: &mut dyn FnMut(Vec<Event>) -> Result<bool, SyncError>|
+     -> Result<(), SyncError> {
+        let fetching = Instant::now();
+        let mut batches =
+            ds_from.get_events_batched(&bucket_from.id, fetch_from, sync_spec.end, BATCH_SIZE);
+        counts.fetch_secs += fetching.elapsed().as_secs_f64();
+        loop {
+            let fetching = Instant::now();
+            let batch = batches.next();
+            counts.fetch_secs += fetching.elapsed().as_secs_f64();
+            let Some(batch) = batch else {
+                break;
+            };
+            let mut events = batch.map_err(SyncError::Access)?;
+            counts.read += events.len();
+            // Unset ID on events, as they are not globally unique
+            for event in &mut events {
+                event.id = None;
+            }
+
+            let events_before = events.len();
+            events.retain(|e| !sync_spec.excludes_event(&bucket_from.id, e));
+            counts.excluded += events_before - events.len();
+            let events_before = events.len();
+            events.retain(|e| !sync_spec.drops_short_event(&bucket_from.id, e));
+            counts.short += events_before - events.len();
+            // Redacted before deduplicating, as the destination only has the redacted versions
+            // (the same goes for dropped fields)
+            for event in &mut events {
+                sync_spec.redact_event(&bucket_from.id, event);
+            }
+
+            // Only the destination events overlapping the batch can be duplicates of its events
+            // or cover them
+            let batch_start = events.first().map(|e| e.timestamp);
+            let batch_end = events.iter().map(|e| e.calculate_endtime()).max();
+            let batch_range = batch_start.zip(batch_end).filter(|_| dedup && !created);
+            if let Some((start, end)) = batch_range {
+                let fetching = Instant::now();
+                let events_to = ds_to
+                    .get_events(bucket_to.id.as_str(), Some(start), Some(end), None)
+                    .map_err(SyncError::Access)?;
+                counts.fetch_secs += fetching.elapsed().as_secs_f64();
+                let existing: HashSet<String> = events_to.iter().map(event_dedup_key).collect();
+                // Edited events are updated by merging instead (if enabled), see the merge module
+                let existing_edited: HashSet<(i64, i64)> =
+                    events_to.iter().map(match_key).collect();
+                let events_before = events.len();
+                events.retain(|e| {
+                    !existing.contains(&event_dedup_key(e))
+                        && !(event_revision(e) > 0 && existing_edited.contains(&match_key(e)))
+                });
+                // Merged events don't match the events they were merged from, so skip events
+                // covered by an event with the same data instead
+                if pulsetime.is_some() {
+                    let coverage = Coverage::new(&events_to);
+                    events.retain(|e| !coverage.covers(e));
+                }
+                counts.skipped += events_before - events.len();
+            }
+
+            // The callback returns false to stop
+            if !events.is_empty() && !f(events)? {
+                break;
+            }
+        }
+        Ok(())
+    };
+    let log_counts = |counts: &EventCounts| {
+        debug!(
+            remote = remote, bucket = bucket, count = counts.read;
+            "{log_prefix}   Read {} events from '{}'", counts.read, bucket_from.id
+        );
+        if counts.excluded > 0 {
+            let excluded = counts.excluded;
+            info!(
+                remote = remote, bucket = bucket, excluded = excluded;
+                "{log_prefix}   - Excluded {excluded} events matching exclusion rules"
+            );
+        }
+        if counts.short > 0 {
+            let (short, min) = (counts.short, sync_spec.min_duration[&bucket_from.id]);
+            info!(
+                remote = remote, bucket = bucket, short = short;
+                "{log_prefix}   - Dropped {short} events shorter than {min}s"
+            );
+        }
+        if counts.skipped > 0 {
+            let skipped = counts.skipped;
+            info!(
+                remote = remote, bucket = bucket, skipped = skipped;
+                "{log_prefix}   - Skipped {skipped} events already in destination"
+            );
+        }
+    };
+
+    // Pushes may wait for more new events to accumulate, which only needs them counted until
+    // there are enough. The state isn't updated, so the deferred events are fetched again on the
+    // next pass.
+    let flushed = state.buckets.get(&state_key).and_then(|s| s.flushed);
+    if is_push && !created && sync_spec.defers_push(1, flushed) {
+        let mut counts = EventCounts::default();
+        let mut pending = 0;
+        let counted = for_each_batch(&mut counts, &mut |events| {
+            pending += events.len();
+            Ok(pending < sync_spec.min_batch)
+        });
+        timings.get_events_secs += counts.fetch_secs;
+        counted?;
+        if pending > 0 && sync_spec.defers_push(pending, flushed) {
+            info!(
+                remote = remote, bucket = bucket, count = pending;
+                "{log_prefix}  - Deferring {pending} new events until more accumulated"
+            );
+            return Ok(0);
+        }
+    }
+
+    // Only this many are synced, the state isn't updated if there are more, so the remaining
+    // events are synced on the next passes
+    let events_limit = sync_spec.max_events_per_bucket.unwrap_or(usize::MAX);
+
+    if sync_spec.dry_run {
+        let mut counts = EventCounts::default();
+        let mut events_total = 0;
+        let counted = for_each_batch(&mut counts, &mut |events| {
+            events_total += events.len();
+            Ok(events_total <= events_limit)
+        });
+        timings.get_events_secs += counts.fetch_secs;
+        counted?;
+        log_counts(&counts);
+        let events_synced = events_total.min(events_limit);
+        if events_synced < events_total {
+            info!(
+                remote = remote, bucket = bucket, count = events_synced;
+                "{log_prefix}  ~ Would only sync {events_synced} new events, the rest are synced \
+                on the next passes"
+            );
+        } else if events_synced > 0 {
+            info!(
+                remote = remote, bucket = bucket, count = events_synced;
+                "{log_prefix}  ~ Would sync {events_synced} new events"
+            );
+        } else {
+            info!(
+                remote = remote, bucket = bucket, count = 0;
+                "{log_prefix}  ✓ Already up to date!"
+            );
+        }
+        return Ok(events_synced);
+    }
+
+    // Only count events within the synced window, as counting whole buckets can be slow
+    let count_window = || {
+        ds_to
+            .get_event_count_range(bucket_to.id.as_str(), fetch_from, sync_spec.end)
+            .map_err(SyncError::Access)
+    };
+    let counting = Instant::now();
+    let eventcount_window_old = if created { 0 } else { count_window()? };
+    // Events aren't counted before inserting them, so the progress is reported out of the events
+    // in the source, some of which may turn out to be excluded or synced already
+    let events_estimate = ds_from
+        .get_event_count_range(&bucket_from.id, fetch_from, sync_spec.end)
+        .map_err(SyncError::Access)?;
+    let events_estimate = (events_estimate.max(0) as usize).min(events_limit);
+    timings.count_secs += counting.elapsed().as_secs_f64();
 
-    // TODO: Do bulk insert using insert_events instead? (for performance)
-    //       Client-side heartbeat queueing should keep things somewhat performant though?
-    // NOTE: First event needs to be inserted with heartbeat, to ensure appropriate
-    // merging/updating of pulsed events.
-    let events_total = events.len();
     let mut events_sent = 0;
-    let mut events_iter = events.into_iter();
-    if let Some(e) = events_iter.next() {
-        ds_to.heartbeat(bucket_to.id.as_str(), e, 0.0).unwrap();
-        events_sent += 1;
-    }
-
-    const BATCH_SIZE: usize = 5000;
-    if BATCH_SIZE == 1 {
-        // TODO: Don't print progress messages if not in a suitable terminal environment (such as a
-        // pipe or systemd journal)
-        for event in events_iter {
-            print!("{} ({}/{})\r", &event.timestamp, events_sent, events_total);
-            ds_to.heartbeat(bucket_to.id.as_str(), event, 0.0).unwrap();
-            events_sent += 1;
+    let mut interrupted = false;
+    let mut truncated = false;
+    let insert_rate = sync_spec.insert_rate.as_ref().filter(|_| ds_to.is_server());
+    let mut insert_secs = 0.0;
+    let mut insert_counts = EventCounts::default();
+    let inserted = for_each_batch(&mut insert_counts, &mut |mut events| {
+        let inserting = Instant::now();
+        // The batches before were committed, so it's safe to stop here
+        if sync_spec.cancelled() {
+            interrupted = true;
+            return Ok(false);
         }
-    } else {
-        let mut batch_events = Vec::with_capacity(BATCH_SIZE);
-        for e in events_iter {
-            print!("{} ({}/{})\r", e.timestamp, events_sent, events_total);
-            batch_events.push(e);
-            events_sent += 1;
-            if batch_events.len() >= BATCH_SIZE {
-                ds_to
-                    .insert_events(bucket_to.id.as_str(), batch_events.clone())
-                    .unwrap();
-                batch_events.clear();
+        if events.len() > events_limit - events_sent {
+            truncated = true;
+            events.truncate(events_limit - events_sent);
+        }
+        if events.is_empty() {
+            return Ok(false);
+        }
+        // NOTE: First event needs to be inserted with heartbeat, to ensure appropriate
+        // merging/updating of pulsed events.
+        // If the bucket has a pulsetime, all events are, so they are merged like the watcher did
+        let heartbeats = match (pulsetime, events_sent) {
+            (Some(_), _) => events.len(),
+            (None, 0) => 1,
+            (None, _) => 0,
+        };
+        let last = events.last().map(|e| e.timestamp);
+        let mut events_iter = events.into_iter();
+        for e in events_iter.by_ref().take(heartbeats) {
+            if let Some(limiter) = insert_rate {
+                limiter.acquire(1);
             }
+            ds_to
+                .heartbeat_retrying(&bucket_to.id, e, pulsetime.unwrap_or(0.0), &sync_spec.retry)
+                .map_err(SyncError::Access)?;
+            events_sent += 1;
         }
 
-        if !batch_events.is_empty() {
+        // Bulk insert the remaining events, a batch per transaction
+        let events_rest: Vec<Event> = events_iter.collect();
+        if !events_rest.is_empty() {
+            let count = events_rest.len();
+            if let Some(limiter) = insert_rate {
+                limiter.acquire(count);
+            }
+            // Not retried, as the events are inserted twice if the server inserted them but the
+            // response was lost
             ds_to
-                .insert_events(bucket_to.id.as_str(), batch_events)
-                .unwrap();
+                .insert_events(bucket_to.id.as_str(), events_rest)
+                .map_err(SyncError::Access)?;
+            events_sent += count;
+        }
+
+        // Only advanced once the batch is committed
+        if let (Some(checkpoints), Some(last)) = (&checkpoints, last) {
+            checkpoints.advance(&state_key, last)?;
+        }
+        progress.report(SyncProgressEvent::EventsInserted {
+            bucket_id: bucket_to.id.clone(),
+            inserted: events_sent,
+            total: events_estimate.max(events_sent),
+        });
+        insert_secs += inserting.elapsed().as_secs_f64();
+        Ok(!truncated)
+    });
+    timings.get_events_secs += insert_counts.fetch_secs;
+    timings.insert_secs += insert_secs;
+    inserted?;
+    log_counts(&insert_counts);
+    if truncated {
+        warn!(
+            remote = remote, bucket = bucket, count = events_sent;
+            "{log_prefix}  ! Only synced {events_sent} new events, the rest are synced on the \
+            next passes"
+        );
+    }
+
+    let counting = Instant::now();
+    let eventcount_window_new = count_window()?;
+    timings.count_secs += counting.elapsed().as_secs_f64();
+    if eventcount_window_new < eventcount_window_old {
+        error!(
+            remote = remote, bucket = bucket, count = eventcount_window_new;
+            "{log_prefix}  ! Events of {} dropped from {eventcount_window_old} to \
+            {eventcount_window_new} while syncing, something else is deleting events!",
+            bucket_to.id
+        );
+        if sync_spec.strict {
+            return Err(SyncError::CountDecreased {
+                bucket: bucket_to.id.clone(),
+                before: eventcount_window_old,
+                after: eventcount_window_new,
+            });
+        }
+    }
+    let new_events_count = (eventcount_window_new - eventcount_window_old).max(0);
+    if interrupted || truncated {
+        // Neither the checkpoint nor the state are updated, so the next pass continues here
+        if interrupted {
+            info!(
+                remote = remote, bucket = bucket, count = new_events_count;
+                "{log_prefix}  ! Interrupted after syncing {new_events_count} new events"
+            );
+        } else {
+            info!(
+                remote = remote, bucket = bucket, count = new_events_count;
+                "{log_prefix}  = Synced {new_events_count} new events, more are left"
+            );
         }
+        return Ok(new_events_count as usize);
     }
 
-    let eventcount_to_new = ds_to.get_event_count(bucket_to.id.as_str()).unwrap();
-    let new_events_count = eventcount_to_new - eventcount_to_old;
-    assert!(new_events_count >= 0);
+    if let Some(checkpoints) = &checkpoints {
+        checkpoints.finish(&state_key)?;
+    }
+    // Fewer events than sent are new if heartbeats were merged
+    debug!(
+        remote = remote, bucket = bucket, count = new_events_count;
+        "{log_prefix}   Sent {events_sent} events, {new_events_count} were inserted"
+    );
     if new_events_count > 0 {
-        info!("  = Synced {} new events", new_events_count);
+        info!(
+            remote = remote, bucket = bucket, count = new_events_count;
+            "{log_prefix}  = Synced {new_events_count} new events"
+        );
     } else {
-        info!("  ✓ Already up to date!");
+        info!(remote = remote, bucket = bucket, count = 0; "{log_prefix}  ✓ Already up to date!");
     }
+
+    if let Some(end) = end_from {
+        let eventcount = count_resumed(end, timings)?;
+        state.buckets.insert(
+            state_key,
+            BucketSyncState {
+                end,
+                eventcount,
+                flushed: if new_events_count > 0 {
+                    Some(Utc::now())
+                } else {
+                    flushed
+                },
+            },
+        );
+    }
+    Ok(new_events_count as usize)
+}
+
+/// Returns where syncing into `bucket_to` resumes, None to sync from the beginning
+pub(crate) fn resume_point(bucket_to: &Bucket, sync_spec: &SyncSpec) -> Option<DateTime<Utc>> {
+    resume_after(bucket_to.metadata.end, sync_spec)
+}
+
+/// Returns where syncing resumes if the events until `end` were synced
+fn resume_after(end: Option<DateTime<Utc>>, sync_spec: &SyncSpec) -> Option<DateTime<Utc>> {
+    // Resume the clock skew tolerance before the last event in the destination, if one is set
+    // for devices whose clocks aren't in sync. The start of get_events is inclusive, so resume a
+    // microsecond later, or the last event is read again on every pass.
+    let resume_time =
+        end.map(|end| end - sync_spec.clock_skew_tolerance + Duration::microseconds(1));
+    // Never sync events from before the start of the time range
+    match (resume_time, sync_spec.start) {
+        (Some(resume_time), Some(start)) => Some(resume_time.max(start)),
+        (resume_time, start) => resume_time.or(start),
+    }
+}
+
+/// Returns a key identifying an event by its contents, for deduplication
+///
+/// Event IDs are unset when syncing, as they differ between datastores, so the same event is
+/// identified by its timestamp, duration and data instead. Timestamps and durations are rounded
+/// to milliseconds, as they may lose some precision when passing through aw-server.
+pub fn event_dedup_key(event: &Event) -> String {
+    // serde_json::Map keeps its keys sorted, so equal data always serializes the same
+    format!(
+        "{}|{}|{}",
+        event.timestamp.timestamp_millis(),
+        event.duration.num_milliseconds(),
+        serde_json::Value::Object(event.data.clone())
+    )
 }
 
 fn log_buckets(ds: &dyn AccessMethod) {
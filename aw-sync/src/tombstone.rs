@@ -0,0 +1,163 @@
+//! Propagation of bucket deletions through tombstones
+//!
+//! When pushing, every bucket in the staging datastore that no longer exists in the source is
+//! deleted from the staging datastore, and a tombstone is recorded in its key-value store in its
+//! place. When pulling, every tombstone found in a remote causes the corresponding
+//! `-synced-from-*` bucket (or whatever the bucket name template names it) to be deleted from
+//! the destination.
+//!
+//! A bucket that exists always takes precedence over a tombstone for it: pushing a bucket removes
+//! its tombstone, and a tombstone is ignored when pulling if the remote also contains the bucket.
+//! So if a bucket is deleted and then re-created with the same ID, the re-created bucket is synced
+//! as usual, no matter whether the deletion was propagated to other devices before the
+//! re-creation was pushed or not. A device which pulls the tombstone before the re-created bucket
+//! will delete its copy, and sync it again from scratch once the re-created bucket arrives.
+
+use std::collections::HashMap;
+
+use aw_datastore::{Datastore, DatastoreError};
+
+use crate::accessmethod::AccessMethod;
+use crate::error::SyncError;
+use crate::state::SyncState;
+use crate::sync::{bucket_origin, pulled_bucket_id, SyncSpec};
+
+/// Prefix of the keys tombstones are stored under, followed by the deleted bucket ID
+const TOMBSTONE_PREFIX: &str = "aw-sync.tombstone.";
+
+/// Deletes buckets from the staging datastore which no longer exist in the source, and records a
+/// tombstone for each of them
+///
+/// Returns the IDs of the deleted buckets. If `sync_spec.dry_run` is set, nothing is written and
+/// the IDs of the buckets which would be deleted are returned.
+pub fn record_deletions(
+    ds_from: &dyn AccessMethod,
+    ds_staging: &Datastore,
+    sync_spec: &SyncSpec,
+    state: &mut SyncState,
+) -> Result<Vec<String>, SyncError> {
+    let buckets_from = ds_from
+        .get_buckets()
+        .map_err(DatastoreError::InternalError)?;
+    let buckets_staging = ds_staging.get_buckets()?;
+
+    // Buckets which exist again take precedence over their tombstones
+    if !sync_spec.dry_run {
+        for key in ds_staging.get_keys_starting(&format!("{TOMBSTONE_PREFIX}%"))? {
+            let bucket_id = &key[TOMBSTONE_PREFIX.len()..];
+            if buckets_from.contains_key(bucket_id) {
+                info!(
+                    " ~ Removing tombstone for re-created bucket '{}'",
+                    bucket_id
+                );
+                ds_staging.delete_key_value(&key)?;
+            }
+        }
+    }
+
+    let mut deleted = Vec::new();
+    for bucket in buckets_staging.values() {
+        if buckets_from.contains_key(&bucket.id)
+            || !sync_spec.includes_bucket(&bucket.id)
+            || !sync_spec.includes_bucket_type(&bucket._type)
+        {
+            continue;
+        }
+        if sync_spec.dry_run {
+            info!(" - Would delete staged bucket '{}'", bucket.id);
+        } else {
+            info!(" - Deleting staged bucket '{}'", bucket.id);
+            // Pushed buckets have their device ID recorded, which pulls are keyed by
+            // The origin lets devices with another bucket name template find their copy
+            let tombstone = serde_json::json!({
+                "synced_id": pulled_bucket_id(bucket, None, &sync_spec.bucket_names),
+                "origin": bucket_origin(bucket, None),
+            });
+            ds_staging.insert_key_value(
+                &format!("{TOMBSTONE_PREFIX}{}", bucket.id),
+                &tombstone.to_string(),
+            )?;
+            ds_staging.delete_bucket(&bucket.id)?;
+            state.buckets.remove(&bucket.id);
+        }
+        deleted.push(bucket.id.clone());
+    }
+    if !sync_spec.dry_run {
+        ds_staging.force_commit()?;
+    }
+    Ok(deleted)
+}
+
+/// Deletes the buckets in `ds_to` which were synced from buckets with a tombstone in the remote
+///
+/// Returns the IDs of the deleted buckets. If `sync_spec.dry_run` is set, nothing is deleted and
+/// the IDs of the buckets which would be deleted are returned.
+pub fn apply_tombstones(
+    ds_remote: &Datastore,
+    ds_to: &dyn AccessMethod,
+    sync_spec: &SyncSpec,
+    state: &mut SyncState,
+    log_prefix: &str,
+) -> Result<Vec<String>, SyncError> {
+    let buckets_remote = ds_remote.get_buckets()?;
+    let tombstones = read_tombstones(ds_remote, sync_spec)?;
+
+    let mut deleted = Vec::new();
+    for (bucket_id, synced_id) in tombstones {
+        if buckets_remote.contains_key(&bucket_id) || !sync_spec.includes_bucket(&bucket_id) {
+            continue;
+        }
+        match ds_to.get_bucket(&synced_id) {
+            Ok(bucket) if !sync_spec.includes_bucket_type(&bucket._type) => continue,
+            Ok(_) => (),
+            Err(DatastoreError::NoSuchBucket(_)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        if sync_spec.dry_run {
+            info!("{log_prefix} - Would delete bucket '{}'", synced_id);
+        } else {
+            info!("{log_prefix} - Deleting bucket '{}'", synced_id);
+            ds_to.delete_bucket(&synced_id)?;
+            // Merged buckets have a state for each of their origins
+            let merged_prefix = format!("{synced_id}@");
+            state
+                .buckets
+                .retain(|key, _| *key != synced_id && !key.starts_with(&merged_prefix));
+        }
+        deleted.push(synced_id);
+    }
+    Ok(deleted)
+}
+
+/// Returns the tombstones in a staging datastore, as a map from the deleted bucket ID to the ID
+/// it was synced to
+///
+/// Origins are resolved through [`SyncSpec::origin_aliases`] like when pulling, so tombstones of
+/// aliased origins match the buckets they were merged into. Tombstones recorded by older versions
+/// only have the synced ID of the device which pushed them, so they only match if it used the
+/// same bucket name template and its origin isn't aliased.
+fn read_tombstones(
+    ds: &Datastore,
+    sync_spec: &SyncSpec,
+) -> Result<HashMap<String, String>, SyncError> {
+    let mut tombstones = HashMap::new();
+    for key in ds.get_keys_starting(&format!("{TOMBSTONE_PREFIX}%"))? {
+        let kv = ds.get_key_value(&key)?;
+        match kv.value.get("synced_id").and_then(|id| id.as_str()) {
+            Some(synced_id) => {
+                let bucket_id = key[TOMBSTONE_PREFIX.len()..].to_string();
+                let synced_id = match kv.value.get("origin").and_then(|o| o.as_str()) {
+                    Some(origin) => {
+                        let origin = sync_spec.aliased_origin(origin);
+                        let names = &sync_spec.bucket_names;
+                        names.format(names.source_id(&bucket_id, origin), origin)
+                    }
+                    None => synced_id.to_string(),
+                };
+                tombstones.insert(bucket_id, synced_id);
+            }
+            None => warn!("Ignoring invalid tombstone '{}'", key),
+        }
+    }
+    Ok(tombstones)
+}
@@ -0,0 +1,145 @@
+//! Verification that synced buckets have all the events of their source buckets
+//!
+//! Compares event counts, for the buckets pulled from every remote in the sync folder and for
+//! the local buckets pushed to the staging datastore. Counts can differ a bit without events
+//! being lost, as duplicate events are deduplicated and excluded events are never synced.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use aw_models::Bucket;
+
+use crate::accessmethod::AccessMethod;
+use crate::crypt::open_remote;
+use crate::error::SyncError;
+use crate::naming::BucketNameTemplate;
+use crate::sync::{find_remotes_nonlocal, local_remote_dbfile, pulled_bucket_id, remote_device_id};
+use crate::version::filter_compatible_remotes;
+
+/// Event counts of a source bucket and the bucket it is synced to
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BucketVerification {
+    /// Device ID of the remote the bucket is pulled from, or the local device ID if pushed
+    pub remote: String,
+    pub source_bucket: String,
+    pub synced_bucket: String,
+    pub source_count: i64,
+    /// None if the synced bucket doesn't exist
+    pub synced_count: Option<i64>,
+    /// Number of events in the source bucket missing from the synced bucket
+    pub delta: i64,
+}
+
+impl BucketVerification {
+    /// Returns true if the counts differ by more than `tolerance` events
+    pub fn is_mismatch(&self, tolerance: i64) -> bool {
+        self.synced_count.is_none() || self.delta.abs() > tolerance
+    }
+}
+
+fn verify_bucket(
+    remote: &str,
+    bucket: &Bucket,
+    ds_from: &dyn AccessMethod,
+    ds_to: &dyn AccessMethod,
+    synced_bucket: String,
+) -> Result<BucketVerification, SyncError> {
+    let source_count = ds_from
+        .get_event_count(&bucket.id)
+        .map_err(SyncError::Access)?;
+    let synced_count = match ds_to.get_bucket(&synced_bucket) {
+        Ok(_) => Some(
+            ds_to
+                .get_event_count(&synced_bucket)
+                .map_err(SyncError::Access)?,
+        ),
+        Err(_) => None,
+    };
+    Ok(BucketVerification {
+        remote: remote.to_string(),
+        source_bucket: bucket.id.clone(),
+        synced_bucket,
+        source_count,
+        synced_count,
+        delta: source_count - synced_count.unwrap_or(0),
+    })
+}
+
+/// Compares the event counts of all synced buckets with their source buckets
+///
+/// `local` is the local aw-server (or a datastore standing in for it), with the device ID
+/// `device_id`. If `buckets` is set, only buckets with those source bucket IDs are verified.
+/// Encrypted datastores are decrypted with `passphrase`, and pulled buckets are expected to be
+/// named by `names`.
+pub fn verify(
+    sync_directory: &Path,
+    local: &dyn AccessMethod,
+    device_id: &str,
+    buckets: Option<&[String]>,
+    instance: Option<&str>,
+    db_filename: Option<&str>,
+    passphrase: Option<&str>,
+    names: &BucketNameTemplate,
+) -> Result<Vec<BucketVerification>, SyncError> {
+    let included = |bucket: &Bucket| buckets.map_or(true, |b| b.contains(&bucket.id));
+    let mut results = Vec::new();
+
+    // Pulled buckets
+    let remote_dbfiles =
+        filter_compatible_remotes(find_remotes_nonlocal(sync_directory, device_id, None)?);
+    for path in remote_dbfiles {
+        let remote = remote_device_id(sync_directory, &path);
+        let (ds, _plaintext) = match open_remote(&path, passphrase) {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("Skipping remote {}: {e}", path.display());
+                continue;
+            }
+        };
+        let mut remote_buckets: Vec<Bucket> = ds
+            .get_buckets()
+            .map_err(SyncError::Datastore)?
+            .into_values()
+            .filter(|b| included(b))
+            .collect();
+        remote_buckets.sort_by(|a, b| a.id.cmp(&b.id));
+        for bucket in remote_buckets {
+            let synced_bucket = pulled_bucket_id(&bucket, Some(&remote), names);
+            results.push(verify_bucket(&remote, &bucket, &ds, local, synced_bucket)?);
+        }
+        ds.close();
+    }
+
+    // Pushed buckets
+    let dbfile = local_remote_dbfile(sync_directory, device_id, instance, db_filename)?;
+    if !dbfile.exists() {
+        warn!(
+            "Staging datastore {} doesn't exist, nothing was pushed yet",
+            dbfile.display()
+        );
+        return Ok(results);
+    }
+    let (ds_localremote, _plaintext) = open_remote(&dbfile, passphrase)?;
+    let mut local_buckets: Vec<Bucket> = local
+        .get_buckets()
+        .map_err(SyncError::Access)?
+        .into_values()
+        // Pulled buckets are never pushed
+        .filter(|b| !names.is_pulled(&b.id) && included(b))
+        .collect();
+    local_buckets.sort_by(|a, b| a.id.cmp(&b.id));
+    for bucket in local_buckets {
+        let synced_bucket = bucket.id.clone();
+        results.push(verify_bucket(
+            device_id,
+            &bucket,
+            local,
+            &ds_localremote,
+            synced_bucket,
+        )?);
+    }
+    ds_localremote.close();
+
+    Ok(results)
+}
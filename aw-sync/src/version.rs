@@ -0,0 +1,168 @@
+//! Version markers for staging datastores, and the version of aw-server required
+//!
+//! Each staging datastore gets a `.version.json` sidecar file when pushed to, so that remotes
+//! written by a newer, possibly incompatible, version of aw-sync can be detected and skipped
+//! without opening them. The version is also stored in the datastore itself, at
+//! [`SCHEMA_VERSION_KEY`], as the sidecar file is lost when only the `.db` files of the sync
+//! folder are synced, such as by sync tools set up to ignore other files.
+//!
+//! Older versions of aw-server lack APIs aw-sync relies on, so the version reported by the local
+//! aw-server is checked before syncing with it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use aw_datastore::{Datastore, DatastoreError};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{io_context, SyncError};
+
+/// Version of the staging datastore format written by this version of aw-sync
+///
+/// Bump it when older versions of aw-sync would misread the staging datastores written by this
+/// one, such as when the meaning of bucket or event data changes. Additions older versions
+/// ignore, such as new keys in the key-value store, don't need a bump. Older versions skip
+/// remotes with a newer version, so after a bump they stop pulling from upgraded devices until
+/// upgraded themselves.
+pub const SYNC_VERSION: u32 = 1;
+
+/// Key of the version of the staging datastore format in the key-value store of the datastore
+pub const SCHEMA_VERSION_KEY: &str = "$aw.sync.schema_version";
+
+/// Oldest version of aw-server supported, as (major, minor, patch)
+pub const MIN_SERVER_VERSION: (u32, u32, u32) = (0, 12, 0);
+
+#[derive(Serialize, Deserialize)]
+struct VersionMarker {
+    version: u32,
+}
+
+pub(crate) fn version_path(dbfile: &Path) -> PathBuf {
+    dbfile.with_extension("version.json")
+}
+
+/// Writes the version marker for a staging datastore
+///
+/// Markers which are of the current version already are left as-is, so that the sync folder
+/// doesn't get a changed file for every push.
+pub fn write_version_marker(dbfile: &Path) -> Result<(), SyncError> {
+    if matches!(read_version_marker(dbfile), Ok(Some(SYNC_VERSION))) {
+        return Ok(());
+    }
+    let path = version_path(dbfile);
+    let marker = VersionMarker {
+        version: SYNC_VERSION,
+    };
+    let content = serde_json::to_string(&marker)?;
+    fs::write(&path, content).map_err(|e| io_context(e, "write version marker", &path))
+}
+
+/// Reads the version marker of a staging datastore
+///
+/// Returns None if there is no marker, as is the case for datastores written before markers
+/// were introduced.
+pub fn read_version_marker(dbfile: &Path) -> Result<Option<u32>, SyncError> {
+    let path = version_path(dbfile);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content =
+        fs::read_to_string(&path).map_err(|e| io_context(e, "read version marker", &path))?;
+    let marker: VersionMarker = serde_json::from_str(&content)?;
+    Ok(Some(marker.version))
+}
+
+/// Checks that a staging datastore wasn't written by a newer version of aw-sync than this one
+pub fn check_version_compatible(dbfile: &Path) -> Result<(), SyncError> {
+    match read_version_marker(dbfile)? {
+        Some(found) if found > SYNC_VERSION => Err(SyncError::Incompatible {
+            found,
+            expected: SYNC_VERSION,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Stamps a staging datastore with [`SYNC_VERSION`]
+pub fn write_schema_version(ds: &Datastore) -> Result<(), SyncError> {
+    ds.insert_key_value(SCHEMA_VERSION_KEY, &SYNC_VERSION.to_string())?;
+    Ok(())
+}
+
+/// Reads the version a staging datastore was stamped with
+///
+/// Returns None if it wasn't stamped, as is the case for datastores last pushed to before the
+/// version was stored in them.
+pub fn read_schema_version(ds: &Datastore) -> Result<Option<u32>, SyncError> {
+    let kv = match ds.get_key_value(SCHEMA_VERSION_KEY) {
+        Ok(kv) => kv,
+        Err(DatastoreError::NoSuchKey(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match kv.value.as_u64().and_then(|v| u32::try_from(v).ok()) {
+        Some(version) => Ok(Some(version)),
+        None => {
+            warn!("Ignoring invalid {SCHEMA_VERSION_KEY}: {}", kv.value);
+            Ok(None)
+        }
+    }
+}
+
+/// Checks that an opened staging datastore wasn't written by a newer version of aw-sync
+///
+/// Like [`check_version_compatible`], but also catches remotes whose marker file was lost.
+pub fn check_schema_version(ds: &Datastore) -> Result<(), SyncError> {
+    match read_schema_version(ds)? {
+        Some(found) if found > SYNC_VERSION => Err(SyncError::Incompatible {
+            found,
+            expected: SYNC_VERSION,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Filters out remote dbs written by a newer version of aw-sync than this one
+pub fn filter_compatible_remotes(dbfiles: Vec<PathBuf>) -> Vec<PathBuf> {
+    dbfiles
+        .into_iter()
+        .filter(|dbfile| match check_version_compatible(dbfile) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Skipping remote {}: {e}", dbfile.display());
+                false
+            }
+        })
+        .collect()
+}
+
+/// Parses the version reported by aw-server, such as `v0.12.3 (rust)` or `v0.12.2.dev+a1b2c3`
+///
+/// Missing minor and patch versions are taken to be 0.
+pub fn parse_server_version(version: &str) -> Option<(u32, u32, u32)> {
+    let version = version.trim().trim_start_matches('v');
+    let version = version
+        .split(|c: char| c.is_whitespace() || c == '+')
+        .next()?;
+    let mut parts = version.split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let mut next = || parts.next().and_then(|part| part.parse().ok()).unwrap_or(0);
+    Some((major, next(), next()))
+}
+
+/// Checks that the version reported by aw-server is at least [`MIN_SERVER_VERSION`]
+///
+/// Versions which can't be parsed (such as of development builds) are assumed to be supported.
+pub fn check_server_version(version: &str) -> Result<(), SyncError> {
+    let format = |(major, minor, patch): (u32, u32, u32)| format!("v{major}.{minor}.{patch}");
+    match parse_server_version(version) {
+        Some(found) if found < MIN_SERVER_VERSION => Err(SyncError::IncompatibleServer {
+            found: version.to_string(),
+            minimum: format(MIN_SERVER_VERSION),
+        }),
+        Some(_) => Ok(()),
+        None => {
+            warn!("Couldn't parse the aw-server version '{version}', assuming it's supported");
+            Ok(())
+        }
+    }
+}
@@ -4,14 +4,35 @@ extern crate aw_sync;
 
 #[cfg(test)]
 mod sync_tests {
+    use std::cell::{Cell, RefCell};
     use std::collections::HashMap;
-    use std::path::Path;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::sync::Mutex;
 
     use chrono::{DateTime, Duration, Utc};
+    use regex::Regex;
 
     use aw_datastore::{Datastore, DatastoreError};
-    use aw_models::{Bucket, Event};
-    use aw_sync::{create_datastore, AccessMethod, SyncSpec};
+    use aw_models::{Bucket, BucketsExport, Event};
+    use aw_sync::{
+        apply_tombstones, audit_buckets, check_schema_version, check_server_version,
+        check_sync_directory, check_version_compatible, create_datastore, download_remotes, export,
+        extended_length_path, filter_compatible_remotes, find_remotes, find_stale_remotes,
+        is_encrypted, list_remotes, migrate_synced_bucket_ids, open_remote, origin_bucket_id,
+        parse_metrics, parse_server_version, prune_remotes, pseudonym, read_schema_version,
+        record_deletions, repair, repair_naming, run_post_hook, unsynced_buckets, upload_staging,
+        validate_device_id, verify, verify_remote_integrity, with_retry, with_retry_if,
+        write_metrics, write_version_marker, AccessMethod, AuditIssue, BucketNameTemplate,
+        BucketSyncState, BucketVerification, Check, CheckStatus, Checkpoints, EventRuleConfig,
+        FolderStore, HttpRemote, JsonExport, NamingRepair, NoProgress, RateLimiter, Redaction,
+        RemoteStoreConfig, RetryPolicy, SyncConfig, SyncError, SyncLock, SyncProgressEvent,
+        SyncRunSummary, SyncSpec, SyncState, MAX_PATH, MIN_SERVER_VERSION, REDACTED, REVISION_KEY,
+        SCHEMA_VERSION_KEY, SYNC_ENABLED_KEY, SYNC_VERSION,
+    };
 
     struct TestState {
         ds_src: Datastore,
@@ -25,6 +46,10 @@ mod sync_tests {
         }
     }
 
+    fn create_file_datastore(path: &Path) -> Datastore {
+        Datastore::new(path.to_str().unwrap().to_string(), false)
+    }
+
     fn create_bucket(ds: &Datastore, n: i32) -> String {
         // Create a bucket
         let bucket_id = format!("bucket-{n}");
@@ -98,21 +123,24 @@ mod sync_tests {
 
     #[test]
     fn test_buckets_created() {
-        // TODO: Split up this test
-        let state = init_teststate();
-        create_bucket(&state.ds_src, 0);
+        let ds_src = MockDatastore::default();
+        ds_src.add_bucket("bucket-0", "device-0", Vec::new());
+        let ds_dest = MockDatastore::default();
 
         aw_sync::sync_datastores(
-            &state.ds_src,
-            &state.ds_dest,
+            &ds_src,
+            &ds_dest,
             false,
             None,
             &SyncSpec::default(),
-        );
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
 
-        let buckets_src: HashMap<String, Bucket> = state.ds_src.get_buckets().unwrap();
-        let buckets_dest: HashMap<String, Bucket> = state.ds_dest.get_buckets().unwrap();
-        assert!(buckets_src.len() == buckets_dest.len());
+        let buckets_dest = ds_dest.get_buckets().unwrap();
+        assert_eq!(buckets_dest.len(), 1);
+        assert!(buckets_dest.contains_key("bucket-0-synced-from-device-0"));
     }
 
     fn check_synced_buckets_equal_to_src(all_buckets_map: &HashMap<String, (&Datastore, Bucket)>) {
@@ -149,7 +177,10 @@ mod sync_tests {
             false,
             None,
             &SyncSpec::default(),
-        );
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
 
         let all_datastores: Vec<&Datastore> = [&state.ds_src, &state.ds_dest].to_vec();
         let all_buckets_map = get_all_buckets_map(all_datastores);
@@ -168,7 +199,10 @@ mod sync_tests {
             false,
             None,
             &SyncSpec::default(),
-        );
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
 
         // Check again that new events were indeed synced
         check_synced_buckets_equal_to_src(&all_buckets_map);
@@ -176,90 +210,4674 @@ mod sync_tests {
 
     #[test]
     fn test_events() {
+        let ds_src = MockDatastore::default();
+        ds_src.add_bucket("bucket-0", "device-0", mock_events(10));
+        let ds_dest = MockDatastore::default();
+        let sync = || {
+            aw_sync::sync_datastores(
+                &ds_src,
+                &ds_dest,
+                false,
+                None,
+                &SyncSpec::default(),
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap()
+        };
+        let events = |ds: &MockDatastore, bucket_id: &str| {
+            AccessMethod::get_events(ds, bucket_id, None, None, None).unwrap()
+        };
+
+        // The synced bucket is identical to the source bucket
+        sync();
+        let synced_id = "bucket-0-synced-from-device-0";
+        assert_eq!(events(&ds_dest, synced_id), events(&ds_src, "bucket-0"));
+
+        // Also after adding some more events
+        let more_events = mock_events_at(Utc::now() - Duration::minutes(30), 10);
+        AccessMethod::insert_events(&ds_src, "bucket-0", more_events).unwrap();
+        sync();
+        assert_eq!(events(&ds_dest, synced_id).len(), 20);
+        assert_eq!(events(&ds_dest, synced_id), events(&ds_src, "bucket-0"));
+    }
+
+    /// In-memory datastore for testing sync logic which doesn't depend on the SQLite datastore
+    #[derive(Debug, Default)]
+    struct MockDatastore {
+        buckets: RefCell<HashMap<String, Bucket>>,
+        /// Events of each bucket, oldest first
+        events: RefCell<HashMap<String, Vec<Event>>>,
+        /// If true, creating a bucket fails as if another process created it at the same time
+        racing_create: bool,
+    }
+
+    impl MockDatastore {
+        fn add_bucket(&self, bucket_id: &str, hostname: &str, events: Vec<Event>) {
+            let bucket: Bucket = serde_json::from_value(serde_json::json!({
+                "id": bucket_id,
+                "type": "test",
+                "hostname": hostname,
+                "client": "test"
+            }))
+            .unwrap();
+            self.buckets
+                .borrow_mut()
+                .insert(bucket_id.to_string(), bucket);
+            self.events
+                .borrow_mut()
+                .insert(bucket_id.to_string(), events);
+        }
+
+        fn no_such_bucket(bucket_id: &str) -> DatastoreError {
+            DatastoreError::NoSuchBucket(bucket_id.to_string())
+        }
+
+        /// Returns the bucket with the time range of its events as metadata, like aw-server
+        fn with_metadata(&self, bucket: &Bucket) -> Bucket {
+            let mut bucket = bucket.clone();
+            if let Some(events) = self.events.borrow().get(&bucket.id) {
+                bucket.metadata.start = events.iter().map(|e| e.timestamp).min();
+                bucket.metadata.end = events.iter().map(|e| e.calculate_endtime()).max();
+            }
+            bucket
+        }
+    }
+
+    impl AccessMethod for MockDatastore {
+        fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
+            Ok(self
+                .buckets
+                .borrow()
+                .iter()
+                .map(|(id, bucket)| (id.clone(), self.with_metadata(bucket)))
+                .collect())
+        }
+        fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
+            self.buckets
+                .borrow()
+                .get(bucket_id)
+                .map(|bucket| self.with_metadata(bucket))
+                .ok_or_else(|| MockDatastore::no_such_bucket(bucket_id))
+        }
+        fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+            let exists = self.buckets.borrow().contains_key(&bucket.id);
+            if !exists {
+                self.buckets
+                    .borrow_mut()
+                    .insert(bucket.id.clone(), bucket.clone());
+                self.events
+                    .borrow_mut()
+                    .insert(bucket.id.clone(), Vec::new());
+            }
+            if exists || self.racing_create {
+                return Err(DatastoreError::BucketAlreadyExists(bucket.id.clone()));
+            }
+            Ok(())
+        }
+        fn delete_bucket(&self, bucket_id: &str) -> Result<(), DatastoreError> {
+            self.events.borrow_mut().remove(bucket_id);
+            self.buckets
+                .borrow_mut()
+                .remove(bucket_id)
+                .map(|_| ())
+                .ok_or_else(|| MockDatastore::no_such_bucket(bucket_id))
+        }
+        fn update_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+            let mut buckets = self.buckets.borrow_mut();
+            let existing = buckets
+                .get_mut(&bucket.id)
+                .ok_or_else(|| MockDatastore::no_such_bucket(&bucket.id))?;
+            existing.data = bucket.data.clone();
+            Ok(())
+        }
+        fn get_events(
+            &self,
+            bucket_id: &str,
+            start: Option<DateTime<Utc>>,
+            end: Option<DateTime<Utc>>,
+            limit: Option<u64>,
+        ) -> Result<Vec<Event>, String> {
+            let events = self.events.borrow();
+            let events = events.get(bucket_id).ok_or("no such bucket")?;
+            // Newest first, like the datastore
+            Ok(events
+                .iter()
+                .rev()
+                .filter(|e| start.map_or(true, |start| e.calculate_endtime() >= start))
+                .filter(|e| end.map_or(true, |end| e.timestamp <= end))
+                .take(limit.map_or(usize::MAX, |l| l as usize))
+                .cloned()
+                .collect())
+        }
+        fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String> {
+            let mut all_events = self.events.borrow_mut();
+            let bucket_events = all_events.get_mut(bucket_id).ok_or("no such bucket")?;
+            for mut event in events {
+                // Events with an ID replace the event with that ID
+                match event.id {
+                    Some(id) => {
+                        let existing = bucket_events.iter_mut().find(|e| e.id == Some(id));
+                        *existing.ok_or("no such event")? = event;
+                    }
+                    None => {
+                        // Not the length, as events may have been deleted
+                        let last_id = bucket_events.iter().filter_map(|e| e.id).max();
+                        event.id = Some(last_id.unwrap_or(0) + 1);
+                        bucket_events.push(event);
+                    }
+                }
+            }
+            bucket_events.sort_by_key(|e| e.timestamp);
+            Ok(())
+        }
+        fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String> {
+            let mut all_events = self.events.borrow_mut();
+            let bucket_events = all_events.get_mut(bucket_id).ok_or("no such bucket")?;
+            bucket_events.retain(|e| !e.id.is_some_and(|id| event_ids.contains(&id)));
+            Ok(())
+        }
+        fn get_event_count_range(
+            &self,
+            bucket_id: &str,
+            start: Option<DateTime<Utc>>,
+            end: Option<DateTime<Utc>>,
+        ) -> Result<i64, String> {
+            Ok(self.get_events(bucket_id, start, end, None)?.len() as i64)
+        }
+        fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String> {
+            let merged = {
+                let mut all_events = self.events.borrow_mut();
+                let bucket_events = all_events.get_mut(bucket_id).ok_or("no such bucket")?;
+                match bucket_events.last_mut() {
+                    Some(last)
+                        if last.data == event.data
+                            && last.timestamp <= event.timestamp
+                            && event.timestamp
+                                <= last.calculate_endtime()
+                                    + Duration::milliseconds((duration * 1000.0) as i64) =>
+                    {
+                        let end = last.calculate_endtime().max(event.calculate_endtime());
+                        last.duration = end - last.timestamp;
+                        true
+                    }
+                    _ => false,
+                }
+            };
+            if !merged {
+                self.insert_events(bucket_id, vec![event])?;
+            }
+            Ok(())
+        }
+        fn close(&self) {}
+    }
+
+    fn mock_events(n: i64) -> Vec<Event> {
+        mock_events_at(Utc::now() - Duration::hours(1), n)
+    }
+
+    /// Returns `n` contiguous events of a second each, starting at `start`
+    fn mock_events_at(start: DateTime<Utc>, n: i64) -> Vec<Event> {
+        (0..n)
+            .map(|i| Event {
+                id: None,
+                timestamp: start + Duration::seconds(i),
+                duration: Duration::seconds(1),
+                data: serde_json::from_value(serde_json::json!({ "test": i })).unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_duplicate_bucket_ids() {
+        // Remotes with buckets of the same ID, from different devices
+        let remotes: Vec<MockDatastore> = (0..2).map(|_| MockDatastore::default()).collect();
+        for (n, ds) in remotes.iter().enumerate() {
+            ds.add_bucket("bucket", &format!("device-{n}"), mock_events(3));
+        }
+
+        let ds_dest = MockDatastore::default();
+        for ds in &remotes {
+            aw_sync::sync_datastores(
+                ds,
+                &ds_dest,
+                false,
+                None,
+                &SyncSpec::default(),
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+        }
+
+        // Each is synced to a bucket of its own
+        let buckets_dest = AccessMethod::get_buckets(&ds_dest).unwrap();
+        assert_eq!(buckets_dest.len(), 2);
+        for n in 0..2 {
+            let count = ds_dest
+                .get_event_count(&format!("bucket-synced-from-device-{n}"))
+                .unwrap();
+            assert_eq!(count, 3);
+        }
+    }
+
+    #[test]
+    fn test_get_events_batched() {
         let state = init_teststate();
+        create_bucket(&state.ds_src, 0);
+        let start = Utc::now() - Duration::hours(1);
+        let events: Vec<Event> = (0..2500)
+            .map(|i| Event {
+                id: None,
+                timestamp: start + Duration::milliseconds(i),
+                duration: Duration::milliseconds(1),
+                data: serde_json::from_value(serde_json::json!({ "test": i })).unwrap(),
+            })
+            .collect();
+        state.ds_src.insert_events("bucket-0", &events).unwrap();
+        let ds_mock = MockDatastore::default();
+        ds_mock.add_bucket("bucket-0", "device-0", events);
 
-        let bucket_id = create_bucket(&state.ds_src, 0);
-        create_events(&state.ds_src, bucket_id.as_str(), 10);
+        // Paged by the datastore, and split up by the default implementation
+        for ds in [&state.ds_src as &dyn AccessMethod, &ds_mock] {
+            let batches: Vec<Vec<Event>> = ds
+                .get_events_batched("bucket-0", None, None, 1000)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+            assert_eq!(sizes, vec![1000, 1000, 500]);
+            let events = batches.concat();
+            assert!(events.windows(2).all(|e| e[0].timestamp < e[1].timestamp));
+        }
 
+        // Synced batch by batch
         aw_sync::sync_datastores(
             &state.ds_src,
             &state.ds_dest,
             false,
             None,
             &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let count = state
+            .ds_dest
+            .get_event_count("bucket-0-synced-from-device-0", None, None)
+            .unwrap();
+        assert_eq!(count, 2500);
+    }
+
+    #[test]
+    fn test_bucket_created_concurrently() {
+        let ds_src = MockDatastore::default();
+        ds_src.add_bucket("bucket-0", "device-0", mock_events(5));
+        let ds_dest = MockDatastore {
+            racing_create: true,
+            ..MockDatastore::default()
+        };
+
+        let stats = aw_sync::sync_datastores(
+            &ds_src,
+            &ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        // Synced into the bucket created by the other process instead of panicking
+        assert!(!stats.buckets[0].created);
+        assert_eq!(
+            ds_dest
+                .get_event_count("bucket-0-synced-from-device-0")
+                .unwrap(),
+            5
         );
 
-        let all_datastores: Vec<&Datastore> = [&state.ds_src, &state.ds_dest].to_vec();
-        let all_buckets_map = get_all_buckets_map(all_datastores);
+        // Syncing again doesn't duplicate events
+        aw_sync::sync_datastores(
+            &ds_src,
+            &ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(
+            ds_dest
+                .get_event_count("bucket-0-synced-from-device-0")
+                .unwrap(),
+            5
+        );
+    }
 
-        // Check that all synced buckets are identical to source bucket
-        check_synced_buckets_equal_to_src(&all_buckets_map);
+    #[test]
+    fn test_merge_edits() {
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 3);
+        let sync = |sync_spec: &SyncSpec| {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                sync_spec,
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+        };
+        sync(&SyncSpec::default());
 
-        // Add some more events
-        create_events(&state.ds_src, bucket_id.as_str(), 10);
+        // Edit the first event in the source, bumping its revision
+        let mut edited = state
+            .ds_src
+            .get_events(bucket_id.as_str(), None, None, None)
+            .unwrap()
+            .pop()
+            .unwrap();
+        edited
+            .data
+            .insert("test".to_string(), serde_json::json!("edited"));
+        edited
+            .data
+            .insert(REVISION_KEY.to_string(), serde_json::json!(1));
+        state
+            .ds_src
+            .insert_events(bucket_id.as_str(), &[edited.clone()])
+            .unwrap();
+        state.ds_src.force_commit().unwrap();
+
+        let get_dest_event = || {
+            state
+                .ds_dest
+                .get_events("bucket-0-synced-from-device-0", None, None, None)
+                .unwrap()
+                .into_iter()
+                .find(|e| e.timestamp == edited.timestamp)
+                .unwrap()
+        };
+
+        // Edits aren't synced by default
+        sync(&SyncSpec::default());
+        assert_eq!(get_dest_event().data["test"], serde_json::json!(0));
+
+        let sync_spec = SyncSpec {
+            merge_edits: true,
+            ..SyncSpec::default()
+        };
+        sync(&sync_spec);
+        let dest_event = get_dest_event();
+        assert_eq!(dest_event.data["test"], serde_json::json!("edited"));
+        assert_eq!(dest_event.data[REVISION_KEY], serde_json::json!(1));
+        let count = state
+            .ds_dest
+            .get_event_count("bucket-0-synced-from-device-0", None, None)
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_unknown_hostname() {
+        let ds_src = MockDatastore::default();
+        ds_src.add_bucket("bucket-unknown", "unknown", mock_events(3));
+        let ds_dest = MockDatastore::default();
+
+        // Without a source device ID to fall back to, the bucket is skipped
+        let stats = aw_sync::sync_datastores(
+            &ds_src,
+            &ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(stats.buckets_processed, 0);
+        assert!(ds_dest.get_buckets().unwrap().is_empty());
+
+        // With one, the bucket is synced as if it came from that device
+        aw_sync::sync_datastores(
+            &ds_src,
+            &ds_dest,
+            false,
+            Some("device-remote"),
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let count = ds_dest
+            .get_event_count("bucket-unknown-synced-from-device-remote")
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_buckets_regex() {
+        let ds_src = MockDatastore::default();
+        for n in 0..3 {
+            ds_src.add_bucket(&format!("bucket-{n}"), &format!("device-{n}"), Vec::new());
+        }
+        let ds_dest = MockDatastore::default();
+
+        let sync_spec = SyncSpec {
+            buckets: Some(vec!["bucket-0".to_string()]),
+            buckets_regex: Some(Regex::new("^(?:bucket-[1])$").unwrap()),
+            ..SyncSpec::default()
+        };
+        aw_sync::sync_datastores(
+            &ds_src,
+            &ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let buckets_dest = ds_dest.get_buckets().unwrap();
+        assert_eq!(buckets_dest.len(), 2);
+        assert!(buckets_dest.contains_key("bucket-0-synced-from-device-0"));
+        assert!(buckets_dest.contains_key("bucket-1-synced-from-device-1"));
+    }
+
+    #[test]
+    fn test_empty_bucket_list() {
+        let ds_src = MockDatastore::default();
+        for n in 0..2 {
+            ds_src.add_bucket(&format!("bucket-{n}"), &format!("device-{n}"), Vec::new());
+        }
+        let ds_dest = MockDatastore::default();
+
+        // An empty list would sync nothing, so it's rejected
+        let sync_spec = SyncSpec {
+            buckets: Some(vec![]),
+            ..SyncSpec::default()
+        };
+        let result = aw_sync::sync_datastores(
+            &ds_src,
+            &ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        );
+        assert!(matches!(result, Err(SyncError::Config(_))));
+        assert!(ds_dest.get_buckets().unwrap().is_empty());
+
+        // Unless all buckets are synced explicitly
+        let sync_spec = SyncSpec {
+            buckets: Some(vec![]),
+            all_buckets: true,
+            ..SyncSpec::default()
+        };
         aw_sync::sync_datastores(
+            &ds_src,
+            &ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(ds_dest.get_buckets().unwrap().len(), 2);
+
+        // Which overrides the bucket filters
+        let sync_spec = SyncSpec {
+            buckets: Some(vec!["bucket-0".to_string()]),
+            all_buckets: true,
+            ..SyncSpec::default()
+        };
+        assert!(sync_spec.includes_bucket("bucket-1"));
+    }
+
+    #[test]
+    fn test_max_events_per_bucket() {
+        let state = init_teststate();
+        let bucket = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, &bucket, 10);
+
+        // The backlog is synced over several passes, oldest events first
+        let sync_spec = SyncSpec {
+            max_events_per_bucket: Some(4),
+            ..SyncSpec::default()
+        };
+        let mut state_file = SyncState::default();
+        let mut counts = Vec::new();
+        for _ in 0..4 {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &sync_spec,
+                &mut state_file,
+                &NoProgress,
+            )
+            .unwrap();
+            let events = state
+                .ds_dest
+                .get_events("bucket-0-synced-from-device-0", None, None, None)
+                .unwrap();
+            counts.push(events.len());
+        }
+        assert_eq!(counts, vec![4, 8, 10, 10]);
+        let source = state.ds_src.get_events(&bucket, None, None, None).unwrap();
+        let synced = state
+            .ds_dest
+            .get_events("bucket-0-synced-from-device-0", None, None, None)
+            .unwrap();
+        let data = |events: &[Event]| -> Vec<_> { events.iter().map(|e| e.data.clone()).collect() };
+        assert_eq!(data(&source), data(&synced));
+    }
+
+    #[test]
+    fn test_skip_buckets_larger_than() {
+        let state = init_teststate();
+        for (n, count) in [(0, 3), (1, 8)] {
+            let bucket = create_bucket(&state.ds_src, n);
+            create_events(&state.ds_src, &bucket, count);
+        }
+
+        let sync_spec = SyncSpec {
+            skip_buckets_larger_than: Some(5),
+            ..SyncSpec::default()
+        };
+        let mut state_file = SyncState::default();
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut state_file,
+            &NoProgress,
+        )
+        .unwrap();
+        let synced: Vec<&str> = stats.buckets.iter().map(|b| b.bucket_id.as_str()).collect();
+        assert_eq!(synced, vec!["bucket-0-synced-from-device-0"]);
+        assert!(state
+            .ds_dest
+            .get_bucket("bucket-1-synced-from-device-1")
+            .is_err());
+
+        // Synced by a full pass later on
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut state_file,
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(stats.events_inserted, 8);
+    }
+
+    #[test]
+    fn test_cancelled_sync() {
+        let state = init_teststate();
+        let bucket = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, &bucket, 10);
+
+        // A cancelled pass stops before syncing any more buckets
+        let sync_spec = SyncSpec {
+            cancel: Some(Arc::new(AtomicBool::new(true))),
+            ..SyncSpec::default()
+        };
+        let mut state_file = SyncState::default();
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut state_file,
+            &NoProgress,
+        )
+        .unwrap();
+        assert!(stats.interrupted);
+        assert_eq!(stats.buckets_processed, 0);
+        assert!(state.ds_dest.get_buckets().unwrap().is_empty());
+
+        // And the next pass syncs everything
+        let stats = aw_sync::sync_datastores(
             &state.ds_src,
             &state.ds_dest,
             false,
             None,
             &SyncSpec::default(),
+            &mut state_file,
+            &NoProgress,
+        )
+        .unwrap();
+        assert!(!stats.interrupted);
+        assert_eq!(stats.buckets_processed, 1);
+    }
+
+    #[test]
+    fn test_bucket_types() {
+        let ds_src = MockDatastore::default();
+        for (bucket_id, bucket_type) in [
+            ("bucket-afk", "afkstatus"),
+            ("bucket-window", "currentwindow"),
+            ("bucket-web", "web.tab.current"),
+        ] {
+            let bucket: Bucket = serde_json::from_value(serde_json::json!({
+                "id": bucket_id,
+                "type": bucket_type,
+                "hostname": "device-0",
+                "client": "test",
+            }))
+            .unwrap();
+            ds_src.create_bucket(&bucket).unwrap();
+        }
+
+        // Type filters apply on their own
+        let sync_spec = SyncSpec {
+            include_types: Some(vec!["afkstatus".to_string(), "currentwindow".to_string()]),
+            ..SyncSpec::default()
+        };
+        let stats = aw_sync::sync_datastores(
+            &ds_src,
+            &MockDatastore::default(),
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let mut synced: Vec<String> = stats.buckets.into_iter().map(|s| s.bucket_id).collect();
+        synced.sort();
+        assert_eq!(
+            synced,
+            vec![
+                "bucket-afk-synced-from-device-0",
+                "bucket-window-synced-from-device-0"
+            ]
         );
 
-        // Check again that new events were indeed synced
-        check_synced_buckets_equal_to_src(&all_buckets_map);
+        // Buckets must pass both the ID and the type filters
+        let sync_spec = SyncSpec {
+            buckets: Some(vec!["bucket-afk".to_string(), "bucket-window".to_string()]),
+            exclude_types: vec!["currentwindow".to_string()],
+            ..SyncSpec::default()
+        };
+        let stats = aw_sync::sync_datastores(
+            &ds_src,
+            &MockDatastore::default(),
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let synced: Vec<String> = stats.buckets.into_iter().map(|s| s.bucket_id).collect();
+        assert_eq!(synced, vec!["bucket-afk-synced-from-device-0"]);
     }
 
-    // TODO: Find a way to reuse this (previously used in an integration test)
-    fn setup_test(sync_directory: &Path) -> std::io::Result<Vec<Datastore>> {
-        let mut datastores: Vec<Datastore> = Vec::new();
-        for n in 0..2 {
-            let dspath = sync_directory.join(format!("test-remote-{n}.db"));
-            let ds_ = create_datastore(&dspath);
-            let ds = &ds_ as &dyn AccessMethod;
-
-            // Create a bucket
-            // NOTE: Created with duplicate name to make sure it still works under such conditions
-            let bucket_jsonstr = format!(
-                r#"{{
-                    "id": "bucket",
-                    "type": "test",
-                    "hostname": "device-{n}",
-                    "client": "test"
-                }}"#
-            );
-            let bucket: Bucket = serde_json::from_str(&bucket_jsonstr)?;
-            match ds.create_bucket(&bucket) {
-                Ok(()) => (),
-                Err(e) => match e {
-                    DatastoreError::BucketAlreadyExists(_) => {
-                        debug!("bucket already exists, skipping");
-                    }
-                    e => panic!("woops! {e:?}"),
-                },
-            };
+    #[test]
+    fn test_changed_within() {
+        let state = init_teststate();
 
-            // Insert some testing events into the bucket
-            let events: Vec<Event> = (0..3)
-                .map(|i| {
-                    let timestamp: DateTime<Utc> = Utc::now() + Duration::milliseconds(i * 10);
-                    let event_jsonstr = format!(
-                        r#"{{
-                            "timestamp": "{}",
-                            "duration": 0,
-                            "data": {{"test": {} }}
-                        }}"#,
-                        timestamp.to_rfc3339(),
-                        i
-                    );
-                    serde_json::from_str(&event_jsonstr).unwrap()
-                })
-                .collect::<Vec<Event>>();
+        // A dormant bucket, a recently updated one, and one without events
+        let dormant = create_bucket(&state.ds_src, 0);
+        let mut old_event = create_event("0");
+        old_event.timestamp = Utc::now() - Duration::days(2);
+        state
+            .ds_src
+            .insert_events(dormant.as_str(), &[old_event])
+            .unwrap();
+        let recent = create_bucket(&state.ds_src, 1);
+        create_events(&state.ds_src, recent.as_str(), 3);
+        create_bucket(&state.ds_src, 2);
+
+        let sync_spec = SyncSpec {
+            changed_within: Some(Duration::hours(1)),
+            ..SyncSpec::default()
+        };
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let buckets_dest = state.ds_dest.get_buckets().unwrap();
+        assert_eq!(buckets_dest.len(), 2);
+        assert!(buckets_dest.contains_key("bucket-1-synced-from-device-1"));
+        assert!(buckets_dest.contains_key("bucket-2-synced-from-device-2"));
+    }
+
+    #[test]
+    fn test_time_range() {
+        let state = init_teststate();
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 10);
+        let mut timestamps: Vec<DateTime<Utc>> = state
+            .ds_src
+            .get_events(bucket_id.as_str(), None, None, None)
+            .unwrap()
+            .iter()
+            .map(|e| e.timestamp)
+            .collect();
+        timestamps.sort();
+
+        let sync_spec = SyncSpec {
+            start: Some(timestamps[3]),
+            end: Some(timestamps[6]),
+            ..SyncSpec::default()
+        };
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(stats.buckets[0].events, 4);
+
+        let events_dest = state
+            .ds_dest
+            .get_events("bucket-0-synced-from-device-0", None, None, None)
+            .unwrap();
+        assert!(events_dest
+            .iter()
+            .all(|e| e.timestamp >= timestamps[3] && e.timestamp <= timestamps[6]));
+    }
+
+    #[test]
+    fn test_dedup_strict() {
+        let state = init_teststate();
 
-            ds.insert_events(bucket.id.as_str(), events).unwrap();
-            //let new_eventcount = ds.get_event_count(bucket.id.as_str(), None, None).unwrap();
-            //info!("Eventcount: {:?} ({} new)", new_eventcount, events.len());
-            datastores.push(ds_);
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 10);
+
+        // Sync the same source twice, rechecking all events the second time
+        let sync_spec = SyncSpec {
+            dedup_strict: true,
+            force_full: true,
+            ..SyncSpec::default()
+        };
+        for _ in 0..2 {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &sync_spec,
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+            let count = state
+                .ds_dest
+                .get_event_count("bucket-0-synced-from-device-0", None, None)
+                .unwrap();
+            assert_eq!(count, 10);
         }
-        Ok(datastores)
+    }
+
+    #[test]
+    fn test_clock_skew_tolerance() {
+        let state = init_teststate();
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 10);
+        let sync_spec = SyncSpec {
+            clock_skew_tolerance: Duration::minutes(5),
+            ..SyncSpec::default()
+        };
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        // An event created on a device with a clock that's behind, so it ends before the last
+        // synced event
+        let last = state
+            .ds_src
+            .get_events(bucket_id.as_str(), None, None, Some(1))
+            .unwrap()
+            .remove(0);
+        let mut late_event = create_event("\"late\"");
+        late_event.timestamp = last.timestamp - Duration::milliseconds(2);
+        state
+            .ds_src
+            .insert_events(bucket_id.as_str(), &[late_event])
+            .unwrap();
+        state.ds_src.force_commit().unwrap();
+
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        // The late event was synced, without duplicating the events synced before
+        let events_dest = state
+            .ds_dest
+            .get_events("bucket-0-synced-from-device-0", None, None, None)
+            .unwrap();
+        assert_eq!(events_dest.len(), 11);
+        assert!(events_dest
+            .iter()
+            .any(|e| e.data.get("test") == Some(&serde_json::json!("late"))));
+    }
+
+    #[test]
+    fn test_resume_after_last_event() {
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 3);
+        // Without a clock skew tolerance, which is the default
+        let sync_spec = SyncSpec::default();
+        let sync = |progress: &dyn aw_sync::SyncProgress| {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &sync_spec,
+                // Not skipped as unchanged, so the resume point is used
+                &mut SyncState::default(),
+                progress,
+            )
+            .unwrap()
+        };
+        assert_eq!(sync(&NoProgress).events_inserted, 3);
+
+        // The last synced event isn't read and sent again
+        let sent = Mutex::new(0);
+        let progress = |event: SyncProgressEvent| {
+            if let SyncProgressEvent::EventsInserted { inserted, .. } = event {
+                *sent.lock().unwrap() += inserted;
+            }
+        };
+        assert_eq!(sync(&progress).events_inserted, 0);
+        assert_eq!(*sent.lock().unwrap(), 0);
+        assert_eq!(
+            state
+                .ds_dest
+                .get_event_count("bucket-0-synced-from-device-0", None, None)
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_event_count_range() {
+        let state = init_teststate();
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 10);
+        let mut timestamps: Vec<DateTime<Utc>> = state
+            .ds_src
+            .get_events(bucket_id.as_str(), None, None, None)
+            .unwrap()
+            .iter()
+            .map(|e| e.timestamp)
+            .collect();
+        timestamps.sort();
+
+        let ds = &state.ds_src as &dyn AccessMethod;
+        assert_eq!(ds.get_event_count(bucket_id.as_str()).unwrap(), 10);
+        assert_eq!(
+            ds.get_event_count_range(bucket_id.as_str(), Some(timestamps[3]), Some(timestamps[6]))
+                .unwrap(),
+            4
+        );
+        assert_eq!(
+            ds.get_event_count_range(bucket_id.as_str(), Some(timestamps[8]), None)
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let state = init_teststate();
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 10);
+
+        let events = Mutex::new(Vec::new());
+        let progress = |event: SyncProgressEvent| events.lock().unwrap().push(event);
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &progress,
+        )
+        .unwrap();
+
+        let synced_id = "bucket-0-synced-from-device-0".to_string();
+        assert_eq!(
+            events.into_inner().unwrap(),
+            vec![
+                SyncProgressEvent::BucketStarted {
+                    bucket_id: synced_id.clone()
+                },
+                SyncProgressEvent::EventsInserted {
+                    bucket_id: synced_id.clone(),
+                    inserted: 10,
+                    total: 10
+                },
+                SyncProgressEvent::BucketFinished {
+                    bucket_id: synced_id,
+                    new_events: 10
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sync_run_summary() {
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 10);
+
+        let pulled = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            Some("device-0"),
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let mut summary = SyncRunSummary::default();
+        summary.record_pull("device-0", pulled);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["buckets_synced"], 1);
+        assert_eq!(json["events_pulled"]["device-0"], 10);
+        assert_eq!(json["events_pushed"], 0);
+        assert_eq!(json["errors"], serde_json::json!({}));
+        assert_eq!(
+            json["buckets"][0]["bucket_id"],
+            "bucket-0-synced-from-device-0"
+        );
+        assert!(json["buckets"][0].get("error").is_none());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let ds_src = MockDatastore::default();
+        ds_src.add_bucket("bucket-0", "device-0", mock_events(10));
+        let ds_dest = MockDatastore::default();
+
+        let sync_spec = SyncSpec {
+            dry_run: true,
+            ..SyncSpec::default()
+        };
+        let stats = aw_sync::sync_datastores(
+            &ds_src,
+            &ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        // Nothing should have been written
+        assert!(ds_dest.get_buckets().unwrap().is_empty());
+
+        assert_eq!(stats.buckets.len(), 1);
+        assert!(stats.buckets[0].created);
+        assert_eq!(stats.buckets[0].events, 10);
+        assert_eq!(stats.buckets_processed, 1);
+        assert_eq!(stats.buckets_created, 1);
+        assert_eq!(stats.events_inserted, 10);
+    }
+
+    #[test]
+    fn test_sync_state() {
+        let state = init_teststate();
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 10);
+
+        let mut sync_state = SyncState::default();
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut sync_state,
+            &NoProgress,
+        )
+        .unwrap();
+        let bucket_state = sync_state
+            .buckets
+            .get("bucket-0-synced-from-device-0")
+            .unwrap()
+            .clone();
+        assert_eq!(bucket_state.eventcount, 10);
+
+        // Unchanged buckets should be skipped, and leave the state as-is
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut sync_state,
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(stats.buckets[0].events, 0);
+        assert_eq!(
+            sync_state.buckets.get("bucket-0-synced-from-device-0"),
+            Some(&bucket_state)
+        );
+    }
+
+    #[test]
+    fn test_stale_remotes() {
+        let now = Utc::now();
+        let mut sync_state = SyncState::default();
+        sync_state.remotes.insert("device-fresh".to_string(), now);
+        sync_state
+            .remotes
+            .insert("device-stale".to_string(), now - Duration::days(10));
+        sync_state
+            .remotes
+            .insert("device-staler".to_string(), now - Duration::days(30));
+
+        let stale = sync_state.stale_remotes(Duration::days(7));
+        let stale_dids: Vec<&str> = stale.iter().map(|(did, _)| *did).collect();
+        assert_eq!(stale_dids, vec!["device-staler", "device-stale"]);
+
+        // Last sync times are kept when saving and loading the state
+        let path =
+            std::env::temp_dir().join(format!("aw-sync-test-state-{}.json", std::process::id()));
+        sync_state.save(&path).unwrap();
+        let loaded = SyncState::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.remotes, sync_state.remotes);
+    }
+
+    #[test]
+    fn test_propagate_deletions() {
+        let state = init_teststate();
+        let ds_staging = Datastore::new_in_memory(false);
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 10);
+
+        let sync_spec = SyncSpec {
+            propagate_deletions: true,
+            ..SyncSpec::default()
+        };
+        let mut sync_state = SyncState::default();
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &ds_staging,
+            true,
+            None,
+            &sync_spec,
+            &mut sync_state,
+            &NoProgress,
+        )
+        .unwrap();
+        aw_sync::sync_datastores(
+            &ds_staging,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut sync_state,
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(state.ds_dest.get_buckets().unwrap().len(), 1);
+
+        // Deleting the source bucket should delete it from the staging datastore, and the
+        // tombstone left in its place should delete the pulled bucket
+        state.ds_src.delete_bucket(&bucket_id).unwrap();
+        let deleted =
+            record_deletions(&state.ds_src, &ds_staging, &sync_spec, &mut sync_state).unwrap();
+        assert_eq!(deleted, vec![bucket_id.clone()]);
+        assert!(ds_staging.get_buckets().unwrap().is_empty());
+
+        let deleted =
+            apply_tombstones(&ds_staging, &state.ds_dest, &sync_spec, &mut sync_state, "").unwrap();
+        assert_eq!(deleted, vec!["bucket-0-synced-from-device-0".to_string()]);
+        assert!(state.ds_dest.get_buckets().unwrap().is_empty());
+
+        // A re-created bucket takes precedence over its tombstone
+        create_bucket(&state.ds_src, 0);
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &ds_staging,
+            true,
+            None,
+            &sync_spec,
+            &mut sync_state,
+            &NoProgress,
+        )
+        .unwrap();
+        let deleted =
+            record_deletions(&state.ds_src, &ds_staging, &sync_spec, &mut sync_state).unwrap();
+        assert!(deleted.is_empty());
+        aw_sync::sync_datastores(
+            &ds_staging,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut sync_state,
+            &NoProgress,
+        )
+        .unwrap();
+        let deleted =
+            apply_tombstones(&ds_staging, &state.ds_dest, &sync_spec, &mut sync_state, "").unwrap();
+        assert!(deleted.is_empty());
+        assert_eq!(state.ds_dest.get_buckets().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_no_synced_bucket_chaining() {
+        // Three devices, each with a server datastore and a staging datastore
+        let servers: Vec<Datastore> = (0..3).map(|_| Datastore::new_in_memory(false)).collect();
+        let stagings: Vec<Datastore> = (0..3).map(|_| Datastore::new_in_memory(false)).collect();
+        for (n, server) in servers.iter().enumerate() {
+            let bucket_id = create_bucket(server, n as i32);
+            create_events(server, bucket_id.as_str(), 3);
+        }
+
+        // Two full passes, so that pulled buckets get a chance to be pushed again
+        for _ in 0..2 {
+            for (server, staging) in servers.iter().zip(stagings.iter()) {
+                aw_sync::sync_datastores(
+                    server,
+                    staging,
+                    true,
+                    None,
+                    &SyncSpec::default(),
+                    &mut SyncState::default(),
+                    &NoProgress,
+                )
+                .unwrap();
+            }
+            for (n, server) in servers.iter().enumerate() {
+                for (m, staging) in stagings.iter().enumerate() {
+                    if n == m {
+                        continue;
+                    }
+                    aw_sync::sync_datastores(
+                        staging,
+                        server,
+                        false,
+                        None,
+                        &SyncSpec::default(),
+                        &mut SyncState::default(),
+                        &NoProgress,
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        for (n, staging) in stagings.iter().enumerate() {
+            let bucket_ids: Vec<String> = staging.get_buckets().unwrap().into_keys().collect();
+            assert_eq!(bucket_ids, vec![format!("bucket-{n}")]);
+        }
+        for server in &servers {
+            let buckets = server.get_buckets().unwrap();
+            assert_eq!(buckets.len(), 3);
+            for bucket_id in buckets.keys() {
+                assert!(bucket_id.matches("-synced-from-").count() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bucket_data_updated() {
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 1);
+        let sync = || {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &SyncSpec::default(),
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap()
+        };
+        sync();
+
+        // Add an attribute to the source bucket after it was first synced
+        let mut data = state.ds_src.get_bucket(&bucket_id).unwrap().data;
+        data.insert("color".to_string(), serde_json::json!("red"));
+        state.ds_src.update_bucket_data(&bucket_id, data).unwrap();
+        sync();
+
+        let bucket_dest = state
+            .ds_dest
+            .get_bucket("bucket-0-synced-from-device-0")
+            .unwrap();
+        assert_eq!(
+            bucket_dest.data.get("color"),
+            Some(&serde_json::json!("red"))
+        );
+        assert_eq!(
+            bucket_dest.data.get("$aw.sync.origin"),
+            Some(&serde_json::json!("device-0"))
+        );
+    }
+
+    fn create_bucket_with_hostname(ds: &Datastore, bucket_id: &str, hostname: &str) {
+        let bucket: Bucket = serde_json::from_value(serde_json::json!({
+            "id": bucket_id,
+            "type": "test",
+            "hostname": hostname,
+            "client": "test"
+        }))
+        .unwrap();
+        ds.create_bucket(&bucket).unwrap();
+    }
+
+    #[test]
+    fn test_bucket_created_kept() {
+        let state = init_teststate();
+        let created: DateTime<Utc> = "2020-01-01T00:00:00Z".parse().unwrap();
+        let bucket: Bucket = serde_json::from_value(serde_json::json!({
+            "id": "bucket-0",
+            "type": "test",
+            "hostname": "device-0",
+            "client": "test",
+            "created": created,
+        }))
+        .unwrap();
+        state.ds_src.create_bucket(&bucket).unwrap();
+        create_events(&state.ds_src, "bucket-0", 3);
+
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let source = state.ds_src.get_bucket("bucket-0").unwrap();
+        let synced = state
+            .ds_dest
+            .get_bucket("bucket-0-synced-from-device-0")
+            .unwrap();
+        assert_eq!(synced.created, Some(created));
+        // The range of the synced events
+        assert_eq!(synced.metadata.start, source.metadata.start);
+        assert_eq!(synced.metadata.end, source.metadata.end);
+    }
+
+    #[test]
+    fn test_same_hostname_remotes_kept_apart() {
+        let ds_dest = MockDatastore::default();
+        for (n, did) in ["device-1", "device-2"].iter().enumerate() {
+            let ds_remote = MockDatastore::default();
+            ds_remote.add_bucket("bucket-window", "laptop", mock_events(n as i64 + 1));
+            aw_sync::sync_datastores(
+                &ds_remote,
+                &ds_dest,
+                false,
+                Some(did),
+                &SyncSpec::default(),
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+        }
+
+        for (n, did) in ["device-1", "device-2"].iter().enumerate() {
+            let bucket_id = format!("bucket-window-synced-from-{did}");
+            assert_eq!(ds_dest.get_event_count(&bucket_id).unwrap(), n as i64 + 1);
+        }
+        assert_eq!(ds_dest.get_buckets().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_migrate_synced_bucket_ids() {
+        let ds_dest = Datastore::new_in_memory(false);
+        let remotes = [
+            Datastore::new_in_memory(false),
+            Datastore::new_in_memory(false),
+        ];
+        create_bucket_with_hostname(&remotes[0], "bucket-a", "laptop");
+        create_events(&remotes[0], "bucket-a", 2);
+        for ds_remote in remotes.iter() {
+            create_bucket_with_hostname(ds_remote, "bucket-b", "laptop");
+            create_events(ds_remote, "bucket-b", 1);
+        }
+
+        // Pull the way older versions did, keyed by hostname
+        let mut sync_state = SyncState::default();
+        for ds_remote in remotes.iter() {
+            aw_sync::sync_datastores(
+                ds_remote,
+                &ds_dest,
+                false,
+                None,
+                &SyncSpec::default(),
+                &mut sync_state,
+                &NoProgress,
+            )
+            .unwrap();
+        }
+        assert!(sync_state
+            .buckets
+            .contains_key("bucket-a-synced-from-laptop"));
+
+        let remotes_with_ids = [
+            (&remotes[0], "device-1".to_string()),
+            (&remotes[1], "device-2".to_string()),
+        ];
+        let renamed = migrate_synced_bucket_ids(
+            &remotes_with_ids,
+            &ds_dest,
+            &SyncSpec::default(),
+            &mut sync_state,
+        )
+        .unwrap();
+        assert_eq!(
+            renamed,
+            vec![(
+                "bucket-a-synced-from-laptop".to_string(),
+                "bucket-a-synced-from-device-1".to_string()
+            )]
+        );
+        assert!(sync_state
+            .buckets
+            .contains_key("bucket-a-synced-from-device-1"));
+        assert!(!sync_state
+            .buckets
+            .contains_key("bucket-a-synced-from-laptop"));
+
+        let buckets = ds_dest.get_buckets().unwrap();
+        let mut bucket_ids: Vec<&String> = buckets.keys().collect();
+        bucket_ids.sort();
+        // Events of both remotes are in the collided bucket, so it is left as is
+        assert_eq!(
+            bucket_ids,
+            vec![
+                "bucket-a-synced-from-device-1",
+                "bucket-b-synced-from-laptop"
+            ]
+        );
+        assert_eq!(
+            ds_dest
+                .get_event_count("bucket-a-synced-from-device-1", None, None)
+                .unwrap(),
+            2
+        );
+
+        // Pulling again doesn't duplicate the renamed bucket
+        let pulled = aw_sync::sync_datastores(
+            &remotes[0],
+            &ds_dest,
+            false,
+            Some("device-1"),
+            &SyncSpec::default(),
+            &mut sync_state,
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(
+            ds_dest
+                .get_event_count("bucket-a-synced-from-device-1", None, None)
+                .unwrap(),
+            2
+        );
+        assert!(pulled.buckets.iter().all(|b| b.error.is_none()));
+    }
+
+    /// Datastore which fails in the middle of a sync
+    #[derive(Debug)]
+    struct FaultyDatastore {
+        inner: Datastore,
+        /// Deleted right before its events are read, like a watcher recreating its bucket
+        churned_bucket: Option<String>,
+        /// Number of inserts to succeed before failing, like a sync being interrupted
+        inserts_left: Cell<Option<usize>>,
+        /// Emptied before each bulk insert, like another client deleting its events
+        cleared_bucket: Option<String>,
+        /// Fails to be created, like a bucket the destination rejects
+        uncreatable_bucket: Option<String>,
+    }
+
+    impl FaultyDatastore {
+        fn new(inner: Datastore) -> FaultyDatastore {
+            FaultyDatastore {
+                inner,
+                churned_bucket: None,
+                inserts_left: Cell::new(None),
+                cleared_bucket: None,
+                uncreatable_bucket: None,
+            }
+        }
+    }
+
+    impl AccessMethod for FaultyDatastore {
+        fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
+            AccessMethod::get_buckets(&self.inner)
+        }
+        fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
+            AccessMethod::get_bucket(&self.inner, bucket_id)
+        }
+        fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+            if self.uncreatable_bucket.as_deref() == Some(bucket.id.as_str()) {
+                return Err(DatastoreError::InternalError("rejected".to_string()));
+            }
+            AccessMethod::create_bucket(&self.inner, bucket)
+        }
+        fn delete_bucket(&self, bucket_id: &str) -> Result<(), DatastoreError> {
+            AccessMethod::delete_bucket(&self.inner, bucket_id)
+        }
+        fn update_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+            AccessMethod::update_bucket(&self.inner, bucket)
+        }
+        fn get_events(
+            &self,
+            bucket_id: &str,
+            start: Option<DateTime<Utc>>,
+            end: Option<DateTime<Utc>>,
+            limit: Option<u64>,
+        ) -> Result<Vec<Event>, String> {
+            if self.churned_bucket.as_deref() == Some(bucket_id) {
+                let _ = AccessMethod::delete_bucket(&self.inner, bucket_id);
+            }
+            AccessMethod::get_events(&self.inner, bucket_id, start, end, limit)
+        }
+        fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String> {
+            if let Some(left) = self.inserts_left.get() {
+                if left == 0 {
+                    return Err("interrupted".to_string());
+                }
+                self.inserts_left.set(Some(left - 1));
+            }
+            if self.cleared_bucket.as_deref() == Some(bucket_id) {
+                let existing = AccessMethod::get_events(&self.inner, bucket_id, None, None, None)?;
+                let ids = existing.iter().filter_map(|e| e.id).collect();
+                AccessMethod::delete_events(&self.inner, bucket_id, ids)?;
+            }
+            AccessMethod::insert_events(&self.inner, bucket_id, events)
+        }
+        fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String> {
+            AccessMethod::delete_events(&self.inner, bucket_id, event_ids)
+        }
+        fn get_event_count_range(
+            &self,
+            bucket_id: &str,
+            start: Option<DateTime<Utc>>,
+            end: Option<DateTime<Utc>>,
+        ) -> Result<i64, String> {
+            AccessMethod::get_event_count_range(&self.inner, bucket_id, start, end)
+        }
+        fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String> {
+            AccessMethod::heartbeat(&self.inner, bucket_id, event, duration)
+        }
+        fn transaction(
+            &self,
+            f: &mut dyn FnMut() -> Result<(), SyncError>,
+        ) -> Result<(), SyncError> {
+            AccessMethod::transaction(&self.inner, f)
+        }
+        fn close(&self) {
+            AccessMethod::close(&self.inner)
+        }
+    }
+
+    #[test]
+    fn test_bucket_deleted_while_syncing() {
+        let state = init_teststate();
+        for n in 0..2 {
+            let bucket_id = create_bucket(&state.ds_src, n);
+            create_events(&state.ds_src, bucket_id.as_str(), 3);
+        }
+        let ds_src = FaultyDatastore {
+            churned_bucket: Some("bucket-0".to_string()),
+            ..FaultyDatastore::new(state.ds_src)
+        };
+
+        let stats = aw_sync::sync_datastores(
+            &ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        // The deleted bucket is skipped without failing the other buckets
+        assert!(stats.buckets.iter().all(|b| b.error.is_none()));
+        assert!(stats
+            .buckets
+            .iter()
+            .all(|b| b.bucket_id != "bucket-0-synced-from-device-0"));
+        assert_eq!(
+            state
+                .ds_dest
+                .get_event_count("bucket-1-synced-from-device-1", None, None)
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_min_batch() {
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 2);
+        let mut sync_state = SyncState::default();
+        let mut push = |sync_spec: &SyncSpec| {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                true,
+                Some("device-0"),
+                sync_spec,
+                &mut sync_state,
+                &NoProgress,
+            )
+            .unwrap();
+            state
+                .ds_dest
+                .get_event_count(&bucket_id, None, None)
+                .unwrap()
+        };
+        let batched = SyncSpec {
+            min_batch: 3,
+            ..SyncSpec::default()
+        };
+
+        // New buckets are always pushed
+        assert_eq!(push(&batched), 2);
+        // Too few new events to push
+        create_events(&state.ds_src, bucket_id.as_str(), 2);
+        assert_eq!(push(&batched), 2);
+        // Enough accumulated
+        create_events(&state.ds_src, bucket_id.as_str(), 1);
+        assert_eq!(push(&batched), 5);
+
+        // Deferred until the interval passed since the last push
+        create_events(&state.ds_src, bucket_id.as_str(), 1);
+        let interval = SyncSpec {
+            min_interval: Some(Duration::hours(1)),
+            ..SyncSpec::default()
+        };
+        assert_eq!(push(&interval), 5);
+        let flush = SyncSpec {
+            flush: true,
+            ..interval
+        };
+        assert_eq!(push(&flush), 6);
+    }
+
+    #[test]
+    fn test_merge_heartbeats() {
+        let state = init_teststate();
+        let bucket: Bucket = serde_json::from_value(serde_json::json!({
+            "id": "aw-watcher-afk_device-0",
+            "type": "afkstatus",
+            "hostname": "device-0",
+            "client": "test"
+        }))
+        .unwrap();
+        state.ds_src.create_bucket(&bucket).unwrap();
+        // Contiguous events with the same status, as left by a watcher which restarted
+        let start = Utc::now() - Duration::minutes(10);
+        let events: Vec<Event> = [("afk", 0), ("afk", 60), ("not-afk", 120)]
+            .iter()
+            .map(|(status, offset)| Event {
+                id: None,
+                timestamp: start + Duration::seconds(*offset),
+                duration: Duration::seconds(60),
+                data: serde_json::from_value(serde_json::json!({ "status": status })).unwrap(),
+            })
+            .collect();
+        state.ds_src.insert_events(&bucket.id, &events).unwrap();
+
+        let sync_spec = SyncSpec {
+            merge_heartbeats: true,
+            ..SyncSpec::default()
+        };
+        let mut sync_state = SyncState::default();
+        // Syncing again must not re-send the events which were merged
+        for _ in 0..2 {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &sync_spec,
+                &mut sync_state,
+                &NoProgress,
+            )
+            .unwrap();
+            sync_state.buckets.clear();
+        }
+
+        let synced = state
+            .ds_dest
+            .get_events(
+                "aw-watcher-afk_device-0-synced-from-device-0",
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            state
+                .ds_src
+                .get_event_count(&bucket.id, None, None)
+                .unwrap(),
+            3
+        );
+        assert_eq!(synced.len(), 2);
+        // Newest first
+        assert_eq!(
+            synced[1].timestamp.timestamp_millis(),
+            start.timestamp_millis()
+        );
+        assert_eq!(synced[1].duration, Duration::seconds(120));
+        assert_eq!(synced[0].data["status"], "not-afk");
+    }
+
+    #[test]
+    fn test_interrupted_pull_resumes_at_checkpoint() {
+        let checkpoint_path = std::env::temp_dir().join(format!(
+            "aw-sync-test-checkpoint-{}.json",
+            std::process::id()
+        ));
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        let start = Utc::now() - Duration::hours(1);
+        let events: Vec<Event> = (0..2500)
+            .map(|i| Event {
+                id: None,
+                timestamp: start + Duration::seconds(i),
+                duration: Duration::seconds(1),
+                data: serde_json::from_value(serde_json::json!({ "test": i })).unwrap(),
+            })
+            .collect();
+        state.ds_src.insert_events(&bucket_id, &events).unwrap();
+        let synced_id = "bucket-0-synced-from-device-0";
+
+        // Interrupted after the first event and the first batch of 1000 were committed
+        let ds_dest = FaultyDatastore::new(state.ds_dest);
+        ds_dest.inserts_left.set(Some(1));
+        let mut sync_state = SyncState {
+            checkpoints: Some(Arc::new(Checkpoints::load(&checkpoint_path))),
+            ..SyncState::default()
+        };
+        let pull = |sync_state: &mut SyncState| {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &ds_dest,
+                false,
+                None,
+                &SyncSpec::default(),
+                sync_state,
+                &NoProgress,
+            )
+            .unwrap()
+        };
+        let interrupted = pull(&mut sync_state);
+        assert!(interrupted.buckets[0].error.is_some());
+        let checkpoints = Checkpoints::load(&checkpoint_path);
+        assert_eq!(
+            checkpoints.get(synced_id).map(|t| t.timestamp_millis()),
+            Some(events[1000].timestamp.timestamp_millis())
+        );
+
+        // Restarted, with the checkpoint read back from the file
+        ds_dest.inserts_left.set(None);
+        let mut sync_state = SyncState {
+            checkpoints: Some(Arc::new(checkpoints)),
+            ..SyncState::default()
+        };
+        pull(&mut sync_state);
+        let finished = Checkpoints::load(&checkpoint_path);
+        fs::remove_file(&checkpoint_path).unwrap();
+
+        let synced = ds_dest
+            .inner
+            .get_events(synced_id, None, None, None)
+            .unwrap();
+        let timestamps: std::collections::HashSet<i64> = synced
+            .iter()
+            .map(|e| e.timestamp.timestamp_millis())
+            .collect();
+        assert_eq!(synced.len(), 2500);
+        assert_eq!(timestamps.len(), 2500);
+        assert_eq!(finished.get(synced_id), None);
+    }
+
+    #[test]
+    fn test_push_rolled_back() {
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        let start = Utc::now() - Duration::hours(1);
+        let events: Vec<Event> = (0..2500)
+            .map(|i| Event {
+                id: None,
+                timestamp: start + Duration::seconds(i),
+                duration: Duration::seconds(1),
+                data: serde_json::from_value(serde_json::json!({ "test": i })).unwrap(),
+            })
+            .collect();
+        state.ds_src.insert_events(&bucket_id, &events).unwrap();
+        // A single event is pushed as a heartbeat, so doesn't fail
+        let other_id = create_bucket(&state.ds_src, 1);
+        create_events(&state.ds_src, &other_id, 1);
+
+        // Failing after the first event and the first batch of 1000 were inserted
+        let ds_dest = FaultyDatastore::new(state.ds_dest);
+        ds_dest.inserts_left.set(Some(1));
+        let push = || {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &ds_dest,
+                true,
+                None,
+                &SyncSpec::default(),
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap()
+        };
+        let failed = push();
+        let failed_bucket = failed.buckets.iter().find(|b| b.bucket_id == bucket_id);
+        assert!(failed_bucket.unwrap().error.is_some());
+        // None of the events of the failed bucket were kept, the next bucket was still pushed
+        let count = |bucket_id: &str| ds_dest.inner.get_event_count(bucket_id, None, None);
+        assert_eq!(count(&bucket_id).unwrap(), 0);
+        assert_eq!(count(&other_id).unwrap(), 1);
+
+        ds_dest.inserts_left.set(None);
+        push();
+        assert_eq!(count(&bucket_id).unwrap(), 2500);
+    }
+
+    #[test]
+    fn test_sync_runner() {
+        // Nothing listens on port 1, so the pass fails when getting the server info
+        let client = aw_client_rust::AwClient::new("127.0.0.1", "1", "aw-sync-test");
+        let configured = std::cell::RefCell::new(None);
+        let result = aw_sync::SyncRunner::new(client, std::env::temp_dir())
+            .buckets(vec!["bucket-0".to_string()])
+            .mode(aw_sync::SyncMode::Pull)
+            .timeout(std::time::Duration::from_secs(60))
+            .configure(|spec| {
+                spec.retry = RetryPolicy {
+                    max_attempts: 1,
+                    base_delay: std::time::Duration::ZERO,
+                };
+                *configured.borrow_mut() = Some((spec.path.clone(), spec.buckets.clone()));
+            })
+            .run();
+
+        assert!(matches!(result, Err(SyncError::Client(_))));
+        assert_eq!(
+            configured.into_inner(),
+            Some((std::env::temp_dir(), Some(vec!["bucket-0".to_string()])))
+        );
+    }
+
+    /// Answers all requests with `status` and the JSON `body` on a free port, returning the port
+    /// and the number of requests answered
+    fn serve(status: &'static str, body: String) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let answered = requests.clone();
+        let port = serve_with(move |_| {
+            answered.fetch_add(1, Ordering::SeqCst);
+            (status, body.clone())
+        });
+        (port, requests)
+    }
+
+    /// Answers requests with the status and JSON body `respond` returns for their request line,
+    /// such as `GET /api/0/info HTTP/1.1`, on a free port, returning the port
+    fn serve_with(respond: impl Fn(&str) -> (&'static str, String) + Send + 'static) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 4096];
+                let read = stream.read(&mut request).unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..read]);
+                let (status, body) = respond(request.lines().next().unwrap_or_default());
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        port.to_string()
+    }
+
+    /// Serves `/api/0/info` reporting `version` on a free port, like an aw-server would
+    fn serve_info(version: &str) -> String {
+        let body = serde_json::json!({
+            "hostname": "device-0",
+            "version": version,
+            "testing": true,
+            "device_id": "device-0",
+        })
+        .to_string();
+        serve("200 OK", body).0
+    }
+
+    #[test]
+    fn test_server_version() {
+        assert_eq!(parse_server_version("v0.12.3 (rust)"), Some((0, 12, 3)));
+        assert_eq!(parse_server_version("v0.12.2.dev+a1b2c3"), Some((0, 12, 2)));
+        assert_eq!(parse_server_version("0.13"), Some((0, 13, 0)));
+        assert_eq!(parse_server_version("v(unknown) (rust)"), None);
+        assert!(check_server_version("v0.12.0 (rust)").is_ok());
+        assert!(check_server_version("v(unknown) (rust)").is_ok());
+
+        // Checked before anything is synced
+        let port = serve_info("v0.11.0");
+        let client = aw_client_rust::AwClient::new("127.0.0.1", &port, "aw-sync-test");
+        let result = aw_sync::SyncRunner::new(client, std::env::temp_dir())
+            .buckets(vec!["bucket-0".to_string()])
+            .run();
+        match result {
+            Err(SyncError::IncompatibleServer { found, minimum }) => {
+                assert_eq!(found, "v0.11.0");
+                let (major, minor, patch) = MIN_SERVER_VERSION;
+                assert_eq!(minimum, format!("v{major}.{minor}.{patch}"));
+            }
+            other => panic!("expected the server to be rejected, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_sync_lock() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-lock-{}", std::process::id()));
+        let device_dir = sync_dir.join("device-0");
+        fs::create_dir_all(&device_dir).unwrap();
+        let dbfile = device_dir.join("device-0.db");
+
+        let lock = SyncLock::acquire(&dbfile).unwrap();
+        assert!(matches!(
+            SyncLock::acquire(&dbfile),
+            Err(SyncError::Locked(_))
+        ));
+
+        // A second run fails fast while the first holds the lock
+        let port = serve_info("v0.12.3 (rust)");
+        let client = aw_client_rust::AwClient::new("127.0.0.1", &port, "aw-sync-test");
+        let result = aw_sync::SyncRunner::new(client, sync_dir.clone())
+            .buckets(vec!["bucket-0".to_string()])
+            .run();
+        match result {
+            Err(SyncError::Locked(path)) => assert_eq!(
+                path,
+                aw_sync::local_state_dir(&dbfile).unwrap().join("sync.lock")
+            ),
+            other => panic!("expected the run to be rejected, got {other:?}"),
+        }
+        assert!(!dbfile.exists());
+
+        // Released when dropped
+        drop(lock);
+        drop(SyncLock::acquire(&dbfile).unwrap());
+        fs::remove_dir_all(&sync_dir).unwrap();
+    }
+
+    #[test]
+    fn test_metrics_file() {
+        let path =
+            std::env::temp_dir().join(format!("aw-sync-test-metrics-{}.prom", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut summary = SyncRunSummary::default();
+        summary.events_pulled.insert("device-1".to_string(), 3);
+        summary.events_pushed = 5;
+        write_metrics(&path, Some(&summary)).unwrap();
+        let metrics = parse_metrics(&fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            metrics["aw_sync_events_pulled_total{remote=\"device-1\"}"],
+            3.0
+        );
+        assert_eq!(metrics["aw_sync_events_pushed_total"], 5.0);
+        assert_eq!(metrics["aw_sync_errors_total"], 0.0);
+        assert_eq!(metrics["aw_sync_last_run_success"], 1.0);
+        let last_success = metrics["aw_sync_last_success_timestamp"];
+        assert!(metrics["aw_sync_last_run_timestamp"] > 0.0);
+
+        // Counters add up across runs, and failed runs keep the last success
+        summary
+            .errors
+            .insert("bucket-0".to_string(), "failed".to_string());
+        write_metrics(&path, Some(&summary)).unwrap();
+        write_metrics(&path, None).unwrap();
+        let metrics = parse_metrics(&fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            metrics["aw_sync_events_pulled_total{remote=\"device-1\"}"],
+            6.0
+        );
+        assert_eq!(metrics["aw_sync_events_pushed_total"], 10.0);
+        assert_eq!(metrics["aw_sync_errors_total"], 2.0);
+        assert_eq!(metrics["aw_sync_runs_total"], 3.0);
+        assert_eq!(metrics["aw_sync_last_run_success"], 0.0);
+        assert_eq!(metrics["aw_sync_last_success_timestamp"], last_success);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_default_sync_directory() {
+        let data_home =
+            std::env::temp_dir().join(format!("aw-sync-test-data-home-{}", std::process::id()));
+        std::env::set_var("XDG_DATA_HOME", &data_home);
+        let dir = aw_sync::default_sync_directory();
+        std::env::remove_var("XDG_DATA_HOME");
+
+        let dir = dir.unwrap();
+        assert_eq!(dir, data_home.join("activitywatch").join("aw-sync"));
+        // Created if absent
+        assert!(dir.is_dir());
+        fs::remove_dir_all(&data_home).unwrap();
+    }
+
+    #[test]
+    fn test_deadline() {
+        let state = init_teststate();
+        for n in 0..3 {
+            let bucket_id = create_bucket(&state.ds_src, n);
+            create_events(&state.ds_src, bucket_id.as_str(), 2);
+        }
+
+        // No buckets are started once the deadline passed
+        let sync_spec = SyncSpec {
+            deadline: Some(std::time::Instant::now()),
+            ..Default::default()
+        };
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert!(stats.timed_out);
+        assert_eq!(stats.buckets_processed, 0);
+        assert!(state.ds_dest.get_buckets().unwrap().is_empty());
+
+        let sync_spec = SyncSpec {
+            deadline: Some(std::time::Instant::now() + std::time::Duration::from_secs(600)),
+            ..Default::default()
+        };
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert!(!stats.timed_out);
+        assert_eq!(stats.buckets_processed, 3);
+        assert_eq!(stats.events_inserted, 6);
+    }
+
+    #[test]
+    fn test_origin_bucket_id() {
+        assert_eq!(origin_bucket_id("bucket-0", "device-0"), "bucket-0");
+        assert_eq!(
+            origin_bucket_id("bucket-0-synced-from-device-0", "device-0"),
+            "bucket-0"
+        );
+        // Accidental double suffixes are collapsed
+        assert_eq!(
+            origin_bucket_id(
+                "bucket-0-synced-from-device-0-synced-from-device-0",
+                "device-0"
+            ),
+            "bucket-0"
+        );
+        // Origins containing the delimiter
+        assert_eq!(
+            origin_bucket_id("bucket-0-synced-from-a-synced-from-b", "a-synced-from-b"),
+            "bucket-0"
+        );
+        // Suffixes of other origins are part of the bucket ID
+        assert_eq!(
+            origin_bucket_id("bucket-0-synced-from-device-1", "device-0"),
+            "bucket-0-synced-from-device-1"
+        );
+        // Origins which are a suffix of another origin
+        assert_eq!(
+            origin_bucket_id("bucket-0-synced-from-my-device-0", "device-0"),
+            "bucket-0-synced-from-my-device-0"
+        );
+        assert_eq!(origin_bucket_id("", "device-0"), "");
+        assert_eq!(origin_bucket_id("-synced-from-device-0", "device-0"), "");
+    }
+
+    #[test]
+    fn test_bucket_name_template() {
+        for invalid in [
+            "{id}",
+            "{origin}",
+            "{id}{origin}",
+            "{id}@{origin}@{origin}",
+            "{id}@{origin}-{host}",
+            "{id}/{origin}",
+            "{id} from {origin}",
+        ] {
+            assert!(BucketNameTemplate::parse(invalid).is_err(), "{invalid}");
+        }
+
+        let names = BucketNameTemplate::parse("{id}@{origin}").unwrap();
+        assert_eq!(names.format("bucket-0", "device-0"), "bucket-0@device-0");
+        assert_eq!(names.source_id("bucket-0@device-0", "device-0"), "bucket-0");
+        assert_eq!(
+            names.source_id("bucket-0@device-0@device-0", "device-0"),
+            "bucket-0"
+        );
+        assert_eq!(
+            names.source_id("bucket-0@device-1", "device-0"),
+            "bucket-0@device-1"
+        );
+        // The origin may come first too
+        let prefixed = BucketNameTemplate::parse("{origin}.{id}").unwrap();
+        assert_eq!(
+            prefixed.source_id(&prefixed.format("bucket-0", "device-0"), "device-0"),
+            "bucket-0"
+        );
+
+        let state = init_teststate();
+        create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, "bucket-0", 3);
+        let sync_spec = SyncSpec {
+            bucket_names: names,
+            ..SyncSpec::default()
+        };
+        let mut sync_state = SyncState::default();
+        for _ in 0..2 {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                Some("device-0"),
+                &sync_spec,
+                &mut sync_state,
+                &NoProgress,
+            )
+            .unwrap();
+        }
+        let buckets_dest = state.ds_dest.get_buckets().unwrap();
+        assert_eq!(
+            buckets_dest.keys().collect::<Vec<_>>(),
+            vec!["bucket-0@device-0"]
+        );
+        assert_eq!(
+            state
+                .ds_dest
+                .get_event_count("bucket-0@device-0", None, None)
+                .unwrap(),
+            3
+        );
+
+        // Pulling the pulled bucket again keeps its name, and it's never pushed back
+        let ds_other = Datastore::new_in_memory(false);
+        aw_sync::sync_datastores(
+            &state.ds_dest,
+            &ds_other,
+            false,
+            Some("device-0"),
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert!(ds_other.get_bucket("bucket-0@device-0").is_ok());
+        let ds_staging = Datastore::new_in_memory(false);
+        aw_sync::sync_datastores(
+            &state.ds_dest,
+            &ds_staging,
+            true,
+            Some("device-1"),
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert!(ds_staging.get_buckets().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_adversarial_bucket_ids() {
+        let state = init_teststate();
+        let bucket_jsonstr = r#"{
+            "id": "bucket-0-synced-from-a-synced-from-b-synced-from-a-synced-from-b",
+            "type": "test",
+            "hostname": "a-synced-from-b",
+            "client": "test"
+        }"#;
+        let bucket: Bucket = serde_json::from_str(bucket_jsonstr).unwrap();
+        state.ds_src.create_bucket(&bucket).unwrap();
+
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let bucket_ids: Vec<String> = state.ds_dest.get_buckets().unwrap().into_keys().collect();
+        assert_eq!(bucket_ids, vec!["bucket-0-synced-from-a-synced-from-b"]);
+    }
+
+    #[test]
+    fn test_find_remotes_skips_non_db() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-find-remotes-{}", std::process::id()));
+        let device_dir = sync_dir.join("device-0");
+        fs::create_dir_all(device_dir.join(".hidden")).unwrap();
+        fs::write(sync_dir.join("foo"), "").unwrap();
+        fs::write(device_dir.join("foo"), "").unwrap();
+        fs::write(device_dir.join("test.db"), "").unwrap();
+
+        let remotes = find_remotes(&sync_dir).unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(remotes, vec![device_dir.join("test.db")]);
+    }
+
+    #[test]
+    fn test_find_remotes_skips_conflict_copies() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-conflicts-{}", std::process::id()));
+        let device_dir = sync_dir.join("device-0");
+        fs::create_dir_all(&device_dir).unwrap();
+        fs::create_dir_all(sync_dir.join(".stversions").join("device-0")).unwrap();
+        for filename in [
+            "test.db",
+            "test.sync-conflict-20240101-120000-ABCDEF.db",
+            "test (Laptop's conflicted copy 2024-01-01).db",
+        ] {
+            fs::write(device_dir.join(filename), "").unwrap();
+        }
+        fs::write(
+            sync_dir
+                .join(".stversions")
+                .join("device-0")
+                .join("test~20240101-120000.db"),
+            "",
+        )
+        .unwrap();
+
+        let remotes = find_remotes(&sync_dir).unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(remotes, vec![device_dir.join("test.db")]);
+    }
+
+    #[test]
+    fn test_list_remotes() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-list-remotes-{}", std::process::id()));
+        for device in ["device-0", "device-1", "device-2"] {
+            fs::create_dir_all(sync_dir.join(device)).unwrap();
+        }
+        let ds_local = create_file_datastore(&sync_dir.join("device-0").join("device-0.db"));
+        let ds_1 = create_file_datastore(&sync_dir.join("device-1").join("device-1.db"));
+        let ds_2 = create_file_datastore(&sync_dir.join("device-2").join("device-2.db"));
+        let bucket_id = create_bucket(&ds_1, 1);
+        create_events(&ds_1, bucket_id.as_str(), 3);
+        ds_2.force_commit().unwrap();
+        let last_event = ds_1
+            .get_events(bucket_id.as_str(), None, None, Some(1))
+            .unwrap()[0]
+            .calculate_endtime();
+        ds_local.close();
+        ds_1.close();
+        ds_2.close();
+        // Give the workers some time to commit before the dbs are opened again
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let remotes = list_remotes(&sync_dir, "device-0").unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        let devices: Vec<&str> = remotes.iter().map(|r| r.device.as_str()).collect();
+        assert_eq!(devices, vec!["device-1", "device-2"]);
+        assert!(remotes.iter().all(|r| r.modified.is_some()));
+        assert_eq!(remotes[0].last_event, Some(last_event));
+        assert_eq!(remotes[1].last_event, None);
+    }
+
+    #[test]
+    fn test_prune_remotes() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-prune-{}", std::process::id()));
+        for device in ["device-0", "device-1", "device-2"] {
+            fs::create_dir_all(sync_dir.join(device)).unwrap();
+        }
+        let ds_local = create_file_datastore(&sync_dir.join("device-0").join("device-0.db"));
+        let ds_1 = create_file_datastore(&sync_dir.join("device-1").join("device-1.db"));
+        let ds_2 = create_file_datastore(&sync_dir.join("device-2").join("device-2.db"));
+        ds_local.force_commit().unwrap();
+        ds_1.force_commit().unwrap();
+        // device-2 has recent events, so is kept even though its db wasn't modified recently
+        let bucket_id = create_bucket(&ds_2, 2);
+        create_events(&ds_2, bucket_id.as_str(), 1);
+        ds_local.close();
+        ds_1.close();
+        ds_2.close();
+        // Give the workers some time to commit before the dbs are opened again
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let long_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(30 * 86400);
+        for device in ["device-0", "device-1", "device-2"] {
+            fs::File::options()
+                .write(true)
+                .open(sync_dir.join(device).join(format!("{device}.db")))
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+        }
+
+        let stale = find_stale_remotes(&sync_dir, "device-0", Duration::days(7)).unwrap();
+        assert_eq!(stale, vec![sync_dir.join("device-1")]);
+        assert!(
+            find_stale_remotes(&sync_dir, "device-0", Duration::days(60))
+                .unwrap()
+                .is_empty()
+        );
+
+        let removed = prune_remotes(&sync_dir, "device-0", Duration::days(7)).unwrap();
+        let local_exists = sync_dir.join("device-0").exists();
+        let remaining = find_remotes(&sync_dir).unwrap().len();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(removed, vec![sync_dir.join("device-1")]);
+        // The local device is never pruned, no matter how stale
+        assert!(local_exists);
+        assert_eq!(remaining, 2);
+    }
+
+    #[test]
+    fn test_check_sync_directory() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-doctor-{}", std::process::id()));
+        let statuses = |checks: Vec<Check>| -> Vec<(&'static str, CheckStatus)> {
+            checks.into_iter().map(|c| (c.name, c.status)).collect()
+        };
+
+        let missing = check_sync_directory(&sync_dir, "device-0", None, None, None);
+        assert_eq!(
+            statuses(missing),
+            vec![("sync directory exists", CheckStatus::Fail)]
+        );
+
+        // Nothing pushed yet
+        fs::create_dir_all(&sync_dir).unwrap();
+        let empty = check_sync_directory(&sync_dir, "device-0", None, None, None);
+
+        let staging = sync_dir.join("device-0").join("device-0.db");
+        fs::create_dir_all(staging.parent().unwrap()).unwrap();
+        let ds = create_file_datastore(&staging);
+        create_bucket(&ds, 0);
+        ds.force_commit().unwrap();
+        ds.close();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let pushed = check_sync_directory(&sync_dir, "device-0", None, None, None);
+        // The check doesn't leave files behind
+        let files = fs::read_dir(&sync_dir).unwrap().count();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(
+            statuses(empty),
+            vec![
+                ("sync directory exists", CheckStatus::Pass),
+                ("sync directory writable", CheckStatus::Pass),
+                ("remotes found", CheckStatus::Warn),
+                ("device folder exists", CheckStatus::Fail),
+            ]
+        );
+        let pushed = statuses(pushed);
+        assert_eq!(pushed[2], ("remotes found", CheckStatus::Warn));
+        assert_eq!(pushed[3], ("device folder exists", CheckStatus::Pass));
+        assert_eq!(pushed[4], ("staging datastore opens", CheckStatus::Pass));
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn test_verify() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-verify-{}", std::process::id()));
+        for device in ["device-0", "device-1"] {
+            fs::create_dir_all(sync_dir.join(device)).unwrap();
+        }
+        // device-0 is the local device, with the in-memory datastore as its server
+        let local = Datastore::new_in_memory(false);
+        let ds_staging = create_file_datastore(&sync_dir.join("device-0").join("device-0.db"));
+        let ds_remote = create_file_datastore(&sync_dir.join("device-1").join("device-1.db"));
+        let bucket_local = create_bucket(&local, 0);
+        create_events(&local, &bucket_local, 2);
+        let bucket_remote = create_bucket(&ds_remote, 1);
+        create_events(&ds_remote, &bucket_remote, 3);
+        for (ds_from, ds_to, is_push) in [(&local, &ds_staging, true), (&ds_remote, &local, false)]
+        {
+            aw_sync::sync_datastores(
+                ds_from,
+                ds_to,
+                is_push,
+                None,
+                &SyncSpec::default(),
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+        }
+        // Not pushed yet
+        create_events(&local, &bucket_local, 1);
+        ds_staging.close();
+        ds_remote.close();
+        // Give the workers some time to commit before the dbs are opened again
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let names = BucketNameTemplate::default();
+        let results = verify(
+            &sync_dir, &local, "device-0", None, None, None, None, &names,
+        )
+        .unwrap();
+        let only_remote = verify(
+            &sync_dir,
+            &local,
+            "device-0",
+            Some(&[bucket_remote]),
+            None,
+            None,
+            None,
+            &names,
+        )
+        .unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(
+            results,
+            vec![
+                BucketVerification {
+                    remote: "device-1".to_string(),
+                    source_bucket: "bucket-1".to_string(),
+                    synced_bucket: "bucket-1-synced-from-device-1".to_string(),
+                    source_count: 3,
+                    synced_count: Some(3),
+                    delta: 0,
+                },
+                BucketVerification {
+                    remote: "device-0".to_string(),
+                    source_bucket: "bucket-0".to_string(),
+                    synced_bucket: "bucket-0".to_string(),
+                    source_count: 3,
+                    synced_count: Some(2),
+                    delta: 1,
+                },
+            ]
+        );
+        assert!(!results[0].is_mismatch(0));
+        assert!(results[1].is_mismatch(0));
+        assert!(!results[1].is_mismatch(1));
+        assert_eq!(only_remote, results[..1]);
+    }
+
+    #[test]
+    fn test_push_to_staging_atomic() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-atomic-push-{}", std::process::id()));
+        let dbfile = sync_dir.join("device-0").join("device-0.db");
+        let tmpfile = sync_dir.join("device-0").join("device-0.db.tmp");
+        fs::create_dir_all(dbfile.parent().unwrap()).unwrap();
+        let local = Datastore::new_in_memory(false);
+        let bucket_id = create_bucket(&local, 0);
+        create_events(&local, &bucket_id, 2);
+
+        let pushed = aw_sync::push_to_staging(
+            &local,
+            &dbfile,
+            "device-0",
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(pushed.events_inserted, 2);
+        assert!(dbfile.exists());
+        assert!(!tmpfile.exists());
+        assert!(dbfile.with_extension("version.json").exists());
+
+        // A push which fails, as the source is unreachable
+        create_events(&local, &bucket_id, 1);
+        let unreachable = HttpRemote::new("http://127.0.0.1:1");
+        let result = aw_sync::push_to_staging(
+            &unreachable,
+            &dbfile,
+            "device-0",
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        );
+        assert!(result.is_err());
+        assert!(!tmpfile.exists());
+
+        // The previous staging datastore is left intact
+        let ds_staging = create_datastore(&dbfile).unwrap();
+        let count = ds_staging.get_event_count(&bucket_id, None, None).unwrap();
+        ds_staging.close();
+        fs::remove_dir_all(&sync_dir).unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_encrypted_staging() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-encrypted-{}", std::process::id()));
+        let dbfile = sync_dir.join("device-0").join("device-0.db");
+        fs::create_dir_all(dbfile.parent().unwrap()).unwrap();
+        let local = Datastore::new_in_memory(false);
+        let bucket_id = create_bucket(&local, 0);
+        create_events(&local, &bucket_id, 2);
+
+        let sync_spec = SyncSpec {
+            passphrase: Some("correct horse".to_string()),
+            ..SyncSpec::default()
+        };
+        let push = |sync_spec: &SyncSpec| {
+            aw_sync::push_to_staging(
+                &local,
+                &dbfile,
+                "device-0",
+                sync_spec,
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+        };
+        push(&sync_spec).unwrap();
+        assert!(is_encrypted(&dbfile));
+        assert!(!dbfile.with_extension("db.tmp").exists());
+
+        // Pushing again decrypts the previous push first
+        create_events(&local, &bucket_id, 1);
+        push(&sync_spec).unwrap();
+        // Encrypted staging datastores can't be pushed to without the passphrase
+        assert!(push(&SyncSpec::default()).is_err());
+
+        let (ds_staging, plaintext) = open_remote(&dbfile, Some("correct horse")).unwrap();
+        let count = ds_staging.get_event_count(&bucket_id, None, None).unwrap();
+        ds_staging.close();
+        let plaintext_path = plaintext.as_ref().unwrap().path().to_path_buf();
+        // The plaintext copy is outside the sync folder, and removed once dropped
+        assert!(!plaintext_path.starts_with(&sync_dir));
+        // Only readable by the current user, in a directory only they can access
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&plaintext_path), 0o600);
+            assert_eq!(mode(plaintext_path.parent().unwrap()), 0o700);
+        }
+        drop(plaintext);
+        assert!(!plaintext_path.parent().unwrap().exists());
+
+        let wrong_passphrase = open_remote(&dbfile, Some("wrong")).map(|_| ());
+        let no_passphrase = open_remote(&dbfile, None).map(|_| ());
+        fs::remove_dir_all(&sync_dir).unwrap();
+        assert_eq!(count, 3);
+        assert!(matches!(wrong_passphrase, Err(SyncError::Crypto(_))));
+        assert!(matches!(no_passphrase, Err(SyncError::Config(_))));
+    }
+
+    #[test]
+    fn test_instance_staging_dbs() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-instances-{}", std::process::id()));
+        // Two instances on one remote device, each with its own staging db
+        let paths = [
+            sync_dir.join("device-1").join("main").join("device-1.db"),
+            sync_dir
+                .join("device-1")
+                .join("testing")
+                .join("device-1.db"),
+        ];
+        for path in &paths {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let ds = create_file_datastore(path);
+            ds.force_commit().unwrap();
+            ds.close();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let mut found = find_remotes(&sync_dir).unwrap();
+        found.sort();
+        let remotes = list_remotes(&sync_dir, "device-0").unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(found, paths.to_vec());
+        // Both belong to the same device
+        assert_eq!(remotes.len(), 2);
+        assert!(remotes.iter().all(|r| r.device == "device-1"));
+    }
+
+    #[test]
+    fn test_folder_store() {
+        let root = std::env::temp_dir().join(format!("aw-sync-test-store-{}", std::process::id()));
+        let (store_dir, sync_dir) = (root.join("store"), root.join("mirror"));
+        for device in ["device-0", "device-1"] {
+            let path = store_dir.join(device).join(format!("{device}.db"));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let ds = create_file_datastore(&path);
+            ds.force_commit().unwrap();
+            ds.close();
+            write_version_marker(&path).unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let store = FolderStore::new(store_dir.clone());
+
+        assert_eq!(download_remotes(&store, &sync_dir, "device-0").unwrap(), 1);
+        let downloaded = find_remotes(&sync_dir).unwrap();
+        assert_eq!(
+            downloaded,
+            vec![sync_dir.join("device-1").join("device-1.db")]
+        );
+        assert!(sync_dir
+            .join("device-1")
+            .join("device-1.version.json")
+            .exists());
+
+        // Only the own staging datastore is uploaded
+        let dbfile = sync_dir.join("device-0").join("device-0.db");
+        fs::create_dir_all(dbfile.parent().unwrap()).unwrap();
+        fs::write(&dbfile, b"pushed").unwrap();
+        upload_staging(&store, &sync_dir, &dbfile).unwrap();
+        let uploaded = fs::read(store_dir.join("device-0").join("device-0.db")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(uploaded, b"pushed");
+
+        let config: SyncConfig = toml::from_str(
+            r#"
+            [remote_store]
+            kind = "s3"
+            endpoint = "https://s3.eu-central-1.amazonaws.com"
+            bucket = "aw"
+            region = "eu-central-1"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            config.remote_store,
+            Some(RemoteStoreConfig::S3 { ref prefix, .. }) if prefix.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_remotes_with_prefixed_device_ids() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-prefixed-{}", std::process::id()));
+        // The local device ID is a prefix of, or contained in, the IDs of the other devices
+        for device in ["device-1", "device-10", "my-device-1"] {
+            let path = sync_dir.join(device).join(format!("{device}.db"));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let ds = create_file_datastore(&path);
+            ds.force_commit().unwrap();
+            ds.close();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let remotes = list_remotes(&sync_dir, "device-1").unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        let devices: Vec<&str> = remotes.iter().map(|r| r.device.as_str()).collect();
+        assert_eq!(devices, vec!["device-10", "my-device-1"]);
+    }
+
+    #[test]
+    fn test_nested_remotes() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-nested-{}", std::process::id()));
+        // Device folders organized by OS, next to the local device folder
+        let paths = [
+            sync_dir.join("linux").join("device-1").join("device-1.db"),
+            sync_dir.join("linux").join("device-2").join("device-2.db"),
+            sync_dir
+                .join("windows")
+                .join("device-3")
+                .join("device-3.db"),
+        ];
+        let local = sync_dir.join("device-0").join("device-0.db");
+        for path in paths.iter().chain([&local]) {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let ds = create_file_datastore(path);
+            ds.force_commit().unwrap();
+            ds.close();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let found = find_remotes(&sync_dir).unwrap();
+        let remotes = list_remotes(&sync_dir, "device-0").unwrap();
+        let stale = find_stale_remotes(&sync_dir, "device-0", Duration::zero()).unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(
+            found,
+            vec![local, paths[0].clone(), paths[1].clone(), paths[2].clone()]
+        );
+        // Named after their own folders, not the OS folders
+        let devices: Vec<&str> = remotes.iter().map(|r| r.device.as_str()).collect();
+        assert_eq!(devices, vec!["device-1", "device-2", "device-3"]);
+        let stale_expected: Vec<PathBuf> = paths
+            .iter()
+            .map(|p| p.parent().unwrap().to_path_buf())
+            .collect();
+        assert_eq!(stale, stale_expected);
+    }
+
+    #[test]
+    fn test_find_remotes_sorted() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-order-{}", std::process::id()));
+        // Created out of order
+        for device in ["device-c", "device-a", "device-d", "device-b"] {
+            let dir = sync_dir.join(device);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(format!("{device}.db")), b"").unwrap();
+        }
+
+        let found: Vec<Vec<PathBuf>> = (0..3).map(|_| find_remotes(&sync_dir).unwrap()).collect();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        let expected: Vec<PathBuf> = ["device-a", "device-b", "device-c", "device-d"]
+            .iter()
+            .map(|device| sync_dir.join(device).join(format!("{device}.db")))
+            .collect();
+        assert!(found.iter().all(|remotes| *remotes == expected));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_remotes_linked_twice() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-links-{}", std::process::id()));
+        for device in [
+            "device-a",
+            "device-b",
+            "device-c",
+            "device-local",
+            "device-z",
+        ] {
+            fs::create_dir_all(sync_dir.join(device)).unwrap();
+        }
+        let db = sync_dir.join("device-a").join("device-a.db");
+        fs::write(&db, b"").unwrap();
+        std::os::unix::fs::symlink(&db, sync_dir.join("device-b").join("device-b.db")).unwrap();
+        fs::hard_link(&db, sync_dir.join("device-c").join("device-c.db")).unwrap();
+        // A link to the own db, from the folder of another device, found before the own db
+        let local_db = sync_dir.join("device-local").join("device-local.db");
+        fs::write(&local_db, b"").unwrap();
+        let local_link = sync_dir.join("device-0").join("device-0.db");
+        std::os::unix::fs::symlink(&local_db, &local_link).unwrap();
+
+        let remotes = find_remotes(&sync_dir).unwrap();
+        let checks = check_sync_directory(&sync_dir, "device-local", None, None, None);
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(remotes, vec![local_link, db]);
+        let remotes_found = checks.iter().find(|c| c.name == "remotes found").unwrap();
+        assert_eq!(remotes_found.detail, "1 remote dbs");
+    }
+
+    #[test]
+    fn test_skip_incompatible_remotes() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-version-{}", std::process::id()));
+        fs::create_dir_all(&sync_dir).unwrap();
+        let legacy = sync_dir.join("legacy.db");
+        let current = sync_dir.join("current.db");
+        let future = sync_dir.join("future.db");
+        write_version_marker(&current).unwrap();
+        fs::write(
+            sync_dir.join("future.version.json"),
+            format!(r#"{{"version": {}}}"#, SYNC_VERSION + 1),
+        )
+        .unwrap();
+
+        let remotes =
+            filter_compatible_remotes(vec![legacy.clone(), current.clone(), future.clone()]);
+        let future_check = check_version_compatible(&future);
+
+        // Markers are only rewritten when their version changes
+        let marker = sync_dir.join("current.version.json");
+        let unchanged = format!(r#"{{ "version": {SYNC_VERSION} }}"#);
+        fs::write(&marker, &unchanged).unwrap();
+        write_version_marker(&current).unwrap();
+        let kept = fs::read_to_string(&marker).unwrap();
+        write_version_marker(&legacy).unwrap();
+        let added = sync_dir.join("legacy.version.json").exists();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert_eq!(kept, unchanged);
+        assert!(added);
+
+        assert_eq!(remotes, vec![legacy, current]);
+        assert!(matches!(
+            future_check,
+            Err(SyncError::Incompatible { found, expected }) if found == SYNC_VERSION + 1 && expected == SYNC_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_remotes_opened_readonly() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-readonly-{}", std::process::id()));
+        fs::create_dir_all(&sync_dir).unwrap();
+        let path = sync_dir.join("remote.db");
+        let ds = create_file_datastore(&path);
+        let bucket_id = create_bucket(&ds, 0);
+        create_events(&ds, bucket_id.as_str(), 3);
+        ds.close();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let ds_remote = create_datastore(&path).unwrap();
+        let events = ds_remote
+            .get_events(bucket_id.as_str(), None, None, None)
+            .unwrap();
+        let inserted = ds_remote.insert_events(bucket_id.as_str(), &events);
+        let count = ds_remote
+            .get_event_count(bucket_id.as_str(), None, None)
+            .unwrap();
+        ds_remote.close();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert!(inserted.is_err());
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_repair() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-repair-{}", std::process::id()));
+        fs::create_dir_all(sync_dir.join("device-1")).unwrap();
+        let local = Datastore::new_in_memory(false);
+        let ds_remote = create_file_datastore(&sync_dir.join("device-1").join("device-1.db"));
+        let bucket_remote = create_bucket(&ds_remote, 1);
+        create_events(&ds_remote, &bucket_remote, 6);
+        aw_sync::sync_datastores(
+            &ds_remote,
+            &local,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let bucket_id = "bucket-1-synced-from-device-1";
+
+        // Leave a gap, and lose the last event too
+        let mut synced = local.get_events(bucket_id, None, None, None).unwrap();
+        synced.reverse();
+        let lost: Vec<i64> = [2, 3, 5].iter().map(|&i| synced[i].id.unwrap()).collect();
+        local.delete_events_by_id(bucket_id, lost).unwrap();
+        local.force_commit().unwrap();
+        ds_remote.close();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let (start, end) = (synced[1].timestamp, synced[4].timestamp);
+        let mut sync_spec = SyncSpec {
+            path: sync_dir.clone(),
+            dry_run: true,
+            ..SyncSpec::default()
+        };
+        let summary = repair(&local, "device-0", bucket_id, start, end, &sync_spec).unwrap();
+        assert_eq!((summary.deleted, summary.pulled), (1, 3));
+        assert_eq!(AccessMethod::get_event_count(&local, bucket_id), Ok(3));
+
+        sync_spec.dry_run = false;
+        let summary = repair(&local, "device-0", bucket_id, start, end, &sync_spec).unwrap();
+        assert_eq!(summary.remote, "device-1");
+        assert_eq!(summary.source_bucket, "bucket-1");
+        assert_eq!((summary.deleted, summary.pulled), (1, 3));
+        let mut repaired = local.get_events(bucket_id, None, None, None).unwrap();
+        repaired.reverse();
+        let timestamps: Vec<DateTime<Utc>> = repaired.iter().map(|e| e.timestamp).collect();
+        // The event after the window is still missing
+        let expected: Vec<DateTime<Utc>> = synced[..5].iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, expected);
+
+        // Only pulled buckets can be repaired
+        let result = repair(&local, "device-0", "bucket-1", start, end, &sync_spec);
+        assert!(matches!(result, Err(SyncError::Config(_))));
+        fs::remove_dir_all(&sync_dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_remote_integrity() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-integrity-{}", std::process::id()));
+        fs::create_dir_all(&sync_dir).unwrap();
+        let valid = sync_dir.join("valid.db");
+        let corrupt = sync_dir.join("corrupt.db");
+        fs::write(&corrupt, "not a sqlite database, just a partial download").unwrap();
+        let ds = create_file_datastore(&valid);
+        ds.force_commit().unwrap();
+        ds.close();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let ds_valid = create_datastore(&valid).unwrap();
+        let ds_corrupt = create_datastore(&corrupt).unwrap();
+        let valid_ok = verify_remote_integrity(&valid, &ds_valid);
+        let corrupt_ok = verify_remote_integrity(&corrupt, &ds_corrupt);
+        ds_valid.close();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        assert!(valid_ok);
+        assert!(!corrupt_ok);
+    }
+
+    #[test]
+    fn test_sync_config() {
+        let dir = std::env::temp_dir().join(format!("aw-sync-test-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.toml");
+        fs::write(
+            &config_path,
+            format!(
+                r#"
+                sync_directory = '{}'
+                buckets = ["bucket-0"]
+
+                [hosts.laptop]
+                buckets_regex = "bucket-.*"
+                "#,
+                dir.display()
+            ),
+        )
+        .unwrap();
+
+        let config = SyncConfig::load(&config_path).unwrap();
+        let missing = SyncConfig::load(&dir.join("missing.toml")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.sync_directory, Some(dir.clone()));
+        assert_eq!(config.buckets, Some(vec!["bucket-0".to_string()]));
+        assert_eq!(config.buckets_regex, None);
+        assert_eq!(config.for_host("desktop"), config);
+
+        let laptop = config.for_host("laptop");
+        assert_eq!(laptop.buckets_regex, Some("bucket-.*".to_string()));
+        assert_eq!(laptop.buckets, config.buckets);
+
+        assert_eq!(missing, SyncConfig::default());
+
+        // The sync directory was removed, and empty bucket lists are rejected
+        assert!(config.validate().is_err());
+        let empty_buckets = SyncConfig {
+            buckets: Some(vec![]),
+            ..SyncConfig::default()
+        };
+        assert!(empty_buckets.validate().is_err());
+        assert!(SyncConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_device_id_override() {
+        let config: SyncConfig = toml::from_str(
+            r#"
+            device_id = "old-device"
+
+            [hosts.laptop]
+            device_id = "3ec4a1f2-8c3a-4b5e-9d3c-1f2e3d4c5b6a"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.device_id.as_deref(), Some("old-device"));
+        assert_eq!(
+            config.for_host("laptop").device_id.as_deref(),
+            Some("3ec4a1f2-8c3a-4b5e-9d3c-1f2e3d4c5b6a")
+        );
+        assert!(config.validate().is_ok());
+
+        for invalid in [
+            "",
+            ".stversions",
+            "../other",
+            "my device",
+            "device/0",
+            &"a".repeat(65),
+        ] {
+            assert!(
+                validate_device_id(invalid).is_err(),
+                "{invalid:?} was accepted"
+            );
+            let config = SyncConfig {
+                device_id: Some(invalid.to_string()),
+                ..SyncConfig::default()
+            };
+            assert!(matches!(config.validate(), Err(SyncError::Config(_))));
+        }
+        assert!(validate_device_id("laptop.home_1").is_ok());
+    }
+
+    #[test]
+    fn test_exclude_events() {
+        let dir = std::env::temp_dir().join(format!("aw-sync-test-exclude-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [[exclude.bucket-0]]
+            field = "test"
+            regex = "^secret"
+
+            [[exclude.bucket-0]]
+            field = "test"
+            equals = "hidden"
+
+            [[exclude.bucket-1]]
+            field = "test"
+            equals = "public"
+            "#,
+        )
+        .unwrap();
+        let config = SyncConfig::load(&config_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        config.validate().unwrap();
+
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        let mut events: Vec<Event> = (0..4)
+            .map(|i| create_event(&format!(r#""secret-{i}""#)))
+            .collect();
+        events.push(create_event(r#""hidden""#));
+        events.extend((0..5).map(|_| create_event(r#""public""#)));
+        state.ds_src.insert_events(&bucket_id, &events).unwrap();
+
+        let sync_spec = SyncSpec {
+            exclude: config.exclude_rules().unwrap(),
+            ..Default::default()
+        };
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let synced = state
+            .ds_dest
+            .get_events("bucket-0-synced-from-device-0", None, None, None)
+            .unwrap();
+        assert_eq!(stats.events_inserted, 5);
+        assert_eq!(synced.len(), 5);
+        assert!(synced
+            .iter()
+            .all(|e| e.data.get("test") == Some(&serde_json::json!("public"))));
+
+        // Rules need exactly one of equals and regex
+        let invalid = SyncConfig {
+            exclude: HashMap::from([(
+                "bucket-0".to_string(),
+                vec![EventRuleConfig {
+                    field: "test".to_string(),
+                    equals: None,
+                    regex: None,
+                }],
+            )]),
+            ..SyncConfig::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_redact_events() {
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        let events: Vec<Event> = ["https://example.com/a", "https://example.com/a", "x"]
+            .iter()
+            .map(|url| {
+                let mut event = create_event("0");
+                event.duration = Duration::seconds(30);
+                event.data = serde_json::from_value(serde_json::json!({
+                    "title": "Secret document",
+                    "url": url,
+                    "app": "firefox",
+                }))
+                .unwrap();
+                event
+            })
+            .collect();
+        state.ds_src.insert_events(&bucket_id, &events).unwrap();
+
+        let sync_spec = SyncSpec {
+            redact: HashMap::from([(
+                bucket_id.clone(),
+                HashMap::from([
+                    ("title".to_string(), Redaction::Replace),
+                    ("url".to_string(), Redaction::Hash),
+                ]),
+            )]),
+            redact_salt: "salt".to_string(),
+            ..Default::default()
+        };
+        // Synced twice, as redacted events need to be deduplicated against the destination
+        for _ in 0..2 {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &sync_spec,
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+        }
+
+        let mut synced = state
+            .ds_dest
+            .get_events("bucket-0-synced-from-device-0", None, None, None)
+            .unwrap();
+        synced.sort_by_key(|e| e.timestamp);
+        assert_eq!(synced.len(), 3);
+        for (event, synced) in events.iter().zip(&synced) {
+            // The timeline is preserved
+            assert_eq!(event.timestamp, synced.timestamp);
+            assert_eq!(event.duration, synced.duration);
+            assert_eq!(synced.data["title"], serde_json::json!(REDACTED));
+            assert_eq!(synced.data["app"], serde_json::json!("firefox"));
+            let url = synced.data["url"].as_str().unwrap();
+            assert_eq!(url.len(), 64);
+            assert!(!url.contains("example.com"));
+        }
+        // Equal values hash equally
+        assert_eq!(synced[0].data["url"], synced[1].data["url"]);
+        assert_ne!(synced[0].data["url"], synced[2].data["url"]);
+
+        // Hashing without a salt is rejected
+        let config = SyncConfig {
+            redact: sync_spec.redact.clone(),
+            ..SyncConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_with_retry() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: std::time::Duration::from_millis(1),
+        };
+
+        // Fails the first two requests, then succeeds
+        let mut attempts = 0;
+        let result = with_retry(&policy, "get info", || {
+            attempts += 1;
+            if attempts <= 2 {
+                Err("connection refused")
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        // Gives up after the maximum number of attempts
+        let mut attempts = 0;
+        let result: Result<(), &str> = with_retry(&policy, "get info", || {
+            attempts += 1;
+            Err("connection refused")
+        });
+        assert_eq!(result, Err("connection refused"));
+        assert_eq!(attempts, 3);
+
+        // Errors which aren't retryable are returned right away
+        let mut attempts = 0;
+        let result: Result<(), &str> = with_retry_if(
+            &policy,
+            "insert event",
+            |e| *e == "connection refused",
+            || {
+                attempts += 1;
+                Err("bad request")
+            },
+        );
+        assert_eq!(result, Err("bad request"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_heartbeat_retrying() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: std::time::Duration::ZERO,
+        };
+        let heartbeat = |status: &'static str| {
+            let (port, requests) = serve(status, "{}".to_string());
+            let client = aw_client_rust::AwClient::new("127.0.0.1", &port, "aw-sync-test");
+            let result = client.heartbeat_retrying("bucket-0", create_event("0"), 0.0, &policy);
+            assert!(result.is_err());
+            requests.load(Ordering::SeqCst)
+        };
+
+        // Server errors are retried, as repeating a heartbeat is safe
+        assert_eq!(heartbeat("503 Service Unavailable"), 3);
+        // Client errors aren't, as they fail the same way again
+        assert_eq!(heartbeat("400 Bad Request"), 1);
+    }
+
+    #[test]
+    fn test_http_remote_url() {
+        let remote = HttpRemote::new("https://aw.example.com:5600/");
+        assert_eq!(remote.url(), "https://aw.example.com:5600");
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = std::env::temp_dir().join(format!("aw-sync-test-export-{}", std::process::id()));
+        let remote = dir.join("device-1").join("device-1.db");
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        let ds = create_file_datastore(&remote);
+        for n in 1..=2 {
+            let bucket_id = create_bucket(&ds, n);
+            create_events(&ds, &bucket_id, n as i64 * 2);
+        }
+        ds.force_commit().unwrap();
+        ds.close();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let out = dir.join("export.json");
+        let exported = export(&remote, &out, None).unwrap();
+        assert_eq!(exported.buckets.len(), 2);
+        let content = fs::read_to_string(&out).unwrap();
+        let missing = export(&dir.join("device-2.db"), &out, None);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(missing, Err(SyncError::Io(_))));
+
+        // Can be read back as an aw-server export
+        let parsed: BucketsExport = serde_json::from_str(&content).unwrap();
+        let mut counts: Vec<(String, usize)> = parsed
+            .buckets
+            .into_iter()
+            .map(|(id, b)| (id, b.events.unwrap().take_inner().len()))
+            .collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![("bucket-1".to_string(), 2), ("bucket-2".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn test_json_export_source() {
+        let content = serde_json::json!({
+            "buckets": {
+                "aw-watcher-window_old": {
+                    "id": "aw-watcher-window_old",
+                    "type": "currentwindow",
+                    "client": "aw-watcher-window",
+                    "hostname": "old",
+                    "created": "2019-06-01T00:00:00Z",
+                    "events": [
+                        {
+                            "id": 2,
+                            "timestamp": "2019-06-01T10:00:10Z",
+                            "duration": 5.0,
+                            "data": {"app": "b"}
+                        },
+                        {
+                            "id": 1,
+                            "timestamp": "2019-06-01T10:00:00Z",
+                            "duration": 10.0,
+                            "data": {"app": "a"}
+                        }
+                    ]
+                },
+                // Without an ID, as it's optional
+                "aw-watcher-afk_old": {
+                    "type": "afkstatus",
+                    "client": "aw-watcher-afk",
+                    "hostname": "old",
+                    "created": null,
+                    "events": []
+                }
+            }
+        })
+        .to_string();
+        let source = JsonExport::from_json(&content).unwrap();
+        let window = source.get_bucket("aw-watcher-window_old").unwrap();
+        assert_eq!(window.metadata.end, "2019-06-01T10:00:15Z".parse().ok());
+        assert!(source.get_bucket("aw-watcher-afk_old").is_ok());
+
+        let ds_staging = Datastore::new_in_memory(false);
+        aw_sync::sync_datastores(
+            &source,
+            &ds_staging,
+            true,
+            Some("device-0"),
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let events = ds_staging
+            .get_events("aw-watcher-window_old", None, None, None)
+            .unwrap();
+        let apps: Vec<&serde_json::Value> = events.iter().map(|e| &e.data["app"]).collect();
+        assert_eq!(apps, vec!["b", "a"]);
+
+        // Malformed exports are rejected with where they're malformed
+        let bad_event =
+            content.replace(r#""timestamp":"2019-06-01T10:00:00Z""#, r#""timestamp":5"#);
+        let error = JsonExport::from_json(&bad_event).unwrap_err().to_string();
+        assert!(
+            error.contains("bucket 'aw-watcher-window_old', event 1"),
+            "{error}"
+        );
+        for malformed in [
+            "{",
+            "[]",
+            r#"{"buckets": []}"#,
+            r#"{"buckets": {"a": {"type": "t"}}}"#,
+        ] {
+            assert!(matches!(
+                JsonExport::from_json(malformed),
+                Err(SyncError::InvalidExport(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_insert_rate() {
+        let limiter = RateLimiter::new(100);
+        let started = std::time::Instant::now();
+        // A burst of a second's worth of events isn't limited, the events after it are
+        limiter.acquire(100);
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+        limiter.acquire(20);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(190));
+
+        // Inserting into datastores isn't limited, or this would take a minute
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 60);
+        let sync_spec = SyncSpec {
+            insert_rate: Some(RateLimiter::new(1)),
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(stats.events_inserted, 60);
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_min_free_space() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-free-space-{}", std::process::id()));
+        let dbfile = sync_dir.join("device-0").join("device-0.db");
+        fs::create_dir_all(dbfile.parent().unwrap()).unwrap();
+        let local = Datastore::new_in_memory(false);
+        let bucket_id = create_bucket(&local, 0);
+        create_events(&local, &bucket_id, 2);
+        let push = |min_free_space: u64| {
+            let sync_spec = SyncSpec {
+                min_free_space,
+                ..Default::default()
+            };
+            aw_sync::push_to_staging(
+                &local,
+                &dbfile,
+                "device-0",
+                &sync_spec,
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+        };
+
+        // No disk has this much free
+        let refused = push(u64::MAX / 2);
+        let staged = dbfile.exists();
+        let pushed = push(0);
+        fs::remove_dir_all(&sync_dir).unwrap();
+        assert!(matches!(refused, Err(SyncError::DiskFull { .. })));
+        assert!(!staged);
+        assert_eq!(pushed.unwrap().events_inserted, 2);
+    }
+
+    #[test]
+    fn test_min_duration() {
+        let state = init_teststate();
+        for n in 0..2 {
+            let bucket_id = create_bucket(&state.ds_src, n);
+            // Pings without a duration, and longer events
+            create_events(&state.ds_src, &bucket_id, 3);
+            let mut event = create_event("\"long\"");
+            event.duration = Duration::seconds(5);
+            state.ds_src.insert_events(&bucket_id, &[event]).unwrap();
+        }
+        let config: SyncConfig = toml::from_str(
+            r#"
+            [min_duration]
+            "bucket-0" = 1.0
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let sync_spec = SyncSpec {
+            min_duration: config.min_duration,
+            ..Default::default()
+        };
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &sync_spec,
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let count = |bucket_id: &str| {
+            state
+                .ds_dest
+                .get_event_count(bucket_id, None, None)
+                .unwrap()
+        };
+        assert_eq!(count("bucket-0-synced-from-device-0"), 1);
+        // Only buckets with a minimum duration drop events
+        assert_eq!(count("bucket-1-synced-from-device-1"), 4);
+
+        let negative: SyncConfig = toml::from_str("[min_duration]\n\"bucket-0\" = -1.0").unwrap();
+        assert!(matches!(negative.validate(), Err(SyncError::Config(_))));
+    }
+
+    #[test]
+    fn test_bucket_timings() {
+        let state = init_teststate();
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, &bucket_id, 10);
+        let stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let timings = &stats.buckets[0].timings;
+        assert!(timings.get_events_secs > 0.0);
+        assert!(timings.insert_secs > 0.0);
+        assert!(timings.total_secs() >= timings.get_events_secs + timings.insert_secs);
+        let mut summary = SyncRunSummary::default();
+        summary.record_pull("device-0", stats);
+        let json = serde_json::to_value(&summary).unwrap();
+        assert!(json["buckets"][0]["timings"]["count_secs"].is_number());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_sync_directory() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let mut name = b"aw-sync-test-non-utf8-\xff-".to_vec();
+        name.extend(std::process::id().to_string().bytes());
+        let sync_dir = std::env::temp_dir().join(OsString::from_vec(name));
+        let remote = sync_dir.join("device-1").join("device-1.db");
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        fs::write(&remote, b"").unwrap();
+        let dbfile = sync_dir.join("device-0").join("device-0.db");
+        fs::create_dir_all(dbfile.parent().unwrap()).unwrap();
+
+        let found = find_remotes(&sync_dir);
+        let listed = list_remotes(&sync_dir, "device-0");
+        let opened = create_datastore(&remote);
+        let checks = check_sync_directory(&sync_dir, "device-0", None, None, None);
+        let local = Datastore::new_in_memory(false);
+        create_bucket(&local, 0);
+        let pushed = aw_sync::push_to_staging(
+            &local,
+            &dbfile,
+            "device-0",
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        );
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        // Remotes are still found, but datastores can't be opened by such paths
+        assert_eq!(found.unwrap(), vec![remote]);
+        assert!(matches!(listed, Err(SyncError::InvalidPath(_))));
+        assert!(matches!(opened, Err(SyncError::InvalidPath(_))));
+        assert!(checks.iter().any(|c| c.name == "remotes found"));
+        assert!(matches!(pushed, Err(SyncError::InvalidPath(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_post_hook() {
+        let dir = std::env::temp_dir().join(format!("aw-sync-test-hook-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("pushed");
+        let staging_db = dir.join("staging.db");
+        let summary = SyncRunSummary {
+            events_pushed: 42,
+            ..Default::default()
+        };
+
+        let command = format!(
+            "echo $AW_SYNC_EVENTS_PUSHED $AW_SYNC_STAGING_DB > '{}'",
+            out.display()
+        );
+        assert!(run_post_hook(&command, &summary, &staging_db));
+        let written = fs::read_to_string(&out).unwrap();
+        assert_eq!(written.trim(), format!("42 {}", staging_db.display()));
+
+        // A failing hook is only reported
+        assert!(!run_post_hook("exit 3", &summary, &staging_db));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_audit_buckets() {
+        let ds_a = Datastore::new_in_memory(false);
+        let ds_b = Datastore::new_in_memory(false);
+        let ds_c = Datastore::new_in_memory(false);
+        let ds_d = Datastore::new_in_memory(false);
+        // The same bucket recorded by a and b at the same time, and a week later by c
+        for ds in [&ds_a, &ds_b, &ds_c] {
+            create_bucket(ds, 0);
+        }
+        let now = Utc::now();
+        let insert = |ds: &Datastore, start: DateTime<Utc>| {
+            let mut event = create_event("0");
+            event.timestamp = start;
+            event.duration = Duration::hours(1);
+            ds.insert_events("bucket-0", &[event]).unwrap();
+        };
+        insert(&ds_a, now - Duration::days(10));
+        insert(&ds_b, now - Duration::days(10) + Duration::minutes(30));
+        insert(&ds_c, now - Duration::days(3));
+        // And one of d is far ahead
+        create_bucket(&ds_d, 1);
+        insert(&ds_d, now + Duration::days(1));
+
+        let remotes: Vec<(String, &dyn AccessMethod)> = vec![
+            ("device-a".to_string(), &ds_a),
+            ("device-b".to_string(), &ds_b),
+            ("device-c".to_string(), &ds_c),
+            ("device-d".to_string(), &ds_d),
+        ];
+        let names = BucketNameTemplate::default();
+        let warnings = audit_buckets(&remotes, &names, now, Duration::minutes(5)).unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].issue, AuditIssue::Overlap);
+        assert_eq!(warnings[0].bucket, "bucket-0");
+        assert_eq!(warnings[0].remotes, vec!["device-a", "device-b"]);
+        assert_eq!(warnings[1].issue, AuditIssue::Future);
+        assert_eq!(warnings[1].bucket, "bucket-1");
+        assert_eq!(warnings[1].remotes, vec!["device-d"]);
+
+        // Not in the future if within the tolerance
+        let later = now + Duration::days(2);
+        let warnings = audit_buckets(&remotes, &names, later, Duration::minutes(5)).unwrap();
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_sync_runner_datastore() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-in-process-{}", std::process::id()));
+        let remote_dir = sync_dir.join("device-1");
+        fs::create_dir_all(&remote_dir).unwrap();
+        let ds_remote = create_file_datastore(&remote_dir.join("device-1.db"));
+        let bucket_remote = create_bucket(&ds_remote, 1);
+        create_events(&ds_remote, &bucket_remote, 3);
+        ds_remote.close();
+        drop(ds_remote);
+
+        // Synced without an aw-server to talk to
+        let ds_local = Arc::new(Datastore::new_in_memory(false));
+        let bucket_local = create_bucket(&ds_local, 0);
+        create_events(&ds_local, &bucket_local, 2);
+        let summary = aw_sync::SyncRunner::from_datastore(ds_local.clone(), "device-0", &sync_dir)
+            .configure(|spec| spec.all_buckets = true)
+            .run()
+            .unwrap();
+        assert_eq!(summary.events_pushed, 2);
+        assert_eq!(summary.events_pulled["device-1"], 3);
+
+        let pulled = ds_local.get_events("bucket-1-synced-from-device-1", None, None, None);
+        assert_eq!(pulled.unwrap().len(), 3);
+        let ds_staging = create_file_datastore(&sync_dir.join("device-0").join("device-0.db"));
+        assert_eq!(
+            ds_staging
+                .get_event_count(&bucket_local, None, None)
+                .unwrap(),
+            2
+        );
+        ds_staging.close();
+        fs::remove_dir_all(&sync_dir).unwrap();
+    }
+
+    #[test]
+    fn test_unsynced_buckets() {
+        let ds = Datastore::new_in_memory(false);
+        create_bucket(&ds, 0);
+        create_bucket(&ds, 1);
+        let bucket: Bucket = serde_json::from_value(serde_json::json!({
+            "id": "bucket-2-synced-from-device-2",
+            "type": "test",
+            "hostname": "device-2",
+            "client": "test",
+        }))
+        .unwrap();
+        ds.create_bucket(&bucket).unwrap();
+
+        // Pulled buckets are never pushed, so aren't missing from the list
+        let sync_spec = SyncSpec {
+            buckets: Some(vec!["bucket-0".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(unsynced_buckets(&ds, &sync_spec).unwrap(), vec!["bucket-1"]);
+
+        let sync_spec = SyncSpec {
+            buckets: Some(vec!["bucket-0".to_string()]),
+            buckets_regex: Some(Regex::new("^(?:bucket-1)$").unwrap()),
+            ..Default::default()
+        };
+        assert!(unsynced_buckets(&ds, &sync_spec).unwrap().is_empty());
+
+        // Buckets of excluded types aren't synced either
+        let sync_spec = SyncSpec {
+            exclude_types: vec!["test".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            unsynced_buckets(&ds, &sync_spec).unwrap(),
+            vec!["bucket-0", "bucket-1"]
+        );
+    }
+
+    #[test]
+    fn test_origin_aliases() {
+        // A device which got a new device ID, with its history in the folder of the old one
+        let ds_old = Datastore::new_in_memory(false);
+        let ds_new = Datastore::new_in_memory(false);
+        let ds_dest = Datastore::new_in_memory(false);
+        let bucket: Bucket = serde_json::from_value(serde_json::json!({
+            "id": "bucket-laptop",
+            "type": "test",
+            "hostname": "laptop",
+            "client": "test",
+        }))
+        .unwrap();
+        ds_old.create_bucket(&bucket).unwrap();
+        ds_new.create_bucket(&bucket).unwrap();
+        let now = Utc::now();
+        let insert = |ds: &Datastore, ago: Duration| {
+            let mut event = create_event("0");
+            event.timestamp = now - ago;
+            event.duration = Duration::minutes(1);
+            ds.insert_events("bucket-laptop", &[event]).unwrap();
+        };
+        for days in [12, 11, 10] {
+            insert(&ds_old, Duration::days(days));
+        }
+        for days in [2, 1] {
+            insert(&ds_new, Duration::days(days));
+        }
+
+        let sync_spec = SyncSpec {
+            origin_aliases: HashMap::from([("device-old".to_string(), "device-new".to_string())]),
+            ..Default::default()
+        };
+        let mut state = SyncState::default();
+        let pull = |ds_from: &Datastore, did: &str, state: &mut SyncState| {
+            aw_sync::sync_datastores(
+                ds_from,
+                &ds_dest,
+                false,
+                Some(did),
+                &sync_spec,
+                state,
+                &NoProgress,
+            )
+            .unwrap()
+        };
+        // The new device is pulled first, the history is still pulled in before its events
+        pull(&ds_new, "device-new", &mut state);
+        pull(&ds_old, "device-old", &mut state);
+        let merged = "bucket-laptop-synced-from-device-new";
+        assert_eq!(ds_dest.get_event_count(merged, None, None).unwrap(), 5);
+        assert!(ds_dest
+            .get_bucket("bucket-laptop-synced-from-device-old")
+            .is_err());
+
+        // Origins resume separately, even when the ranges overlap
+        insert(&ds_old, Duration::hours(36));
+        insert(&ds_new, Duration::hours(12));
+        assert_eq!(pull(&ds_old, "device-old", &mut state).events_inserted, 1);
+        assert_eq!(pull(&ds_new, "device-new", &mut state).events_inserted, 1);
+        assert_eq!(pull(&ds_old, "device-old", &mut state).events_inserted, 0);
+        assert_eq!(ds_dest.get_event_count(merged, None, None).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_tombstone_origin_aliases() {
+        let state = init_teststate();
+        let ds_staging = Datastore::new_in_memory(false);
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, &bucket_id, 3);
+
+        let sync_spec = SyncSpec {
+            propagate_deletions: true,
+            origin_aliases: HashMap::from([("device-0".to_string(), "device-new".to_string())]),
+            ..SyncSpec::default()
+        };
+        let mut sync_state = SyncState::default();
+        for (ds_from, ds_to, is_push) in [
+            (&state.ds_src, &ds_staging, true),
+            (&ds_staging, &state.ds_dest, false),
+        ] {
+            aw_sync::sync_datastores(
+                ds_from,
+                ds_to,
+                is_push,
+                Some("device-0"),
+                &sync_spec,
+                &mut sync_state,
+                &NoProgress,
+            )
+            .unwrap();
+        }
+        let merged = "bucket-0-synced-from-device-new";
+        let merged_state = format!("{merged}@device-0");
+        assert!(sync_state.buckets.contains_key(&merged_state));
+
+        // The tombstone of the aliased origin deletes the bucket it was merged into
+        state.ds_src.delete_bucket(&bucket_id).unwrap();
+        record_deletions(&state.ds_src, &ds_staging, &sync_spec, &mut sync_state).unwrap();
+        let deleted =
+            apply_tombstones(&ds_staging, &state.ds_dest, &sync_spec, &mut sync_state, "").unwrap();
+        assert_eq!(deleted, vec![merged.to_string()]);
+        assert!(state.ds_dest.get_buckets().unwrap().is_empty());
+        assert!(!sync_state.buckets.contains_key(&merged_state));
+    }
+
+    #[test]
+    fn test_three_device_topology() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-topology-{}", std::process::id()));
+        let devices: Vec<(String, Arc<Datastore>)> = (0..3)
+            .map(|n| {
+                let ds = Arc::new(Datastore::new_in_memory(false));
+                let bucket_id = create_bucket(&ds, n);
+                create_events(&ds, &bucket_id, n as i64 + 1);
+                (format!("device-{n}"), ds)
+            })
+            .collect();
+        let sync_spec = SyncSpec {
+            path: sync_dir.clone(),
+            all_buckets: true,
+            ..Default::default()
+        };
+        let sync_round = || {
+            for (device_id, ds) in &devices {
+                aw_sync::sync_run_datastore(
+                    ds.clone(),
+                    device_id,
+                    &sync_spec,
+                    aw_sync::SyncMode::Both,
+                    &NoProgress,
+                )
+                .unwrap();
+            }
+        };
+        let bucket_ids = |ds: &Datastore| {
+            let mut ids: Vec<String> = ds.get_buckets().unwrap().into_keys().collect();
+            ids.sort();
+            ids
+        };
+
+        // Devices pull what the others pushed in the round before, so everything converges after
+        // two rounds
+        sync_round();
+        sync_round();
+        for (n, (_, ds)) in devices.iter().enumerate() {
+            let mut expected: Vec<String> = (0..3)
+                .map(|m| {
+                    if m == n {
+                        format!("bucket-{m}")
+                    } else {
+                        format!("bucket-{m}-synced-from-device-{m}")
+                    }
+                })
+                .collect();
+            expected.sort();
+            // Pulled buckets are never pushed on, so none are chained
+            assert_eq!(bucket_ids(ds), expected);
+            for m in (0..3).filter(|m| *m != n) {
+                let pulled = format!("bucket-{m}-synced-from-device-{m}");
+                let count = ds.get_event_count(&pulled, None, None).unwrap();
+                assert_eq!(count, m as i64 + 1, "{pulled} on device-{n}");
+            }
+        }
+        for (device_id, _) in &devices {
+            let dbfile = sync_dir.join(device_id).join(format!("{device_id}.db"));
+            let ds_staging = create_file_datastore(&dbfile);
+            assert_eq!(ds_staging.get_buckets().unwrap().len(), 1);
+            ds_staging.close();
+        }
+
+        // Another round resumes where the last one stopped, without syncing anything again
+        sync_round();
+        for (n, (_, ds)) in devices.iter().enumerate() {
+            let total: i64 = bucket_ids(ds)
+                .iter()
+                .map(|id| ds.get_event_count(id, None, None).unwrap())
+                .sum();
+            assert_eq!(total, 6, "events on device-{n}");
+        }
+        fs::remove_dir_all(&sync_dir).unwrap();
+    }
+
+    #[test]
+    fn test_pending_counts() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-pending-{}", std::process::id()));
+        let remote_dir = sync_dir.join("device-1");
+        fs::create_dir_all(&remote_dir).unwrap();
+        let remote_dbfile = remote_dir.join("device-1.db");
+        // Events are only committed for others to see once the datastore is closed
+        let insert_remote = |n| {
+            let ds_remote = create_file_datastore(&remote_dbfile);
+            let bucket_remote = create_bucket(&ds_remote, 1);
+            create_events(&ds_remote, &bucket_remote, n);
+            ds_remote.close();
+        };
+        insert_remote(3);
+
+        let ds_local = Arc::new(Datastore::new_in_memory(false));
+        let bucket_local = create_bucket(&ds_local, 0);
+        create_events(&ds_local, &bucket_local, 2);
+        let runner = || {
+            aw_sync::SyncRunner::from_datastore(ds_local.clone(), "device-0", &sync_dir).configure(
+                |spec| {
+                    spec.all_buckets = true;
+                    spec.clock_skew_tolerance = Duration::zero();
+                },
+            )
+        };
+        let pending = |runner: aw_sync::SyncRunner| -> Vec<(String, i64)> {
+            let counts = runner.pending_counts().unwrap();
+            counts.into_iter().map(|c| (c.remote, c.events)).collect()
+        };
+
+        // Everything is pending before the first pass, even without a staging datastore
+        let expected = vec![("device-1".to_string(), 3), ("device-0".to_string(), 2)];
+        assert_eq!(pending(runner()), expected);
+
+        runner().run().unwrap();
+        let expected = vec![("device-1".to_string(), 0), ("device-0".to_string(), 0)];
+        assert_eq!(pending(runner()), expected);
+
+        insert_remote(1);
+        create_events(&ds_local, &bucket_local, 4);
+        let expected = vec![("device-1".to_string(), 1), ("device-0".to_string(), 4)];
+        assert_eq!(pending(runner()), expected);
+
+        fs::remove_dir_all(&sync_dir).unwrap();
+    }
+
+    #[test]
+    fn test_extended_length_path() {
+        // Device IDs can be long enough on their own for the staging path to exceed MAX_PATH
+        let device_id = "d".repeat(300);
+        let staging = format!("C:\\Users\\me\\sync/{device_id}/{device_id}.db");
+        assert!(staging.len() > MAX_PATH);
+        assert_eq!(
+            extended_length_path(&staging),
+            Some(format!(
+                "\\\\?\\C:\\Users\\me\\sync\\{device_id}\\{device_id}.db"
+            ))
+        );
+
+        assert_eq!(
+            extended_length_path(r"\\nas\sync\device"),
+            Some(r"\\?\UNC\nas\sync\device".to_string())
+        );
+        // Already extended, or relative
+        assert_eq!(extended_length_path(r"\\?\C:\sync"), None);
+        assert_eq!(extended_length_path(r"sync\device"), None);
+        assert_eq!(extended_length_path("/home/me/sync"), None);
+    }
+
+    #[test]
+    fn test_only_remote() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-only-remote-{}", std::process::id()));
+        let sync_spec = SyncSpec {
+            path: sync_dir.clone(),
+            all_buckets: true,
+            ..Default::default()
+        };
+        for n in 1..3 {
+            let ds = Arc::new(Datastore::new_in_memory(false));
+            let bucket_id = create_bucket(&ds, n);
+            create_events(&ds, &bucket_id, 2);
+            let device_id = format!("device-{n}");
+            aw_sync::sync_run_datastore(
+                ds,
+                &device_id,
+                &sync_spec,
+                aw_sync::SyncMode::Push,
+                &NoProgress,
+            )
+            .unwrap();
+        }
+
+        let ds = Arc::new(Datastore::new_in_memory(false));
+        let pull = |only_remote: &str| {
+            let sync_spec = SyncSpec {
+                path: sync_dir.clone(),
+                all_buckets: true,
+                only_remote: Some(only_remote.to_string()),
+                ..Default::default()
+            };
+            aw_sync::sync_run_datastore(
+                ds.clone(),
+                "device-0",
+                &sync_spec,
+                aw_sync::SyncMode::Pull,
+                &NoProgress,
+            )
+        };
+        pull("device-2").unwrap();
+        let buckets: Vec<String> = ds.get_buckets().unwrap().into_keys().collect();
+        assert_eq!(buckets, vec!["bucket-2-synced-from-device-2".to_string()]);
+
+        // A mistyped device ID fails instead of pulling nothing
+        match pull("device-3") {
+            Err(SyncError::Config(msg)) => assert!(msg.contains("device-1, device-2"), "{msg}"),
+            _ => panic!("expected a config error"),
+        }
+        fs::remove_dir_all(&sync_dir).unwrap();
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-schema-{}", std::process::id()));
+        let dbfile = sync_dir.join("device-1").join("device-1.db");
+        let ds = Arc::new(Datastore::new_in_memory(false));
+        let bucket_id = create_bucket(&ds, 1);
+        create_events(&ds, &bucket_id, 1);
+        assert_eq!(read_schema_version(&ds).unwrap(), None);
+
+        // Stamped when pushing, and read back from the staging datastore
+        let sync_spec = SyncSpec {
+            path: sync_dir.clone(),
+            all_buckets: true,
+            ..Default::default()
+        };
+        aw_sync::sync_run_datastore(
+            ds,
+            "device-1",
+            &sync_spec,
+            aw_sync::SyncMode::Push,
+            &NoProgress,
+        )
+        .unwrap();
+        let ds_staging = create_file_datastore(&dbfile);
+        assert_eq!(
+            read_schema_version(&ds_staging).unwrap(),
+            Some(SYNC_VERSION)
+        );
+        assert!(check_schema_version(&ds_staging).is_ok());
+
+        // Remotes of newer versions are incompatible, even without their marker file
+        let newer = (SYNC_VERSION + 1).to_string();
+        ds_staging
+            .insert_key_value(SCHEMA_VERSION_KEY, &newer)
+            .unwrap();
+        assert!(matches!(
+            check_schema_version(&ds_staging),
+            Err(SyncError::Incompatible { .. })
+        ));
+        ds_staging.close();
+        fs::remove_dir_all(&sync_dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_fields() {
+        let state = init_teststate();
+        let bucket_ids: Vec<String> = (0..2).map(|n| create_bucket(&state.ds_src, n)).collect();
+        let mut event = create_event("0");
+        event.duration = Duration::seconds(30);
+        event.data = serde_json::from_value(serde_json::json!({
+            "app": "firefox",
+            "title": "ActivityWatch",
+            "extra": {"tabs": ["a", "b", "c"]},
+        }))
+        .unwrap();
+        for bucket_id in &bucket_ids {
+            state
+                .ds_src
+                .insert_events(bucket_id, &[event.clone()])
+                .unwrap();
+        }
+
+        // An empty list drops all data
+        let sync_spec = SyncSpec {
+            keep_fields: HashMap::from([
+                (
+                    bucket_ids[0].clone(),
+                    vec!["app".to_string(), "title".to_string()],
+                ),
+                (bucket_ids[1].clone(), vec![]),
+            ]),
+            ..Default::default()
+        };
+        // Synced twice, as events with dropped fields need to be deduplicated too
+        for _ in 0..2 {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &sync_spec,
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+        }
+
+        let synced = |n: i32| {
+            let bucket_id = format!("bucket-{n}-synced-from-device-{n}");
+            let synced = state
+                .ds_dest
+                .get_events(&bucket_id, None, None, None)
+                .unwrap();
+            assert_eq!(synced.len(), 1);
+            // The timeline is preserved
+            assert_eq!(synced[0].timestamp, event.timestamp);
+            assert_eq!(synced[0].duration, event.duration);
+            serde_json::Value::Object(synced[0].data.clone())
+        };
+        assert_eq!(
+            synced(0),
+            serde_json::json!({"app": "firefox", "title": "ActivityWatch"})
+        );
+        assert_eq!(synced(1), serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_get_buckets_paged() {
+        let ds = Datastore::new_in_memory(false);
+        for n in 0..5 {
+            create_bucket(&ds, n);
+        }
+        let pages: Vec<Vec<String>> = AccessMethod::get_buckets_paged(&ds, 2)
+            .map(|page| page.unwrap().into_iter().map(|b| b.id).collect())
+            .collect();
+        assert_eq!(
+            pages,
+            vec![
+                vec!["bucket-0", "bucket-1"],
+                vec!["bucket-2", "bucket-3"],
+                vec!["bucket-4"],
+            ]
+        );
+        // A full last page is the last one too
+        let pages: Vec<usize> = AccessMethod::get_buckets_paged(&ds, 5)
+            .map(|page| page.unwrap().len())
+            .collect();
+        assert_eq!(pages, vec![5]);
+    }
+
+    #[test]
+    fn test_pseudonymize() {
+        // Stable for a salt, and different for other IDs and salts
+        let alias = pseudonym("device-1", "salt");
+        assert_eq!(alias, pseudonym("device-1", "salt"));
+        assert_ne!(alias, pseudonym("device-2", "salt"));
+        assert_ne!(alias, pseudonym("device-1", "pepper"));
+        assert!(!alias.contains("device"));
+        validate_device_id(&alias).unwrap();
+
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-pseudonym-{}", std::process::id()));
+        let ds_pushing = Arc::new(Datastore::new_in_memory(false));
+        let bucket_id = create_bucket(&ds_pushing, 1);
+        create_events(&ds_pushing, &bucket_id, 2);
+        let ds_pulling = Arc::new(Datastore::new_in_memory(false));
+        create_bucket(&ds_pulling, 0);
+        let pseudonymizing = SyncSpec {
+            path: sync_dir.clone(),
+            all_buckets: true,
+            pseudonym_salt: Some("salt".to_string()),
+            ..Default::default()
+        };
+        let sync_spec = SyncSpec {
+            path: sync_dir.clone(),
+            all_buckets: true,
+            ..Default::default()
+        };
+        // Pushed twice, as the device has to keep its pseudonym across runs
+        for _ in 0..2 {
+            for (ds, device_id, spec) in [
+                (&ds_pushing, "device-1", &pseudonymizing),
+                (&ds_pulling, "device-0", &sync_spec),
+            ] {
+                aw_sync::sync_run_datastore(
+                    ds.clone(),
+                    device_id,
+                    spec,
+                    aw_sync::SyncMode::Both,
+                    &NoProgress,
+                )
+                .unwrap();
+            }
+        }
+
+        // Neither the device ID nor the hostname is in the sync folder, only the pseudonym
+        assert!(!sync_dir.join("device-1").exists());
+        let ds_staging = create_file_datastore(&sync_dir.join(&alias).join(format!("{alias}.db")));
+        let staged = ds_staging.get_buckets().unwrap();
+        assert_eq!(staged.len(), 1);
+        let staged = &staged[&bucket_id];
+        assert_eq!(staged.hostname, alias);
+        assert_eq!(staged.data["$aw.sync.origin"], serde_json::json!(alias));
+        assert_eq!(
+            staged.data["$aw.sync.origin_device"],
+            serde_json::json!(alias)
+        );
+        ds_staging.close();
+
+        // The other device pulls it as coming from the pseudonym, once
+        let pulled = format!("{bucket_id}-synced-from-{alias}");
+        assert_eq!(ds_pulling.get_bucket(&pulled).unwrap().hostname, alias);
+        assert_eq!(ds_pulling.get_event_count(&pulled, None, None).unwrap(), 2);
+        // Pulling isn't pseudonymized, device-1 has the bucket of device-0 as it is
+        ds_pushing
+            .get_bucket("bucket-0-synced-from-device-0")
+            .unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair_naming() {
+        let ds = Datastore::new_in_memory(false);
+        let create = |id: &str, hostname: &str, data: serde_json::Value| {
+            let bucket: Bucket = serde_json::from_value(serde_json::json!({
+                "id": id,
+                "type": "test",
+                "hostname": hostname,
+                "client": "test",
+                "data": data,
+            }))
+            .unwrap();
+            ds.create_bucket(&bucket).unwrap();
+        };
+        // Pulled without the origin, suffixed with the hostname or the device ID
+        create(
+            "bucket-1-synced-from-laptop",
+            "laptop",
+            serde_json::json!({}),
+        );
+        create(
+            "bucket-2-synced-from-3ec4a1f2",
+            "desktop",
+            serde_json::json!({"$aw.sync.origin_device": "3ec4a1f2"}),
+        );
+        // Suffixed twice
+        let repeated = "bucket-3-synced-from-phone-synced-from-phone";
+        create(
+            repeated,
+            "phone",
+            serde_json::json!({"$aw.sync.origin": "phone"}),
+        );
+        create_events(&ds, repeated, 3);
+        // Already fine, and not pulled
+        create(
+            "bucket-4-synced-from-tablet",
+            "tablet",
+            serde_json::json!({"$aw.sync.origin": "tablet"}),
+        );
+        create_bucket(&ds, 0);
+        let mut state = SyncState::default();
+        state.buckets.insert(
+            repeated.to_string(),
+            BucketSyncState {
+                end: Utc::now(),
+                eventcount: 3,
+                flushed: None,
+            },
+        );
+
+        let repaired = |repairs: Vec<NamingRepair>| {
+            repairs
+                .into_iter()
+                .map(|r| (r.bucket_id, r.renamed_to, r.origin))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            (
+                "bucket-1-synced-from-laptop".to_string(),
+                None,
+                Some("laptop".to_string()),
+            ),
+            (
+                "bucket-2-synced-from-3ec4a1f2".to_string(),
+                None,
+                Some("desktop".to_string()),
+            ),
+            (
+                repeated.to_string(),
+                Some("bucket-3-synced-from-phone".to_string()),
+                None,
+            ),
+        ];
+        let dry_run = repair_naming(&ds, true, &mut state).unwrap();
+        assert_eq!(repaired(dry_run), expected);
+        assert!(ds.get_bucket(repeated).is_ok());
+        assert!(!ds
+            .get_bucket("bucket-1-synced-from-laptop")
+            .unwrap()
+            .data
+            .contains_key("$aw.sync.origin"));
+
+        assert_eq!(
+            repaired(repair_naming(&ds, false, &mut state).unwrap()),
+            expected
+        );
+        for (bucket_id, origin) in [
+            ("bucket-1-synced-from-laptop", "laptop"),
+            ("bucket-2-synced-from-3ec4a1f2", "desktop"),
+            ("bucket-3-synced-from-phone", "phone"),
+        ] {
+            let bucket = ds.get_bucket(bucket_id).unwrap();
+            assert_eq!(bucket.data["$aw.sync.origin"], serde_json::json!(origin));
+        }
+        assert!(ds.get_bucket(repeated).is_err());
+        let renamed = "bucket-3-synced-from-phone";
+        assert_eq!(ds.get_event_count(renamed, None, None).unwrap(), 3);
+        assert!(state.buckets.contains_key(renamed));
+        assert!(!state.buckets.contains_key(repeated));
+
+        // Nothing is left to repair
+        assert!(repair_naming(&ds, false, &mut state).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pulsetime_override() {
+        let ds_src = Datastore::new_in_memory(false);
+        let bucket_id = create_bucket(&ds_src, 0);
+        // Contiguous events with the same data
+        let start = Utc::now() - Duration::minutes(10);
+        let events: Vec<Event> = (0..4)
+            .map(|i| Event {
+                id: None,
+                timestamp: start + Duration::seconds(60 * i),
+                duration: Duration::seconds(60),
+                data: serde_json::from_value(serde_json::json!({"app": "editor"})).unwrap(),
+            })
+            .collect();
+        ds_src.insert_events(&bucket_id, &events).unwrap();
+
+        let synced_count = |pulsetime_override: f64| {
+            let ds_dest = Datastore::new_in_memory(false);
+            // Takes precedence over the pulsetimes of buckets
+            let sync_spec = SyncSpec {
+                pulsetime: HashMap::from([(bucket_id.clone(), 120.0)]),
+                pulsetime_override: Some(pulsetime_override),
+                ..Default::default()
+            };
+            aw_sync::sync_datastores(
+                &ds_src,
+                &ds_dest,
+                false,
+                None,
+                &sync_spec,
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+            ds_dest
+                .get_event_count("bucket-0-synced-from-device-0", None, None)
+                .unwrap()
+        };
+        assert_eq!(synced_count(0.0), 4);
+        assert_eq!(synced_count(3600.0), 1);
+    }
+
+    #[test]
+    fn test_count_decreased() {
+        let pull_into_cleared = |strict: bool| {
+            let state = init_teststate();
+            let bucket_id = create_bucket(&state.ds_src, 0);
+            create_events(&state.ds_src, &bucket_id, 3);
+            let mut sync_state = SyncState::default();
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &SyncSpec::default(),
+                &mut sync_state,
+                &NoProgress,
+            )
+            .unwrap();
+
+            // The 3 events synced before are deleted while syncing 2 new ones
+            create_events(&state.ds_src, &bucket_id, 2);
+            let ds_dest = FaultyDatastore {
+                cleared_bucket: Some("bucket-0-synced-from-device-0".to_string()),
+                ..FaultyDatastore::new(state.ds_dest)
+            };
+            let sync_spec = SyncSpec {
+                strict,
+                ..SyncSpec::default()
+            };
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &ds_dest,
+                false,
+                None,
+                &sync_spec,
+                &mut sync_state,
+                &NoProgress,
+            )
+            .unwrap()
+            .buckets
+            .remove(0)
+        };
+
+        // Only logged by default
+        let summary = pull_into_cleared(false);
+        assert_eq!(summary.error, None);
+        assert_eq!(summary.events, 0);
+
+        // Syncing the bucket fails in strict mode
+        let summary = pull_into_cleared(true);
+        let error = summary.error.unwrap();
+        assert!(error.contains("dropped from 3 to 1"), "{error}");
+    }
+
+    #[test]
+    fn test_bucket_flags() {
+        let state = init_teststate();
+        let flag = |bucket_id: &str, enabled: bool| {
+            let mut data = state.ds_src.get_bucket(bucket_id).unwrap().data;
+            data.insert(SYNC_ENABLED_KEY.to_string(), serde_json::json!(enabled));
+            state.ds_src.update_bucket_data(bucket_id, data).unwrap();
+        };
+        for n in 0..4 {
+            let bucket_id = create_bucket(&state.ds_src, n);
+            create_events(&state.ds_src, &bucket_id, 1);
+        }
+        let sync_spec = SyncSpec {
+            buckets: Some(vec!["bucket-2".to_string()]),
+            bucket_flags: true,
+            ..SyncSpec::default()
+        };
+        let synced = || {
+            let stats = aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                false,
+                None,
+                &sync_spec,
+                &mut SyncState::default(),
+                &NoProgress,
+            )
+            .unwrap();
+            let mut synced: Vec<String> = stats.buckets.into_iter().map(|b| b.bucket_id).collect();
+            synced.sort();
+            synced
+        };
+
+        // Without any flags, the list of buckets is used
+        assert_eq!(synced(), vec!["bucket-2-synced-from-device-2"]);
+
+        // Once a bucket has the flag, only the ones flagged with true are synced
+        flag("bucket-0", true);
+        flag("bucket-1", false);
+        assert_eq!(synced(), vec!["bucket-0-synced-from-device-0"]);
+        assert_eq!(
+            unsynced_buckets(&state.ds_src, &sync_spec).unwrap(),
+            vec!["bucket-1", "bucket-2", "bucket-3"]
+        );
+    }
+
+    #[test]
+    fn test_bucket_creation_failed() {
+        let state = init_teststate();
+        for n in 0..2 {
+            let bucket_id = create_bucket(&state.ds_src, n);
+            create_events(&state.ds_src, bucket_id.as_str(), 3);
+        }
+        let ds_dest = FaultyDatastore {
+            uncreatable_bucket: Some("bucket-0-synced-from-device-0".to_string()),
+            ..FaultyDatastore::new(state.ds_dest)
+        };
+
+        let mut stats = aw_sync::sync_datastores(
+            &state.ds_src,
+            &ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+            &mut SyncState::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        // The bucket which can't be created fails without failing the other bucket
+        stats.buckets.sort_by(|a, b| a.bucket_id.cmp(&b.bucket_id));
+        assert_eq!(stats.buckets[0].bucket_id, "bucket-0-synced-from-device-0");
+        assert!(stats.buckets[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("rejected"));
+        assert_eq!(stats.buckets[1].error, None);
+        assert_eq!(stats.buckets[1].events, 3);
+    }
+
+    #[test]
+    fn test_client_bucket_deleted() {
+        // Like an aw-server on which the bucket was deleted while syncing
+        let body = serde_json::json!({ "message": "The requested bucket does not exist" });
+        let (port, _) = serve("404 Not Found", body.to_string());
+        let client = aw_client_rust::AwClient::new("127.0.0.1", &port, "aw-sync-test");
+
+        assert!(matches!(
+            AccessMethod::get_bucket(&client, "bucket-0"),
+            Err(DatastoreError::NoSuchBucket(id)) if id == "bucket-0"
+        ));
+        assert!(AccessMethod::get_buckets(&client).is_err());
+        assert!(AccessMethod::get_events(&client, "bucket-0", None, None, None).is_err());
+        assert!(AccessMethod::get_event_count_range(&client, "bucket-0", None, None).is_err());
+        let bucket: Bucket = serde_json::from_value(serde_json::json!({
+            "id": "bucket-0",
+            "type": "test",
+            "hostname": "device-0",
+            "client": "test"
+        }))
+        .unwrap();
+        assert!(AccessMethod::create_bucket(&client, &bucket).is_err());
+    }
+
+    #[test]
+    fn test_unreadable_remote_skipped() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-unreadable-{}", std::process::id()));
+        for device in ["device-1", "device-2"] {
+            fs::create_dir_all(sync_dir.join(device)).unwrap();
+        }
+        let ds_remote = create_file_datastore(&sync_dir.join("device-1").join("device-1.db"));
+        let bucket_remote = create_bucket(&ds_remote, 1);
+        create_events(&ds_remote, &bucket_remote, 3);
+        ds_remote.close();
+        let unreadable = sync_dir.join("device-2").join("device-2.db");
+        fs::write(&unreadable, "not a datastore").unwrap();
+
+        assert!(create_datastore(&unreadable).is_err());
+
+        let ds_local = Arc::new(Datastore::new_in_memory(false));
+        let runner = || {
+            aw_sync::SyncRunner::from_datastore(ds_local.clone(), "device-0", &sync_dir).configure(
+                |spec| {
+                    spec.all_buckets = true;
+                    spec.clock_skew_tolerance = Duration::zero();
+                },
+            )
+        };
+        let pending = runner().pending_counts().unwrap();
+        let remotes: Vec<&str> = pending.iter().map(|c| c.remote.as_str()).collect();
+        assert_eq!(remotes, vec!["device-1"]);
+        runner().run().unwrap();
+        let remotes = list_remotes(&sync_dir, "device-0").unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        let buckets = ds_local.get_buckets().unwrap();
+        let synced = &buckets[&format!("{bucket_remote}-synced-from-device-1")];
+        assert_eq!(ds_local.get_event_count(&synced.id, None, None).unwrap(), 3);
+        // The unreadable remote is still listed, only without its last event
+        let devices: Vec<&str> = remotes.iter().map(|r| r.device.as_str()).collect();
+        assert_eq!(devices, vec!["device-1", "device-2"]);
+        assert_eq!(remotes[1].last_event, None);
+    }
+
+    #[test]
+    fn test_parallel_pull_state() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-parallel-{}", std::process::id()));
+        let insert_remote = |n, count| {
+            let remote_dir = sync_dir.join(format!("device-{n}"));
+            fs::create_dir_all(&remote_dir).unwrap();
+            let ds_remote = create_file_datastore(&remote_dir.join(format!("device-{n}.db")));
+            let bucket_remote = create_bucket(&ds_remote, n);
+            create_events(&ds_remote, &bucket_remote, count);
+            ds_remote.close();
+        };
+        insert_remote(1, 3);
+        insert_remote(2, 2);
+
+        let ds_local = Arc::new(Datastore::new_in_memory(false));
+        let runner = || {
+            aw_sync::SyncRunner::from_datastore(ds_local.clone(), "device-0", &sync_dir).configure(
+                |spec| {
+                    spec.all_buckets = true;
+                    spec.clock_skew_tolerance = Duration::zero();
+                    // Both remotes are pulled from in the same chunk
+                    spec.pull_concurrency = 2;
+                },
+            )
+        };
+        runner().run().unwrap();
+        // Only the first remote changes, the second one's unchanged state must not replace it
+        insert_remote(1, 4);
+        runner().run().unwrap();
+
+        let dbfile = sync_dir.join("device-0").join("device-0.db");
+        let state = SyncState::load(&aw_sync::state_file(&dbfile).unwrap());
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        // The state of each bucket is at the end of the events synced into it
+        let synced = |bucket_id: &str| {
+            let end = ds_local.get_bucket(bucket_id).unwrap().metadata.end;
+            assert_eq!(Some(state.buckets[bucket_id].end), end);
+            ds_local.get_event_count(bucket_id, None, None).unwrap()
+        };
+        assert_eq!(synced("bucket-1-synced-from-device-1"), 7);
+        assert_eq!(synced("bucket-2-synced-from-device-2"), 2);
+    }
+
+    #[test]
+    fn test_local_state_dir() {
+        let sync_dir =
+            std::env::temp_dir().join(format!("aw-sync-test-state-dir-{}", std::process::id()));
+        let device_dir = sync_dir.join("device-0");
+        fs::create_dir_all(&device_dir).unwrap();
+        let dbfile = device_dir.join("device-0.db");
+
+        // State of older versions is moved out of the sync folder
+        let mut legacy = SyncState::default();
+        legacy.remotes.insert("device-1".to_string(), Utc::now());
+        legacy.save(&dbfile.with_extension("state.json")).unwrap();
+        let state_path = aw_sync::state_file(&dbfile).unwrap();
+        assert!(!dbfile.with_extension("state.json").exists());
+        assert_eq!(SyncState::load(&state_path).remotes, legacy.remotes);
+
+        let ds_local = Arc::new(Datastore::new_in_memory(false));
+        let bucket_id = create_bucket(&ds_local, 0);
+        create_events(&ds_local, &bucket_id, 3);
+        aw_sync::SyncRunner::from_datastore(ds_local, "device-0", &sync_dir)
+            .configure(|spec| spec.all_buckets = true)
+            .run()
+            .unwrap();
+        let mut synced: Vec<String> = fs::read_dir(&device_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| !name.starts_with("device-0.db"))
+            .collect();
+        synced.sort();
+        let state_dir = aw_sync::local_state_dir(&dbfile).unwrap();
+        fs::remove_dir_all(&sync_dir).unwrap();
+
+        // The local state and lock aren't synced, only what other devices need
+        assert_eq!(synced, vec!["device-0.version.json"]);
+        assert!(!state_dir.starts_with(&sync_dir));
+        assert_eq!(state_path.parent(), Some(state_dir.as_path()));
+        assert!(SyncState::load(&state_path)
+            .remotes
+            .contains_key("device-1"));
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_events_paged() {
+        let events: Vec<serde_json::Value> = (1..=5)
+            .map(|id| {
+                serde_json::json!({
+                    "id": id,
+                    "timestamp": format!("2020-01-01T00:00:0{id}Z"),
+                    "duration": 1.0,
+                    "data": {}
+                })
+            })
+            .collect();
+        let param = |request: &str, name: &str| -> Option<usize> {
+            let query = request.split(' ').nth(1)?.split('?').nth(1)?;
+            let value = query
+                .split('&')
+                .find_map(|p| p.strip_prefix(&format!("{name}=")))?;
+            value.parse().ok()
+        };
+        let batches = |paging: bool| {
+            let events = events.clone();
+            let requests = Arc::new(AtomicUsize::new(0));
+            let answered = requests.clone();
+            let port = serve_with(move |request| {
+                answered.fetch_add(1, Ordering::SeqCst);
+                if !request.contains("/events/page") {
+                    // All at once, newest first
+                    let newest_first: Vec<_> = events.iter().rev().cloned().collect();
+                    return ("200 OK", serde_json::Value::from(newest_first).to_string());
+                }
+                if !paging {
+                    return ("404 Not Found", "{}".to_string());
+                }
+                let after = param(request, "after_id").unwrap_or(0);
+                let limit = param(request, "limit").unwrap();
+                let page: Vec<_> = events.iter().skip(after).take(limit).cloned().collect();
+                ("200 OK", serde_json::Value::from(page).to_string())
+            });
+            let client = aw_client_rust::AwClient::new("127.0.0.1", &port, "aw-sync-test");
+            let batches: Vec<Vec<Option<i64>>> = client
+                .get_events_batched("bucket-0", None, None, 2)
+                .map(|batch| batch.unwrap().iter().map(|e| e.id).collect())
+                .collect();
+            (batches, requests.load(Ordering::SeqCst))
+        };
+
+        // Paged through oldest first, a request per page
+        let expected = vec![
+            vec![Some(1), Some(2)],
+            vec![Some(3), Some(4)],
+            vec![Some(5)],
+        ];
+        assert_eq!(batches(true), (expected.clone(), 3));
+        // Servers which can't page are asked for all events at once instead
+        assert_eq!(batches(false), (expected, 2));
     }
 }